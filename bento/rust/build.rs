@@ -11,6 +11,8 @@ const INCLUDED_TYPES: &[&str] = &[
     "fuse_init_in",
     "fuse_init_out",
     "fuse_forget_in",
+    "fuse_forget_one",
+    "fuse_batch_forget_in",
     "fuse_ioctl_in",
     "fuse_ioctl_out",
     "fuse_attr",
@@ -42,6 +44,12 @@ const INCLUDED_TYPES: &[&str] = &[
     "fuse_poll_in",
     "fuse_poll_out",
     "fuse_fallocate_in",
+    "fuse_copy_file_range_in",
+    "fuse_notify_code",
+    "fuse_notify_inval_inode_out",
+    "fuse_notify_inval_entry_out",
+    "fuse_notify_delete_out",
+    "fuse_notify_store_out",
     "fuse_access_in",
     "fuse_setxattr_in",
     "fuse_rename2_in",
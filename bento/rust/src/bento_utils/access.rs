@@ -0,0 +1,79 @@
+use libc;
+
+use crate::fuse::*;
+
+use super::consts::{CAP_DAC_OVERRIDE, CAP_DAC_READ_SEARCH, CAP_FOWNER};
+
+/// Check if the caller of `req` may access a file with the attributes `attr`.
+///
+/// This implements the POSIX permission check done by the kernel for filesystems mounted with
+/// 'default_permissions', and can be used to implement `bento_access` or to check permissions in
+/// other requests. The owner, group or other permission bits are used depending on whether the
+/// caller is the owner of the file or a member of its group, including through its supplementary
+/// groups. A caller with `CAP_DAC_OVERRIDE` may read and write any file, and execute any file
+/// that has at least one execute bit set. A caller with `CAP_DAC_READ_SEARCH` may read any file
+/// and search any directory.
+///
+/// Returns `EACCES` if access is denied.
+///
+/// Arguments:
+/// * `attr: &FileAttr` - Attributes of the file being accessed.
+/// * `req: &Request` - Request data structure of the caller.
+/// * `mask: u32` - Access mask, a combination of `R_OK`, `W_OK` and `X_OK`, or `F_OK`.
+pub fn check_access(attr: &FileAttr, req: &Request, mask: u32) -> Result<(), i32> {
+    let mask = mask & (libc::R_OK | libc::W_OK | libc::X_OK) as u32;
+    if mask == libc::F_OK as u32 {
+        return Ok(());
+    }
+
+    let perm = attr.perm as u32;
+    let granted = if req.uid() == attr.uid {
+        (perm >> 6) & 0o7
+    } else if in_group(req, attr.gid) {
+        (perm >> 3) & 0o7
+    } else {
+        perm & 0o7
+    };
+    if mask & !granted == 0 {
+        return Ok(());
+    }
+
+    let is_dir = attr.kind == FileType::Directory;
+    let wants_exec = mask & libc::X_OK as u32 != 0;
+    if req.capable(CAP_DAC_OVERRIDE) && (!wants_exec || is_dir || perm & 0o111 != 0) {
+        return Ok(());
+    }
+    if req.capable(CAP_DAC_READ_SEARCH)
+        && mask & libc::W_OK as u32 == 0
+        && (!wants_exec || is_dir)
+    {
+        return Ok(());
+    }
+    return Err(libc::EACCES);
+}
+
+/// Check if the caller of `req` may change the metadata of a file with the attributes `attr`.
+///
+/// Operations such as changing the mode or setting the timestamps to arbitrary values are only
+/// allowed for the owner of the file or a caller with `CAP_FOWNER`.
+///
+/// Returns `EPERM` if the caller is not allowed.
+///
+/// Arguments:
+/// * `attr: &FileAttr` - Attributes of the file being changed.
+/// * `req: &Request` - Request data structure of the caller.
+pub fn check_owner(attr: &FileAttr, req: &Request) -> Result<(), i32> {
+    if req.uid() == attr.uid || req.capable(CAP_FOWNER) {
+        return Ok(());
+    }
+    return Err(libc::EPERM);
+}
+
+/// Check if the caller of `req` is a member of the group `gid`.
+///
+/// Arguments:
+/// * `req: &Request` - Request data structure of the caller.
+/// * `gid: u32` - Group id to check.
+pub fn in_group(req: &Request, gid: u32) -> bool {
+    return req.gid() == gid || req.groups().contains(&gid);
+}
@@ -1,11 +1,27 @@
+mod access;
 mod disk;
+mod errno;
+mod lookup;
+mod options;
+mod result;
+mod upgrade;
+pub use self::access::*;
 pub use self::disk::*;
+pub use self::errno::*;
+pub use self::lookup::*;
+pub use self::options::*;
+pub use self::result::*;
+pub use self::upgrade::*;
 
-use libc::ENOSYS;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use libc::{EINVAL, ENOSYS};
 
 use crate::fuse::*;
 
 use kernel::ffi::{register_bento_fs,unregister_bento_fs, reregister_bento_fs};
+use kernel::ffi::{rs_register_bento_fs_factory, rs_reregister_bento_fs_factory};
+use kernel::ffi::{bento_register_stats, bento_unregister_stats};
 use kernel::raw;
 
 use std::ffi::OsStr;
@@ -38,6 +54,67 @@ pub mod consts {
     pub const FUSE_PARALLEL_DIROPS: u32     = 1 << 18;
     pub const FUSE_HANDLE_KILLPRIV: u32     = 1 << 19;
     pub const FUSE_POSIX_ACL: u32           = 1 << 20;
+
+    // Ioctl flags
+    pub const FUSE_IOCTL_COMPAT: u32        = 1 << 0;
+    pub const FUSE_IOCTL_UNRESTRICTED: u32  = 1 << 1;
+    pub const FUSE_IOCTL_RETRY: u32         = 1 << 2;
+    pub const FUSE_IOCTL_32BIT: u32         = 1 << 3;
+    pub const FUSE_IOCTL_DIR: u32           = 1 << 4;
+
+    // Capabilities used for permission checks, from /include/uapi/linux/capability.h
+    pub const CAP_CHOWN: i32                = 0;
+    pub const CAP_DAC_OVERRIDE: i32         = 1;
+    pub const CAP_DAC_READ_SEARCH: i32      = 2;
+    pub const CAP_FOWNER: i32               = 3;
+    pub const CAP_FSETID: i32               = 4;
+    pub const CAP_SYS_ADMIN: i32            = 21;
+}
+
+// The create and destroy functions passed to BentoFS by `register_factory` or
+// `reregister_factory`, or 0 if the filesystem was registered as a single instance.
+static FACTORY_CREATE: AtomicUsize = AtomicUsize::new(0);
+static FACTORY_DESTROY: AtomicUsize = AtomicUsize::new(0);
+
+/// Register this module with Bento again after a live upgrade to another module was rolled back.
+///
+/// The filesystem is registered the same way it was registered before the upgrade, with the
+/// create and destroy functions of this module if it was registered with `register_factory`.
+///
+/// Arguments:
+/// * `fs: *const raw::c_void` - The filesystem instance that took back the state.
+/// * `name: &'static str` - Name of the filesystem, nul-terminated.
+/// * `dispatch: *const raw::c_void` - The dispatch function of this module.
+pub(crate) fn register_again(fs: *const raw::c_void, name: &'static str, dispatch: *const raw::c_void) -> i32 {
+    let create = FACTORY_CREATE.load(Ordering::SeqCst);
+    let destroy = FACTORY_DESTROY.load(Ordering::SeqCst);
+    let name = name.as_bytes().as_ptr() as *const raw::c_void;
+    unsafe {
+        if create != 0 && destroy != 0 {
+            rs_reregister_bento_fs_factory(name, create as *const raw::c_void,
+                destroy as *const raw::c_void, dispatch)
+        } else {
+            reregister_bento_fs(fs, name, dispatch)
+        }
+    }
+}
+
+fn set_factory(create: *const raw::c_void, destroy: *const raw::c_void) {
+    FACTORY_CREATE.store(create as usize, Ordering::SeqCst);
+    FACTORY_DESTROY.store(destroy as usize, Ordering::SeqCst);
+}
+
+// Expose the statistics kept by `dispatch` in /sys/kernel/debug/bento/<name>/stats. Reading the
+// file shows the output of `dump_stats` and writing to it clears the statistics. Failing to
+// create the file, e.g. because debugfs is not available, doesn't fail the registration.
+fn register_stats(name: &str) {
+    unsafe {
+        bento_register_stats(
+            name.as_bytes().as_ptr() as *const raw::c_void,
+            show_stats as *const raw::c_void,
+            clear_stats as *const raw::c_void,
+        );
+    }
 }
 
 /// BentoFilesystem trait
@@ -57,18 +134,23 @@ pub trait BentoFilesystem<'de, TransferIn: Send + Deserialize<'de>=i32,TransferO
     /// Register the filesystem with Bento.
     ///
     /// This should be called when the filesystem module is inserted and before
-    /// a filesystem is mounted.
+    /// a filesystem is mounted. Per-operation statistics of the filesystem can be read from
+    /// `/sys/kernel/debug/bento/<name>/stats` once it is registered.
     fn register(&self) -> i32
     where
         Self: core::marker::Sized,
     {
-        return unsafe {
+        let ret = unsafe {
             register_bento_fs(
                 self as *const Self as *const raw::c_void,
                 self.get_name().as_bytes().as_ptr() as *const raw::c_void,
                 dispatch::<TransferIn, TransferOut, Self> as *const raw::c_void,
             )
         };
+        if ret == 0 {
+            register_stats(self.get_name());
+        }
+        return ret;
     }
 
     /// Reregister the filesystem with Bento on top of an existing register.
@@ -80,47 +162,163 @@ pub trait BentoFilesystem<'de, TransferIn: Send + Deserialize<'de>=i32,TransferO
     where
         Self: core::marker::Sized,
     {
-        return unsafe {
+        let ret = unsafe {
             reregister_bento_fs(
                 self as *const Self as *const raw::c_void,
                 self.get_name().as_bytes().as_ptr() as *const raw::c_void,
                 dispatch::<TransferIn, TransferOut, Self> as *const raw::c_void,
             )
         };
+        if ret == 0 {
+            register_stats(self.get_name());
+        }
+        return ret;
+    }
+
+    /// Register a constructor for the filesystem with Bento.
+    ///
+    /// Unlike `register`, which shares one instance between all mounts, every mount gets its own
+    /// instance created with `Default::default`, so several devices can be mounted with the same
+    /// filesystem at once. Instances are kept per superblock and dropped when their filesystem is
+    /// unmounted. `self` is only used for the name of the filesystem.
+    ///
+    /// This should be called when the filesystem module is inserted and before a filesystem is
+    /// mounted. Like with `register`, per-operation statistics of all instances can be read from
+    /// `/sys/kernel/debug/bento/<name>/stats`.
+    ///
+    /// Versions of BentoFS without per-mount instances don't export `register_bento_fs_factory`.
+    /// With those, this falls back to `register`, so all mounts share `self`.
+    fn register_factory(&self) -> i32
+    where
+        Self: core::marker::Sized + Default,
+    {
+        let create = create_instance::<TransferIn, TransferOut, Self> as *const raw::c_void;
+        let destroy = destroy_instance::<TransferIn, TransferOut, Self> as *const raw::c_void;
+        let ret = unsafe {
+            rs_register_bento_fs_factory(
+                self.get_name().as_bytes().as_ptr() as *const raw::c_void,
+                create,
+                destroy,
+                dispatch::<TransferIn, TransferOut, Self> as *const raw::c_void,
+            )
+        };
+        if ret == -ENOSYS {
+            return self.register();
+        }
+        if ret == 0 {
+            set_factory(create, destroy);
+            register_stats(self.get_name());
+        }
+        return ret;
+    }
+
+    /// Reregister the filesystem constructor with Bento on top of an existing register.
+    ///
+    /// This is the `register_factory` version of `reregister`. Every mounted instance of the
+    /// existing filesystem is handed over to a new instance created by this module. Like
+    /// `register_factory`, this falls back to `reregister` if BentoFS has no per-mount instances.
+    fn reregister_factory(&self) -> i32
+    where
+        Self: core::marker::Sized + Default,
+    {
+        let create = create_instance::<TransferIn, TransferOut, Self> as *const raw::c_void;
+        let destroy = destroy_instance::<TransferIn, TransferOut, Self> as *const raw::c_void;
+        let ret = unsafe {
+            rs_reregister_bento_fs_factory(
+                self.get_name().as_bytes().as_ptr() as *const raw::c_void,
+                create,
+                destroy,
+                dispatch::<TransferIn, TransferOut, Self> as *const raw::c_void,
+            )
+        };
+        if ret == -ENOSYS {
+            return self.reregister();
+        }
+        if ret == 0 {
+            set_factory(create, destroy);
+            register_stats(self.get_name());
+        }
+        return ret;
     }
 
     /// Unregister the filesystem with Bento.
     ///
     /// This should be called when the filesystem module is removed and after a filesystem is
-    /// unmounted.
+    /// unmounted. The statistics file is removed too, unless a newer module of the filesystem
+    /// took it over in a live upgrade.
     fn unregister(&self) -> i32 {
-        return unsafe {
-            unregister_bento_fs(self.get_name().as_bytes().as_ptr() as *const raw::c_void)
-        };
+        let name = self.get_name().as_bytes().as_ptr() as *const raw::c_void;
+        unsafe { bento_unregister_stats(name) };
+        return unsafe { unregister_bento_fs(name) };
     }
 
+    /// Prepare for a live upgrade and return the state to hand to the new module.
+    ///
+    /// This is called on the old module. The returned state is serialized together with
+    /// `bento_update_version` and passed to `bento_update_transfer` on the new module. State that
+    /// is not returned here is lost. The returned state is not dropped after it is serialized, so
+    /// resources it refers to, such as a `Journal`, stay alive for the new module.
     fn bento_update_prepare(&mut self) -> Option<TransferOut> {
-    //fn bento_update_prepare(&mut self) -> Option<*const raw::c_void> {
         None
     }
 
-    fn bento_update_transfer(&mut self, Option<TransferIn>) { }
-    //fn bento_update_transfer(&mut self, Option<*const raw::c_void>) { }
+    /// Take over the state of the previous module after a live upgrade.
+    ///
+    /// This is called on the new module. `state` is `None` if the previous module did not return
+    /// any state. The serialized state is freed when this returns, so `TransferIn` must not
+    /// borrow from it.
+    ///
+    /// If this returns an error, the upgrade is rolled back: the state is handed back to the
+    /// previous module, which is registered again through `reregister_bento_fs` and keeps
+    /// handling requests. Resources that the state refers to are still owned by the previous
+    /// module in that case and must not be released, e.g. by using `mem::forget` on them.
+    ///
+    /// Arguments:
+    /// * `state: Option<TransferIn>` - State returned by `bento_update_prepare` on the old module.
+    fn bento_update_transfer(&mut self, _state: Option<TransferIn>) -> Result<(), i32> {
+        return Ok(());
+    }
+
+    /// Get the version of the state returned by `bento_update_prepare`.
+    ///
+    /// This must be changed whenever the layout of the state changes. If the state handed over
+    /// during a live upgrade has a different version than the one the new module expects,
+    /// `bento_update_migrate` is called instead of deserializing the state directly.
+    fn bento_update_version(&self) -> u32 {
+        0
+    }
+
+    /// Migrate state with a different version than `bento_update_version` from the previous
+    /// module.
+    ///
+    /// The filesystem can deserialize an older state type with `deserialize_state` and convert
+    /// it, or reject the state by returning an error. A rejected state fails the upgrade with that
+    /// error. By default, all other versions are rejected with `EINVAL`.
+    ///
+    /// Arguments:
+    /// * `version: u32` - State version returned by `bento_update_version` on the old module.
+    /// * `payload: &[u8]` - The serialized state.
+    fn bento_update_migrate(&mut self, _version: u32, _payload: &[u8]) -> Result<TransferIn, i32> {
+        return Err(EINVAL);
+    }
 
     /// Initialize the file system and fill in initialization flags.
     ///
     /// Possible initialization flags are defined /include/uapi/linux/fuse.h.
-    /// No support is provided for readdirplus and async DIO.
+    /// Readdirplus is supported through `bento_readdirplus` if `FUSE_DO_READDIRPLUS` is set.
+    /// No support is provided for async DIO.
     ///
     /// Arguments:
     /// * `req: &Request` - Request data structure.
     /// * `devname: &OsStr` - Name of the backing device file.
+    /// * `opts: &MountOptions` - Mount options passed with `-o`.
     /// * `fc_info: &mut FuseConnInfo` - Connection information used to pass initialization
     /// arguments to Bento.
     fn bento_init(
         &mut self,
         _req: &Request,
         _devname: &OsStr,
+        _opts: &MountOptions,
         _fc_info: &mut FuseConnInfo,
     ) -> Result<(), i32> {
         return Err(ENOSYS);
@@ -169,6 +367,9 @@ pub trait BentoFilesystem<'de, TransferIn: Send + Deserialize<'de>=i32,TransferO
     /// On unmount the lookup count for all inodes implicitly drops to zero. It is not guaranteed
     /// that the file system will receive corresponding forget messages for the affected inodes.
     ///
+    /// Batched forgets are delivered as one call per inode. `LookupCountTable` can be used to keep
+    /// track of the lookup counts.
+    ///
     /// Arguments:
     /// * `req: &Request` - Request data structure.
     /// * `ino: u64` - Filesystem-provided inode number of the inode to forget.
@@ -671,6 +872,37 @@ pub trait BentoFilesystem<'de, TransferIn: Send + Deserialize<'de>=i32,TransferO
         return reply.error(ENOSYS);
     }
 
+    /// Read directory with attributes
+    ///
+    /// Like `bento_readdir`, but each entry also carries the attributes that a lookup of the entry
+    /// would return, so the kernel does not need a separate lookup for every entry. Only sent if
+    /// `FUSE_DO_READDIRPLUS` was set in `bento_init`. Entries are added with
+    /// `ReplyDirectoryPlus::add`. Send an empty buffer on end of stream.
+    ///
+    /// Unlike readdir, returning an entry increments its lookup count, except for the '.' and
+    /// '..' entries, exactly as if a lookup had been done for it.
+    ///
+    /// The same rules for `offset` apply as for `bento_readdir`.
+    ///
+    /// Arguments:
+    /// * `req: &Request` - Request data structure.
+    /// * `ino: u64` - Filesystem-provided inode number.
+    /// * `fh: u64` - Filesystem-provided file handle.
+    /// * `offset: i64` - Offset into the directory. From the offset values used in previous
+    /// readdirplus requests.
+    /// * `reply: ReplyDirectoryPlus` - Output data structure for the read directory information or
+    /// error value.
+    fn bento_readdirplus(
+        &self,
+        _req: &Request,
+        _ino: u64,
+        _fh: u64,
+        _offset: i64,
+        reply: ReplyDirectoryPlus,
+    ) {
+        return reply.error(ENOSYS);
+    }
+
     /// Release an open directory
     ///
     /// For every opendir call there will be exactly one releasedir call (unless the filesystem is
@@ -848,6 +1080,8 @@ pub trait BentoFilesystem<'de, TransferIn: Send + Deserialize<'de>=i32,TransferO
     /// success, i.e. this and all future `access()` requests will succeed without being sent to
     /// the filesystem.
     ///
+    /// `check_access` implements the POSIX permission check for a file's attributes.
+    ///
     /// Arguments:
     /// * `req: &Request` - Request data structure.
     /// * `ino: u64` - Filesystem-provided inode number.
@@ -982,4 +1216,159 @@ pub trait BentoFilesystem<'de, TransferIn: Send + Deserialize<'de>=i32,TransferO
     ) {
         return reply.error(ENOSYS);
     }
+
+    /// Allocate requested space
+    ///
+    /// If this function returns success then subsequent writes to the specified range shall not
+    /// fail due to the lack of free space on the file system storage media.
+    ///
+    /// `mode` may contain `FALLOC_FL_KEEP_SIZE`, in which case the file size is not changed even
+    /// if the range extends past the end of the file, and `FALLOC_FL_PUNCH_HOLE`, in which case
+    /// the range is deallocated instead. `FALLOC_FL_PUNCH_HOLE` is always combined with
+    /// `FALLOC_FL_KEEP_SIZE`.
+    ///
+    /// If this request is answered with an error code of `ENOSYS`, this is treated as a permanent
+    /// failure with error code `EOPNOTSUPP`, i.e. all future `fallocate()` requests will fail with
+    /// `EOPNOTSUPP` without being sent to the filesystem.
+    ///
+    /// Arguments:
+    /// * `req: &Request` - Request data structure.
+    /// * `ino: u64` - Filesystem-provided inode number.
+    /// * `fh: u64` - Filesystem-provided file handle.
+    /// * `offset: i64` - Offset into the file where the range starts.
+    /// * `length: i64` - Length of the range.
+    /// * `mode: u32` - Fallocate mode flags.
+    /// * `reply: ReplyEmpty` - Output data structure for a possible error value.
+    fn bento_fallocate(
+        &self,
+        _req: &Request,
+        _ino: u64,
+        _fh: u64,
+        _offset: i64,
+        _length: i64,
+        _mode: u32,
+        reply: ReplyEmpty,
+    ) {
+        return reply.error(ENOSYS);
+    }
+
+    /// Find next data or hole after the specified offset
+    ///
+    /// This is called for `lseek()` with `SEEK_DATA` or `SEEK_HOLE`. `SEEK_SET`, `SEEK_CUR` and
+    /// `SEEK_END` are handled by Bento without consulting the filesystem. If there is no data
+    /// (`SEEK_DATA`) at or after `offset`, or `offset` is past the end of the file, the filesystem
+    /// should return `ENXIO`. The end of the file counts as an implicit hole.
+    ///
+    /// If this request is answered with an error code of `ENOSYS`, this is treated as a permanent
+    /// failure, i.e. all future `lseek()` requests will fail with the same error code without being
+    /// sent to the filesystem.
+    ///
+    /// Arguments:
+    /// * `req: &Request` - Request data structure.
+    /// * `ino: u64` - Filesystem-provided inode number.
+    /// * `fh: u64` - Filesystem-provided file handle.
+    /// * `offset: i64` - Offset into the file to start searching from.
+    /// * `whence: u32` - Either `SEEK_DATA` or `SEEK_HOLE`.
+    /// * `reply: ReplyLseek` - Output data structure for the resulting offset or error value.
+    fn bento_lseek(
+        &self,
+        _req: &Request,
+        _ino: u64,
+        _fh: u64,
+        _offset: i64,
+        _whence: u32,
+        reply: ReplyLseek,
+    ) {
+        return reply.error(ENOSYS);
+    }
+
+    /// Perform an ioctl on a file
+    ///
+    /// Only restricted ioctls are supported: the size of the input and output data is encoded in
+    /// `cmd` (see `_IOR`/`_IOW`), so the kernel copies in `in_data` and copies back at most
+    /// `out_size` bytes of the reply. Unrestricted ioctls, which would require the filesystem to
+    /// ask for specific iovecs, are rejected with `ENOSYS` before reaching the filesystem.
+    ///
+    /// `FUSE_IOCTL_DIR` is set in `flags` if the ioctl was issued on a directory. Filesystems
+    /// should reply with `ENOTTY` for commands they do not recognize.
+    ///
+    /// Arguments:
+    /// * `req: &Request` - Request data structure.
+    /// * `ino: u64` - Filesystem-provided inode number.
+    /// * `fh: u64` - Filesystem-provided file handle.
+    /// * `flags: u32` - Ioctl flags (`FUSE_IOCTL_*`).
+    /// * `cmd: u32` - Ioctl command.
+    /// * `in_data: &[u8]` - Data copied in from the caller.
+    /// * `out_size: u32` - Maximum size of the data that will be copied back to the caller.
+    /// * `reply: ReplyIoctl` - Output data structure for the result and data or error value.
+    fn bento_ioctl(
+        &self,
+        _req: &Request,
+        _ino: u64,
+        _fh: u64,
+        _flags: u32,
+        _cmd: u32,
+        _in_data: &[u8],
+        _out_size: u32,
+        reply: ReplyIoctl,
+    ) {
+        return reply.error(ENOSYS);
+    }
+
+    /// Copy a range of data from one file to another
+    ///
+    /// Lets the filesystem copy data without it passing through the page cache or the caller. If
+    /// this request is answered with an error code of `ENOSYS`, this is treated as a permanent
+    /// failure, and the kernel falls back to copying with reads and writes.
+    ///
+    /// The reply is the number of bytes copied, which may be less than `len`.
+    ///
+    /// Arguments:
+    /// * `req: &Request` - Request data structure.
+    /// * `ino_in: u64` - Filesystem-provided inode number of the source file.
+    /// * `fh_in: u64` - Filesystem-provided file handle of the source file.
+    /// * `offset_in: i64` - Offset into the source file to copy from.
+    /// * `ino_out: u64` - Filesystem-provided inode number of the destination file.
+    /// * `fh_out: u64` - Filesystem-provided file handle of the destination file.
+    /// * `offset_out: i64` - Offset into the destination file to copy to.
+    /// * `len: u64` - Number of bytes to copy.
+    /// * `flags: u32` - Flags passed to `copy_file_range()`. Currently always 0.
+    /// * `reply: ReplyWrite` - Output data structure for the number of bytes copied or error value.
+    fn bento_copy_file_range(
+        &self,
+        _req: &Request,
+        _ino_in: u64,
+        _fh_in: u64,
+        _offset_in: i64,
+        _ino_out: u64,
+        _fh_out: u64,
+        _offset_out: i64,
+        _len: u64,
+        _flags: u32,
+        reply: ReplyWrite,
+    ) {
+        return reply.error(ENOSYS);
+    }
+
+    /// Handle a request with an opcode that Bento doesn't dispatch to any other method.
+    ///
+    /// This can be used to experiment with FUSE opcodes that Bento doesn't support yet. The raw
+    /// arguments of the request are available through `inarg` and `outarg`. If this returns an
+    /// error, the request fails with that error. By default, all such requests fail with
+    /// `ENOSYS`, so the kernel knows the operation is not implemented.
+    ///
+    /// Arguments:
+    /// * `req: &Request` - Request data structure.
+    /// * `opcode: u32` - FUSE opcode of the request.
+    /// * `inarg: &bento_in` - Input arguments of the request.
+    /// * `outarg: &mut bento_out` - Output arguments of the request.
+    fn bento_unknown_opcode(
+        &self,
+        _req: &Request,
+        _opcode: u32,
+        _inarg: &bento_in,
+        _outarg: &mut bento_out,
+    ) -> Result<(), i32> {
+        return Err(ENOSYS);
+    }
 }
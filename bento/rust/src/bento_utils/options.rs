@@ -0,0 +1,146 @@
+use alloc::string::String;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+
+use core::cell::Cell;
+
+use crate::libc;
+
+struct MountOption {
+    name: String,
+    value: Option<String>,
+    // Set once a getter asked for this option
+    used: Cell<bool>,
+}
+
+/// Mount options passed with `-o` when the filesystem was mounted.
+///
+/// Options are a comma-separated list of `name` or `name=value` entries, e.g.
+/// `ttl=5,noatime,server=127.0.0.1:1234`. If an option is given more than once, the last one is
+/// used. Options that no getter asked for are reported by `unknown`, so typos in mount options
+/// don't go unnoticed.
+pub struct MountOptions {
+    opts: Vec<MountOption>,
+}
+
+impl MountOptions {
+    /// Create an empty set of mount options.
+    pub fn new() -> Self {
+        MountOptions { opts: Vec::new() }
+    }
+
+    /// Parse a comma-separated mount option string.
+    ///
+    /// Arguments:
+    /// * `opts: &str` - The mount options, e.g. `ttl=5,noatime`.
+    pub fn parse(opts: &str) -> Self {
+        let mut options = MountOptions::new();
+        for opt in opts.split(',') {
+            let opt = opt.trim();
+            if opt.is_empty() {
+                continue;
+            }
+            let mut parts = opt.splitn(2, '=');
+            let name = parts.next().unwrap_or("");
+            options.opts.push(MountOption {
+                name: name.to_string(),
+                value: parts.next().map(|value| value.to_string()),
+                used: Cell::new(false),
+            });
+        }
+        return options;
+    }
+
+    fn find(&self, name: &str) -> Option<&MountOption> {
+        let opt = self.opts.iter().rev().find(|opt| opt.name == name)?;
+        for other in self.opts.iter().filter(|opt| opt.name == name) {
+            other.used.set(true);
+        }
+        Some(opt)
+    }
+
+    /// Returns true if the option `name` was given, with or without a value.
+    ///
+    /// Arguments:
+    /// * `name: &str` - Name of the option.
+    pub fn contains(&self, name: &str) -> bool {
+        self.find(name).is_some()
+    }
+
+    /// Get the value of the option `name`.
+    ///
+    /// Returns `None` if the option wasn't given and an empty string if it was given without a
+    /// value.
+    ///
+    /// Arguments:
+    /// * `name: &str` - Name of the option.
+    pub fn get_str(&self, name: &str) -> Option<&str> {
+        self.find(name)
+            .map(|opt| opt.value.as_ref().map(|value| value.as_str()).unwrap_or(""))
+    }
+
+    /// Get the value of the option `name` as a number.
+    ///
+    /// Returns `Ok(None)` if the option wasn't given and `EINVAL` if its value is not a number.
+    /// Hexadecimal values starting with `0x` are accepted.
+    ///
+    /// Arguments:
+    /// * `name: &str` - Name of the option.
+    pub fn get_u64(&self, name: &str) -> Result<Option<u64>, i32> {
+        let value = match self.find(name) {
+            Some(opt) => opt.value.as_ref().ok_or(libc::EINVAL)?,
+            None => return Ok(None),
+        };
+        let parsed = if value.starts_with("0x") {
+            u64::from_str_radix(&value[2..], 16)
+        } else {
+            value.parse::<u64>()
+        };
+        parsed.map(Some).map_err(|_| libc::EINVAL)
+    }
+
+    /// Get the value of the flag `name`.
+    ///
+    /// `name`, `name=1`, `name=true`, `name=yes` and `name=on` turn the flag on. `noname`,
+    /// `name=0`, `name=false`, `name=no` and `name=off` turn it off. Returns `Ok(None)` if the
+    /// flag wasn't given and `EINVAL` for any other value.
+    ///
+    /// Arguments:
+    /// * `name: &str` - Name of the flag, without the `no` prefix.
+    pub fn get_bool(&self, name: &str) -> Result<Option<bool>, i32> {
+        let mut negated = String::from("no");
+        negated.push_str(name);
+        // The last of `name` and `noname` wins.
+        let pos = self.opts.iter().rposition(|opt| opt.name == name || opt.name == negated);
+        self.find(name);
+        self.find(&negated);
+        let opt = match pos {
+            Some(pos) => &self.opts[pos],
+            None => return Ok(None),
+        };
+        if opt.name == negated {
+            return match opt.value {
+                None => Ok(Some(false)),
+                Some(_) => Err(libc::EINVAL),
+            };
+        }
+        match opt.value.as_ref().map(|value| value.as_str()) {
+            None | Some("1") | Some("true") | Some("yes") | Some("on") => Ok(Some(true)),
+            Some("0") | Some("false") | Some("no") | Some("off") => Ok(Some(false)),
+            Some(_) => Err(libc::EINVAL),
+        }
+    }
+
+    /// Get the names of the options that no getter asked for.
+    ///
+    /// Bento reports these after `bento_init` returns.
+    pub fn unknown(&self) -> Vec<&str> {
+        let mut unknown: Vec<&str> = Vec::new();
+        for opt in self.opts.iter().filter(|opt| !opt.used.get()) {
+            if !unknown.contains(&opt.name.as_str()) {
+                unknown.push(opt.name.as_str());
+            }
+        }
+        return unknown;
+    }
+}
@@ -0,0 +1,973 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use core::cmp::min;
+
+use crate::fuse::*;
+
+use std::ffi::OsStr;
+use std::path::Path;
+
+use time::Timespec;
+
+use serde::{Serialize, Deserialize};
+
+use super::{BentoFilesystem, Errno, MountOptions};
+
+/// Attributes of a directory entry returned by `lookup`, `mknod`, `mkdir`, `symlink`, `link` and
+/// `create`.
+#[derive(Clone, Copy, Debug)]
+pub struct Entry {
+    pub attr: FileAttr,
+    pub generation: u64,
+}
+
+/// File handle and open flags returned by `open`, `opendir` and `create`.
+#[derive(Clone, Copy, Debug)]
+pub struct Opened {
+    pub fh: u64,
+    pub flags: u32,
+}
+
+/// A directory entry returned by `readdir` and `readdirplus`.
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    pub ino: u64,
+    pub offset: i64,
+    pub kind: FileType,
+    pub name: String,
+}
+
+/// Filesystem statistics returned by `statfs`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Statfs {
+    pub blocks: u64,
+    pub bfree: u64,
+    pub bavail: u64,
+    pub files: u64,
+    pub ffree: u64,
+    pub bsize: u32,
+    pub namelen: u32,
+    pub frsize: u32,
+}
+
+/// A conflicting lock returned by `getlk`.
+#[derive(Clone, Copy, Debug)]
+pub struct Lock {
+    pub start: u64,
+    pub end: u64,
+    pub typ: u32,
+    pub pid: u32,
+}
+
+/// BentoFilesystemResult trait
+///
+/// An alternative to `BentoFilesystem` where methods return their result instead of filling in a
+/// reply. Any type implementing this trait also implements `BentoFilesystem`, so it can be
+/// registered and mounted like any other Bento filesystem. Errors are returned as a typed `Errno`
+/// and are passed to the reply's `error` method.
+///
+/// The methods correspond to the `bento_*` methods of `BentoFilesystem` with the `bento_` prefix
+/// removed and the reply argument turned into the return value. The adapter takes care of the
+/// reply protocol details, such as answering a size query for `getxattr` and `listxattr` and
+/// stopping `readdir` when the reply buffer is full. Replies can not be deferred, so
+/// `BentoFilesystem` should be implemented directly if a request must be completed from another
+/// thread.
+///
+/// The user must provide a name for the file system. Otherwise, default implementations returning
+/// `ENOSYS` are provided here.
+pub trait BentoFilesystemResult<'de, TransferIn: Send + Deserialize<'de>=i32, TransferOut: Send + Serialize=i32> {
+    /// Get the name of the file system.
+    ///
+    /// This must be provided to mount the filesystem. It is returned by
+    /// `BentoFilesystem::get_name`.
+    fn name(&self) -> &'static str;
+
+    /// Get the time attributes and entries returned by this filesystem may be cached for.
+    fn ttl(&self) -> Timespec {
+        Timespec::new(1, 0)
+    }
+
+    /// See `BentoFilesystem::bento_update_prepare`.
+    fn update_prepare(&mut self) -> Option<TransferOut> {
+        None
+    }
+
+    /// See `BentoFilesystem::bento_update_transfer`.
+    fn update_transfer(&mut self, _state: Option<TransferIn>) -> Result<(), Errno> {
+        return Ok(());
+    }
+
+    /// Initialize the file system and fill in initialization flags.
+    ///
+    /// See `BentoFilesystem::bento_init`.
+    fn init(
+        &mut self,
+        _req: &Request,
+        _devname: &OsStr,
+        _opts: &MountOptions,
+        _fc_info: &mut FuseConnInfo,
+    ) -> Result<(), Errno> {
+        return Err(Errno::ENOSYS);
+    }
+
+    /// Perform any necessary cleanup on the file system.
+    fn destroy(&mut self, _req: &Request) {}
+
+    /// Lookup a directory entry by name and get its attributes.
+    fn lookup(&self, _req: &Request, _parent: u64, _name: &OsStr) -> Result<Entry, Errno> {
+        return Err(Errno::ENOSYS);
+    }
+
+    /// Forget about an inode.
+    fn forget(&self, _req: &Request, _ino: u64, _nlookup: u64) {}
+
+    /// Get file attributes.
+    fn getattr(&self, _req: &Request, _ino: u64) -> Result<FileAttr, Errno> {
+        return Err(Errno::ENOSYS);
+    }
+
+    /// Set file attributes and return the updated attributes.
+    fn setattr(
+        &self,
+        _req: &Request,
+        _ino: u64,
+        _mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        _size: Option<u64>,
+        _atime: Option<Timespec>,
+        _mtime: Option<Timespec>,
+        _fh: Option<u64>,
+        _crtime: Option<Timespec>,
+        _chgtime: Option<Timespec>,
+        _bkuptime: Option<Timespec>,
+        _flags: Option<u32>,
+    ) -> Result<FileAttr, Errno> {
+        return Err(Errno::ENOSYS);
+    }
+
+    /// Read a symbolic link.
+    fn readlink(&self, _req: &Request, _ino: u64) -> Result<Vec<u8>, Errno> {
+        return Err(Errno::ENOSYS);
+    }
+
+    /// Create a file node.
+    fn mknod(
+        &self,
+        _req: &Request,
+        _parent: u64,
+        _name: &OsStr,
+        _mode: u32,
+        _rdev: u32,
+    ) -> Result<Entry, Errno> {
+        return Err(Errno::ENOSYS);
+    }
+
+    /// Create a directory.
+    fn mkdir(
+        &self,
+        _req: &Request,
+        _parent: u64,
+        _name: &OsStr,
+        _mode: u32,
+    ) -> Result<Entry, Errno> {
+        return Err(Errno::ENOSYS);
+    }
+
+    /// Remove a file.
+    fn unlink(&self, _req: &Request, _parent: u64, _name: &OsStr) -> Result<(), Errno> {
+        return Err(Errno::ENOSYS);
+    }
+
+    /// Remove a directory.
+    fn rmdir(&self, _req: &Request, _parent: u64, _name: &OsStr) -> Result<(), Errno> {
+        return Err(Errno::ENOSYS);
+    }
+
+    /// Create a symbolic link.
+    fn symlink(
+        &self,
+        _req: &Request,
+        _parent: u64,
+        _name: &OsStr,
+        _link: &Path,
+    ) -> Result<Entry, Errno> {
+        return Err(Errno::ENOSYS);
+    }
+
+    /// Rename a file.
+    fn rename(
+        &self,
+        _req: &Request,
+        _parent: u64,
+        _name: &OsStr,
+        _newparent: u64,
+        _newname: &OsStr,
+        _flags: u32,
+    ) -> Result<(), Errno> {
+        return Err(Errno::ENOSYS);
+    }
+
+    /// Create a hard link.
+    fn link(
+        &self,
+        _req: &Request,
+        _ino: u64,
+        _newparent: u64,
+        _newname: &OsStr,
+    ) -> Result<Entry, Errno> {
+        return Err(Errno::ENOSYS);
+    }
+
+    /// Open a file.
+    fn open(&self, _req: &Request, _ino: u64, _flags: u32) -> Result<Opened, Errno> {
+        return Err(Errno::ENOSYS);
+    }
+
+    /// Read data.
+    ///
+    /// At most `size` bytes of the returned data are passed to the kernel.
+    fn read(
+        &self,
+        _req: &Request,
+        _ino: u64,
+        _fh: u64,
+        _offset: i64,
+        _size: u32,
+    ) -> Result<Vec<u8>, Errno> {
+        return Err(Errno::ENOSYS);
+    }
+
+    /// Write data and return the number of bytes written.
+    fn write(
+        &self,
+        _req: &Request,
+        _ino: u64,
+        _fh: u64,
+        _offset: i64,
+        _data: &[u8],
+        _flags: u32,
+    ) -> Result<u32, Errno> {
+        return Err(Errno::ENOSYS);
+    }
+
+    /// Flush method.
+    fn flush(&self, _req: &Request, _ino: u64, _fh: u64, _lock_owner: u64) -> Result<(), Errno> {
+        return Err(Errno::ENOSYS);
+    }
+
+    /// Release an open file.
+    fn release(
+        &self,
+        _req: &Request,
+        _ino: u64,
+        _fh: u64,
+        _flags: u32,
+        _lock_owner: u64,
+        _flush: bool,
+    ) -> Result<(), Errno> {
+        return Err(Errno::ENOSYS);
+    }
+
+    /// Synchronize file contents.
+    fn fsync(&self, _req: &Request, _ino: u64, _fh: u64, _datasync: bool) -> Result<(), Errno> {
+        return Err(Errno::ENOSYS);
+    }
+
+    /// Open a directory.
+    fn opendir(&self, _req: &Request, _ino: u64, _flags: u32) -> Result<Opened, Errno> {
+        return Err(Errno::ENOSYS);
+    }
+
+    /// Read a directory.
+    ///
+    /// Returns the entries following `offset`. Entries that do not fit in the reply buffer are
+    /// dropped, and the kernel will ask for them again starting at the offset of the last entry
+    /// that fit.
+    fn readdir(
+        &self,
+        _req: &Request,
+        _ino: u64,
+        _fh: u64,
+        _offset: i64,
+    ) -> Result<Vec<DirEntry>, Errno> {
+        return Err(Errno::ENOSYS);
+    }
+
+    /// Read a directory, including the attributes of each entry.
+    ///
+    /// Like `readdir`, entries that do not fit in the reply buffer are dropped.
+    fn readdirplus(
+        &self,
+        _req: &Request,
+        _ino: u64,
+        _fh: u64,
+        _offset: i64,
+    ) -> Result<Vec<(DirEntry, Entry)>, Errno> {
+        return Err(Errno::ENOSYS);
+    }
+
+    /// Release an open directory.
+    fn releasedir(&self, _req: &Request, _ino: u64, _fh: u64, _flags: u32) -> Result<(), Errno> {
+        return Err(Errno::ENOSYS);
+    }
+
+    /// Synchronize directory contents.
+    fn fsyncdir(&self, _req: &Request, _ino: u64, _fh: u64, _datasync: bool) -> Result<(), Errno> {
+        return Err(Errno::ENOSYS);
+    }
+
+    /// Get file system statistics.
+    fn statfs(&self, _req: &Request, _ino: u64) -> Result<Statfs, Errno> {
+        return Err(Errno::ENOSYS);
+    }
+
+    /// Set an extended attribute.
+    fn setxattr(
+        &self,
+        _req: &Request,
+        _ino: u64,
+        _name: &OsStr,
+        _value: &[u8],
+        _flags: u32,
+        _position: u32,
+    ) -> Result<(), Errno> {
+        return Err(Errno::ENOSYS);
+    }
+
+    /// Get an extended attribute.
+    ///
+    /// Returns the whole value. Size queries and values larger than the requested size are handled
+    /// by the adapter.
+    fn getxattr(&self, _req: &Request, _ino: u64, _name: &OsStr) -> Result<Vec<u8>, Errno> {
+        return Err(Errno::ENOSYS);
+    }
+
+    /// List extended attribute names.
+    ///
+    /// Returns the nul-separated list of names. Size queries and lists larger than the requested
+    /// size are handled by the adapter.
+    fn listxattr(&self, _req: &Request, _ino: u64) -> Result<Vec<u8>, Errno> {
+        return Err(Errno::ENOSYS);
+    }
+
+    /// Remove an extended attribute.
+    fn removexattr(&self, _req: &Request, _ino: u64, _name: &OsStr) -> Result<(), Errno> {
+        return Err(Errno::ENOSYS);
+    }
+
+    /// Check file access permissions.
+    fn access(&self, _req: &Request, _ino: u64, _mask: u32) -> Result<(), Errno> {
+        return Err(Errno::ENOSYS);
+    }
+
+    /// Create and open a file.
+    fn create(
+        &self,
+        _req: &Request,
+        _parent: u64,
+        _name: &OsStr,
+        _mode: u32,
+        _flags: u32,
+    ) -> Result<(Entry, Opened), Errno> {
+        return Err(Errno::ENOSYS);
+    }
+
+    /// Test for a POSIX file lock.
+    fn getlk(
+        &self,
+        _req: &Request,
+        _ino: u64,
+        _fh: u64,
+        _lock_owner: u64,
+        _start: u64,
+        _end: u64,
+        _typ: u32,
+        _pid: u32,
+    ) -> Result<Lock, Errno> {
+        return Err(Errno::ENOSYS);
+    }
+
+    /// Acquire, modify or release a POSIX file lock.
+    fn setlk(
+        &self,
+        _req: &Request,
+        _ino: u64,
+        _fh: u64,
+        _lock_owner: u64,
+        _start: u64,
+        _end: u64,
+        _typ: u32,
+        _pid: u32,
+        _sleep: bool,
+    ) -> Result<(), Errno> {
+        return Err(Errno::ENOSYS);
+    }
+
+    /// Map a block index within a file to a block index within the device.
+    fn bmap(&self, _req: &Request, _ino: u64, _blocksize: u32, _idx: u64) -> Result<u64, Errno> {
+        return Err(Errno::ENOSYS);
+    }
+
+    /// Allocate or deallocate space in a file.
+    fn fallocate(
+        &self,
+        _req: &Request,
+        _ino: u64,
+        _fh: u64,
+        _offset: i64,
+        _length: i64,
+        _mode: u32,
+    ) -> Result<(), Errno> {
+        return Err(Errno::ENOSYS);
+    }
+
+    /// Find the next data or hole after `offset` and return its offset.
+    fn lseek(
+        &self,
+        _req: &Request,
+        _ino: u64,
+        _fh: u64,
+        _offset: i64,
+        _whence: u32,
+    ) -> Result<i64, Errno> {
+        return Err(Errno::ENOSYS);
+    }
+
+    /// Perform an ioctl and return its result and the data to copy back to the caller.
+    ///
+    /// The adapter replies with `EIO` if the returned data is larger than `out_size`.
+    fn ioctl(
+        &self,
+        _req: &Request,
+        _ino: u64,
+        _fh: u64,
+        _flags: u32,
+        _cmd: u32,
+        _in_data: &[u8],
+        _out_size: u32,
+    ) -> Result<(i32, Vec<u8>), Errno> {
+        return Err(Errno::ENOSYS);
+    }
+
+    /// Copy a range of data from one file to another and return the number of bytes copied.
+    fn copy_file_range(
+        &self,
+        _req: &Request,
+        _ino_in: u64,
+        _fh_in: u64,
+        _offset_in: i64,
+        _ino_out: u64,
+        _fh_out: u64,
+        _offset_out: i64,
+        _len: u64,
+        _flags: u32,
+    ) -> Result<u32, Errno> {
+        return Err(Errno::ENOSYS);
+    }
+}
+
+impl<'de, TransferIn, TransferOut, T> BentoFilesystem<'de, TransferIn, TransferOut> for T
+where
+    TransferIn: Send + Deserialize<'de>,
+    TransferOut: Send + Serialize,
+    T: BentoFilesystemResult<'de, TransferIn, TransferOut>,
+{
+    fn get_name(&self) -> &'static str {
+        self.name()
+    }
+
+    fn bento_update_prepare(&mut self) -> Option<TransferOut> {
+        self.update_prepare()
+    }
+
+    fn bento_update_transfer(&mut self, state: Option<TransferIn>) -> Result<(), i32> {
+        return self.update_transfer(state).map_err(|e| e.code());
+    }
+
+    fn bento_init(
+        &mut self,
+        req: &Request,
+        devname: &OsStr,
+        opts: &MountOptions,
+        fc_info: &mut FuseConnInfo,
+    ) -> Result<(), i32> {
+        return self.init(req, devname, opts, fc_info).map_err(|e| e.code());
+    }
+
+    fn bento_destroy(&mut self, req: &Request) {
+        self.destroy(req)
+    }
+
+    fn bento_lookup(&self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        match self.lookup(req, parent, name) {
+            Ok(entry) => reply.entry(&self.ttl(), &entry.attr, entry.generation),
+            Err(e) => reply.error(e.code()),
+        }
+    }
+
+    fn bento_forget(&self, req: &Request, ino: u64, nlookup: u64) {
+        self.forget(req, ino, nlookup)
+    }
+
+    fn bento_getattr(&self, req: &Request, ino: u64, reply: ReplyAttr) {
+        match self.getattr(req, ino) {
+            Ok(attr) => reply.attr(&self.ttl(), &attr),
+            Err(e) => reply.error(e.code()),
+        }
+    }
+
+    fn bento_setattr(
+        &self,
+        req: &Request,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<Timespec>,
+        mtime: Option<Timespec>,
+        fh: Option<u64>,
+        crtime: Option<Timespec>,
+        chgtime: Option<Timespec>,
+        bkuptime: Option<Timespec>,
+        flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        match self.setattr(
+            req, ino, mode, uid, gid, size, atime, mtime, fh, crtime, chgtime, bkuptime, flags,
+        ) {
+            Ok(attr) => reply.attr(&self.ttl(), &attr),
+            Err(e) => reply.error(e.code()),
+        }
+    }
+
+    fn bento_readlink(&self, req: &Request, ino: u64, reply: ReplyData) {
+        match self.readlink(req, ino) {
+            Ok(data) => reply.data(&data),
+            Err(e) => reply.error(e.code()),
+        }
+    }
+
+    fn bento_mknod(
+        &self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        match self.mknod(req, parent, name, mode, rdev) {
+            Ok(entry) => reply.entry(&self.ttl(), &entry.attr, entry.generation),
+            Err(e) => reply.error(e.code()),
+        }
+    }
+
+    fn bento_mkdir(&self, req: &Request, parent: u64, name: &OsStr, mode: u32, reply: ReplyEntry) {
+        match self.mkdir(req, parent, name, mode) {
+            Ok(entry) => reply.entry(&self.ttl(), &entry.attr, entry.generation),
+            Err(e) => reply.error(e.code()),
+        }
+    }
+
+    fn bento_unlink(&self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self.unlink(req, parent, name) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.code()),
+        }
+    }
+
+    fn bento_rmdir(&self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self.rmdir(req, parent, name) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.code()),
+        }
+    }
+
+    fn bento_symlink(
+        &self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        link: &Path,
+        reply: ReplyEntry,
+    ) {
+        match self.symlink(req, parent, name, link) {
+            Ok(entry) => reply.entry(&self.ttl(), &entry.attr, entry.generation),
+            Err(e) => reply.error(e.code()),
+        }
+    }
+
+    fn bento_rename(
+        &self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        match self.rename(req, parent, name, newparent, newname, flags) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.code()),
+        }
+    }
+
+    fn bento_link(
+        &self,
+        req: &Request,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        match self.link(req, ino, newparent, newname) {
+            Ok(entry) => reply.entry(&self.ttl(), &entry.attr, entry.generation),
+            Err(e) => reply.error(e.code()),
+        }
+    }
+
+    fn bento_open(&self, req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
+        match self.open(req, ino, flags) {
+            Ok(opened) => reply.opened(opened.fh, opened.flags),
+            Err(e) => reply.error(e.code()),
+        }
+    }
+
+    fn bento_read(
+        &self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        reply: ReplyData,
+    ) {
+        match self.read(req, ino, fh, offset, size) {
+            Ok(data) => reply.data(&data[..min(data.len(), size as usize)]),
+            Err(e) => reply.error(e.code()),
+        }
+    }
+
+    fn bento_write(
+        &self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        flags: u32,
+        reply: ReplyWrite,
+    ) {
+        match self.write(req, ino, fh, offset, data, flags) {
+            Ok(size) => reply.written(size),
+            Err(e) => reply.error(e.code()),
+        }
+    }
+
+    fn bento_flush(&self, req: &Request, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
+        match self.flush(req, ino, fh, lock_owner) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.code()),
+        }
+    }
+
+    fn bento_release(
+        &self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        flags: u32,
+        lock_owner: u64,
+        flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        match self.release(req, ino, fh, flags, lock_owner, flush) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.code()),
+        }
+    }
+
+    fn bento_fsync(&self, req: &Request, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        match self.fsync(req, ino, fh, datasync) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.code()),
+        }
+    }
+
+    fn bento_opendir(&self, req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
+        match self.opendir(req, ino, flags) {
+            Ok(opened) => reply.opened(opened.fh, opened.flags),
+            Err(e) => reply.error(e.code()),
+        }
+    }
+
+    #[allow(unused_mut)]
+    fn bento_readdir(
+        &self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        match self.readdir(req, ino, fh, offset) {
+            Ok(entries) => {
+                for entry in entries {
+                    if reply.add(entry.ino, entry.offset, entry.kind, entry.name.as_str()) {
+                        break;
+                    }
+                }
+                reply.ok();
+            }
+            Err(e) => reply.error(e.code()),
+        }
+    }
+
+    #[allow(unused_mut)]
+    fn bento_readdirplus(
+        &self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectoryPlus,
+    ) {
+        match self.readdirplus(req, ino, fh, offset) {
+            Ok(entries) => {
+                let ttl = self.ttl();
+                for (dirent, entry) in entries {
+                    if reply.add(
+                        dirent.ino,
+                        dirent.offset,
+                        &entry.attr,
+                        &ttl,
+                        entry.generation,
+                        dirent.name.as_str(),
+                    ) {
+                        break;
+                    }
+                }
+                reply.ok();
+            }
+            Err(e) => reply.error(e.code()),
+        }
+    }
+
+    fn bento_releasedir(&self, req: &Request, ino: u64, fh: u64, flags: u32, reply: ReplyEmpty) {
+        match self.releasedir(req, ino, fh, flags) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.code()),
+        }
+    }
+
+    fn bento_fsyncdir(&self, req: &Request, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        match self.fsyncdir(req, ino, fh, datasync) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.code()),
+        }
+    }
+
+    fn bento_statfs(&self, req: &Request, ino: u64, reply: ReplyStatfs) {
+        match self.statfs(req, ino) {
+            Ok(st) => reply.statfs(
+                st.blocks, st.bfree, st.bavail, st.files, st.ffree, st.bsize, st.namelen, st.frsize,
+            ),
+            Err(e) => reply.error(e.code()),
+        }
+    }
+
+    fn bento_setxattr(
+        &self,
+        req: &Request,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: u32,
+        position: u32,
+        reply: ReplyEmpty,
+    ) {
+        match self.setxattr(req, ino, name, value, flags, position) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.code()),
+        }
+    }
+
+    fn bento_getxattr(&self, req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        match self.getxattr(req, ino, name) {
+            Ok(value) => xattr_reply(&value, size, reply),
+            Err(e) => reply.error(e.code()),
+        }
+    }
+
+    fn bento_listxattr(&self, req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        match self.listxattr(req, ino) {
+            Ok(names) => xattr_reply(&names, size, reply),
+            Err(e) => reply.error(e.code()),
+        }
+    }
+
+    fn bento_removexattr(&self, req: &Request, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self.removexattr(req, ino, name) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.code()),
+        }
+    }
+
+    fn bento_access(&self, req: &Request, ino: u64, mask: u32, reply: ReplyEmpty) {
+        match self.access(req, ino, mask) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.code()),
+        }
+    }
+
+    fn bento_create(
+        &self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        flags: u32,
+        reply: ReplyCreate,
+    ) {
+        match self.create(req, parent, name, mode, flags) {
+            Ok((entry, opened)) => reply.created(
+                &self.ttl(),
+                &entry.attr,
+                entry.generation,
+                opened.fh,
+                opened.flags,
+            ),
+            Err(e) => reply.error(e.code()),
+        }
+    }
+
+    fn bento_getlk(
+        &self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: u32,
+        pid: u32,
+        reply: ReplyLock,
+    ) {
+        match self.getlk(req, ino, fh, lock_owner, start, end, typ, pid) {
+            Ok(lock) => reply.locked(lock.start, lock.end, lock.typ, lock.pid),
+            Err(e) => reply.error(e.code()),
+        }
+    }
+
+    fn bento_setlk(
+        &self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: u32,
+        pid: u32,
+        sleep: bool,
+        reply: ReplyEmpty,
+    ) {
+        match self.setlk(req, ino, fh, lock_owner, start, end, typ, pid, sleep) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.code()),
+        }
+    }
+
+    fn bento_bmap(&self, req: &Request, ino: u64, blocksize: u32, idx: u64, reply: ReplyBmap) {
+        match self.bmap(req, ino, blocksize, idx) {
+            Ok(block) => reply.bmap(block),
+            Err(e) => reply.error(e.code()),
+        }
+    }
+
+    fn bento_fallocate(
+        &self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        length: i64,
+        mode: u32,
+        reply: ReplyEmpty,
+    ) {
+        match self.fallocate(req, ino, fh, offset, length, mode) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.code()),
+        }
+    }
+
+    fn bento_lseek(
+        &self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        whence: u32,
+        reply: ReplyLseek,
+    ) {
+        match self.lseek(req, ino, fh, offset, whence) {
+            Ok(offset) => reply.offset(offset),
+            Err(e) => reply.error(e.code()),
+        }
+    }
+
+    fn bento_ioctl(
+        &self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        flags: u32,
+        cmd: u32,
+        in_data: &[u8],
+        out_size: u32,
+        reply: ReplyIoctl,
+    ) {
+        match self.ioctl(req, ino, fh, flags, cmd, in_data, out_size) {
+            Ok((_, data)) if data.len() > out_size as usize => reply.error(Errno::EIO.code()),
+            Ok((result, data)) => reply.ioctl(result, &data),
+            Err(e) => reply.error(e.code()),
+        }
+    }
+
+    fn bento_copy_file_range(
+        &self,
+        req: &Request,
+        ino_in: u64,
+        fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        flags: u32,
+        reply: ReplyWrite,
+    ) {
+        match self.copy_file_range(
+            req, ino_in, fh_in, offset_in, ino_out, fh_out, offset_out, len, flags,
+        ) {
+            Ok(size) => reply.written(size),
+            Err(e) => reply.error(e.code()),
+        }
+    }
+}
+
+// A size of 0 asks for the size of the value only. If the value does not fit in the requested
+// size, the caller gets ERANGE.
+fn xattr_reply(value: &[u8], size: u32, reply: ReplyXattr) {
+    if size == 0 {
+        reply.size(value.len() as u32);
+    } else if value.len() > size as usize {
+        reply.error(Errno::ERANGE.code());
+    } else {
+        reply.data(value);
+    }
+}
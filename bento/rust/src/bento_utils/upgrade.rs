@@ -0,0 +1,186 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use core::mem;
+use core::ptr;
+use core::slice;
+
+use crate::libc;
+
+use kernel::raw::c_void;
+
+use postcard;
+
+use serde::{Serialize, Deserialize};
+
+/// Magic number at the start of every serialized upgrade state ("BTNS").
+const BENTO_STATE_MAGIC: u32 = 0x42544e53;
+
+/// Layout of the header and encoding of the state payload. Bumped if `StateHeader` or the serde
+/// format used for the payload changes.
+///
+/// 2: added the read-only flag and the origin fields to the header.
+const BENTO_STATE_FORMAT: u32 = 2;
+
+/// Set in `StateHeader::flags` if the old module returned a state from `bento_update_prepare`.
+const STATE_PRESENT: u32 = 1 << 0;
+
+/// Set in `StateHeader::flags` if the filesystem instance is mounted read-only.
+const STATE_READ_ONLY: u32 = 1 << 1;
+
+/// Header in front of the serialized state handed from the old module to the new module.
+///
+/// The header is stored unaligned in a byte buffer, so it must only be accessed through
+/// `ptr::read_unaligned` and `ptr::write_unaligned`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct StateHeader {
+    magic: u32,
+    format: u32,
+    version: u32,
+    flags: u32,
+    len: u64,
+    // The filesystem and dispatch function of the old module, so the new module can tell it when
+    // the upgrade is complete.
+    origin_fs: u64,
+    origin_dispatch: u64,
+}
+
+/// Serialized state of a filesystem during a live upgrade.
+///
+/// The state is serialized with postcard, a compact no_std serde format, and prefixed with a
+/// header carrying the state version set by the old module. Unlike passing the state as a raw
+/// pointer, the new module never interprets the old module's memory layout, so a state type can
+/// change between versions as long as the version is bumped.
+///
+/// A `SerializedState` is passed to C as a single pointer. The length is stored in the header, so
+/// `into_raw` and `from_raw` don't need a separate size argument.
+pub struct SerializedState {
+    buf: Box<[u8]>,
+}
+
+impl SerializedState {
+    /// Serialize `state` with the state version `version`.
+    ///
+    /// `state` is `None` if the old module has no state to hand over. Returns `EIO` if the state
+    /// can't be serialized.
+    pub fn new<T: Serialize>(version: u32, state: Option<&T>) -> Result<Self, i32> {
+        let (flags, payload) = match state {
+            Some(x) => (STATE_PRESENT, postcard::to_allocvec(x).map_err(|_| libc::EIO)?),
+            None => (0, Vec::new()),
+        };
+        let header = StateHeader {
+            magic: BENTO_STATE_MAGIC,
+            format: BENTO_STATE_FORMAT,
+            version: version,
+            flags: flags,
+            len: payload.len() as u64,
+            origin_fs: 0,
+            origin_dispatch: 0,
+        };
+        let mut buf: Vec<u8> = Vec::with_capacity(mem::size_of::<StateHeader>() + payload.len());
+        buf.resize(mem::size_of::<StateHeader>(), 0);
+        unsafe {
+            ptr::write_unaligned(buf.as_mut_ptr() as *mut StateHeader, header);
+        }
+        buf.extend_from_slice(&payload);
+        Ok(SerializedState {
+            buf: buf.into_boxed_slice(),
+        })
+    }
+
+    /// Give up ownership of the buffer so it can be passed to the new module.
+    pub fn into_raw(self) -> *const u8 {
+        Box::into_raw(self.buf) as *const u8
+    }
+
+    /// Take ownership of a buffer created by `into_raw`, possibly in another module.
+    ///
+    /// Returns `EINVAL` if the buffer doesn't start with a valid header. In that case, the buffer
+    /// can't be freed because its size is unknown.
+    ///
+    /// # Safety
+    /// `ptr` must have been returned by `into_raw` and not been passed to `from_raw` before.
+    pub unsafe fn from_raw(ptr: *const u8) -> Result<Self, i32> {
+        if ptr.is_null() {
+            return Err(libc::EINVAL);
+        }
+        // The magic and the format are at the start of the header in every format, so check them
+        // before reading a header that may be larger than the one written by an older module.
+        let magic = ptr::read_unaligned(ptr as *const u32);
+        let format = ptr::read_unaligned((ptr as *const u32).offset(1));
+        if magic != BENTO_STATE_MAGIC || format != BENTO_STATE_FORMAT {
+            return Err(libc::EINVAL);
+        }
+        let header = ptr::read_unaligned(ptr as *const StateHeader);
+        let len = mem::size_of::<StateHeader>() + header.len as usize;
+        let buf = slice::from_raw_parts_mut(ptr as *mut u8, len);
+        Ok(SerializedState {
+            buf: Box::from_raw(buf as *mut [u8]),
+        })
+    }
+
+    fn header(&self) -> StateHeader {
+        unsafe { ptr::read_unaligned(self.buf.as_ptr() as *const StateHeader) }
+    }
+
+    /// Get the state version set by the module that serialized the state.
+    pub fn version(&self) -> u32 {
+        self.header().version
+    }
+
+    /// Returns true if the old module returned a state from `bento_update_prepare`.
+    pub fn has_state(&self) -> bool {
+        self.header().flags & STATE_PRESENT != 0
+    }
+
+    /// Get the serialized state, without the header.
+    pub fn payload(&self) -> &[u8] {
+        &self.buf[mem::size_of::<StateHeader>()..]
+    }
+
+    /// Returns true if the filesystem instance that serialized the state is mounted read-only.
+    pub fn read_only(&self) -> bool {
+        self.header().flags & STATE_READ_ONLY != 0
+    }
+
+    /// Record whether the filesystem instance that serialized the state is mounted read-only.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        let mut header = self.header();
+        if read_only {
+            header.flags |= STATE_READ_ONLY;
+        } else {
+            header.flags &= !STATE_READ_ONLY;
+        }
+        unsafe {
+            ptr::write_unaligned(self.buf.as_mut_ptr() as *mut StateHeader, header);
+        }
+    }
+
+    /// Record the filesystem and dispatch function of the module that serialized the state.
+    pub fn set_origin(&mut self, fs: *const c_void, dispatch: *const c_void) {
+        let mut header = self.header();
+        header.origin_fs = fs as u64;
+        header.origin_dispatch = dispatch as u64;
+        unsafe {
+            ptr::write_unaligned(self.buf.as_mut_ptr() as *mut StateHeader, header);
+        }
+    }
+
+    /// Get the filesystem and dispatch function of the module that serialized the state.
+    pub fn origin(&self) -> (*const c_void, *const c_void) {
+        let header = self.header();
+        (header.origin_fs as *const c_void, header.origin_dispatch as *const c_void)
+    }
+}
+
+/// Deserialize a state payload serialized by `SerializedState::new`.
+///
+/// This can be used in `bento_update_migrate` to deserialize an older version of the state.
+/// Returns `EINVAL` if the payload doesn't match the layout of `T`.
+///
+/// Arguments:
+/// * `payload: &[u8]` - The serialized state, without the header.
+pub fn deserialize_state<'a, T: Deserialize<'a>>(payload: &'a [u8]) -> Result<T, i32> {
+    postcard::from_bytes(payload).map_err(|_| libc::EINVAL)
+}
@@ -0,0 +1,130 @@
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use core::ops::{Deref, DerefMut};
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+use crate::std::sync::{Condvar, Mutex};
+
+struct Completion {
+    done: Mutex<bool>,
+    cond: Condvar,
+}
+
+impl Completion {
+    fn complete(&self) {
+        if let Ok(mut done) = self.done.lock() {
+            *done = true;
+        }
+        self.cond.notify_all();
+    }
+
+    fn wait(&self) {
+        if let Ok(done) = self.done.lock() {
+            if !*done {
+                let _ = self.cond.wait_while(done, |done| *done);
+            }
+        }
+    }
+}
+
+type Registry = Mutex<Vec<(usize, Arc<Completion>)>>;
+
+// Deferred replies that dispatch has not waited on yet, keyed by the address of the reply.
+static REGISTRY: AtomicPtr<Registry> = AtomicPtr::new(ptr::null_mut());
+// Number of entries in the registry, so requests that are answered directly never take its lock.
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+
+fn registry() -> &'static Registry {
+    let mut reg = REGISTRY.load(Ordering::SeqCst);
+    if reg.is_null() {
+        let new = Box::into_raw(Box::new(Mutex::new(Vec::new())));
+        match REGISTRY.compare_exchange(ptr::null_mut(), new, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => reg = new,
+            Err(x) => {
+                unsafe { drop(Box::from_raw(new)) };
+                reg = x;
+            }
+        }
+    }
+    return unsafe { &*reg };
+}
+
+/// A reply that is completed after the request handler returns.
+///
+/// Created by calling `defer` on a reply, e.g. `reply.defer()`. The deferred reply can be moved to
+/// another thread, such as a `std::thread::spawn` worker, and is used just like the original
+/// reply. The request is answered when the deferred reply is dropped.
+///
+/// Only the thread that issued the request waits for the deferred reply: `dispatch` blocks after
+/// the handler returns until the deferred reply is dropped, while other requests keep being
+/// dispatched. The handler should therefore release any locks it holds before returning. After
+/// calling `defer`, the handler must not use the original reply again, and every reply can only
+/// be deferred once.
+pub struct Deferred<T> {
+    reply: *mut T,
+    done: Arc<Completion>,
+}
+
+unsafe impl<T> Send for Deferred<T> {}
+
+impl<T> Deferred<T> {
+    /// Safety: `reply` must be the reply passed to the handler by `dispatch`, which keeps it alive
+    /// until the `Deferred` is dropped.
+    pub(crate) unsafe fn new(reply: *mut T) -> Self {
+        let done = Arc::new(Completion {
+            done: Mutex::new(false),
+            cond: Condvar::new(),
+        });
+        if let Ok(mut reg) = registry().lock() {
+            reg.push((reply as usize, done.clone()));
+            PENDING.fetch_add(1, Ordering::SeqCst);
+        }
+        Deferred {
+            reply: reply,
+            done: done,
+        }
+    }
+}
+
+impl<T> Deref for Deferred<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.reply }
+    }
+}
+
+impl<T> DerefMut for Deferred<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.reply }
+    }
+}
+
+impl<T> Drop for Deferred<T> {
+    fn drop(&mut self) {
+        self.done.complete();
+    }
+}
+
+/// Wait until a deferred reply to `reply` is completed.
+///
+/// Returns immediately if the reply was not deferred. Called by `dispatch` after the request
+/// handler returns and before the reply is read.
+pub fn wait_deferred<T>(reply: &T) {
+    if PENDING.load(Ordering::SeqCst) == 0 {
+        return;
+    }
+    let key = reply as *const T as usize;
+    let done = match registry().lock() {
+        Ok(mut reg) => match reg.iter().position(|(k, _)| *k == key) {
+            Some(idx) => reg.swap_remove(idx).1,
+            None => return,
+        },
+        Err(_) => return,
+    };
+    PENDING.fetch_sub(1, Ordering::SeqCst);
+    done.wait();
+}
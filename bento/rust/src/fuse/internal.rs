@@ -42,6 +42,12 @@ pub use bindings::fuse_opcode_FUSE_CREATE;
 pub use bindings::fuse_opcode_FUSE_BMAP;
 pub use bindings::fuse_opcode_FUSE_DESTROY;
 pub use bindings::fuse_opcode_FUSE_RENAME2;
+pub use bindings::fuse_opcode_FUSE_FALLOCATE;
+pub use bindings::fuse_opcode_FUSE_LSEEK;
+pub use bindings::fuse_opcode_FUSE_IOCTL;
+pub use bindings::fuse_opcode_FUSE_READDIRPLUS;
+pub use bindings::fuse_opcode_FUSE_COPY_FILE_RANGE;
+pub use bindings::fuse_opcode_FUSE_BATCH_FORGET;
 
 pub use bindings::fuse_bmap_in;
 pub use bindings::fuse_bmap_out;
@@ -70,7 +76,24 @@ pub use bindings::fuse_setattr_in;
 pub use bindings::fuse_attr_out;
 pub use bindings::fuse_getattr_in;
 pub use bindings::fuse_forget_in;
+pub use bindings::fuse_forget_one;
+pub use bindings::fuse_batch_forget_in;
 pub use bindings::fuse_init_out;
+pub use bindings::fuse_fallocate_in;
+pub use bindings::fuse_lseek_in;
+pub use bindings::fuse_lseek_out;
+pub use bindings::fuse_ioctl_in;
+pub use bindings::fuse_ioctl_out;
+pub use bindings::fuse_copy_file_range_in;
+
+pub use bindings::fuse_notify_code_FUSE_NOTIFY_INVAL_INODE;
+pub use bindings::fuse_notify_code_FUSE_NOTIFY_INVAL_ENTRY;
+pub use bindings::fuse_notify_code_FUSE_NOTIFY_STORE;
+pub use bindings::fuse_notify_code_FUSE_NOTIFY_DELETE;
+pub use bindings::fuse_notify_inval_inode_out;
+pub use bindings::fuse_notify_inval_entry_out;
+pub use bindings::fuse_notify_delete_out;
+pub use bindings::fuse_notify_store_out;
 
 pub use bindings::fuse_in_header;
 pub use bindings::fuse_out_header;
@@ -1,13 +1,23 @@
+mod deferred;
 mod internal;
+mod notify;
+mod quiesce;
+mod readonly;
 mod reply;
 mod request;
+mod stats;
 
 use time::Timespec;
 
 pub use self::reply::{ReplyEmpty, ReplyData, ReplyEntry, ReplyAttr, ReplyOpen};
 pub use self::reply::{ReplyWrite, ReplyStatfs, ReplyCreate, ReplyLock, ReplyBmap, ReplyDirectory};
-pub use self::reply::ReplyXattr;
-pub use self::request::{Request,FuseConnInfo,dispatch};
+pub use self::reply::{ReplyXattr, ReplyLseek, ReplyIoctl, ReplyDirectoryPlus};
+pub use self::request::{Request,FuseConnInfo,dispatch,create_instance,destroy_instance,bento_in,bento_out};
+pub use self::notify::Notifier;
+pub use self::deferred::Deferred;
+pub use self::quiesce::last_upgrade_pause_ns;
+pub use self::stats::{OpStats, LATENCY_BUCKETS, stats_snapshot, reset_stats, dump_stats};
+pub(crate) use self::stats::{show_stats, clear_stats};
 
 pub mod consts {
     // Bitmasks for fuse_setattr_in.valid
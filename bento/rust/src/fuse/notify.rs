@@ -0,0 +1,132 @@
+use alloc::vec::Vec;
+
+use core::mem;
+
+use crate::libc;
+
+use fuse::internal::*;
+
+use kernel::ffi::rs_bento_notify;
+use kernel::raw;
+
+use crate::std::ffi::OsStr;
+
+/// Handle for sending cache invalidation notifications to the kernel.
+///
+/// Filesystems whose contents can change without going through Bento, such as a filesystem backed
+/// by a remote server, can use this to tell the kernel that cached attributes, directory entries or
+/// page cache contents are stale. The `Notifier` of a mount is passed to `bento_init` in
+/// `FuseConnInfo::notifier`, and the filesystem can keep a copy of it.
+///
+/// Notifications are sent through BentoFS, which looks up the mount of the instance. They fail
+/// with `ENOSYS` if the loaded version of BentoFS doesn't support notifications. A notifier must
+/// not be used once `bento_destroy` has returned.
+///
+/// Notifications must not be sent from a request handler while the kernel holds a lock on the
+/// affected inode or directory, e.g. `inval_entry` on `parent` from inside a lookup in `parent`,
+/// or the request will deadlock.
+#[derive(Clone, Copy, Debug)]
+pub struct Notifier {
+    // Address of the filesystem instance, which BentoFS keeps with the superblock of the mount
+    fs: usize,
+}
+
+impl Notifier {
+    // Create a notifier for the mount of the filesystem instance at address `fs`.
+    pub(crate) fn new(fs: usize) -> Self {
+        Notifier { fs: fs }
+    }
+
+    /// Invalidate cached attributes and data of an inode.
+    ///
+    /// Data in the range starting at `offset` of length `len` is dropped from the page cache. A
+    /// negative `offset` only invalidates the attributes, and a `len` of 0 invalidates up to the
+    /// end of the file.
+    ///
+    /// Arguments:
+    /// * `ino: u64` - Filesystem-provided inode number.
+    /// * `offset: i64` - Start of the range to invalidate.
+    /// * `len: i64` - Length of the range to invalidate.
+    pub fn inval_inode(&self, ino: u64, offset: i64, len: i64) -> Result<(), i32> {
+        let mut arg: fuse_notify_inval_inode_out = unsafe { mem::zeroed() };
+        arg.ino = ino;
+        arg.off = offset;
+        arg.len = len;
+        return self.send(fuse_notify_code_FUSE_NOTIFY_INVAL_INODE, &arg, &[]);
+    }
+
+    /// Invalidate a cached directory entry and the attributes of its parent.
+    ///
+    /// Arguments:
+    /// * `parent: u64` - Filesystem-provided inode number of the parent directory.
+    /// * `name: &OsStr` - Name of the directory entry.
+    pub fn inval_entry(&self, parent: u64, name: &OsStr) -> Result<(), i32> {
+        let name_bytes = name.to_str().ok_or(libc::EINVAL)?.as_bytes();
+        let mut arg: fuse_notify_inval_entry_out = unsafe { mem::zeroed() };
+        arg.parent = parent;
+        arg.namelen = name_bytes.len() as u32;
+        let data = Self::name_data(name_bytes);
+        return self.send(fuse_notify_code_FUSE_NOTIFY_INVAL_ENTRY, &arg, &data);
+    }
+
+    /// Store data in the page cache of an inode.
+    ///
+    /// The data is written to the page cache starting at `offset`, and the file size is extended
+    /// if needed. This does not cause a write request.
+    ///
+    /// Arguments:
+    /// * `ino: u64` - Filesystem-provided inode number.
+    /// * `offset: u64` - Offset into the file to store the data at.
+    /// * `data: &[u8]` - Data to store.
+    pub fn store(&self, ino: u64, offset: u64, data: &[u8]) -> Result<(), i32> {
+        let mut arg: fuse_notify_store_out = unsafe { mem::zeroed() };
+        arg.nodeid = ino;
+        arg.offset = offset;
+        arg.size = data.len() as u32;
+        return self.send(fuse_notify_code_FUSE_NOTIFY_STORE, &arg, data);
+    }
+
+    /// Notify the kernel that a directory entry has been removed.
+    ///
+    /// Like `inval_entry`, but if the entry still points to `child`, the kernel also removes it
+    /// from any open directory handles and drops the dentry as if it was unlinked.
+    ///
+    /// Arguments:
+    /// * `parent: u64` - Filesystem-provided inode number of the parent directory.
+    /// * `child: u64` - Filesystem-provided inode number of the removed entry.
+    /// * `name: &OsStr` - Name of the removed entry.
+    pub fn delete(&self, parent: u64, child: u64, name: &OsStr) -> Result<(), i32> {
+        let name_bytes = name.to_str().ok_or(libc::EINVAL)?.as_bytes();
+        let mut arg: fuse_notify_delete_out = unsafe { mem::zeroed() };
+        arg.parent = parent;
+        arg.child = child;
+        arg.namelen = name_bytes.len() as u32;
+        let data = Self::name_data(name_bytes);
+        return self.send(fuse_notify_code_FUSE_NOTIFY_DELETE, &arg, &data);
+    }
+
+    // The kernel expects names to be nul-terminated, with the nul not counted in namelen.
+    fn name_data(name_bytes: &[u8]) -> Vec<u8> {
+        let mut data = Vec::with_capacity(name_bytes.len() + 1);
+        data.extend_from_slice(name_bytes);
+        data.push(0);
+        return data;
+    }
+
+    fn send<T>(&self, code: u32, arg: &T, data: &[u8]) -> Result<(), i32> {
+        let ret = unsafe {
+            rs_bento_notify(
+                self.fs as *const raw::c_void,
+                code,
+                arg as *const T as *const raw::c_void,
+                mem::size_of::<T>() as raw::c_size_t,
+                data.as_ptr() as *const raw::c_void,
+                data.len() as raw::c_size_t,
+            )
+        };
+        match ret {
+            0 => Ok(()),
+            x => Err(-x),
+        }
+    }
+}
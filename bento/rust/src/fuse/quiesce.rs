@@ -0,0 +1,179 @@
+use alloc::boxed::Box;
+use alloc::collections::btree_map::BTreeMap;
+
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
+
+use crate::std::sync::{Condvar, Mutex};
+use crate::std::time::SystemTime;
+
+/// Filesystem and dispatch function of the module that took over after a live upgrade.
+#[derive(Clone, Copy)]
+pub(crate) struct UpgradeTarget {
+    pub fs: usize,
+    pub dispatch: usize,
+}
+
+// Requests to one filesystem instance
+#[derive(Default)]
+struct InstanceGate {
+    // Requests currently being handled by this module
+    inflight: usize,
+    // New requests wait while the instance is paused for an upgrade
+    paused: bool,
+    paused_at: Option<SystemTime>,
+}
+
+struct GateState {
+    // Keyed by the address of the filesystem instance in this module, so an upgrade of one
+    // instance doesn't pause the others. Instances are added by their first request.
+    instances: BTreeMap<usize, InstanceGate>,
+    // Set once the upgrade of a filesystem instance is complete, keyed by the address of the
+    // instance in this module. Requests that waited for the upgrade are sent to the new instance.
+    targets: BTreeMap<usize, UpgradeTarget>,
+}
+
+struct Gate {
+    state: Mutex<GateState>,
+    cond: Condvar,
+}
+
+static GATE: AtomicPtr<Gate> = AtomicPtr::new(ptr::null_mut());
+// Duration of the last upgrade pause in nanoseconds
+static LAST_PAUSE_NS: AtomicU64 = AtomicU64::new(0);
+
+fn gate() -> &'static Gate {
+    let mut gate = GATE.load(Ordering::SeqCst);
+    if gate.is_null() {
+        let new = Box::into_raw(Box::new(Gate {
+            state: Mutex::new(GateState {
+                instances: BTreeMap::new(),
+                targets: BTreeMap::new(),
+            }),
+            cond: Condvar::new(),
+        }));
+        match GATE.compare_exchange(ptr::null_mut(), new, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => gate = new,
+            Err(x) => {
+                unsafe { drop(Box::from_raw(new)) };
+                gate = x;
+            }
+        }
+    }
+    return unsafe { &*gate };
+}
+
+/// Start handling a request.
+///
+/// Blocks while the filesystem instance `fs` is paused for a live upgrade. Returns the module that
+/// took over the instance if its upgrade completed, in which case the request must be sent there
+/// instead of being handled here. Otherwise, `exit` must be called when the request is done.
+pub(crate) fn enter(fs: usize) -> Option<UpgradeTarget> {
+    let g = gate();
+    let mut state = match g.state.lock() {
+        Ok(x) => x,
+        Err(_) => return None,
+    };
+    if is_paused(&state, fs) {
+        state = match g.cond.wait_while(state, |s| !is_paused(s, fs)) {
+            Ok(x) => x,
+            Err(_) => return None,
+        };
+    }
+    if let Some(target) = state.targets.get(&fs) {
+        return Some(*target);
+    }
+    state.instances.entry(fs).or_insert_with(InstanceGate::default).inflight += 1;
+    return None;
+}
+
+/// Finish handling a request to the filesystem instance `fs` started with `enter`.
+pub(crate) fn exit(fs: usize) {
+    let g = gate();
+    if let Ok(mut state) = g.state.lock() {
+        if let Some(instance) = state.instances.get_mut(&fs) {
+            instance.inflight -= 1;
+            if instance.inflight == 0 && instance.paused {
+                g.cond.notify_all();
+            }
+        }
+    }
+}
+
+fn is_paused(state: &GateState, fs: usize) -> bool {
+    state.instances.get(&fs).map_or(false, |i| i.paused)
+}
+
+fn inflight(state: &GateState, fs: usize) -> usize {
+    state.instances.get(&fs).map_or(0, |i| i.inflight)
+}
+
+/// Stop new requests to the filesystem instance `fs` from being handled and wait for its
+/// in-flight requests to finish.
+///
+/// Called before `bento_update_prepare`, so the filesystem state is not handed over while a
+/// request is still using it. Requests to other instances are not affected.
+pub(crate) fn pause(fs: usize) {
+    let g = gate();
+    let mut state = match g.state.lock() {
+        Ok(x) => x,
+        Err(_) => return,
+    };
+    {
+        let instance = state.instances.entry(fs).or_insert_with(InstanceGate::default);
+        instance.paused = true;
+        instance.paused_at = Some(SystemTime::now());
+    }
+    if inflight(&state, fs) > 0 {
+        let _ = g.cond.wait_while(state, |s| inflight(s, fs) == 0);
+    }
+}
+
+/// Let requests through again after an upgrade.
+///
+/// If `target` is set, the upgrade of the filesystem instance `fs` succeeded and requests for it
+/// are sent to the new module from now on. Otherwise, this module keeps handling requests. Returns
+/// how long requests were paused in nanoseconds.
+pub(crate) fn resume(fs: usize, target: Option<UpgradeTarget>) -> u64 {
+    let g = gate();
+    let mut pause_ns = 0;
+    if let Ok(mut state) = g.state.lock() {
+        if let Some(instance) = state.instances.get_mut(&fs) {
+            if let Some(paused_at) = instance.paused_at.take() {
+                if let Ok(d) = SystemTime::now().duration_since(paused_at) {
+                    pause_ns = d.as_secs() * 1_000_000_000 + d.subsec_nanos() as u64;
+                }
+            }
+            instance.paused = false;
+        }
+        if let Some(target) = target {
+            state.targets.insert(fs, target);
+        }
+        g.cond.notify_all();
+    }
+    record_pause(pause_ns);
+    return pause_ns;
+}
+
+/// Forget the filesystem instance `fs` and where its requests were sent after an upgrade.
+///
+/// Called when the instance is destroyed, since its address may be reused by a new instance.
+pub(crate) fn forget(fs: usize) {
+    if let Ok(mut state) = gate().state.lock() {
+        state.instances.remove(&fs);
+        state.targets.remove(&fs);
+    }
+}
+
+/// Record the pause time of an upgrade measured by the old module.
+pub(crate) fn record_pause(pause_ns: u64) {
+    LAST_PAUSE_NS.store(pause_ns, Ordering::SeqCst);
+}
+
+/// Get how long requests were paused during the last live upgrade, in nanoseconds.
+///
+/// This covers draining in-flight requests, `bento_update_prepare` on the old module and
+/// `bento_update_transfer` on the new module. Returns 0 if there has been no upgrade.
+pub fn last_upgrade_pause_ns() -> u64 {
+    LAST_PAUSE_NS.load(Ordering::SeqCst)
+}
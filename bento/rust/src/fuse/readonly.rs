@@ -0,0 +1,47 @@
+use alloc::boxed::Box;
+use alloc::collections::btree_set::BTreeSet;
+
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use crate::std::sync::RwLock;
+
+// Addresses of the filesystem instances that are mounted read-only
+static READ_ONLY: AtomicPtr<RwLock<BTreeSet<usize>>> = AtomicPtr::new(ptr::null_mut());
+
+fn read_only_set() -> &'static RwLock<BTreeSet<usize>> {
+    let mut set = READ_ONLY.load(Ordering::SeqCst);
+    if set.is_null() {
+        let new = Box::into_raw(Box::new(RwLock::new(BTreeSet::new())));
+        match READ_ONLY.compare_exchange(ptr::null_mut(), new, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => set = new,
+            Err(x) => {
+                unsafe { drop(Box::from_raw(new)) };
+                set = x;
+            }
+        }
+    }
+    return unsafe { &*set };
+}
+
+/// Returns true if the filesystem instance `fs` is mounted read-only.
+pub(crate) fn is_read_only(fs: usize) -> bool {
+    match read_only_set().read() {
+        Ok(set) => set.contains(&fs),
+        Err(_) => false,
+    }
+}
+
+/// Set whether the filesystem instance `fs` is mounted read-only.
+///
+/// Called after `bento_init` and after a live upgrade, so requests can be checked before they
+/// reach the filesystem.
+pub(crate) fn set_read_only(fs: usize, read_only: bool) {
+    if let Ok(mut set) = read_only_set().write() {
+        if read_only {
+            set.insert(fs);
+        } else {
+            set.remove(&fs);
+        }
+    }
+}
@@ -1,9 +1,14 @@
+use core::mem;
+use core::slice;
+
 use crate::libc;
 
 use fuse::internal::*;
 
 use fuse::{FileAttr,FileType};
 
+use fuse::deferred::Deferred;
+
 use kernel::fuse::*;
 use kernel::mem::MemContainer;
 use kernel::raw;
@@ -60,6 +65,56 @@ pub fn bento_add_direntry(
     return Ok(entlen_padded);
 }
 
+/// Add a directory entry along with its attributes to the buffer
+///
+/// Encodes a `fuse_direntplus`, which is the `fuse_entry_out` that a lookup of the entry would
+/// return followed by the same record `bento_add_direntry` writes. Buffer needs to be large enough
+/// to hold the entry. If it's not, then the entry is not filled in and `EOVERFLOW` is returned.
+///
+/// The same rules for `off` apply as for `bento_add_direntry`.
+pub fn bento_add_direntry_plus(
+    buf_slice: &mut [raw::c_uchar],
+    name: &str,
+    ttl: &Timespec,
+    attr: &FileAttr,
+    generation: u64,
+    off: u64,
+) -> Result<usize, libc::c_int> {
+    let entry_len = mem::size_of::<fuse_entry_out>();
+    let entlen_padded = fuse_dirent_align(FUSE_NAME_OFFSET_DIRENTPLUS + name.len());
+
+    if entlen_padded > buf_slice.len() {
+        return Err(libc::EOVERFLOW);
+    }
+
+    let mut entry_out: fuse_entry_out = unsafe { mem::zeroed() };
+    let mut entry = ReplyEntryInternal {
+        reply: Ok(&mut entry_out),
+    };
+    entry.entry(ttl, attr, generation);
+    let entry_bytes = unsafe {
+        slice::from_raw_parts(&entry_out as *const fuse_entry_out as *const u8, entry_len)
+    };
+    buf_slice[0..entry_len].copy_from_slice(entry_bytes);
+
+    let mode = file_type_mode(attr.kind);
+    let dirent_len = bento_add_direntry(&mut buf_slice[entry_len..], name, attr.ino, mode, off)?;
+
+    return Ok(entry_len + dirent_len);
+}
+
+fn file_type_mode(kind: FileType) -> u16 {
+    match kind {
+        FileType::NamedPipe => stat::S_IFIFO,
+        FileType::CharDevice => stat::S_IFCHR,
+        FileType::BlockDevice => stat::S_IFBLK,
+        FileType::Directory => stat::S_IFDIR,
+        FileType::RegularFile => stat::S_IFREG,
+        FileType::Symlink => stat::S_IFLNK,
+        FileType::Socket => stat::S_IFSOCK,
+    }
+}
+
 pub type ReplyEntry<'a, 'b> = &'a mut ReplyEntryInternal<'b>;
 
 #[derive(Debug)]
@@ -301,6 +356,64 @@ impl<'a> ReplyDirectoryInternal<'a> {
         return &self.reply;
     }
 }
+pub type ReplyDirectoryPlus<'a, 'b> = &'a mut ReplyDirectoryPlusInternal<'b>;
+
+#[derive(Debug)]
+pub struct ReplyDirectoryPlusInternal<'a> {
+    pub reply: Result<&'a mut MemContainer<raw::c_uchar>, i32>,
+    pub length: usize,
+}
+
+impl<'a> ReplyDirectoryPlusInternal<'a> {
+    /// Add an entry to the directory reply buffer. Returns true if the buffer is full.
+    pub fn add(
+        &mut self,
+        ino: u64,
+        offset: i64,
+        attr: &FileAttr,
+        ttl: &Timespec,
+        generation: u64,
+        name: &str,
+    ) -> bool {
+        if let Ok(rep) = &mut self.reply {
+            let buf = rep.to_slice_mut();
+            let buf_slice = &mut buf[self.length..];
+            let mut entry_attr = *attr;
+            entry_attr.ino = ino;
+            return match bento_add_direntry_plus(
+                buf_slice,
+                name,
+                ttl,
+                &entry_attr,
+                generation,
+                offset as u64,
+            ) {
+                Ok(len) => {
+                    self.length += len;
+                    false
+                }
+                Err(libc::EOVERFLOW) => true,
+                _ => false,
+            };
+        }
+        return false;
+    }
+
+    pub fn ok(&mut self) {
+        if let Ok(rep) = &mut self.reply {
+            rep.truncate(self.length);
+        }
+    }
+
+    pub fn error(&mut self, err: i32) {
+        self.reply = Err(err);
+    }
+
+    pub fn reply(&self) -> &Result<&'a mut MemContainer<raw::c_uchar>, i32> {
+        return &self.reply;
+    }
+}
+
 //impl<'a> ReplyDirectory<'a,'_> {
 //    pub fn add(&mut self, ino: u64, offset: i64, kind: FileType, name: &str) -> bool {
 //        if let Ok(rep) = &mut self.reply {
@@ -529,3 +642,110 @@ impl<'a> ReplyBmapInternal<'a> {
         return &self.reply;
     }
 }
+
+pub type ReplyLseek<'a, 'b> = &'a mut ReplyLseekInternal<'b>;
+
+#[derive(Debug)]
+pub struct ReplyLseekInternal<'a> {
+    pub reply: Result<&'a mut fuse_lseek_out, i32>,
+}
+
+impl<'a> ReplyLseekInternal<'a> {
+    pub fn offset(&mut self, offset: i64) {
+        if let Ok(rep) = &mut self.reply {
+            rep.offset = offset as u64;
+        }
+    }
+
+    pub fn error(&mut self, err: i32) {
+        self.reply = Err(err);
+    }
+
+    pub fn reply(&self) -> &Result<&'a mut fuse_lseek_out, i32> {
+        return &self.reply;
+    }
+}
+
+pub type ReplyIoctl<'a, 'b> = &'a mut ReplyIoctlInternal<'b>;
+
+#[derive(Debug)]
+pub struct ReplyIoctlInternal<'a> {
+    pub reply_arg: Result<&'a mut fuse_ioctl_out, i32>,
+    pub reply_buf: Option<&'a mut MemContainer<raw::c_uchar>>,
+}
+
+impl<'a> ReplyIoctlInternal<'a> {
+    /// Reply with the ioctl return value and the data to copy back to the caller.
+    ///
+    /// `data` may not be larger than the `out_size` passed to `bento_ioctl`. If it is, the
+    /// request fails with `EIO`.
+    pub fn ioctl(&mut self, result: i32, data: &[u8]) {
+        match &mut self.reply_buf {
+            Some(buf) if data.len() <= buf.len() => {
+                buf.truncate(data.len());
+                let buf_slice = buf.to_slice_mut();
+                buf_slice.copy_from_slice(data);
+            }
+            None if data.is_empty() => {}
+            _ => {
+                self.error(libc::EIO);
+                return;
+            }
+        }
+        if let Ok(rep) = &mut self.reply_arg {
+            rep.result = result;
+            rep.flags = 0;
+            rep.in_iovs = 0;
+            rep.out_iovs = 0;
+        }
+    }
+
+    pub fn error(&mut self, err: i32) {
+        self.reply_arg = Err(err);
+    }
+
+    pub fn reply_arg(&self) -> &Result<&'a mut fuse_ioctl_out, i32> {
+        return &self.reply_arg;
+    }
+}
+
+macro_rules! impl_defer {
+    ($($t:ident),*) => {
+        $(
+            impl<'a> $t<'a> {
+                /// Defer the reply so it can be completed after the handler returns.
+                ///
+                /// See `Deferred` for details.
+                pub fn defer(&mut self) -> Deferred<$t<'static>> {
+                    unsafe { Deferred::new(self as *mut Self as *mut $t<'static>) }
+                }
+            }
+        )*
+    };
+}
+
+impl_defer!(
+    ReplyEntryInternal,
+    ReplyAttrInternal,
+    ReplyDataInternal,
+    ReplyOpenInternal,
+    ReplyWriteInternal,
+    ReplyDirectoryInternal,
+    ReplyDirectoryPlusInternal,
+    ReplyStatfsInternal,
+    ReplyXattrInternal,
+    ReplyCreateInternal,
+    ReplyLockInternal,
+    ReplyBmapInternal,
+    ReplyLseekInternal,
+    ReplyIoctlInternal
+);
+
+impl ReplyEmptyInternal {
+    /// Defer the reply so it can be completed after the handler returns.
+    ///
+    /// See `Deferred` for details.
+    pub fn defer(&mut self) -> Deferred<ReplyEmptyInternal> {
+        unsafe { Deferred::new(self as *mut Self) }
+    }
+}
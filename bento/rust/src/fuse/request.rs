@@ -1,4 +1,8 @@
 use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::cmp::min;
+use core::mem;
+use core::slice;
 use core::str;
 
 use crate::libc;
@@ -6,19 +10,50 @@ use crate::libc;
 use crate::std::ffi::OsStr;
 use crate::std::path::Path;
 
+use kernel::ffi::{capable, current_umask, rs_current_groups};
 use kernel::kobj::*;
 use kernel::mem::*;
+use kernel::panic::{fs_failed, fail_instance, forget_instance, RunningRequest};
 use kernel::raw;
+use kernel::time::{Timespec64, diff_ns, getnstimeofday64_rs};
 use crate::time::Timespec;
 
+use fuse::deferred::wait_deferred;
+use fuse::notify::Notifier;
+use fuse::quiesce;
+use fuse::quiesce::UpgradeTarget;
+use fuse::readonly::{is_read_only, set_read_only};
 use fuse::reply::*;
-use crate::bento_utils::BentoFilesystem;
+use fuse::stats;
+use crate::bento_utils::{BentoFilesystem, MountOptions, SerializedState, deserialize_state, register_again};
+use crate::bento_utils::consts::FUSE_IOCTL_UNRESTRICTED;
 use fuse::internal::*;
 
 use serde::{Serialize, Deserialize};
 
+// Direction bits of an ioctl command, from /include/uapi/asm-generic/ioctl.h
+const IOC_DIRSHIFT: u32 = 30;
+const IOC_READ: u32 = 2;
+
 const BENTO_UPDATE_PREPARE: u32 = 8192;
 const BENTO_UPDATE_TRANSFER: u32 = 8193;
+// Sent by the new module to the old module when bento_update_transfer is done
+const BENTO_UPDATE_COMPLETE: u32 = 8194;
+// Sent by the new module to the old module when bento_update_transfer failed
+const BENTO_UPDATE_ROLLBACK: u32 = 8195;
+
+/// Argument of `BENTO_UPDATE_COMPLETE`.
+///
+/// `fs` and `dispatch` are the new module's filesystem and dispatch function. The old module fills
+/// in `pause_ns`.
+#[repr(C)]
+struct bento_update_complete {
+    fs: u64,
+    dispatch: u64,
+    pause_ns: u64,
+}
+
+type DispatchFn = fn(*mut raw::c_void, fuse_opcode, &bento_in, &mut bento_out) -> i32;
 
 #[repr(C)]
 pub struct bento_in_arg {
@@ -51,6 +86,61 @@ pub struct bento_out {
     args: [bento_arg; 2],
 }
 
+impl bento_in {
+    /// Get the node id that the request is for.
+    pub fn nodeid(&self) -> u64 {
+        self.h.nodeid
+    }
+
+    /// Get the number of input arguments.
+    pub fn numargs(&self) -> usize {
+        min(self.numargs as usize, self.args.len())
+    }
+
+    /// Get an input argument as bytes.
+    ///
+    /// Returns `None` if there is no such argument or if the argument is stored in pages.
+    ///
+    /// Arguments:
+    /// * `idx: usize` - Index of the argument.
+    pub fn arg(&self, idx: usize) -> Option<&[u8]> {
+        if idx >= self.numargs() || (self.argpages != 0 && idx + 1 == self.numargs()) {
+            return None;
+        }
+        let arg = &self.args[idx];
+        if arg.value.is_null() {
+            return None;
+        }
+        Some(unsafe { slice::from_raw_parts(arg.value as *const u8, arg.size as usize) })
+    }
+}
+
+impl bento_out {
+    /// Get the number of output arguments.
+    pub fn numargs(&self) -> usize {
+        min(self.numargs as usize, self.args.len())
+    }
+
+    /// Get an output argument as bytes.
+    ///
+    /// Returns `None` if there is no such argument or if the argument is stored in pages or has a
+    /// variable size.
+    ///
+    /// Arguments:
+    /// * `idx: usize` - Index of the argument.
+    pub fn arg_mut(&mut self, idx: usize) -> Option<&mut [u8]> {
+        let last = idx + 1 == self.numargs();
+        if idx >= self.numargs() || ((self.argpages != 0 || self.argvar != 0) && last) {
+            return None;
+        }
+        let arg = &self.args[idx];
+        if arg.value.is_null() {
+            return None;
+        }
+        Some(unsafe { slice::from_raw_parts_mut(arg.value as *mut u8, arg.size as usize) })
+    }
+}
+
 #[derive(Default)]
 #[allow(dead_code)]
 pub struct FuseConnInfo {
@@ -64,6 +154,17 @@ pub struct FuseConnInfo {
     pub max_background: u32,
     pub congestion_threshold: u32,
     pub time_gran: u32,
+    /// Reject requests that change the filesystem with `EROFS`. Set from the `ro` or `read_only`
+    /// mount option before `bento_init` is called. The filesystem may also set it in `bento_init`.
+    ///
+    /// This is not set by the `MS_RDONLY` mount flag. mount(8) turns `-o ro` into `MS_RDONLY` and
+    /// removes it from the option string, and BentoFS doesn't pass the mount flags on. The VFS
+    /// still refuses changes through such a mount, but the filesystem isn't told that it is
+    /// read-only, so e.g. xv6fs still replays its journal. Mount with `-o ro,read_only` to get both.
+    pub read_only: bool,
+    /// Sends cache invalidation notifications for this mount. Always set before `bento_init` is
+    /// called.
+    pub notifier: Option<Notifier>,
     reserved: [u32; 22],
 }
 
@@ -73,7 +174,7 @@ impl FuseConnInfo {
         me.proto_major = inarg.major;
         me.proto_minor = inarg.minor;
         me.max_readahead = inarg.max_readahead;
-        me.capable = inarg.flags;
+        me.capable = inarg.flags & !BENTO_INIT_MOUNT_OPTS;
         me
     }
 
@@ -89,6 +190,14 @@ impl FuseConnInfo {
     }
 }
 
+/// Header of a request
+///
+/// `umask`, `groups` and `capable` read the credentials of the current task. Most requests are
+/// dispatched in the context of the task that made them, but requests that are not made on behalf
+/// of a process, such as writeback of dirty pages, have a pid of 0 and are dispatched from a
+/// kernel thread. For those, these methods return a umask of 022, no groups and no capabilities
+/// instead of the credentials of the kernel thread. Work that is moved to another task, e.g. with
+/// `Deferred`, must read the credentials before it is handed off.
 pub struct Request<'a> {
     pub h: &'a fuse_in_header,
 }
@@ -119,8 +228,64 @@ impl<'a> Request<'a> {
     pub fn pid(&self) -> u32 {
         self.h.pid
     }
+
+    /// Returns the umask of the process that made this request
+    ///
+    /// This is the umask of the current task, or 022 if the request has no process. For mknod,
+    /// mkdir and create, the umask has already been applied to the mode unless `FUSE_DONT_MASK`
+    /// was set in `bento_init`.
+    #[inline]
+    #[allow(dead_code)]
+    pub fn umask(&self) -> u32 {
+        if !self.has_process() {
+            return 0o022;
+        }
+        unsafe { current_umask() as u32 }
+    }
+
+    /// Returns the supplementary group ids of the process that made this request
+    ///
+    /// These are the groups of the current task, or none if the request has no process.
+    #[allow(dead_code)]
+    pub fn groups(&self) -> Vec<u32> {
+        if !self.has_process() {
+            return Vec::new();
+        }
+        let ngroups = unsafe { rs_current_groups(core::ptr::null_mut(), 0) };
+        if ngroups <= 0 {
+            return Vec::new();
+        }
+        let mut groups: Vec<u32> = Vec::new();
+        groups.resize(ngroups as usize, 0);
+        // The groups may have changed in between, so only keep what was filled in
+        let ngroups = unsafe { rs_current_groups(groups.as_mut_ptr(), groups.len() as u32) };
+        groups.truncate(min(ngroups.max(0) as usize, groups.len()));
+        return groups;
+    }
+
+    /// Returns true if the process that made this request has the capability `cap`
+    ///
+    /// Capabilities are defined in `bento_utils::consts`, e.g. `CAP_DAC_OVERRIDE` and
+    /// `CAP_FOWNER`. This checks the current task, and is always false if the request has no
+    /// process, so writeback never gets the capabilities of the kernel thread that runs it.
+    #[inline]
+    #[allow(dead_code)]
+    pub fn capable(&self, cap: i32) -> bool {
+        self.has_process() && unsafe { capable(cap) }
+    }
+
+    // Requests that are not made on behalf of a process, such as writeback, have a pid of 0 and
+    // run in a kernel thread, so the current task is not the requester.
+    #[inline]
+    fn has_process(&self) -> bool {
+        self.h.pid != 0
+    }
 }
 
+// Set in `bento_init_in.flags` by versions of BentoFS that pass `mount_opts`. Older versions
+// don't have the field at all, so it must not be read unless this is set.
+const BENTO_INIT_MOUNT_OPTS: u32 = 1 << 31;
+
 #[repr(C)]
 struct bento_init_in {
     major: u32,
@@ -128,6 +293,9 @@ struct bento_init_in {
     max_readahead: u32,
     flags: u32,
     devname: CStr,
+    // Mount options that BentoFS doesn't handle itself, or NULL if there are none. Only present
+    // if `BENTO_INIT_MOUNT_OPTS` is set in `flags`.
+    mount_opts: CStr,
 }
 
 pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Serialize, T: BentoFilesystem<'de, TransferIn, TransferOut>>(
@@ -135,6 +303,120 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
     opcode: fuse_opcode,
     inarg: &bento_in,
     outarg: &mut bento_out,
+) -> i32 {
+    match opcode {
+        BENTO_UPDATE_PREPARE | BENTO_UPDATE_TRANSFER | BENTO_UPDATE_COMPLETE | BENTO_UPDATE_ROLLBACK => {
+            let _running = RunningRequest::enter(&*fs as *const T as usize);
+            return dispatch_update(fs, opcode, inarg, outarg);
+        }
+        _ => {}
+    }
+
+    let mut start = Timespec64::new();
+    getnstimeofday64_rs(&mut start);
+
+    // A filesystem instance that panicked can't be trusted with any more requests
+    let fs_ptr = &*fs as *const T as usize;
+    if fs_failed(fs_ptr) {
+        record_stats(opcode, -libc::EIO, &start);
+        return -libc::EIO;
+    }
+
+    // Requests that waited for a live upgrade to finish are handled by the new module, which
+    // records them in its own statistics
+    if let Some(target) = quiesce::enter(fs_ptr) {
+        let target_dispatch: DispatchFn = unsafe { mem::transmute(target.dispatch) };
+        return target_dispatch(target.fs as *mut raw::c_void, opcode, inarg, outarg);
+    }
+    let ret = if fs_failed(fs_ptr) {
+        -libc::EIO
+    } else if is_read_only(fs_ptr) && is_mutating(opcode, inarg) {
+        -libc::EROFS
+    } else {
+        let _running = RunningRequest::enter(fs_ptr);
+        dispatch_request(fs, opcode, inarg, outarg)
+    };
+    quiesce::exit(fs_ptr);
+    record_stats(opcode, ret, &start);
+    return ret;
+}
+
+fn record_stats(opcode: fuse_opcode, ret: i32, start: &Timespec64) {
+    let mut end = Timespec64::new();
+    getnstimeofday64_rs(&mut end);
+    stats::record(opcode, ret, diff_ns(&end, start).max(0) as u64);
+}
+
+// Returns true if the request changes the filesystem, so it must be rejected on read-only mounts.
+fn is_mutating(opcode: fuse_opcode, inarg: &bento_in) -> bool {
+    match opcode {
+        fuse_opcode_FUSE_WRITE
+        | fuse_opcode_FUSE_SETATTR
+        | fuse_opcode_FUSE_MKNOD
+        | fuse_opcode_FUSE_MKDIR
+        | fuse_opcode_FUSE_UNLINK
+        | fuse_opcode_FUSE_RMDIR
+        | fuse_opcode_FUSE_SYMLINK
+        | fuse_opcode_FUSE_RENAME
+        | fuse_opcode_FUSE_RENAME2
+        | fuse_opcode_FUSE_LINK
+        | fuse_opcode_FUSE_CREATE
+        | fuse_opcode_FUSE_SETXATTR
+        | fuse_opcode_FUSE_REMOVEXATTR
+        | fuse_opcode_FUSE_FALLOCATE
+        | fuse_opcode_FUSE_COPY_FILE_RANGE => true,
+        // Only ioctls that just copy data out, like FS_IOC_GETFLAGS, are allowed. Ioctls without
+        // a direction, like a forced journal commit, may change the filesystem too.
+        fuse_opcode_FUSE_IOCTL => {
+            if inarg.numargs < 1 {
+                return true;
+            }
+            let ioctl_in = unsafe { &*(inarg.args[0].value as *const fuse_ioctl_in) };
+            ioctl_in.cmd >> IOC_DIRSHIFT != IOC_READ
+        }
+        // Opening a file for writing or truncating it
+        fuse_opcode_FUSE_OPEN => {
+            if inarg.numargs != 1 {
+                return false;
+            }
+            let open_in = unsafe { &*(inarg.args[0].value as *const fuse_open_in) };
+            let flags = open_in.flags as i32;
+            flags & libc::O_ACCMODE != 0 || flags & libc::O_TRUNC != 0
+        }
+        _ => false,
+    }
+}
+
+/// Create a new instance of a filesystem registered with `register_factory`.
+///
+/// BentoFS calls this for every mount and keeps the returned instance with the superblock. All
+/// requests for that mount are dispatched to the instance.
+pub fn create_instance<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Serialize, T: BentoFilesystem<'de, TransferIn, TransferOut> + Default>(
+) -> *mut raw::c_void {
+    Box::into_raw(Box::new(T::default())) as *mut raw::c_void
+}
+
+/// Destroy an instance created by `create_instance`.
+///
+/// BentoFS calls this when the superblock of the instance is killed on unmount, after
+/// `FUSE_DESTROY` was dispatched to it. Other mounts of the same filesystem are not affected.
+pub fn destroy_instance<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Serialize, T: BentoFilesystem<'de, TransferIn, TransferOut>>(
+    fs: *mut raw::c_void,
+) {
+    if fs.is_null() {
+        return;
+    }
+    quiesce::forget(fs as usize);
+    set_read_only(fs as usize, false);
+    forget_instance(fs as usize);
+    unsafe { drop(Box::from_raw(fs as *mut T)) };
+}
+
+fn dispatch_request<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Serialize, T: BentoFilesystem<'de, TransferIn, TransferOut>>(
+    fs: &'static mut T,
+    opcode: fuse_opcode,
+    inarg: &bento_in,
+    outarg: &mut bento_out,
 ) -> i32 {
     match opcode {
         fuse_opcode_FUSE_INIT => {
@@ -153,13 +435,36 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
                 str::from_utf8(init_in.devname.to_bytes_with_nul()).unwrap()
             };
             let devname = OsStr::new(devname_str);
-            match fs.bento_init(&req, devname, &mut fc_info) {
+            let opts = if init_in.flags & BENTO_INIT_MOUNT_OPTS == 0
+                || init_in.mount_opts.to_raw().is_null()
+            {
+                MountOptions::new()
+            } else {
+                match str::from_utf8(init_in.mount_opts.to_bytes_with_nul()) {
+                    Ok(x) => MountOptions::parse(x),
+                    Err(_) => return -libc::EINVAL,
+                }
+            };
+            // `ro` only arrives here if it is passed in the mount data directly, since mount(8)
+            // turns it into MS_RDONLY, which BentoFS doesn't pass on. `read_only` is passed
+            // through by mount(8). See `FuseConnInfo::read_only`.
+            fc_info.read_only = match (opts.get_bool("ro"), opts.get_bool("read_only")) {
+                (Ok(ro), Ok(read_only)) => ro.unwrap_or(false) || read_only.unwrap_or(false),
+                (Err(x), _) | (_, Err(x)) => return -x,
+            };
+            fc_info.notifier = Some(Notifier::new(&*fs as *const T as usize));
+            let ret = match fs.bento_init(&req, devname, &opts, &mut fc_info) {
                 Ok(()) => {
                     fc_info.to_init_out(init_out);
+                    set_read_only(&*fs as *const T as usize, fc_info.read_only);
                     0
                 }
                 Err(x) => x as i32,
+            };
+            for opt in opts.unknown() {
+                println!("{}: unknown mount option {}", display_name(fs.get_name()), opt);
             }
+            ret
         }
         fuse_opcode_FUSE_DESTROY => {
             let req = Request { h: &inarg.h };
@@ -178,6 +483,7 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
             };
             let name_str = OsStr::new(str::from_utf8(name.to_bytes_with_nul()).unwrap());
             fs.bento_lookup(&req, inarg.h.nodeid, name_str, &mut reply);
+            wait_deferred(&reply);
             match reply.reply() {
                 Ok(_) => 0,
                 Err(x) => -*x,
@@ -193,6 +499,23 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
             fs.bento_forget(&req, inarg.h.nodeid, forget_in.nlookup);
             0
         }
+        fuse_opcode_FUSE_BATCH_FORGET => {
+            if inarg.numargs != 2 || outarg.numargs != 0 {
+                return -1;
+            }
+            let req = Request { h: &inarg.h };
+            let batch_in = unsafe { &*(inarg.args[0].value as *const fuse_batch_forget_in) };
+            let max_count = inarg.args[1].size as usize / mem::size_of::<fuse_forget_one>();
+            let count = min(batch_in.count as usize, max_count);
+            let forgets = unsafe {
+                slice::from_raw_parts(inarg.args[1].value as *const fuse_forget_one, count)
+            };
+
+            for forget in forgets {
+                fs.bento_forget(&req, forget.nodeid, forget.nlookup);
+            }
+            0
+        }
         fuse_opcode_FUSE_GETATTR => {
             if inarg.numargs != 1 || outarg.numargs != 1 {
                 return -1;
@@ -206,6 +529,7 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
                 reply: Ok(getattr_out),
             };
             fs.bento_getattr(&req, inarg.h.nodeid, &mut reply);
+            wait_deferred(&reply);
             match reply.reply() {
                 Ok(_) => 0,
                 Err(x) => -*x,
@@ -273,6 +597,7 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
                 None,
                 &mut reply,
             );
+            wait_deferred(&reply);
             match reply.reply() {
                 Ok(_) => 0,
                 Err(x) => -*x,
@@ -290,6 +615,7 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
                 reply: Ok(data_out),
             };
             fs.bento_readlink(&req, inarg.h.nodeid, &mut reply);
+            wait_deferred(&reply);
             match reply.reply() {
                 Ok(buf) => {
                     let buf_slice = buf.to_slice();
@@ -319,6 +645,7 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
                 mknod_in.rdev,
                 &mut reply,
             );
+            wait_deferred(&reply);
             match reply.reply() {
                 Ok(_) => 0,
                 Err(x) => -*x,
@@ -337,6 +664,7 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
                 reply: Ok(entry_out),
             };
             fs.bento_mkdir(&req, inarg.h.nodeid, name_str, mkdir_in.mode, &mut reply);
+            wait_deferred(&reply);
             match reply.reply() {
                 Ok(_) => 0,
                 Err(x) => -*x,
@@ -353,6 +681,7 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
                 reply: Err(libc::ENOSYS),
             };
             fs.bento_unlink(&req, inarg.h.nodeid, name_str, &mut reply);
+            wait_deferred(&reply);
             match reply.reply() {
                 Ok(_) => 0,
                 Err(x) => -*x,
@@ -369,6 +698,7 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
                 reply: Err(libc::ENOSYS),
             };
             fs.bento_rmdir(&req, inarg.h.nodeid, name_str, &mut reply);
+            wait_deferred(&reply);
             match reply.reply() {
                 Ok(_) => 0,
                 Err(x) => -*x,
@@ -389,6 +719,7 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
                 reply: Ok(entry_out),
             };
             fs.bento_symlink(&req, inarg.h.nodeid, name_str, link_path, &mut reply);
+            wait_deferred(&reply);
             match reply.reply() {
                 Ok(_) => 0,
                 Err(x) => -*x,
@@ -416,6 +747,7 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
                 rename_in.flags,
                 &mut reply,
             );
+            wait_deferred(&reply);
             match reply.reply() {
                 Ok(_) => 0,
                 Err(x) => -*x,
@@ -441,6 +773,7 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
                 name_str,
                 &mut reply,
             );
+            wait_deferred(&reply);
             match reply.reply() {
                 Ok(_) => 0,
                 Err(x) => -*x,
@@ -459,6 +792,7 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
                 reply: Ok(open_out),
             };
             fs.bento_open(&req, inarg.h.nodeid, open_in.flags, &mut reply);
+            wait_deferred(&reply);
             match reply.reply() {
                 Ok(_) => 0,
                 Err(x) => -*x,
@@ -484,6 +818,7 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
                 read_in.size,
                 &mut reply,
             );
+            wait_deferred(&reply);
             match reply.reply() {
                 Ok(buf) => buf.len() as i32,
                 Err(x) => -*x,
@@ -511,6 +846,7 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
                 write_in.write_flags,
                 &mut reply,
             );
+            wait_deferred(&reply);
             match reply.reply() {
                 Ok(rep) => rep.size as i32,
                 Err(x) => -*x,
@@ -533,6 +869,7 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
                 flush_in.lock_owner,
                 &mut reply,
             );
+            wait_deferred(&reply);
             match reply.reply() {
                 Ok(_) => 0,
                 Err(x) => -*x,
@@ -557,6 +894,7 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
                 false,
                 &mut reply,
             );
+            wait_deferred(&reply);
             match reply.reply() {
                 Ok(_) => 0,
                 Err(x) => -*x,
@@ -577,6 +915,7 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
                 _ => false,
             };
             fs.bento_fsync(&req, inarg.h.nodeid, fsync_in.fh, datasync, &mut reply);
+            wait_deferred(&reply);
             match reply.reply() {
                 Ok(_) => 0,
                 Err(x) => -*x,
@@ -595,6 +934,7 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
                 reply: Ok(open_out),
             };
             fs.bento_opendir(&req, inarg.h.nodeid, open_in.flags, &mut reply);
+            wait_deferred(&reply);
             match reply.reply() {
                 Ok(_) => 0,
                 Err(x) => -*x,
@@ -620,6 +960,36 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
                 read_in.offset as i64,
                 &mut reply,
             );
+            wait_deferred(&reply);
+            match reply.reply() {
+                Ok(buf) => {
+                    outarg.args[0].size = buf.len() as u32;
+                    0
+                }
+                Err(x) => -*x,
+            }
+        }
+        fuse_opcode_FUSE_READDIRPLUS => {
+            if inarg.numargs != 1 || outarg.numargs != 1 {
+                return -1;
+            }
+
+            let req = Request { h: &inarg.h };
+            let read_in = unsafe { &*(inarg.args[0].value as *const fuse_read_in) };
+            let data_out =
+                unsafe { &mut *(outarg.args[0].value as *mut MemContainer<raw::c_uchar>) };
+            let mut reply = ReplyDirectoryPlusInternal {
+                reply: Ok(data_out),
+                length: 0,
+            };
+            fs.bento_readdirplus(
+                &req,
+                inarg.h.nodeid,
+                read_in.fh,
+                read_in.offset as i64,
+                &mut reply,
+            );
+            wait_deferred(&reply);
             match reply.reply() {
                 Ok(buf) => {
                     outarg.args[0].size = buf.len() as u32;
@@ -645,6 +1015,7 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
                 release_in.flags,
                 &mut reply,
             );
+            wait_deferred(&reply);
             match reply.reply() {
                 Ok(_) => 0,
                 Err(x) => -*x,
@@ -665,6 +1036,7 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
                 _ => false,
             };
             fs.bento_fsyncdir(&req, inarg.h.nodeid, fsync_in.fh, datasync, &mut reply);
+            wait_deferred(&reply);
             match reply.reply() {
                 Ok(_) => 0,
                 Err(x) => -*x,
@@ -681,6 +1053,7 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
                 reply: Ok(statfs_out),
             };
             fs.bento_statfs(&req, inarg.h.nodeid, &mut reply);
+            wait_deferred(&reply);
             match reply.reply() {
                 Ok(_) => 0,
                 Err(x) => -*x,
@@ -711,6 +1084,7 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
                 setxattr_in.size,
                 &mut reply,
             );
+            wait_deferred(&reply);
             match reply.reply() {
                 Ok(_) => 0,
                 Err(x) => -*x,
@@ -734,6 +1108,7 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
                     reply_buf: Ok(data_out),
                 };
                 fs.bento_getxattr(&req, inarg.h.nodeid, name_str, getxattr_in.size, &mut reply);
+                wait_deferred(&reply);
                 match reply.reply_buf() {
                     Ok(_) => 0,
                     Err(x) => -*x,
@@ -746,6 +1121,7 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
                     reply_buf: Err(libc::ENOSYS),
                 };
                 fs.bento_getxattr(&req, inarg.h.nodeid, name_str, getxattr_in.size, &mut reply);
+                wait_deferred(&reply);
                 match reply.reply_arg() {
                     Ok(_) => 0,
                     Err(x) => -*x,
@@ -768,6 +1144,7 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
                     reply_buf: Ok(data_out),
                 };
                 fs.bento_listxattr(&req, inarg.h.nodeid, getxattr_in.size, &mut reply);
+                wait_deferred(&reply);
                 match reply.reply_buf() {
                     Ok(_) => 0,
                     Err(x) => -*x,
@@ -780,6 +1157,7 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
                     reply_buf: Err(libc::ENOSYS),
                 };
                 fs.bento_listxattr(&req, inarg.h.nodeid, getxattr_in.size, &mut reply);
+                wait_deferred(&reply);
                 match reply.reply_arg() {
                     Ok(_) => 0,
                     Err(x) => -*x,
@@ -799,6 +1177,7 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
                 reply: Err(libc::ENOSYS),
             };
             fs.bento_removexattr(&req, inarg.h.nodeid, name_str, &mut reply);
+            wait_deferred(&reply);
             match reply.reply() {
                 Ok(_) => 0,
                 Err(x) => -*x,
@@ -816,6 +1195,7 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
                 reply: Err(libc::ENOSYS),
             };
             fs.bento_access(&req, inarg.h.nodeid, access_in.mask, &mut reply);
+            wait_deferred(&reply);
             match reply.reply() {
                 Ok(_) => 0,
                 Err(x) => -*x,
@@ -843,6 +1223,7 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
                 create_in.flags,
                 &mut reply,
             );
+            wait_deferred(&reply);
             match reply.reply() {
                 Ok(_) => 0,
                 Err(x) => -*x,
@@ -870,6 +1251,7 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
                 getlk_in.lk.pid,
                 &mut reply,
             );
+            wait_deferred(&reply);
             match reply.reply() {
                 Ok(_) => 0,
                 Err(x) => -*x,
@@ -897,6 +1279,7 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
                 false,
                 &mut reply,
             );
+            wait_deferred(&reply);
             match reply.reply() {
                 Ok(_) => 0,
                 Err(x) => -*x,
@@ -924,6 +1307,7 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
                 true,
                 &mut reply,
             );
+            wait_deferred(&reply);
             match reply.reply() {
                 Ok(_) => 0,
                 Err(x) => -*x,
@@ -947,23 +1331,194 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
                 bmap_in.block,
                 &mut reply,
             );
+            wait_deferred(&reply);
             match reply.reply() {
                 Ok(_) => 0,
                 Err(x) => -*x,
             }
         }
+        fuse_opcode_FUSE_FALLOCATE => {
+            if inarg.numargs != 1 {
+                return -1;
+            }
+
+            let req = Request { h: &inarg.h };
+            let fallocate_in = unsafe { &*(inarg.args[0].value as *const fuse_fallocate_in) };
+            let mut reply = ReplyEmptyInternal {
+                reply: Err(libc::ENOSYS),
+            };
+            fs.bento_fallocate(
+                &req,
+                inarg.h.nodeid,
+                fallocate_in.fh,
+                fallocate_in.offset as i64,
+                fallocate_in.length as i64,
+                fallocate_in.mode,
+                &mut reply,
+            );
+            wait_deferred(&reply);
+            match reply.reply() {
+                Ok(_) => 0,
+                Err(x) => -*x,
+            }
+        }
+        fuse_opcode_FUSE_LSEEK => {
+            if inarg.numargs != 1 || outarg.numargs != 1 {
+                return -1;
+            }
+
+            let req = Request { h: &inarg.h };
+            let lseek_in = unsafe { &*(inarg.args[0].value as *const fuse_lseek_in) };
+            let lseek_out = unsafe { &mut *(outarg.args[0].value as *mut fuse_lseek_out) };
+            let mut reply = ReplyLseekInternal {
+                reply: Ok(lseek_out),
+            };
+            fs.bento_lseek(
+                &req,
+                inarg.h.nodeid,
+                lseek_in.fh,
+                lseek_in.offset as i64,
+                lseek_in.whence,
+                &mut reply,
+            );
+            wait_deferred(&reply);
+            match reply.reply() {
+                Ok(_) => 0,
+                Err(x) => -*x,
+            }
+        }
+        fuse_opcode_FUSE_IOCTL => {
+            if inarg.numargs < 1 || inarg.numargs > 2 || outarg.numargs < 1 || outarg.numargs > 2 {
+                return -1;
+            }
+
+            let req = Request { h: &inarg.h };
+            let ioctl_in = unsafe { &*(inarg.args[0].value as *const fuse_ioctl_in) };
+            // Only restricted ioctls are supported, so the kernel has already copied in all input
+            // and there is no need to ask it to retry with different iovecs.
+            if ioctl_in.flags & FUSE_IOCTL_UNRESTRICTED != 0 {
+                return -libc::ENOSYS;
+            }
+            let data: &[u8] = if inarg.numargs == 2 {
+                let data_in =
+                    unsafe { &*(inarg.args[1].value as *const MemContainer<raw::c_uchar>) };
+                data_in.to_slice()
+            } else {
+                &[]
+            };
+            let ioctl_out = unsafe { &mut *(outarg.args[0].value as *mut fuse_ioctl_out) };
+            let data_out = if outarg.numargs == 2 {
+                Some(unsafe { &mut *(outarg.args[1].value as *mut MemContainer<raw::c_uchar>) })
+            } else {
+                None
+            };
+            let mut reply = ReplyIoctlInternal {
+                reply_arg: Ok(ioctl_out),
+                reply_buf: data_out,
+            };
+            fs.bento_ioctl(
+                &req,
+                inarg.h.nodeid,
+                ioctl_in.fh,
+                ioctl_in.flags,
+                ioctl_in.cmd,
+                data,
+                ioctl_in.out_size,
+                &mut reply,
+            );
+            wait_deferred(&reply);
+            match reply.reply_arg() {
+                Ok(_) => 0,
+                Err(x) => -*x,
+            }
+        }
+        fuse_opcode_FUSE_COPY_FILE_RANGE => {
+            if inarg.numargs != 1 || outarg.numargs != 1 {
+                return -1;
+            }
+
+            let req = Request { h: &inarg.h };
+            let copy_in = unsafe { &*(inarg.args[0].value as *const fuse_copy_file_range_in) };
+            let write_out = unsafe { &mut *(outarg.args[0].value as *mut fuse_write_out) };
+            let mut reply = ReplyWriteInternal {
+                reply: Ok(write_out),
+            };
+            fs.bento_copy_file_range(
+                &req,
+                inarg.h.nodeid,
+                copy_in.fh_in,
+                copy_in.off_in as i64,
+                copy_in.nodeid_out,
+                copy_in.fh_out,
+                copy_in.off_out as i64,
+                copy_in.len,
+                copy_in.flags as u32,
+                &mut reply,
+            );
+            wait_deferred(&reply);
+            match reply.reply() {
+                Ok(_) => 0,
+                Err(x) => -*x,
+            }
+        }
+        _ => {
+            let req = Request { h: &inarg.h };
+            match fs.bento_unknown_opcode(&req, opcode, inarg, outarg) {
+                Ok(()) => 0,
+                Err(x) => -x,
+            }
+        }
+    }
+}
+
+// Live upgrade requests. These bypass the quiesce gate: the old module pauses requests in
+// BENTO_UPDATE_PREPARE and lets them through again in BENTO_UPDATE_COMPLETE, which the new module
+// sends once BENTO_UPDATE_TRANSFER is done. If the new module can't take over the state, it sends
+// BENTO_UPDATE_ROLLBACK instead, which hands the state back to the old module.
+fn dispatch_update<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Serialize, T: BentoFilesystem<'de, TransferIn, TransferOut>>(
+    fs: &'static mut T,
+    opcode: fuse_opcode,
+    inarg: &bento_in,
+    outarg: &mut bento_out,
+) -> i32 {
+    match opcode {
         BENTO_UPDATE_PREPARE => {
             if outarg.numargs != 1 {
                 return -1;
             }
+            // The state of a failed filesystem can't be trusted, and a request that panicked never
+            // finishes, so the filesystem can't be paused.
+            let fs_ptr = &mut *fs as *mut T as *const raw::c_void;
+            if fs_failed(fs_ptr as usize) {
+                return -libc::EIO;
+            }
 
-            match fs.bento_update_prepare() {
-                Some(x_val) => {
-                    // TODO: Serialize the struct once bincode supports no_std
-                    outarg.args[0].value = Box::into_raw(Box::new(x_val)) as *const _ as *const raw::c_void;
+            quiesce::pause(fs_ptr as usize);
+            let dispatch_ptr = dispatch::<TransferIn, TransferOut, T> as *const raw::c_void;
+            let version = fs.bento_update_version();
+            let transfer_out = fs.bento_update_prepare();
+            match SerializedState::new(version, transfer_out.as_ref()) {
+                Ok(mut state) => {
+                    // The serialized state now owns whatever the state refers to, so the state
+                    // itself must not be dropped.
+                    mem::forget(transfer_out);
+                    state.set_origin(fs_ptr, dispatch_ptr);
+                    state.set_read_only(is_read_only(fs_ptr as usize));
+                    outarg.args[0].value = state.into_raw() as *const raw::c_void;
                     0
                 }
-                None => 0
+                Err(x) => {
+                    // bento_update_prepare has already handed its resources over to the state,
+                    // and the state can't be given back to the filesystem. Dropping it could
+                    // release resources that the filesystem still refers to, so it is leaked, and
+                    // the filesystem fails all requests from now on.
+                    println!("{}: unable to serialize upgrade state, failing the filesystem",
+                        display_name(fs.get_name()));
+                    mem::forget(transfer_out);
+                    fail_instance(fs_ptr as usize);
+                    quiesce::resume(fs_ptr as usize, None);
+                    -x
+                }
             }
         }
         BENTO_UPDATE_TRANSFER => {
@@ -971,19 +1526,123 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
                 return -1;
             }
 
-            let transfer_ptr = inarg.args[0].value as *mut raw::c_void;
-            let transfer_in = if transfer_ptr.is_null() {
-                None
-            } else {
-                // TODO: Deserialize the struct once bincode supports no_std
-                unsafe { Some(*Box::from_raw(transfer_ptr as *mut TransferIn)) }
+            let transfer_ptr = inarg.args[0].value as *const u8;
+            if transfer_ptr.is_null() {
+                return match fs.bento_update_transfer(None) {
+                    Ok(()) => 0,
+                    Err(x) => -x,
+                };
+            }
+            let state = match unsafe { SerializedState::from_raw(transfer_ptr) } {
+                Ok(x) => x,
+                Err(x) => return -x,
             };
-            fs.bento_update_transfer(transfer_in);
-            0
+            let ret = transfer_state(fs, &state);
+            if ret == 0 {
+                set_read_only(&*fs as *const T as usize, state.read_only());
+            }
+
+            let (origin_fs, origin_dispatch) = state.origin();
+            if origin_dispatch.is_null() {
+                return ret;
+            }
+            let origin_dispatch: DispatchFn = unsafe { mem::transmute(origin_dispatch) };
+            let mut complete = bento_update_complete {
+                fs: &mut *fs as *mut T as u64,
+                dispatch: dispatch::<TransferIn, TransferOut, T> as *const raw::c_void as u64,
+                pause_ns: 0,
+            };
+            let mut update_in: bento_in = unsafe { mem::zeroed() };
+            update_in.numargs = 1;
+            let mut update_out: bento_out = unsafe { mem::zeroed() };
+
+            if ret == 0 {
+                update_in.args[0].size = mem::size_of::<bento_update_complete>() as u32;
+                update_in.args[0].value = &mut complete as *mut _ as *const raw::c_void;
+                origin_dispatch(origin_fs as *mut raw::c_void, BENTO_UPDATE_COMPLETE, &update_in,
+                    &mut update_out);
+                quiesce::record_pause(complete.pause_ns);
+                println!("{}: requests paused for {} us during upgrade", display_name(fs.get_name()),
+                    complete.pause_ns / 1000);
+                return 0;
+            }
+
+            // Hand the state back to the old module, which makes itself the registered filesystem
+            // again
+            println!("{}: upgrade failed with {}, rolling back", display_name(fs.get_name()), -ret);
+            update_in.args[0].value = state.into_raw() as *const raw::c_void;
+            let rollback_ret = origin_dispatch(origin_fs as *mut raw::c_void,
+                BENTO_UPDATE_ROLLBACK, &update_in, &mut update_out);
+            if rollback_ret != 0 {
+                println!("{}: rollback failed with {}", display_name(fs.get_name()), -rollback_ret);
+            }
+            ret
         }
-        _ => {
-            println!("got a different opcode");
+        BENTO_UPDATE_COMPLETE => {
+            if inarg.numargs != 1 {
+                return -1;
+            }
+
+            let complete = unsafe { &mut *(inarg.args[0].value as *mut bento_update_complete) };
+            let target = UpgradeTarget {
+                fs: complete.fs as usize,
+                dispatch: complete.dispatch as usize,
+            };
+            complete.pause_ns = quiesce::resume(&*fs as *const T as usize, Some(target));
             0
         }
+        BENTO_UPDATE_ROLLBACK => {
+            if inarg.numargs != 1 {
+                return -1;
+            }
+
+            // The state was serialized by this module, so it can always be deserialized here
+            let ret = match unsafe { SerializedState::from_raw(inarg.args[0].value as *const u8) } {
+                Ok(state) => transfer_state(fs, &state),
+                Err(x) => -x,
+            };
+            let register_ret = register_again(
+                &mut *fs as *mut T as *const raw::c_void,
+                fs.get_name(),
+                dispatch::<TransferIn, TransferOut, T> as *const raw::c_void,
+            );
+            if register_ret != 0 {
+                println!("{}: unable to register again after rollback: {}", display_name(fs.get_name()),
+                    -register_ret);
+            }
+            let pause_ns = quiesce::resume(&*fs as *const T as usize, None);
+            println!("{}: upgrade rolled back, requests paused for {} us", display_name(fs.get_name()),
+                pause_ns / 1000);
+            ret
+        }
+        _ => -libc::EINVAL,
     }
 }
+
+// Deserialize or migrate the state and pass it to bento_update_transfer.
+fn transfer_state<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Serialize, T: BentoFilesystem<'de, TransferIn, TransferOut>>(
+    fs: &mut T,
+    state: &SerializedState,
+) -> i32 {
+    let transfer_in = if !state.has_state() {
+        Ok(None)
+    } else if state.version() == fs.bento_update_version() {
+        // The state is only borrowed until bento_update_transfer returns, see the documentation
+        // of BentoFilesystem::bento_update_transfer.
+        let payload: &'de [u8] = unsafe {
+            slice::from_raw_parts(state.payload().as_ptr(), state.payload().len())
+        };
+        deserialize_state::<TransferIn>(payload).map(Some)
+    } else {
+        fs.bento_update_migrate(state.version(), state.payload()).map(Some)
+    };
+    match transfer_in.and_then(|x| fs.bento_update_transfer(x)) {
+        Ok(()) => 0,
+        Err(x) => -x,
+    }
+}
+
+// Filesystem names are nul-terminated for C.
+fn display_name(name: &'static str) -> &'static str {
+    name.trim_end_matches('\0')
+}
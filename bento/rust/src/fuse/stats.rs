@@ -0,0 +1,297 @@
+use alloc::boxed::Box;
+use alloc::collections::btree_map::BTreeMap;
+use alloc::vec::Vec;
+
+use core::cmp;
+use core::fmt;
+use core::fmt::Write;
+use core::ptr;
+use core::slice;
+use core::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
+
+use kernel::raw;
+
+use fuse::internal::*;
+
+/// Number of latency buckets. Bucket `i` counts requests that took less than `2^i` ns, but at
+/// least `2^(i-1)` ns. The last bucket also counts all slower requests.
+pub const LATENCY_BUCKETS: usize = 40;
+
+// Counters are kept for opcodes below this, all other opcodes are counted as unknown.
+const MAX_OPCODE: usize = 64;
+// Errors are counted for errno values below this. Larger errno values are counted as 0.
+const MAX_ERRNO: usize = 134;
+
+/// Statistics of one operation.
+#[derive(Clone)]
+pub struct OpStats {
+    /// Number of requests
+    pub count: u64,
+    /// Total time spent handling the requests in nanoseconds
+    pub total_ns: u64,
+    /// Time spent handling the slowest request in nanoseconds
+    pub max_ns: u64,
+    /// Number of failed requests per errno. Errno values the kernel doesn't define are counted as
+    /// 0.
+    pub errors: BTreeMap<i32, u64>,
+    /// Latency histogram, see `LATENCY_BUCKETS`
+    pub latency: [u64; LATENCY_BUCKETS],
+}
+
+// Counters of one opcode. Requests are recorded without taking a lock, so requests on different
+// CPUs don't serialize on the statistics.
+struct OpCounters {
+    count: AtomicU64,
+    total_ns: AtomicU64,
+    max_ns: AtomicU64,
+    errors: Vec<AtomicU64>,
+    latency: Vec<AtomicU64>,
+}
+
+impl OpCounters {
+    fn new() -> Self {
+        OpCounters {
+            count: AtomicU64::new(0),
+            total_ns: AtomicU64::new(0),
+            max_ns: AtomicU64::new(0),
+            errors: (0..MAX_ERRNO).map(|_| AtomicU64::new(0)).collect(),
+            latency: (0..LATENCY_BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    fn record(&self, ret: i32, elapsed_ns: u64) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.total_ns.fetch_add(elapsed_ns, Ordering::Relaxed);
+        let mut max_ns = self.max_ns.load(Ordering::Relaxed);
+        while elapsed_ns > max_ns {
+            match self.max_ns.compare_exchange_weak(max_ns, elapsed_ns, Ordering::Relaxed,
+                Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(x) => max_ns = x,
+            }
+        }
+        if ret < 0 {
+            let errno = ret.wrapping_neg() as usize;
+            let idx = if errno < MAX_ERRNO { errno } else { 0 };
+            self.errors[idx].fetch_add(1, Ordering::Relaxed);
+        }
+        let bucket = (64 - elapsed_ns.leading_zeros()) as usize;
+        self.latency[cmp::min(bucket, LATENCY_BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> OpStats {
+        let mut stats = OpStats {
+            count: self.count.load(Ordering::Relaxed),
+            total_ns: self.total_ns.load(Ordering::Relaxed),
+            max_ns: self.max_ns.load(Ordering::Relaxed),
+            errors: BTreeMap::new(),
+            latency: [0; LATENCY_BUCKETS],
+        };
+        for (errno, count) in self.errors.iter().enumerate() {
+            let count = count.load(Ordering::Relaxed);
+            if count > 0 {
+                stats.errors.insert(errno as i32, count);
+            }
+        }
+        for (bucket, count) in self.latency.iter().enumerate() {
+            stats.latency[bucket] = count.load(Ordering::Relaxed);
+        }
+        return stats;
+    }
+
+    fn reset(&self) {
+        self.count.store(0, Ordering::Relaxed);
+        self.total_ns.store(0, Ordering::Relaxed);
+        self.max_ns.store(0, Ordering::Relaxed);
+        for count in self.errors.iter().chain(self.latency.iter()) {
+            count.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+// Counters of all filesystem instances of this module, indexed by opcode
+static STATS: AtomicPtr<Vec<OpCounters>> = AtomicPtr::new(ptr::null_mut());
+
+fn stats() -> &'static Vec<OpCounters> {
+    let mut stats = STATS.load(Ordering::SeqCst);
+    if stats.is_null() {
+        let counters: Vec<OpCounters> = (0..MAX_OPCODE).map(|_| OpCounters::new()).collect();
+        let new = Box::into_raw(Box::new(counters));
+        match STATS.compare_exchange(ptr::null_mut(), new, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => stats = new,
+            Err(x) => {
+                unsafe { drop(Box::from_raw(new)) };
+                stats = x;
+            }
+        }
+    }
+    return unsafe { &*stats };
+}
+
+// Opcode 0 is not used by FUSE, so it collects the opcodes that have no counters of their own.
+fn opcode_index(opcode: u32) -> usize {
+    if (opcode as usize) < MAX_OPCODE && opcode_name(opcode) != "unknown" {
+        opcode as usize
+    } else {
+        0
+    }
+}
+
+/// Record a request handled by `dispatch`.
+///
+/// Arguments:
+/// * `opcode: u32` - Opcode of the request.
+/// * `ret: i32` - Return value of the request, a negative errno if it failed.
+/// * `elapsed_ns: u64` - Time spent handling the request in nanoseconds.
+pub(crate) fn record(opcode: u32, ret: i32, elapsed_ns: u64) {
+    stats()[opcode_index(opcode)].record(ret, elapsed_ns);
+}
+
+/// Get the statistics of every operation that was requested at least once.
+///
+/// Statistics are kept per module, so they cover every mounted instance of the filesystem and
+/// start over after a live upgrade.
+pub fn stats_snapshot() -> BTreeMap<&'static str, OpStats> {
+    let mut snapshot = BTreeMap::new();
+    for (opcode, counters) in stats().iter().enumerate() {
+        let op = counters.snapshot();
+        if op.count > 0 {
+            snapshot.insert(opcode_name(opcode as u32), op);
+        }
+    }
+    return snapshot;
+}
+
+/// Clear the statistics of all operations.
+///
+/// Requests that finish while the statistics are cleared may be partially counted.
+pub fn reset_stats() {
+    for counters in stats().iter() {
+        counters.reset();
+    }
+}
+
+/// Write the statistics of all operations as text.
+///
+/// Every operation gets a line with its request count and average and maximum latency,
+/// followed by a line with the number of failures per errno and a line with the non-empty
+/// latency buckets, labeled by their upper bound in nanoseconds.
+///
+/// Arguments:
+/// * `w: &mut W` - Where to write the statistics.
+pub fn dump_stats<W: Write>(w: &mut W) -> fmt::Result {
+    let stats = stats_snapshot();
+    for (name, op) in stats.iter() {
+        writeln!(
+            w,
+            "{}: count {} avg_ns {} max_ns {}",
+            name,
+            op.count,
+            op.total_ns / cmp::max(op.count, 1),
+            op.max_ns
+        )?;
+        if !op.errors.is_empty() {
+            write!(w, "  errors:")?;
+            for (errno, count) in op.errors.iter() {
+                write!(w, " {}={}", errno, count)?;
+            }
+            writeln!(w)?;
+        }
+        write!(w, "  latency_ns:")?;
+        for (bucket, count) in op.latency.iter().enumerate().filter(|(_, count)| **count > 0) {
+            if bucket == LATENCY_BUCKETS - 1 {
+                write!(w, " inf={}", count)?;
+            } else {
+                write!(w, " {}={}", 1u64 << bucket, count)?;
+            }
+        }
+        writeln!(w)?;
+    }
+    return Ok(());
+}
+
+// Writes to a buffer owned by C, dropping whatever doesn't fit
+struct BufWriter<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> Write for BufWriter<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = cmp::min(self.buf.len() - self.pos, s.len());
+        self.buf[self.pos..self.pos + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.pos += len;
+        Ok(())
+    }
+}
+
+/// Fill the statistics file of the filesystem.
+///
+/// BentoFS calls this when `/sys/kernel/debug/bento/<name>/stats` is read. Output that doesn't
+/// fit in the buffer is truncated. Returns the number of bytes written.
+pub(crate) extern "C" fn show_stats(buf: *mut raw::c_char, size: raw::c_size_t) -> raw::c_size_t {
+    if buf.is_null() {
+        return 0;
+    }
+    let mut w = BufWriter {
+        buf: unsafe { slice::from_raw_parts_mut(buf as *mut u8, size as usize) },
+        pos: 0,
+    };
+    let _ = dump_stats(&mut w);
+    return w.pos as raw::c_size_t;
+}
+
+/// Clear the statistics when anything is written to the statistics file of the filesystem.
+pub(crate) extern "C" fn clear_stats() {
+    reset_stats();
+}
+
+// Operation names match the names of the methods of the userspace `fuse::Filesystem` trait, so
+// the statistics of both versions can be compared.
+fn opcode_name(opcode: u32) -> &'static str {
+    match opcode {
+        fuse_opcode_FUSE_LOOKUP => "lookup",
+        fuse_opcode_FUSE_FORGET => "forget",
+        fuse_opcode_FUSE_GETATTR => "getattr",
+        fuse_opcode_FUSE_SETATTR => "setattr",
+        fuse_opcode_FUSE_READLINK => "readlink",
+        fuse_opcode_FUSE_SYMLINK => "symlink",
+        fuse_opcode_FUSE_MKNOD => "mknod",
+        fuse_opcode_FUSE_MKDIR => "mkdir",
+        fuse_opcode_FUSE_UNLINK => "unlink",
+        fuse_opcode_FUSE_RMDIR => "rmdir",
+        fuse_opcode_FUSE_RENAME => "rename",
+        fuse_opcode_FUSE_LINK => "link",
+        fuse_opcode_FUSE_OPEN => "open",
+        fuse_opcode_FUSE_READ => "read",
+        fuse_opcode_FUSE_WRITE => "write",
+        fuse_opcode_FUSE_STATFS => "statfs",
+        fuse_opcode_FUSE_RELEASE => "release",
+        fuse_opcode_FUSE_FSYNC => "fsync",
+        fuse_opcode_FUSE_SETXATTR => "setxattr",
+        fuse_opcode_FUSE_GETXATTR => "getxattr",
+        fuse_opcode_FUSE_LISTXATTR => "listxattr",
+        fuse_opcode_FUSE_REMOVEXATTR => "removexattr",
+        fuse_opcode_FUSE_FLUSH => "flush",
+        fuse_opcode_FUSE_INIT => "init",
+        fuse_opcode_FUSE_OPENDIR => "opendir",
+        fuse_opcode_FUSE_READDIR => "readdir",
+        fuse_opcode_FUSE_RELEASEDIR => "releasedir",
+        fuse_opcode_FUSE_FSYNCDIR => "fsyncdir",
+        fuse_opcode_FUSE_GETLK => "getlk",
+        fuse_opcode_FUSE_SETLK => "setlk",
+        fuse_opcode_FUSE_SETLKW => "setlkw",
+        fuse_opcode_FUSE_ACCESS => "access",
+        fuse_opcode_FUSE_CREATE => "create",
+        fuse_opcode_FUSE_BMAP => "bmap",
+        fuse_opcode_FUSE_DESTROY => "destroy",
+        fuse_opcode_FUSE_RENAME2 => "rename2",
+        fuse_opcode_FUSE_FALLOCATE => "fallocate",
+        fuse_opcode_FUSE_LSEEK => "lseek",
+        fuse_opcode_FUSE_IOCTL => "ioctl",
+        fuse_opcode_FUSE_READDIRPLUS => "readdirplus",
+        fuse_opcode_FUSE_COPY_FILE_RANGE => "copy_file_range",
+        fuse_opcode_FUSE_BATCH_FORGET => "batch_forget",
+        _ => "unknown",
+    }
+}
@@ -20,6 +20,9 @@
 #include <linux/net.h>
 #include <linux/kthread.h>
 #include <linux/timekeeping.h>
+#include <linux/cred.h>
+#include <linux/debugfs.h>
+#include <linux/seq_file.h>
 
 void
 wait_a_bit(void) {
@@ -240,3 +243,292 @@ void rs_jbd2_journal_setup(journal_t *journal) {
         jbd2_journal_clear_features(journal, 0, 0,
                         JBD2_FEATURE_INCOMPAT_ASYNC_COMMIT);
 }
+
+/*
+ * Copy the supplementary groups of the current task into groups, mapped into
+ * the user namespace of the current task. At most size groups are copied, and
+ * the total number of groups is returned, so this can be called with size 0
+ * to find out how many groups there are.
+ */
+int rs_current_groups(u32 *groups, u32 size)
+{
+	const struct cred *cred = current_cred();
+	struct group_info *gi = cred->group_info;
+	int i;
+
+	for (i = 0; i < gi->ngroups && (u32)i < size; i++)
+		groups[i] = from_kgid_munged(current_user_ns(), gi->gid[i]);
+
+	return gi->ngroups;
+}
+
+void *rs_current_task(void)
+{
+	return current;
+}
+
+/*
+ * Put the current task to sleep after a panic in a Rust module, which can't
+ * unwind. The task keeps running code of the module while it is parked, so a
+ * reference to the module is taken to keep it from being unloaded.
+ *
+ * A task that made a request is woken by a fatal signal or by the teardown of
+ * its filesystem instance, which unpark() reports. The locks it holds are
+ * never released, so instead of returning into the filesystem, the task
+ * drops the module reference and exits. Kernel threads, e.g. during
+ * writeback, can't exit like this, so they stay parked and keep the module
+ * loaded.
+ */
+void __noreturn rs_park_current(bool (*unpark)(bool killed))
+{
+	__module_get(THIS_MODULE);
+	for (;;) {
+		set_current_state(TASK_IDLE | TASK_WAKEKILL);
+		if (!(current->flags & PF_KTHREAD) &&
+		    unpark(fatal_signal_pending(current)))
+			break;
+		schedule();
+	}
+	__set_current_state(TASK_RUNNING);
+	module_put_and_exit(0);
+}
+
+void rs_wake_up_task(void *task)
+{
+	wake_up_process(task);
+}
+
+/*
+ * Locks of Rust modules. A task parked after a panic never releases the
+ * locks it holds, so waiting for them with down_read or down_write could
+ * block a request forever. Instead, waiters sleep on bento_lock_wq until they
+ * get the lock or failed() returns true, in which case -EIO is returned. The
+ * queue is woken when a lock is released while any task waits, and when a
+ * filesystem instance fails.
+ */
+static DECLARE_WAIT_QUEUE_HEAD(bento_lock_wq);
+
+int rs_down_read_failable(struct rw_semaphore *sem, bool (*failed)(void))
+{
+	int locked = 0;
+
+	if (down_read_trylock(sem))
+		return 0;
+	wait_event(bento_lock_wq,
+		   (locked = down_read_trylock(sem)) || failed());
+	return locked ? 0 : -EIO;
+}
+
+int rs_down_write_failable(struct rw_semaphore *sem, bool (*failed)(void))
+{
+	int locked = 0;
+
+	if (down_write_trylock(sem))
+		return 0;
+	wait_event(bento_lock_wq,
+		   (locked = down_write_trylock(sem)) || failed());
+	return locked ? 0 : -EIO;
+}
+
+void rs_up_read(struct rw_semaphore *sem)
+{
+	up_read(sem);
+	if (wq_has_sleeper(&bento_lock_wq))
+		wake_up_all(&bento_lock_wq);
+}
+
+void rs_up_write(struct rw_semaphore *sem)
+{
+	up_write(sem);
+	if (wq_has_sleeper(&bento_lock_wq))
+		wake_up_all(&bento_lock_wq);
+}
+
+void rs_wake_lock_waiters(void)
+{
+	wake_up_all(&bento_lock_wq);
+}
+
+typedef int (*bento_factory_fn)(const void *fs_name, const void *create,
+				const void *destroy, const void *dispatch);
+
+/*
+ * Per-mount instances need a version of BentoFS that exports
+ * register_bento_fs_factory and reregister_bento_fs_factory. They are looked
+ * up at runtime instead of being linked against, so the module still loads
+ * with older versions of BentoFS. Returns -ENOSYS if the symbol is missing.
+ */
+static int call_factory_symbol(const char *symbol, const void *fs_name,
+			       const void *create, const void *destroy,
+			       const void *dispatch)
+{
+	bento_factory_fn fn = (bento_factory_fn)__symbol_get(symbol);
+	int ret;
+
+	if (!fn)
+		return -ENOSYS;
+	ret = fn(fs_name, create, destroy, dispatch);
+	symbol_put_addr(fn);
+	return ret;
+}
+
+int rs_register_bento_fs_factory(const void *fs_name, const void *create,
+				 const void *destroy, const void *dispatch)
+{
+	return call_factory_symbol("register_bento_fs_factory", fs_name,
+				   create, destroy, dispatch);
+}
+
+int rs_reregister_bento_fs_factory(const void *fs_name, const void *create,
+				   const void *destroy, const void *dispatch)
+{
+	return call_factory_symbol("reregister_bento_fs_factory", fs_name,
+				   create, destroy, dispatch);
+}
+
+typedef int (*bento_notify_fn)(const void *fs, u32 code, const void *arg,
+			       size_t arg_size, const void *data,
+			       size_t data_size);
+
+/*
+ * Send a notification to the FUSE connection of the mount that owns the
+ * instance fs. Like the factory functions, bento_notify is looked up at
+ * runtime. Returns -ENOSYS if BentoFS doesn't export it.
+ */
+int rs_bento_notify(const void *fs, u32 code, const void *arg, size_t arg_size,
+		    const void *data, size_t data_size)
+{
+	bento_notify_fn fn = (bento_notify_fn)__symbol_get("bento_notify");
+	int ret;
+
+	if (!fn)
+		return -ENOSYS;
+	ret = fn(fs, code, arg, arg_size, data, data_size);
+	symbol_put_addr(fn);
+	return ret;
+}
+
+/*
+ * Statistics files in /sys/kernel/debug/bento/<name>/stats. The callbacks are
+ * implemented in Rust by each module: show fills a buffer with the statistics
+ * and returns its length, and clear resets them.
+ */
+#define BENTO_STATS_SIZE (64 * 1024)
+
+struct bento_stats_ops {
+	size_t (*show)(char *buf, size_t size);
+	void (*clear)(void);
+};
+
+/*
+ * This file is linked into every module, so the address of the callbacks
+ * tells which module a statistics file belongs to.
+ */
+static struct bento_stats_ops bento_stats_ops;
+
+static int bento_stats_show(struct seq_file *m, void *v)
+{
+	struct bento_stats_ops *ops = m->private;
+	size_t len;
+	char *buf;
+
+	buf = kvmalloc(BENTO_STATS_SIZE, GFP_KERNEL);
+	if (!buf)
+		return -ENOMEM;
+	len = ops->show(buf, BENTO_STATS_SIZE);
+	seq_write(m, buf, len);
+	kvfree(buf);
+	return 0;
+}
+
+static int bento_stats_open(struct inode *inode, struct file *file)
+{
+	return single_open(file, bento_stats_show, inode->i_private);
+}
+
+static ssize_t bento_stats_write(struct file *file, const char __user *ubuf,
+				 size_t count, loff_t *ppos)
+{
+	struct seq_file *m = file->private_data;
+	struct bento_stats_ops *ops = m->private;
+
+	ops->clear();
+	return count;
+}
+
+static const struct file_operations bento_stats_fops = {
+	.owner = THIS_MODULE,
+	.open = bento_stats_open,
+	.read = seq_read,
+	.write = bento_stats_write,
+	.llseek = seq_lseek,
+	.release = single_release,
+};
+
+/*
+ * Look up the directory name in parent, or create it if it doesn't exist.
+ * Directories are shared by all modules and are kept until they are removed
+ * by bento_unregister_stats.
+ */
+static struct dentry *bento_stats_dir(const char *name, struct dentry *parent)
+{
+	struct dentry *dir = debugfs_lookup(name, parent);
+
+	if (dir) {
+		/* debugfs keeps its own reference until the directory is removed */
+		dput(dir);
+		return dir;
+	}
+	dir = debugfs_create_dir(name, parent);
+	return IS_ERR(dir) ? NULL : dir;
+}
+
+int bento_register_stats(const char *fs_name,
+			 size_t (*show)(char *buf, size_t size),
+			 void (*clear)(void))
+{
+	struct dentry *root, *dir, *old, *file;
+
+	bento_stats_ops.show = show;
+	bento_stats_ops.clear = clear;
+
+	root = bento_stats_dir("bento", NULL);
+	if (!root)
+		return -ENOMEM;
+	dir = bento_stats_dir(fs_name, root);
+	if (!dir)
+		return -ENOMEM;
+
+	/* After a live upgrade, the file of the old module is replaced */
+	old = debugfs_lookup("stats", dir);
+	if (old) {
+		debugfs_remove(old);
+		dput(old);
+	}
+	file = debugfs_create_file("stats", 0600, dir, &bento_stats_ops,
+				   &bento_stats_fops);
+	return IS_ERR(file) ? PTR_ERR(file) : 0;
+}
+
+void bento_unregister_stats(const char *fs_name)
+{
+	struct dentry *root, *dir, *file;
+	bool owned = false;
+
+	root = debugfs_lookup("bento", NULL);
+	if (!root)
+		return;
+	dir = debugfs_lookup(fs_name, root);
+	if (dir) {
+		/* The file may have been taken over by a newer module */
+		file = debugfs_lookup("stats", dir);
+		if (file) {
+			owned = d_inode(file)->i_private == &bento_stats_ops;
+			dput(file);
+		}
+		if (owned)
+			debugfs_remove_recursive(dir);
+		dput(dir);
+	}
+	dput(root);
+}
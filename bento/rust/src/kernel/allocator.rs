@@ -8,6 +8,7 @@
 
 use core::alloc::{GlobalAlloc, Layout};
 use kernel::ffi::*;
+use kernel::panic::fail_current_task;
 use kernel::raw;
 //use crate::bindings::PAGE_SIZE;
 
@@ -33,6 +34,7 @@ unsafe impl GlobalAlloc for KernelAllocator {
 }
 
 #[alloc_error_handler]
-fn oom(_layout: Layout) -> ! {
-    loop {}
+fn oom(layout: Layout) -> ! {
+    println!("bento: out of memory allocating {} bytes", layout.size());
+    fail_current_task()
 }
@@ -260,12 +260,14 @@ extern "C" {
 
     pub fn rs_get_semaphore() -> *mut raw::c_void;
     pub fn rs_put_semaphore(sem: *const raw::c_void);
-    pub fn down_read(sem: *const raw::c_void);
-    pub fn up_read(sem: *const raw::c_void);
-    pub fn down_write(sem: *const raw::c_void);
+    // Fail with -EIO once `failed` returns true, see `kernel::sync`
+    pub fn rs_down_read_failable(sem: *const raw::c_void, failed: extern "C" fn() -> bool) -> i32;
+    pub fn rs_down_write_failable(sem: *const raw::c_void, failed: extern "C" fn() -> bool) -> i32;
     pub fn down_write_trylock(sem: *const raw::c_void) -> i32;
     pub fn down_read_trylock(sem: *const raw::c_void) -> i32;
-    pub fn up_write(sem: *const raw::c_void);
+    pub fn rs_up_read(sem: *const raw::c_void);
+    pub fn rs_up_write(sem: *const raw::c_void);
+    pub fn rs_wake_lock_waiters();
 
     // journal
     pub fn rs_jbd2_journal_init_dev(bdev: *const raw::c_void, fs_dev: *const raw::c_void, 
@@ -284,6 +286,16 @@ extern "C" {
     pub fn rs_lock_buffer(bh: *const raw::c_void);
     pub fn unlock_buffer(bh: *const raw::c_void);
 
+    // credentials of the current task
+    pub fn current_umask() -> u16;
+    pub fn capable(cap: i32) -> bool;
+    pub fn rs_current_groups(groups: *mut u32, size: u32) -> i32;
+
+    // task
+    pub fn rs_current_task() -> *const raw::c_void;
+    pub fn rs_park_current(unpark: extern "C" fn(killed: bool) -> bool) -> !;
+    pub fn rs_wake_up_task(task: *const raw::c_void);
+
     // string
     pub fn strnlen(s: *const raw::c_char, max_len: u64) -> u64;
     pub fn strcmp(s1: *const raw::c_char, s2: *const raw::c_char) -> i32;
@@ -311,7 +323,35 @@ extern "C" {
         fs_name: *const raw::c_void,
         dispatch: *const raw::c_void,
     ) -> i32;
+    // Resolved at runtime, fail with -ENOSYS if BentoFS doesn't export them
+    pub fn rs_register_bento_fs_factory(
+        fs_name: *const raw::c_void,
+        create: *const raw::c_void,
+        destroy: *const raw::c_void,
+        dispatch: *const raw::c_void,
+    ) -> i32;
+    pub fn rs_reregister_bento_fs_factory(
+        fs_name: *const raw::c_void,
+        create: *const raw::c_void,
+        destroy: *const raw::c_void,
+        dispatch: *const raw::c_void,
+    ) -> i32;
+    // Resolved at runtime, fails with -ENOSYS if BentoFS doesn't export bento_notify
+    pub fn rs_bento_notify(
+        fs: *const raw::c_void,
+        code: u32,
+        arg: *const raw::c_void,
+        arg_size: raw::c_size_t,
+        data: *const raw::c_void,
+        data_size: raw::c_size_t,
+    ) -> i32;
     pub fn unregister_bento_fs(fs_name: *const raw::c_void) -> i32;
+    pub fn bento_register_stats(
+        fs_name: *const raw::c_void,
+        show: *const raw::c_void,
+        clear: *const raw::c_void,
+    ) -> i32;
+    pub fn bento_unregister_stats(fs_name: *const raw::c_void);
     pub fn mount() -> i32;
     pub fn lookup_bdev(dev_name: *const raw::c_char, mode: u32) -> *mut raw::c_void;
     pub fn get_bdev_helper(dev_name: *const raw::c_char, mode: u32) -> *mut raw::c_void;
@@ -10,6 +10,9 @@ use core::mem::size_of;
 use crate::bindings::*;
 
 pub const FUSE_NAME_OFFSET: usize = 24;
+/// Offset of the name in a `fuse_direntplus`, which is a `fuse_entry_out` followed by a
+/// `fuse_dirent`.
+pub const FUSE_NAME_OFFSET_DIRENTPLUS: usize = size_of::<fuse_entry_out>() + FUSE_NAME_OFFSET;
 
 pub const FUSE_MAX_MAX_PAGES: u32 = 256;
 pub const FUSE_DEFAULT_MAX_PAGES_PER_REQ: u32 = 32;
@@ -9,6 +9,7 @@ use core::cell::UnsafeCell;
 
 use kernel::ffi::*;
 use kernel::kobj::*;
+use kernel::panic::set_fs_failed;
 use kernel::raw::*;
 
 use core::cell::RefCell;
@@ -186,8 +187,9 @@ impl Drop for Handle {
         if res == 0 {
              ()
         } else {
+             // The journal is aborted, so nothing can be written anymore.
              println!("some log transaction was aborted");
-             loop {};
+             set_fs_failed();
         }
     }
 }
@@ -51,12 +51,10 @@ def_kobj_op!(BufferHead, mark_buffer_dirty, mark_buffer_dirty, ());
 def_kobj_op!(BufferHead, set_buffer_uptodate, rs_set_buffer_uptodate, ());
 def_kobj_op!(BufferHead, sync_dirty_buffer, sync_dirty_buffer, i32);
 
-def_kobj_immut_op!(RsRwSemaphore, down_read, down_read, ());
-def_kobj_immut_op!(RsRwSemaphore, up_read, up_read, ());
-def_kobj_immut_op!(RsRwSemaphore, down_write, down_write, ());
+def_kobj_immut_op!(RsRwSemaphore, up_read, rs_up_read, ());
 def_kobj_immut_op!(RsRwSemaphore, down_write_trylock, down_write_trylock, i32);
 def_kobj_immut_op!(RsRwSemaphore, down_read_trylock, down_read_trylock, i32);
-def_kobj_immut_op!(RsRwSemaphore, up_write, up_write, ());
+def_kobj_immut_op!(RsRwSemaphore, up_write, rs_up_write, ());
 def_kobj_op!(RsRwSemaphore, put, rs_put_semaphore, ());
 
 def_kobj_immut_op!(RsWaitQueueHead, wake_up, rs_wake_up, ());
@@ -4,6 +4,7 @@ pub mod fs;
 pub mod fuse;
 pub mod kobj;
 pub mod mem;
+pub mod panic;
 pub mod raw;
 pub mod stat;
 pub mod string;
@@ -0,0 +1,303 @@
+/*
+ * SPDX-License-Identifier: GPL-2.0
+ * Copyright (C) 2020 Samantha Miller, Kaiyuan Zhang, Danyang Zhuo, Tom
+      Anderson, Ang Chen, University of Washington
+ *
+ */
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use core::panic::PanicInfo;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+
+use kernel::ffi::{rs_current_task, rs_park_current, rs_wake_lock_waiters, rs_wake_up_task};
+use kernel::raw;
+
+// Number of requests that can be tracked at once, number of instances that can be failed before
+// the whole module is failed instead, and number of parked tasks that can be woken when their
+// instance is destroyed.
+const MAX_RUNNING: usize = 256;
+const MAX_FAILED: usize = 64;
+const MAX_PARKED: usize = 64;
+
+// Instance of a parked task whose instance was destroyed
+const RELEASED: usize = usize::max_value();
+
+// Set once the whole module can no longer be trusted, e.g. after a panic outside of a request
+static ALL_FAILED: AtomicBool = AtomicBool::new(false);
+// Set while printing a panic, so a panic in the panic handler doesn't print again
+static PANICKING: AtomicBool = AtomicBool::new(false);
+static SLOTS: AtomicPtr<Slots> = AtomicPtr::new(ptr::null_mut());
+
+// The requests that are running and the instances that failed.
+//
+// A panic can happen while any lock is held or while allocating, so the slots are claimed with
+// compare_exchange instead of being kept in a locked map. A slot value of 0 means the slot is free.
+struct Slots {
+    // Pairs of the task that runs a request and the filesystem instance it was made to
+    running: Vec<(AtomicUsize, AtomicUsize)>,
+    failed: Vec<AtomicUsize>,
+    // Pairs of a parked task and the instance it was running a request for
+    parked: Vec<(AtomicUsize, AtomicUsize)>,
+}
+
+// The slots are allocated by the first request, so a panic never allocates them.
+fn slots() -> &'static Slots {
+    let mut slots = SLOTS.load(Ordering::SeqCst);
+    if slots.is_null() {
+        let new = Box::into_raw(Box::new(Slots {
+            running: (0..MAX_RUNNING).map(|_| (AtomicUsize::new(0), AtomicUsize::new(0))).collect(),
+            failed: (0..MAX_FAILED).map(|_| AtomicUsize::new(0)).collect(),
+            parked: (0..MAX_PARKED).map(|_| (AtomicUsize::new(0), AtomicUsize::new(0))).collect(),
+        }));
+        match SLOTS.compare_exchange(ptr::null_mut(), new, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => slots = new,
+            Err(x) => {
+                unsafe { drop(Box::from_raw(new)) };
+                slots = x;
+            }
+        }
+    }
+    return unsafe { &*slots };
+}
+
+fn current_task() -> usize {
+    unsafe { rs_current_task() as usize }
+}
+
+// Returns the instance the current task is running a request for, if any.
+fn current_instance() -> Option<usize> {
+    let slots = SLOTS.load(Ordering::SeqCst);
+    if slots.is_null() {
+        return None;
+    }
+    let task = current_task();
+    unsafe { &*slots }
+        .running
+        .iter()
+        .find(|(t, _)| t.load(Ordering::SeqCst) == task)
+        .map(|(_, fs)| fs.load(Ordering::SeqCst))
+}
+
+/// A request that is being run by the current task.
+///
+/// If the filesystem fails while the request is running, e.g. because it panics, only the
+/// instance the request was made to is failed. The request is no longer tracked once this is
+/// dropped.
+pub struct RunningRequest {
+    slot: Option<usize>,
+}
+
+impl RunningRequest {
+    /// Start tracking a request to the filesystem instance `fs` on the current task.
+    ///
+    /// If too many requests are running to track another one, a failure during this request
+    /// fails the whole module.
+    ///
+    /// Arguments:
+    /// * `fs: usize` - Address of the filesystem instance.
+    pub fn enter(fs: usize) -> Self {
+        let task = current_task();
+        for (i, (t, f)) in slots().running.iter().enumerate() {
+            if t.compare_exchange(0, task, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+                f.store(fs, Ordering::SeqCst);
+                return RunningRequest { slot: Some(i) };
+            }
+        }
+        RunningRequest { slot: None }
+    }
+}
+
+impl Drop for RunningRequest {
+    fn drop(&mut self) {
+        if let Some(i) = self.slot {
+            let (t, f) = &slots().running[i];
+            f.store(0, Ordering::SeqCst);
+            t.store(0, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Returns true if the filesystem instance `fs` failed, e.g. because it panicked.
+///
+/// Requests to a failed instance are answered with `EIO` without calling the filesystem. Other
+/// instances of the same filesystem keep working.
+///
+/// Arguments:
+/// * `fs: usize` - Address of the filesystem instance.
+pub fn fs_failed(fs: usize) -> bool {
+    if ALL_FAILED.load(Ordering::SeqCst) {
+        return true;
+    }
+    let slots = SLOTS.load(Ordering::SeqCst);
+    if slots.is_null() {
+        return false;
+    }
+    unsafe { &*slots }.failed.iter().any(|f| f.load(Ordering::SeqCst) == fs)
+}
+
+/// Mark the filesystem instance `fs` as failed.
+///
+/// All requests to the instance after this fail with `EIO`. If too many instances failed to
+/// track another one, the whole module is failed.
+///
+/// Arguments:
+/// * `fs: usize` - Address of the filesystem instance.
+pub fn fail_instance(fs: usize) {
+    mark_failed(fs);
+    // Requests waiting for locks held by a parked task give up
+    unsafe { rs_wake_lock_waiters() };
+}
+
+fn mark_failed(fs: usize) {
+    let slots = SLOTS.load(Ordering::SeqCst);
+    if !slots.is_null() {
+        let failed = &unsafe { &*slots }.failed;
+        if failed.iter().any(|f| f.load(Ordering::SeqCst) == fs) {
+            return;
+        }
+        for f in failed.iter() {
+            if f.compare_exchange(0, fs, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+                return;
+            }
+        }
+    }
+    ALL_FAILED.store(true, Ordering::SeqCst);
+}
+
+// Fail the whole module.
+fn fail_all() {
+    ALL_FAILED.store(true, Ordering::SeqCst);
+    unsafe { rs_wake_lock_waiters() };
+}
+
+/// Returns true if the filesystem instance of the current request failed.
+///
+/// Waiting for a lock gives up once this returns true, see `kernel::sync`. If the current task is
+/// not running a request, this is only true once the whole module failed.
+pub extern "C" fn current_request_failed() -> bool {
+    match current_instance() {
+        Some(fs) => fs_failed(fs),
+        None => ALL_FAILED.load(Ordering::SeqCst),
+    }
+}
+
+/// Forget that the filesystem instance `fs` failed and release the tasks parked in it.
+///
+/// Called when the instance is destroyed, so a new instance at the same address is not failed.
+/// Tasks that were parked while running a request for the instance exit and drop their reference
+/// to the module, see `fail_current_task`.
+///
+/// Arguments:
+/// * `fs: usize` - Address of the filesystem instance.
+pub fn forget_instance(fs: usize) {
+    let slots = SLOTS.load(Ordering::SeqCst);
+    if slots.is_null() {
+        return;
+    }
+    let slots = unsafe { &*slots };
+    for f in slots.failed.iter() {
+        let _ = f.compare_exchange(fs, 0, Ordering::SeqCst, Ordering::SeqCst);
+    }
+    for (t, f) in slots.parked.iter() {
+        if f.compare_exchange(fs, RELEASED, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+            let task = t.load(Ordering::SeqCst);
+            if task != 0 {
+                unsafe { rs_wake_up_task(task as *const raw::c_void) };
+            }
+        }
+    }
+}
+
+// Called by a parked task whenever it wakes up. Returns true if the task should exit, because it
+// was killed or its instance was destroyed. The task no longer runs a request and is no longer
+// parked once it exits.
+extern "C" fn unpark(killed: bool) -> bool {
+    let slots = SLOTS.load(Ordering::SeqCst);
+    if slots.is_null() {
+        return killed;
+    }
+    let slots = unsafe { &*slots };
+    let task = current_task();
+    let parked = slots.parked.iter().find(|(t, _)| t.load(Ordering::SeqCst) == task);
+    let released = parked.map_or(false, |(_, f)| f.load(Ordering::SeqCst) == RELEASED);
+    if !killed && !released {
+        return false;
+    }
+    if let Some((t, f)) = parked {
+        f.store(0, Ordering::SeqCst);
+        t.store(0, Ordering::SeqCst);
+    }
+    for (t, f) in slots.running.iter() {
+        if t.load(Ordering::SeqCst) == task {
+            f.store(0, Ordering::SeqCst);
+            t.store(0, Ordering::SeqCst);
+        }
+    }
+    return true;
+}
+
+/// Mark the filesystem instance of the current request as failed.
+///
+/// This can be used by a filesystem that detects an unrecoverable error, such as an aborted
+/// journal. All requests to the instance after this fail with `EIO`. If the current task is not
+/// running a request, the instance is unknown and the whole module is failed.
+pub fn set_fs_failed() {
+    match current_instance() {
+        Some(fs) => fail_instance(fs),
+        None => fail_all(),
+    }
+}
+
+/// Mark the filesystem instance of the current request as failed and park the current task.
+///
+/// Bento modules can't unwind, so this is how a panic is contained. The request that was running
+/// can't be answered, so the task sleeps instead, without counting towards the load average or
+/// triggering hung task warnings. Locks held by the task are never released. Requests waiting for
+/// them and all new requests to the instance fail with `EIO`.
+///
+/// A task that made a request exits once it is killed or the instance is destroyed on unmount, so
+/// the module can be unloaded again. A process holding files of the mount must be killed before
+/// it can be unmounted. Kernel threads, e.g. during writeback, stay parked and keep the module
+/// loaded.
+pub fn fail_current_task() -> ! {
+    let fs = current_instance();
+    set_fs_failed();
+    // Without a slot, the task can only exit once it is killed
+    let slots = SLOTS.load(Ordering::SeqCst);
+    if !slots.is_null() {
+        let task = current_task();
+        for (t, f) in unsafe { &*slots }.parked.iter() {
+            if t.compare_exchange(0, task, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+                f.store(fs.unwrap_or(0), Ordering::SeqCst);
+                break;
+            }
+        }
+    }
+    unsafe { rs_park_current(unpark) }
+}
+
+/// Handle a panic in a Bento filesystem.
+///
+/// Prints the panic message and location to the kernel log and calls `fail_current_task`.
+///
+/// Arguments:
+/// * `info: &PanicInfo` - Information about the panic.
+pub fn fs_panic(info: &PanicInfo) -> ! {
+    if !PANICKING.swap(true, Ordering::SeqCst) {
+        match (info.message(), info.location()) {
+            (Some(msg), Some(loc)) => {
+                println!("bento: panicked at {}:{}:{}: {}", loc.file(), loc.line(), loc.column(), msg)
+            }
+            (Some(msg), None) => println!("bento: panicked: {}", msg),
+            (None, Some(loc)) => {
+                println!("bento: panicked at {}:{}:{}", loc.file(), loc.line(), loc.column())
+            }
+            (None, None) => println!("bento: panicked"),
+        }
+        PANICKING.store(false, Ordering::SeqCst);
+    }
+    fail_current_task()
+}
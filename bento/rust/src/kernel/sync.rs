@@ -6,6 +6,7 @@
  */
 
 use kernel::ffi::*;
+use kernel::panic::current_request_failed;
 use kernel::kobj::*;
 use kernel::raw::*;
 
@@ -32,9 +33,14 @@ pub fn put_semaphore(semaphore: Option<RsRwSemaphore>) -> Result<(), i32> {
     Ok(())
 }
 
+// Taking a lock fails with EIO instead of blocking forever once the filesystem instance of the
+// current request failed, since the lock may be held by a task that was parked by a panic.
 pub fn down_read(semaphore: &Option<RsRwSemaphore>) -> Result<(), i32> {
     if let Some(sem) = semaphore {
-        sem.down_read();
+        let ret = unsafe { rs_down_read_failable(sem.get_raw(), current_request_failed) };
+        if ret != 0 {
+            return Err(-ret);
+        }
     }
     Ok(())
 }
@@ -48,7 +54,10 @@ pub fn up_read(semaphore: &Option<RsRwSemaphore>) -> Result<(), i32> {
 
 pub fn down_write(semaphore: &Option<RsRwSemaphore>) -> Result<(), i32> {
     if let Some(sem) = semaphore {
-        sem.down_write();
+        let ret = unsafe { rs_down_write_failable(sem.get_raw(), current_request_failed) };
+        if ret != 0 {
+            return Err(-ret);
+        }
     }
     Ok(())
 }
@@ -37,6 +37,7 @@ pub mod time;
 
 extern crate datablock;
 extern crate hash32;
+extern crate postcard;
 extern crate rlibc;
 pub use datablock::*;
 
@@ -53,8 +54,8 @@ use core::panic::PanicInfo;
 
 #[panic_handler]
 // #[cfg(not(test))]
-fn panic(_info: &PanicInfo) -> ! {
-    loop {}
+fn panic(info: &PanicInfo) -> ! {
+    kernel::panic::fs_panic(info)
 }
 
 #[global_allocator]
@@ -82,6 +82,7 @@ pub const EXFULL: c_int = 54;
 pub const ENOANO: c_int = 55;
 pub const EBADRQC: c_int = 56;
 pub const EBADSLT: c_int = 57;
+pub const ENODATA: c_int = 61;
 pub const EMULTIHOP: c_int = 72;
 pub const EOVERFLOW: c_int = 75;
 pub const ENOTUNIQ: c_int = 76;
@@ -157,6 +158,9 @@ pub const O_CLOEXEC: c_int = 0o2000000;
 pub const O_PATH: c_int = 0o10000000;
 pub const O_TMPFILE: c_int = 0o20000000 | O_DIRECTORY;
 
+pub const O_WRONLY: c_int = 1;
+pub const O_RDWR: c_int = 2;
+pub const O_ACCMODE: c_int = 3;
 pub const O_TRUNC: c_int = 512;
 
 pub const SOCK_CLOEXEC: c_int = O_CLOEXEC;
@@ -164,3 +168,17 @@ pub const SOCK_CLOEXEC: c_int = O_CLOEXEC;
 pub const RENAME_NOREPLACE: c_int = 1;
 pub const RENAME_EXCHANGE: c_int = 2;
 pub const RENAME_WHITEOUT: c_int = 4;
+
+pub const FALLOC_FL_KEEP_SIZE: c_int = 0x01;
+pub const FALLOC_FL_PUNCH_HOLE: c_int = 0x02;
+
+pub const SEEK_SET: c_int = 0;
+pub const SEEK_CUR: c_int = 1;
+pub const SEEK_END: c_int = 2;
+pub const SEEK_DATA: c_int = 3;
+pub const SEEK_HOLE: c_int = 4;
+
+pub const F_OK: c_int = 0;
+pub const X_OK: c_int = 1;
+pub const W_OK: c_int = 2;
+pub const R_OK: c_int = 4;
@@ -1,4 +1,5 @@
 use core::cell::UnsafeCell;
+use core::mem;
 use core::ops::FnMut;
 use core::sync::atomic::{AtomicBool, Ordering};
 
@@ -89,7 +90,11 @@ impl Condvar {
                     head.wait_event(wait_cont);
                 }
             }
-            let _ = down_write(sem);
+            // The guard no longer holds the lock, so it must not release it
+            if down_write(sem).is_err() {
+                mem::forget(guard);
+                return Err(PoisonError::failed());
+            }
             if condition(&mut *guard) {
                 break;
             }
@@ -109,7 +114,10 @@ impl Condvar {
                 head.wait_event(wait_cont);
             }
         }
-        let _ = down_write(sem);
+        if down_write(sem).is_err() {
+            mem::forget(guard);
+            return Err(PoisonError::failed());
+        }
         return Ok(guard)
     }
 }
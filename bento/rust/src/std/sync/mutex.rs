@@ -4,7 +4,7 @@ use core::ops::{Deref, DerefMut};
 use crate::kernel::kobj::*;
 use crate::kernel::sync::*;
 
-use crate::std::sys_common::poison::{LockResult, TryLockError, TryLockResult};
+use crate::std::sys_common::poison::{LockResult, PoisonError, TryLockError, TryLockResult};
 
 /// A wrapper around the kernel mutex.
 ///
@@ -105,8 +105,8 @@ impl<T: ?Sized> Mutex<T> {
     /// }
     /// ```
     pub fn lock(&self) -> LockResult<MutexGuard<T>> {
-        unsafe {
-            let _ = down_write(&*self.lock.get());
+        if unsafe { down_write(&*self.lock.get()) }.is_err() {
+            return Err(PoisonError::failed());
         }
         Ok(MutexGuard {
             lock: self,
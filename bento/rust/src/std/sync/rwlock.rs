@@ -11,7 +11,7 @@ use core::ops::{Deref, DerefMut};
 
 use crate::kernel::kobj::*;
 use crate::kernel::sync::*;
-use crate::std::sys_common::poison::{LockResult, TryLockError, TryLockResult};
+use crate::std::sys_common::poison::{LockResult, PoisonError, TryLockError, TryLockResult};
 
 /// A wrapper around the kernel semaphore.
 ///
@@ -124,8 +124,8 @@ impl<T: ?Sized> RwLock<T> {
     /// ```
     #[inline]
     pub fn read(&self) -> LockResult<RwLockReadGuard<'_, T>> {
-        unsafe {
-            let _ = down_read(&*self.lock.get());
+        if unsafe { down_read(&*self.lock.get()) }.is_err() {
+            return Err(PoisonError::failed());
         }
         Ok(RwLockReadGuard {
             lock: self,
@@ -186,8 +186,8 @@ impl<T: ?Sized> RwLock<T> {
     /// ```
     #[inline]
     pub fn write(&self) -> LockResult<RwLockWriteGuard<'_, T>> {
-        unsafe {
-            let _ = down_write(&*self.lock.get());
+        if unsafe { down_write(&*self.lock.get()) }.is_err() {
+            return Err(PoisonError::failed());
         }
         Ok(RwLockWriteGuard {
             lock: self,
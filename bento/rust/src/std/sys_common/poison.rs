@@ -1,7 +1,12 @@
 use core::fmt;
 
+/// The error returned when a lock can't be taken.
+///
+/// Bento locks are not poisoned by a panic. Instead, waiting for a lock gives up once the
+/// filesystem instance of the current request failed, since the lock may be held by a task that
+/// was parked by a panic and never releases it. Such an error holds no guard.
 pub struct PoisonError<T> {
-    guard: T,
+    guard: Option<T>,
 }
 
 pub enum TryLockError<T> {
@@ -37,7 +42,13 @@ impl<T> PoisonError<T> {
     /// [`Mutex::lock`]: ../../std/sync/struct.Mutex.html#method.lock
     /// [`RwLock::read`]: ../../std/sync/struct.RwLock.html#method.read
     pub fn new(guard: T) -> PoisonError<T> {
-        PoisonError { guard }
+        PoisonError { guard: Some(guard) }
+    }
+
+    /// Creates a `PoisonError` for a lock that was given up because the filesystem instance of
+    /// the current request failed.
+    pub fn failed() -> PoisonError<T> {
+        PoisonError { guard: None }
     }
 
     /// Consumes this error indicating that a lock is poisoned, returning the
@@ -64,20 +75,22 @@ impl<T> PoisonError<T> {
     /// let data = p_err.into_inner();
     /// println!("recovered {} items", data.len());
     /// ```
+    ///
+    /// Panics if the lock was given up because the filesystem failed.
     pub fn into_inner(self) -> T {
-        self.guard
+        self.guard.expect("lock was given up because the filesystem failed")
     }
 
     /// Reaches into this error indicating that a lock is poisoned, returning a
     /// reference to the underlying guard to allow access regardless.
     pub fn get_ref(&self) -> &T {
-        &self.guard
+        self.guard.as_ref().expect("lock was given up because the filesystem failed")
     }
 
     /// Reaches into this error indicating that a lock is poisoned, returning a
     /// mutable reference to the underlying guard to allow access regardless.
     pub fn get_mut(&mut self) -> &mut T {
-        &mut self.guard
+        self.guard.as_mut().expect("lock was given up because the filesystem failed")
     }
 }
 
@@ -113,6 +126,6 @@ where
 {
     match result {
         Ok(t) => Ok(f(t)),
-        Err(PoisonError { guard }) => Err(PoisonError::new(f(guard))),
+        Err(PoisonError { guard }) => Err(PoisonError { guard: guard.map(f) }),
     }
 }
@@ -0,0 +1,471 @@
+use std::convert::TryFrom;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::os::unix::fs::FileExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::io::RawFd;
+use std::ops::Range;
+use std::sync::Arc;
+use std::sync::RwLock;
+
+/// Storage underneath a `Disk`.
+///
+/// A backend reads and writes whole blocks. The block size is the length of the buffer passed
+/// to `read_block` and `write_block`, so block `blockno` starts at byte `blockno * buf.len()`.
+pub trait BlockBackend: Send + Sync {
+    /// Read block `blockno` into `buf`.
+    ///
+    /// Parts of the block that were never written read as zeros.
+    ///
+    /// Arguments:
+    /// * `blockno: u64` - Block number.
+    /// * `buf: &mut [u8]` - Buffer of one block.
+    fn read_block(&self, blockno: u64, buf: &mut [u8]) -> Result<(), libc::c_int>;
+
+    /// Write `data` to block `blockno`.
+    ///
+    /// Arguments:
+    /// * `blockno: u64` - Block number.
+    /// * `data: &[u8]` - Contents of one block.
+    fn write_block(&self, blockno: u64, data: &[u8]) -> Result<(), libc::c_int>;
+
+    /// Make all written blocks durable.
+    fn flush(&self) -> Result<(), libc::c_int>;
+
+    /// Get the size of the backend in bytes.
+    fn len(&self) -> u64;
+
+    /// Returns true if the backend has a size of 0.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the file descriptor of the backend, if it has one.
+    ///
+    /// `Disk` uses it as the device id returned by `as_raw_fd`.
+    fn raw_fd(&self) -> Option<RawFd> {
+        None
+    }
+}
+
+impl<B: BlockBackend + ?Sized> BlockBackend for Box<B> {
+    fn read_block(&self, blockno: u64, buf: &mut [u8]) -> Result<(), libc::c_int> {
+        (**self).read_block(blockno, buf)
+    }
+
+    fn write_block(&self, blockno: u64, data: &[u8]) -> Result<(), libc::c_int> {
+        (**self).write_block(blockno, data)
+    }
+
+    fn flush(&self) -> Result<(), libc::c_int> {
+        (**self).flush()
+    }
+
+    fn len(&self) -> u64 {
+        (**self).len()
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        (**self).raw_fd()
+    }
+}
+
+impl<B: BlockBackend + ?Sized> BlockBackend for Arc<B> {
+    fn read_block(&self, blockno: u64, buf: &mut [u8]) -> Result<(), libc::c_int> {
+        (**self).read_block(blockno, buf)
+    }
+
+    fn write_block(&self, blockno: u64, data: &[u8]) -> Result<(), libc::c_int> {
+        (**self).write_block(blockno, data)
+    }
+
+    fn flush(&self) -> Result<(), libc::c_int> {
+        (**self).flush()
+    }
+
+    fn len(&self) -> u64 {
+        (**self).len()
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        (**self).raw_fd()
+    }
+}
+
+// Byte offset of block `blockno` for blocks of `bsize` bytes, or EIO if it is out of range
+fn block_offset(blockno: u64, bsize: usize) -> Result<u64, libc::c_int> {
+    blockno.checked_mul(bsize as u64).ok_or(libc::EIO)
+}
+
+// Read a whole block, filling whatever lies beyond the end of the file with zeros
+fn read_full(file: &File, buf: &mut [u8], offset: u64) -> Result<(), libc::c_int> {
+    let mut read = 0;
+    while read < buf.len() {
+        match file.read_at(&mut buf[read..], offset + read as u64) {
+            Ok(0) => break,
+            Ok(x) => read += x,
+            Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.raw_os_error().unwrap_or(libc::EIO)),
+        }
+    }
+    for b in buf[read..].iter_mut() {
+        *b = 0;
+    }
+    Ok(())
+}
+
+fn write_full(file: &File, data: &[u8], offset: u64) -> Result<(), libc::c_int> {
+    file.write_all_at(data, offset)
+        .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))
+}
+
+fn file_len(file: &File) -> u64 {
+    file.metadata().map(|m| m.len()).unwrap_or(0)
+}
+
+fn open(name: &str, flags: i32) -> Result<File, libc::c_int> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(flags)
+        .open(name)
+        .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))
+}
+
+/// File or block device opened with `O_DIRECT`, bypassing the page cache.
+///
+/// This is the backend used by `Disk::new`. Buffers passed to it must be aligned to the block
+/// size, which the buffers of `Disk` are. `O_DIRECT` isn't supported on tmpfs, use `FileBackend`
+/// for images stored there.
+pub struct DirectFileBackend {
+    file: File,
+}
+
+impl DirectFileBackend {
+    /// Open the file or block device `name`.
+    pub fn open(name: &str) -> Result<Self, libc::c_int> {
+        Ok(DirectFileBackend {
+            file: open(name, libc::O_DIRECT)?,
+        })
+    }
+}
+
+impl BlockBackend for DirectFileBackend {
+    fn read_block(&self, blockno: u64, buf: &mut [u8]) -> Result<(), libc::c_int> {
+        read_full(&self.file, buf, block_offset(blockno, buf.len())?)
+    }
+
+    fn write_block(&self, blockno: u64, data: &[u8]) -> Result<(), libc::c_int> {
+        write_full(&self.file, data, block_offset(blockno, data.len())?)
+    }
+
+    fn flush(&self) -> Result<(), libc::c_int> {
+        self.file.sync_all().map_err(|_| libc::EIO)
+    }
+
+    fn len(&self) -> u64 {
+        file_len(&self.file)
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        Some(self.file.as_raw_fd())
+    }
+}
+
+/// File accessed through the page cache.
+pub struct FileBackend {
+    file: File,
+}
+
+impl FileBackend {
+    /// Open the file `name`.
+    pub fn open(name: &str) -> Result<Self, libc::c_int> {
+        Ok(FileBackend {
+            file: open(name, 0)?,
+        })
+    }
+
+    /// Use an already opened file.
+    pub fn from_file(file: File) -> Self {
+        FileBackend { file }
+    }
+}
+
+impl BlockBackend for FileBackend {
+    fn read_block(&self, blockno: u64, buf: &mut [u8]) -> Result<(), libc::c_int> {
+        read_full(&self.file, buf, block_offset(blockno, buf.len())?)
+    }
+
+    fn write_block(&self, blockno: u64, data: &[u8]) -> Result<(), libc::c_int> {
+        write_full(&self.file, data, block_offset(blockno, data.len())?)
+    }
+
+    fn flush(&self) -> Result<(), libc::c_int> {
+        self.file.sync_all().map_err(|_| libc::EIO)
+    }
+
+    fn len(&self) -> u64 {
+        file_len(&self.file)
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        Some(self.file.as_raw_fd())
+    }
+}
+
+/// Sparse file that only stores blocks that aren't all zeros.
+///
+/// Writing a block of zeros punches a hole instead of writing data, so a large, mostly empty
+/// image only takes up the space of the blocks that are in use.
+pub struct SparseFileBackend {
+    file: File,
+}
+
+impl SparseFileBackend {
+    /// Open the file `name`.
+    pub fn open(name: &str) -> Result<Self, libc::c_int> {
+        Ok(SparseFileBackend {
+            file: open(name, 0)?,
+        })
+    }
+
+    /// Create the file `name` with a size of `len` bytes, without allocating any blocks.
+    ///
+    /// An existing file is truncated.
+    pub fn create(name: &str, len: u64) -> Result<Self, libc::c_int> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(name)
+            .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))?;
+        file.set_len(len)
+            .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))?;
+        Ok(SparseFileBackend { file })
+    }
+
+    /// Get the number of bytes allocated on disk for the file.
+    pub fn allocated(&self) -> u64 {
+        use std::os::unix::fs::MetadataExt;
+        self.file.metadata().map(|m| m.blocks() * 512).unwrap_or(0)
+    }
+}
+
+impl BlockBackend for SparseFileBackend {
+    fn read_block(&self, blockno: u64, buf: &mut [u8]) -> Result<(), libc::c_int> {
+        read_full(&self.file, buf, block_offset(blockno, buf.len())?)
+    }
+
+    fn write_block(&self, blockno: u64, data: &[u8]) -> Result<(), libc::c_int> {
+        let offset = block_offset(blockno, data.len())?;
+        let end = offset.checked_add(data.len() as u64).ok_or(libc::EIO)?;
+        if data.iter().any(|b| *b != 0) || end > file_len(&self.file) {
+            return write_full(&self.file, data, offset);
+        }
+        let ret = unsafe {
+            libc::fallocate(
+                self.file.as_raw_fd(),
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                offset as libc::off_t,
+                data.len() as libc::off_t,
+            )
+        };
+        if ret < 0 {
+            // Filesystems without hole punching store the zeros
+            return write_full(&self.file, data, offset);
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), libc::c_int> {
+        self.file.sync_all().map_err(|_| libc::EIO)
+    }
+
+    fn len(&self) -> u64 {
+        file_len(&self.file)
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        Some(self.file.as_raw_fd())
+    }
+}
+
+/// Disk image kept in memory.
+///
+/// The size is fixed when the backend is created, and accesses beyond the end fail with `EIO`
+/// like they would on a device. Useful to run a filesystem in tests without touching the disk.
+pub struct MemoryBackend {
+    data: RwLock<Vec<u8>>,
+}
+
+impl MemoryBackend {
+    /// Create an image of `len` bytes filled with zeros.
+    pub fn new(len: usize) -> Self {
+        MemoryBackend::from_vec(vec![0; len])
+    }
+
+    /// Use `data` as the image.
+    pub fn from_vec(data: Vec<u8>) -> Self {
+        MemoryBackend {
+            data: RwLock::new(data),
+        }
+    }
+
+    /// Get a copy of the image.
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.data.read().unwrap().clone()
+    }
+
+    /// Take the image out of the backend.
+    pub fn into_vec(self) -> Vec<u8> {
+        self.data.into_inner().unwrap()
+    }
+}
+
+// Bytes of block `blockno` in an image with blocks of `bsize` bytes, or EIO if they can't be
+// addressed in memory
+fn block_range(blockno: u64, bsize: usize) -> Result<Range<usize>, libc::c_int> {
+    let start = usize::try_from(blockno)
+        .ok()
+        .and_then(|blockno| blockno.checked_mul(bsize))
+        .ok_or(libc::EIO)?;
+    let end = start.checked_add(bsize).ok_or(libc::EIO)?;
+    Ok(start..end)
+}
+
+impl BlockBackend for MemoryBackend {
+    fn read_block(&self, blockno: u64, buf: &mut [u8]) -> Result<(), libc::c_int> {
+        let data = self.data.read().map_err(|_| libc::EIO)?;
+        let block = data.get(block_range(blockno, buf.len())?).ok_or(libc::EIO)?;
+        buf.copy_from_slice(block);
+        Ok(())
+    }
+
+    fn write_block(&self, blockno: u64, data: &[u8]) -> Result<(), libc::c_int> {
+        let mut image = self.data.write().map_err(|_| libc::EIO)?;
+        let block = image
+            .get_mut(block_range(blockno, data.len())?)
+            .ok_or(libc::EIO)?;
+        block.copy_from_slice(data);
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), libc::c_int> {
+        Ok(())
+    }
+
+    fn len(&self) -> u64 {
+        self.data.read().unwrap().len() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use disk::Disk;
+    use std::fs;
+    use std::path::PathBuf;
+
+    const BSIZE: u64 = 4096;
+
+    // Path of a scratch image for `test`, removed when dropped
+    struct Image(PathBuf);
+
+    impl Image {
+        fn new(test: &str, len: u64) -> Image {
+            let path = std::env::temp_dir().join(format!("bento-{}-{}.img", test, std::process::id()));
+            File::create(&path).unwrap().set_len(len).unwrap();
+            Image(path)
+        }
+
+        fn name(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for Image {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    // Write a few blocks through a `Disk` on `backend`, then read them back through a new `Disk`
+    // on `reopen()`, so nothing comes from the buffer cache.
+    fn round_trip<B, F>(backend: B, reopen: F)
+    where
+        B: BlockBackend + 'static,
+        F: FnOnce() -> B,
+    {
+        {
+            let disk = Disk::from_backend_with_cache(backend, BSIZE, 4, None);
+            for blockno in 0..3 {
+                let mut bh = disk.bread(blockno).unwrap();
+                for (i, b) in bh.data_mut().iter_mut().enumerate() {
+                    *b = (blockno as usize + i) as u8;
+                }
+                bh.mark_buffer_dirty();
+            }
+            // Overwrite a block with zeros, which a sparse backend stores as a hole
+            let mut bh = disk.bread(2).unwrap();
+            bh.data_mut().iter_mut().for_each(|b| *b = 0);
+            bh.mark_buffer_dirty();
+            drop(bh);
+            disk.sync_all().unwrap();
+        }
+        let disk = Disk::from_backend_with_cache(reopen(), BSIZE, 4, None);
+        for blockno in 0..2 {
+            let bh = disk.bread(blockno).unwrap();
+            assert!(bh.data().iter().enumerate().all(|(i, b)| *b == (blockno as usize + i) as u8));
+        }
+        assert!(disk.bread(2).unwrap().data().iter().all(|b| *b == 0));
+        assert!(disk.bread(3).unwrap().data().iter().all(|b| *b == 0));
+        assert_eq!(disk.backend().len(), 4 * BSIZE);
+    }
+
+    #[test]
+    fn direct_file_round_trip() {
+        let image = Image::new("direct", 4 * BSIZE);
+        round_trip(DirectFileBackend::open(image.name()).unwrap(), || {
+            DirectFileBackend::open(image.name()).unwrap()
+        });
+    }
+
+    #[test]
+    fn file_round_trip() {
+        let image = Image::new("file", 4 * BSIZE);
+        round_trip(FileBackend::open(image.name()).unwrap(), || {
+            FileBackend::open(image.name()).unwrap()
+        });
+    }
+
+    #[test]
+    fn sparse_file_round_trip() {
+        let image = Image::new("sparse", 0);
+        let backend = SparseFileBackend::create(image.name(), 4 * BSIZE).unwrap();
+        assert_eq!(backend.allocated(), 0);
+        round_trip(backend, || SparseFileBackend::open(image.name()).unwrap());
+    }
+
+    #[test]
+    fn memory_round_trip() {
+        let memory = Arc::new(MemoryBackend::new(4 * BSIZE as usize));
+        round_trip(Arc::clone(&memory), || Arc::clone(&memory));
+    }
+
+    #[test]
+    fn out_of_range_blocks() {
+        let memory = MemoryBackend::new(4 * BSIZE as usize);
+        let mut buf = vec![0; BSIZE as usize];
+        assert_eq!(memory.read_block(4, &mut buf), Err(libc::EIO));
+        assert_eq!(memory.write_block(4, &buf), Err(libc::EIO));
+        assert_eq!(memory.read_block(u64::MAX, &mut buf), Err(libc::EIO));
+        assert_eq!(memory.write_block(u64::MAX / 2, &buf), Err(libc::EIO));
+
+        let image = Image::new("range", 4 * BSIZE);
+        let file = FileBackend::open(image.name()).unwrap();
+        assert_eq!(file.read_block(u64::MAX, &mut buf), Err(libc::EIO));
+        assert_eq!(file.write_block(u64::MAX, &buf), Err(libc::EIO));
+    }
+}
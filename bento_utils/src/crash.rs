@@ -0,0 +1,519 @@
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::sync::Mutex;
+
+use fuse::*;
+
+use super::{BentoFilesystem, BlockBackend, Disk, MemoryBackend, TestDriver};
+
+/// Default number of writes between two flushes that are tried in every order. Logs with larger
+/// epochs are rejected, see `CrashStates`.
+pub const DEFAULT_MAX_REORDER: usize = 10;
+
+// Inode number of the root directory
+const ROOT_INO: u64 = 1;
+
+// Size of the reads used to check that files are readable
+const READ_SIZE: u32 = 128 * 1024;
+
+/// Operation recorded by a `RecordingBackend`.
+#[derive(Clone)]
+pub enum DiskOp {
+    /// Block `blockno` was written with `data`.
+    Write { blockno: u64, data: Vec<u8> },
+    /// All earlier writes were made durable.
+    Flush,
+}
+
+/// In-memory disk that records every block write and flush.
+///
+/// The recorded operations are turned into the images the disk could hold after a crash with
+/// `crash_states`. Writes made before `checkpoint` are part of the starting image and are never
+/// lost, so setting up a filesystem doesn't add to the crash states.
+///
+/// ```ignore
+/// let recording = Arc::new(RecordingBackend::new(std::fs::read("xv6.img")?));
+/// let disk: Disk = Disk::from_backend(Box::new(Arc::clone(&recording)), BSIZE as u64);
+/// // Mount xv6fs on `disk` with a `TestDriver` and run a workload
+/// let states = recording.crash_states(DEFAULT_MAX_REORDER)?;
+/// let mount = |disk| {
+///     let mut fs = Xv6FileSystem::new();
+///     fs.disk = Some(Arc::new(disk));
+///     fs
+/// };
+/// check_crash_states(states, BSIZE as u64, mount, |_, _: &TestDriver<_, Xv6State, Xv6State>| {
+///     Ok(())
+/// })?;
+/// ```
+pub struct RecordingBackend {
+    base: Mutex<Vec<u8>>,
+    image: MemoryBackend,
+    ops: Mutex<Vec<DiskOp>>,
+}
+
+impl RecordingBackend {
+    /// Record the operations on a copy of `image`.
+    pub fn new(image: Vec<u8>) -> Self {
+        RecordingBackend {
+            base: Mutex::new(image.clone()),
+            image: MemoryBackend::from_vec(image),
+            ops: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Get the operations recorded since the last checkpoint.
+    pub fn ops(&self) -> Vec<DiskOp> {
+        self.ops.lock().unwrap().clone()
+    }
+
+    /// Make the current image the starting image and forget the recorded operations.
+    pub fn checkpoint(&self) {
+        let mut ops = self.ops.lock().unwrap();
+        *self.base.lock().unwrap() = self.image.to_vec();
+        ops.clear();
+    }
+
+    /// Get a copy of the current image, with every recorded write applied.
+    pub fn image(&self) -> Vec<u8> {
+        self.image.to_vec()
+    }
+
+    /// Get the images the disk could hold after a crash at any point since the last checkpoint.
+    ///
+    /// Fails with `E2BIG` if more than `max_reorder` writes were made between two flushes, see
+    /// `CrashStates::new`.
+    ///
+    /// Arguments:
+    /// * `max_reorder: usize` - Largest number of writes between two flushes whose orders are
+    ///   all tried.
+    pub fn crash_states(&self, max_reorder: usize) -> Result<CrashStates, i32> {
+        let ops = self.ops.lock().unwrap();
+        let base = self.base.lock().unwrap().clone();
+        CrashStates::new(base, ops.clone(), max_reorder)
+    }
+}
+
+impl BlockBackend for RecordingBackend {
+    fn read_block(&self, blockno: u64, buf: &mut [u8]) -> Result<(), libc::c_int> {
+        self.image.read_block(blockno, buf)
+    }
+
+    fn write_block(&self, blockno: u64, data: &[u8]) -> Result<(), libc::c_int> {
+        // Hold the log while writing, so the log has the order the image saw
+        let mut ops = self.ops.lock().map_err(|_| libc::EIO)?;
+        self.image.write_block(blockno, data)?;
+        ops.push(DiskOp::Write {
+            blockno,
+            data: data.to_vec(),
+        });
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), libc::c_int> {
+        self.ops.lock().map_err(|_| libc::EIO)?.push(DiskOp::Flush);
+        Ok(())
+    }
+
+    fn len(&self) -> u64 {
+        self.image.len()
+    }
+}
+
+/// Image the disk could hold after a crash.
+#[derive(Clone)]
+pub struct CrashState {
+    /// Number of flushes that completed before the crash
+    pub epoch: usize,
+    /// Indices in the operation log of the writes after the last completed flush that reached
+    /// the disk, in the order they were issued
+    pub writes: Vec<usize>,
+    /// Contents of the disk
+    pub image: Vec<u8>,
+}
+
+impl fmt::Display for CrashState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "epoch {} writes {:?}", self.epoch, self.writes)
+    }
+}
+
+/// Iterator over the images the disk could hold after a crash.
+///
+/// A flush splits the operation log into epochs. A crash keeps every write of the epochs before
+/// it and any subset of the writes of the epoch it happens in, since the disk may complete
+/// unflushed writes in any order. Writes to the same block are applied in the order they were
+/// issued.
+///
+/// Every subset of the writes of every epoch is produced, so checking all the states of a
+/// workload covers every crash. The number of subsets grows exponentially with the size of an
+/// epoch, so logs with epochs of more than `max_reorder` writes are rejected instead of being
+/// checked partially.
+pub struct CrashStates {
+    ops: Vec<DiskOp>,
+    // Indices in `ops` of the writes of each epoch
+    epochs: Vec<Vec<usize>>,
+    epoch: usize,
+    // Durable image at the start of `epoch`
+    image: Vec<u8>,
+    // Subsets of the writes of `epoch` left to return, last one first
+    subsets: Vec<Vec<usize>>,
+}
+
+impl CrashStates {
+    /// Enumerate the crash states of the operations `ops` applied to `base`.
+    ///
+    /// Fails with `E2BIG` if an epoch has more than `max_reorder` writes, or more than 63.
+    /// Workloads that hit the limit must flush more often or be split up.
+    ///
+    /// Arguments:
+    /// * `base: Vec<u8>` - Image before the first operation.
+    /// * `ops: Vec<DiskOp>` - Operation log, e.g. from `RecordingBackend::ops`.
+    /// * `max_reorder: usize` - Largest number of writes in an epoch.
+    pub fn new(base: Vec<u8>, ops: Vec<DiskOp>, max_reorder: usize) -> Result<Self, i32> {
+        let mut epochs = vec![Vec::new()];
+        for (idx, op) in ops.iter().enumerate() {
+            match *op {
+                DiskOp::Write { .. } => epochs.last_mut().unwrap().push(idx),
+                DiskOp::Flush => epochs.push(Vec::new()),
+            }
+        }
+        if epochs.iter().any(|writes| writes.len() > max_reorder || writes.len() >= 64) {
+            return Err(libc::E2BIG);
+        }
+        let subsets = subsets(&epochs[0], true);
+        Ok(CrashStates {
+            ops,
+            epochs,
+            epoch: 0,
+            image: base,
+            subsets,
+        })
+    }
+
+    fn apply(&self, image: &mut [u8], idx: usize) {
+        if let DiskOp::Write { blockno, ref data } = self.ops[idx] {
+            let offset = blockno as usize * data.len();
+            image[offset..offset + data.len()].copy_from_slice(data);
+        }
+    }
+}
+
+// Subsets of `writes`, in reverse order. The empty subset is the same state as all writes of the
+// previous epoch, so it's only returned for the first epoch.
+fn subsets(writes: &[usize], first: bool) -> Vec<Vec<usize>> {
+    let mut subsets = Vec::new();
+    for mask in 0..(1u64 << writes.len()) {
+        if mask == 0 && !first {
+            continue;
+        }
+        let subset = writes
+            .iter()
+            .enumerate()
+            .filter(|&(bit, _)| mask & (1 << bit) != 0)
+            .map(|(_, idx)| *idx)
+            .collect();
+        subsets.push(subset);
+    }
+    subsets.reverse();
+    subsets
+}
+
+impl Iterator for CrashStates {
+    type Item = CrashState;
+
+    fn next(&mut self) -> Option<CrashState> {
+        loop {
+            if let Some(writes) = self.subsets.pop() {
+                let mut image = self.image.clone();
+                for idx in writes.iter() {
+                    self.apply(&mut image, *idx);
+                }
+                return Some(CrashState {
+                    epoch: self.epoch,
+                    writes,
+                    image,
+                });
+            }
+            if self.epoch + 1 >= self.epochs.len() {
+                return None;
+            }
+            let mut image = Vec::new();
+            std::mem::swap(&mut image, &mut self.image);
+            for idx in self.epochs[self.epoch].iter() {
+                self.apply(&mut image, *idx);
+            }
+            self.image = image;
+            self.epoch += 1;
+            self.subsets = subsets(&self.epochs[self.epoch], false);
+        }
+    }
+}
+
+/// Crash state that failed a check.
+pub struct CrashFailure {
+    pub state: CrashState,
+    pub error: String,
+}
+
+impl fmt::Display for CrashFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "crash state {}: {}", self.state, self.error)
+    }
+}
+
+impl fmt::Debug for CrashFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// Mount every crash state and check that the filesystem recovers to a consistent state.
+///
+/// For every state, the image is put on a `Disk` and passed to `mount`, which creates the
+/// filesystem on it. The filesystem is initialized with `TestDriver::init`, which runs its
+/// recovery, like replaying the journal in xv6fs. Then the tree is checked with `check_tree` and
+/// with `check`, for invariants specific to the workload, and the filesystem is destroyed.
+///
+/// Returns the number of states checked, or the first state that failed. A failed mount and a
+/// panic during recovery or the checks are failures too.
+///
+/// Arguments:
+/// * `states: CrashStates` - States to check, e.g. from `RecordingBackend::crash_states`.
+/// * `bsize: u64` - Block size of the disk.
+/// * `mount: M` - Create the filesystem on a disk.
+/// * `check: C` - Check the recovered filesystem.
+pub fn check_crash_states<'de, T, TransferIn, TransferOut, M, C>(
+    states: CrashStates,
+    bsize: u64,
+    mut mount: M,
+    mut check: C,
+) -> Result<usize, CrashFailure>
+where
+    T: BentoFilesystem<'de, TransferIn, TransferOut>,
+    M: FnMut(Disk) -> T,
+    C: FnMut(&CrashState, &TestDriver<'de, T, TransferIn, TransferOut>) -> Result<(), String>,
+{
+    let mut checked = 0;
+    for state in states {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            // No background writeback, the image is dropped after the checks
+            let backend: Box<dyn BlockBackend> =
+                Box::new(MemoryBackend::from_vec(state.image.clone()));
+            let disk = Disk::from_backend_with_cache(backend, bsize, 1024, None);
+            let mut driver = TestDriver::new(mount(disk));
+            driver
+                .init("crash", "")
+                .map_err(|err| format!("mount failed with errno {}", err))?;
+            let res = check_tree(&driver).and_then(|_| check(&state, &driver));
+            driver.destroy();
+            res
+        }));
+        let error = match result {
+            Ok(Ok(())) => {
+                checked += 1;
+                continue;
+            }
+            Ok(Err(error)) => error,
+            Err(payload) => match payload.downcast_ref::<&str>() {
+                Some(msg) => format!("panicked: {}", msg),
+                None => match payload.downcast_ref::<String>() {
+                    Some(msg) => format!("panicked: {}", msg),
+                    None => "panicked".to_string(),
+                },
+            },
+        };
+        return Err(CrashFailure {
+            state,
+            error,
+        });
+    }
+    Ok(checked)
+}
+
+/// Check that the whole tree of a filesystem is consistent.
+///
+/// Walks every directory from the root and checks that:
+/// * every entry can be looked up, with the inode number and type of the entry,
+/// * every directory is reachable through a single path,
+/// * every file, other than directories, has at least as many links as entries,
+/// * every regular file can be read up to its size, and every symlink can be read.
+///
+/// Arguments:
+/// * `driver: &TestDriver` - Driver of the initialized filesystem.
+pub fn check_tree<'de, T, TransferIn, TransferOut>(
+    driver: &TestDriver<'de, T, TransferIn, TransferOut>,
+) -> Result<(), String>
+where
+    T: BentoFilesystem<'de, TransferIn, TransferOut>,
+{
+    let mut dirs = vec![(ROOT_INO, "/".to_string())];
+    let mut visited = BTreeSet::new();
+    // Number of entries and link count of every file that isn't a directory
+    let mut links: BTreeMap<u64, (u32, u32, String)> = BTreeMap::new();
+    visited.insert(ROOT_INO);
+    while let Some((dir, path)) = dirs.pop() {
+        let opened = driver
+            .opendir(dir, 0)
+            .map_err(|err| format!("opendir {} failed with errno {}", path, err))?;
+        let entries = driver.readdir_all(dir, opened.fh);
+        let _ = driver.releasedir(dir, opened.fh, 0);
+        let entries =
+            entries.map_err(|err| format!("readdir {} failed with errno {}", path, err))?;
+        for entry in entries {
+            let name = entry.name.to_string_lossy().into_owned();
+            if name == "." || name == ".." {
+                continue;
+            }
+            let entry_path = format!("{}{}", path, name);
+            let attr = driver
+                .lookup(dir, &name)
+                .map_err(|err| format!("lookup {} failed with errno {}", entry_path, err))?;
+            driver.forget(attr.ino, 1);
+            if attr.ino != entry.ino {
+                return Err(format!(
+                    "{} is inode {} in its directory, but lookup returns {}",
+                    entry_path, entry.ino, attr.ino
+                ));
+            }
+            if attr.kind != entry.kind {
+                return Err(format!(
+                    "{} is a {:?} in its directory, but lookup returns a {:?}",
+                    entry_path, entry.kind, attr.kind
+                ));
+            }
+            match attr.kind {
+                FileType::Directory => {
+                    if !visited.insert(attr.ino) {
+                        return Err(format!("directory {} is reachable twice", entry_path));
+                    }
+                    dirs.push((attr.ino, entry_path + "/"));
+                    continue;
+                }
+                FileType::RegularFile => check_read(driver, attr.ino, attr.size, &entry_path)?,
+                FileType::Symlink => {
+                    driver.readlink(attr.ino).map_err(|err| {
+                        format!("readlink {} failed with errno {}", entry_path, err)
+                    })?;
+                }
+                _ => {}
+            }
+            links
+                .entry(attr.ino)
+                .or_insert((0, attr.nlink, entry_path))
+                .0 += 1;
+        }
+    }
+    for (ino, &(entries, nlink, ref path)) in links.iter() {
+        if entries > nlink {
+            return Err(format!(
+                "inode {} ({}) has {} entries, but a link count of {}",
+                ino, path, entries, nlink
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Read the regular file `ino` up to `size` bytes
+fn check_read<'de, T, TransferIn, TransferOut>(
+    driver: &TestDriver<'de, T, TransferIn, TransferOut>,
+    ino: u64,
+    size: u64,
+    path: &str,
+) -> Result<(), String>
+where
+    T: BentoFilesystem<'de, TransferIn, TransferOut>,
+{
+    let opened = driver
+        .open(ino, libc::O_RDONLY as u32)
+        .map_err(|err| format!("open {} failed with errno {}", path, err))?;
+    let mut offset = 0;
+    let mut res = Ok(());
+    while offset < size {
+        match driver.read(ino, opened.fh, offset as i64, READ_SIZE) {
+            Ok(ref data) if data.is_empty() => {
+                res = Err(format!(
+                    "{} has a size of {}, but ends at {}",
+                    path, size, offset
+                ));
+                break;
+            }
+            Ok(data) => offset += data.len() as u64,
+            Err(err) => {
+                res = Err(format!(
+                    "read {} at {} failed with errno {}",
+                    path, offset, err
+                ));
+                break;
+            }
+        }
+    }
+    let _ = driver.release(ino, opened.fh, libc::O_RDONLY as u32, 0, false);
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(blockno: u64, byte: u8) -> DiskOp {
+        DiskOp::Write {
+            blockno,
+            data: vec![byte; 2],
+        }
+    }
+
+    fn states(ops: Vec<DiskOp>) -> Vec<(usize, Vec<usize>, Vec<u8>)> {
+        CrashStates::new(vec![0; 6], ops, DEFAULT_MAX_REORDER)
+            .unwrap()
+            .map(|state| (state.epoch, state.writes, state.image))
+            .collect()
+    }
+
+    #[test]
+    fn every_subset_of_an_epoch() {
+        let ops = vec![
+            write(0, 1),
+            write(1, 2),
+            DiskOp::Flush,
+            write(2, 3),
+            write(0, 4),
+            DiskOp::Flush,
+        ];
+        assert_eq!(
+            states(ops),
+            vec![
+                (0, vec![], vec![0, 0, 0, 0, 0, 0]),
+                (0, vec![0], vec![1, 1, 0, 0, 0, 0]),
+                (0, vec![1], vec![0, 0, 2, 2, 0, 0]),
+                (0, vec![0, 1], vec![1, 1, 2, 2, 0, 0]),
+                (1, vec![3], vec![1, 1, 2, 2, 3, 3]),
+                (1, vec![4], vec![4, 4, 2, 2, 0, 0]),
+                (1, vec![3, 4], vec![4, 4, 2, 2, 3, 3]),
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_first_epoch() {
+        let ops = vec![DiskOp::Flush, write(1, 5)];
+        assert_eq!(
+            states(ops),
+            vec![
+                (0, vec![], vec![0, 0, 0, 0, 0, 0]),
+                (1, vec![1], vec![0, 0, 5, 5, 0, 0]),
+            ]
+        );
+        assert_eq!(states(Vec::new()), vec![(0, vec![], vec![0; 6])]);
+    }
+
+    #[test]
+    fn large_epochs_are_rejected() {
+        let ops = vec![write(0, 1), write(1, 2), DiskOp::Flush, write(2, 3)];
+        assert_eq!(CrashStates::new(vec![0; 6], ops.clone(), 2).unwrap().count(), 5);
+        assert_eq!(CrashStates::new(vec![0; 6], ops, 1).err(), Some(libc::E2BIG));
+    }
+}
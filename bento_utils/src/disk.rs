@@ -1,20 +1,25 @@
 use std::alloc;
-use std::fs::File;
-use std::fs::OpenOptions;
 use std::io;
 use std::ops::Deref;
 use std::ops::DerefMut;
 use std::os::unix::fs::FileExt;
-use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::io::AsRawFd;
 use std::os::unix::io::RawFd;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::sync::Arc;
-use std::sync::Weak;
-use std::sync::RwLock;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
 
 use std::cmp::min;
 
+use backend::BlockBackend;
+use backend::DirectFileBackend;
+
 unsafe fn alloc_aligned_vec(bsize: usize) -> Result<Vec<u8>, alloc::LayoutErr> {
     let layout = alloc::Layout::from_size_align(bsize as usize, bsize as usize)?;
     let ptr = alloc::alloc(layout);
@@ -25,23 +30,25 @@ struct ArrWrapper {
     arr: Vec<u8>,
     dirty: bool,
     blockno: u64,
-    disk: Arc<File>,
-    bsize: u64,
+    backend: Arc<dyn BlockBackend>,
 }
 
 impl ArrWrapper {
-    fn new(blockno: u64, disk: Arc<File>, bsize: u64) -> Result<ArrWrapper, libc::c_int> {
+    fn new(
+        blockno: u64,
+        backend: Arc<dyn BlockBackend>,
+        bsize: u64,
+    ) -> Result<ArrWrapper, libc::c_int> {
         // Allocate a vector aligned to `bsize`
         let mut block_arr = unsafe {
             alloc_aligned_vec(bsize as usize)
         }.map_err(|_| libc::EIO)?;
-        disk.read_at(block_arr.as_mut_slice(), blockno * bsize).map_err(|_| libc::EIO)?;
+        backend.read_block(blockno, block_arr.as_mut_slice())?;
         Ok(Self {
             arr: block_arr,
             dirty: false,
             blockno: blockno,
-            disk: disk,
-            bsize: bsize,
+            backend,
         })
     }
 
@@ -52,10 +59,11 @@ impl ArrWrapper {
     fn set_buffer_uptodate(&mut self) {}
 
     fn sync_dirty_buffer(&mut self) {
+        // Cleared before writing, so changes made while the block is written keep it dirty
         if self.dirty {
-            let _ = self.disk.write_at(self.arr.as_slice(), self.blockno * self.bsize);
+            self.dirty = false;
+            let _ = self.backend.write_block(self.blockno, self.arr.as_slice());
         }
-        self.dirty = false;
     }
 
     fn data(&self) -> &[u8] {
@@ -70,7 +78,7 @@ impl ArrWrapper {
 impl Drop for ArrWrapper {
     fn drop(&mut self) {
         if self.dirty {
-            let _ = self.disk.write_at(self.arr.as_mut_slice(), self.blockno * self.bsize);
+            let _ = self.backend.write_block(self.blockno, self.arr.as_slice());
         }
     }
 }
@@ -152,93 +160,426 @@ impl DerefMut for BHLockGuard<'_> {
 }
 
 
+/// Default number of blocks kept in the buffer cache.
+pub const DEFAULT_CACHE_BLOCKS: usize = 4096;
+
+/// Default interval between background writebacks of dirty blocks, like the default
+/// `dirty_writeback_centisecs` of the kernel.
+pub const DEFAULT_WRITEBACK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Buffer cache statistics.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CacheStats {
+    /// Number of `bread` and `getblk` calls that found the block in the cache
+    pub hits: u64,
+    /// Number of `bread` and `getblk` calls that read the block from disk
+    pub misses: u64,
+    /// Number of blocks dropped from the cache to make room for other blocks
+    pub evictions: u64,
+    /// Number of dirty blocks written by the background writeback thread
+    pub writebacks: u64,
+    /// Number of blocks currently in the cache
+    pub cached: usize,
+}
+
+struct CacheEntry {
+    buffer: Arc<ArrWrapper>,
+    // Position in `CacheState::lru`
+    tick: u64,
+    // Set while the block is part of a transaction that hasn't been installed yet
+    pinned: bool,
+}
+
+struct CacheState {
+    entries: HashMap<u64, CacheEntry>,
+    // Block numbers ordered from least to most recently used
+    lru: BTreeMap<u64, u64>,
+    next_tick: u64,
+    // Blocks that are being read from or written to the disk without holding the lock. They
+    // can't be used until the I/O is done, see `CacheShared::idle`.
+    reading: HashSet<u64>,
+    writing: HashSet<u64>,
+    stats: CacheStats,
+}
+
+impl CacheState {
+    fn busy(&self, blockno: u64) -> bool {
+        self.reading.contains(&blockno) || self.writing.contains(&blockno)
+    }
+
+    fn insert(&mut self, blockno: u64, buffer: Arc<ArrWrapper>) {
+        let tick = self.next_tick;
+        self.next_tick += 1;
+        self.entries.insert(blockno, CacheEntry {
+            buffer,
+            tick,
+            pinned: false,
+        });
+        self.lru.insert(tick, blockno);
+    }
+
+    fn touch(&mut self, blockno: u64) {
+        let tick = self.next_tick;
+        self.next_tick += 1;
+        if let Some(entry) = self.entries.get_mut(&blockno) {
+            self.lru.remove(&entry.tick);
+            entry.tick = tick;
+            self.lru.insert(tick, blockno);
+        }
+    }
+
+    // Drop the least recently used blocks until at most `capacity` blocks are cached. Blocks
+    // that are held by a `BufferHead` or pinned stay in the cache, like buffer heads with a
+    // reference in the kernel. Returns the dropped blocks that are dirty, marked as being written,
+    // which the caller must write with `CacheShared::write_marked`.
+    fn evict(&mut self, capacity: usize) -> Vec<(u64, Arc<ArrWrapper>)> {
+        let mut victims = Vec::new();
+        let mut excess = self.entries.len().saturating_sub(capacity);
+        for (tick, blockno) in self.lru.iter() {
+            if excess == 0 {
+                break;
+            }
+            let entry = &self.entries[blockno];
+            if !entry.pinned && Arc::strong_count(&entry.buffer) == 1 {
+                victims.push((*tick, *blockno));
+                excess -= 1;
+            }
+        }
+        let mut dirty = Vec::new();
+        for (tick, blockno) in victims {
+            self.lru.remove(&tick);
+            let entry = self.entries.remove(&blockno).unwrap();
+            self.stats.evictions += 1;
+            if entry.buffer.dirty {
+                self.writing.insert(blockno);
+                dirty.push((blockno, entry.buffer));
+            }
+        }
+        dirty
+    }
+
+    // Mark the dirty, unpinned blocks for which `filter` returns true as being written, so they
+    // can be written without holding the lock.
+    fn take_dirty<F: Fn(u64, &CacheEntry) -> bool>(
+        &mut self,
+        filter: F,
+    ) -> Vec<(u64, Arc<ArrWrapper>)> {
+        let mut dirty = Vec::new();
+        for (blockno, entry) in self.entries.iter() {
+            if entry.pinned || !entry.buffer.dirty || self.busy(*blockno) {
+                continue;
+            }
+            if filter(*blockno, entry) {
+                dirty.push((*blockno, Arc::clone(&entry.buffer)));
+            }
+        }
+        for (blockno, _) in dirty.iter() {
+            self.writing.insert(*blockno);
+        }
+        dirty
+    }
+}
+
+struct CacheShared {
+    backend: Arc<dyn BlockBackend>,
+    state: Mutex<CacheState>,
+    // Signalled when blocks are no longer being read or written
+    idle: Condvar,
+    bsize: u64,
+    capacity: usize,
+}
+
+impl CacheShared {
+    // Write blocks that were marked as being written and let other threads use them again. The
+    // disk is accessed without holding the lock.
+    fn write_marked(&self, buffers: Vec<(u64, Arc<ArrWrapper>)>) {
+        if buffers.is_empty() {
+            return;
+        }
+        for (_, buffer) in buffers.iter() {
+            let mut buffer = Arc::clone(buffer);
+            unsafe {
+                Arc::get_mut_unchecked(&mut buffer).sync_dirty_buffer();
+            }
+        }
+        let mut state = self.state.lock().unwrap();
+        for (blockno, _) in buffers.iter() {
+            state.writing.remove(blockno);
+        }
+        drop(state);
+        self.idle.notify_all();
+    }
+
+    // Wait until none of `blocks` is being written.
+    fn wait_written(&self, blocks: &[u64]) {
+        let mut state = self.state.lock().unwrap();
+        while blocks.iter().any(|blockno| state.writing.contains(blockno)) {
+            state = self.idle.wait(state).unwrap();
+        }
+    }
+
+    // Write back the dirty blocks that nobody holds. Held blocks may be modified concurrently, so
+    // they are only written by an explicit sync or once they are released and evicted. Pinned
+    // blocks are only written by their journal.
+    fn writeback(&self) {
+        let dirty = {
+            let mut state = self.state.lock().unwrap();
+            let dirty = state.take_dirty(|_, entry| Arc::strong_count(&entry.buffer) == 1);
+            state.stats.writebacks += dirty.len() as u64;
+            dirty
+        };
+        self.write_marked(dirty);
+    }
+
+    // Write the dirty blocks for which `filter` returns true, including held ones, and wait for
+    // writes of those blocks that were already in progress.
+    fn sync<F: Fn(u64) -> bool>(&self, filter: F) {
+        let (dirty, in_flight) = {
+            let mut state = self.state.lock().unwrap();
+            let in_flight: Vec<u64> =
+                state.writing.iter().copied().filter(|blockno| filter(*blockno)).collect();
+            (state.take_dirty(|blockno, _| filter(blockno)), in_flight)
+        };
+        self.write_marked(dirty);
+        self.wait_written(&in_flight);
+    }
+}
+
+struct WritebackThread {
+    // Set to true to stop the thread
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    handle: Option<JoinHandle<()>>,
+}
+
+/// Capacity-bounded LRU cache of disk blocks.
+///
+/// Up to `capacity` blocks stay cached after they are released, so hot metadata blocks are read
+/// from disk only once. If a writeback interval is set, a background thread writes back dirty
+/// blocks that aren't held by anyone at that interval.
 struct BufferCache {
-    file: Arc<File>,
-    cache: RwLock<HashMap<u64, Weak<ArrWrapper>>>,
-    bsize: u64
+    shared: Arc<CacheShared>,
+    writeback: Option<WritebackThread>,
 }
 
 impl BufferCache {
-    fn new(name: &str, bsize: u64) -> Self {
-        let f = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .custom_flags(libc::O_DIRECT)
-            .open(name)
-            .unwrap();
-        Self {
-            file: Arc::new(f),
-            cache: RwLock::new(HashMap::new()),
+    fn new(
+        backend: Arc<dyn BlockBackend>,
+        bsize: u64,
+        capacity: usize,
+        writeback: Option<Duration>,
+    ) -> Self {
+        let shared = Arc::new(CacheShared {
+            backend,
+            state: Mutex::new(CacheState {
+                entries: HashMap::new(),
+                lru: BTreeMap::new(),
+                next_tick: 0,
+                reading: HashSet::new(),
+                writing: HashSet::new(),
+                stats: Default::default(),
+            }),
+            idle: Condvar::new(),
             bsize: bsize,
+            capacity,
+        });
+        let writeback = writeback.map(|interval| {
+            let stop = Arc::new((Mutex::new(false), Condvar::new()));
+            let thread_stop = Arc::clone(&stop);
+            let thread_shared = Arc::clone(&shared);
+            let handle = thread::Builder::new()
+                .name("bento-writeback".to_string())
+                .spawn(move || {
+                    let (ref lock, ref cond) = *thread_stop;
+                    let mut stop = lock.lock().unwrap();
+                    while !*stop {
+                        stop = cond.wait_timeout(stop, interval).unwrap().0;
+                        if !*stop {
+                            thread_shared.writeback();
+                        }
+                    }
+                })
+                .unwrap();
+            WritebackThread {
+                stop,
+                handle: Some(handle),
+            }
+        });
+        Self {
+            shared,
+            writeback,
         }
     }
 
+    fn sync_buffers(&self) {
+        self.shared.sync(|_| true);
+    }
+
     fn sync_all(&self) -> Result<(), libc::c_int> {
-        let cache_read = self.cache.read().unwrap();
-        for weak in cache_read.values() {
-            if let Some(mut bh) = weak.upgrade() {
-                unsafe {
-                    Arc::get_mut_unchecked(&mut bh).sync_dirty_buffer();
-                }
-            }
-        }
-        self.file.sync_all().map_err(|_| libc::EIO)
+        self.sync_buffers();
+        self.shared.backend.flush()
     }
 
     fn sync_data(&self) -> Result<(), libc::c_int> {
-        let cache_read = self.cache.read().unwrap();
-        for weak in cache_read.values() {
-            if let Some(mut bh) = weak.upgrade() {
-                unsafe {
-                    Arc::get_mut_unchecked(&mut bh).sync_dirty_buffer();
-                }
-            }
-        }
-        self.file.sync_data().map_err(|_| libc::EIO)
+        self.sync_buffers();
+        self.shared.backend.flush()
     }
 
     #[allow(dead_code)]
     fn sync_block(&self, blockno: u64) -> Result<(), libc::c_int> {
-        let cache_read = self.cache.read().unwrap();
-        if let Some(weak) = cache_read.get(&blockno) {
-            if let Some(mut bh) = weak.upgrade() {
-                unsafe {
-                    Arc::get_mut_unchecked(&mut bh).sync_dirty_buffer();
-                }
-            }
-        }
-        self.file.sync_data().map_err(|_| libc::EIO)
+        self.shared.sync(|x| x == blockno);
+        self.shared.backend.flush()
     }
 
+    // A block that isn't cached is read without holding the lock. It is marked as being read meanwhile,
+    // so other threads that want it wait for the read and use the same buffer, and a dirty copy
+    // that is still being written back after its eviction is never read back from the disk.
     fn bread(&self, blockno: u64) -> Result<BufferHead, libc::c_int> {
-        let mut cache_lock = self.cache.write().unwrap();
-        if let Some(weak) = cache_lock.get_mut(&blockno) {
-            if let Some(buf_lock) = weak.upgrade() {
-                return Ok(BufferHead::new(Arc::clone(&buf_lock), blockno));
-            }
+        let mut state = self.shared.state.lock().unwrap();
+        while state.busy(blockno) {
+            state = self.shared.idle.wait(state).unwrap();
+        }
+        if let Some(buffer) = state.entries.get(&blockno).map(|entry| Arc::clone(&entry.buffer)) {
+            state.stats.hits += 1;
+            state.touch(blockno);
+            return Ok(BufferHead::new(buffer, blockno));
         }
-        let bh_buf = ArrWrapper::new(blockno, Arc::clone(&self.file), self.bsize)?;
-        let new_arc = Arc::new(bh_buf);
-        cache_lock.insert(blockno, Arc::downgrade(&new_arc));
+        state.stats.misses += 1;
+        state.reading.insert(blockno);
+        drop(state);
+
+        let bh_buf = ArrWrapper::new(blockno, Arc::clone(&self.shared.backend), self.shared.bsize);
+        let mut state = self.shared.state.lock().unwrap();
+        state.reading.remove(&blockno);
+        self.shared.idle.notify_all();
+        let new_arc = Arc::new(bh_buf?);
+        state.insert(blockno, Arc::clone(&new_arc));
+        let evicted = state.evict(self.shared.capacity);
+        drop(state);
+        self.shared.write_marked(evicted);
         return Ok(BufferHead::new(new_arc, blockno));
     }
 
     fn getblk(&self, blockno: u64) -> Result<BufferHead, libc::c_int> {
         self.bread(blockno)
     }
+
+    fn set_pinned(&self, blockno: u64, pinned: bool) {
+        let mut state = self.shared.state.lock().unwrap();
+        if let Some(entry) = state.entries.get_mut(&blockno) {
+            entry.pinned = pinned;
+        }
+    }
+
+    fn stats(&self) -> CacheStats {
+        let state = self.shared.state.lock().unwrap();
+        let mut stats = state.stats;
+        stats.cached = state.entries.len();
+        stats
+    }
+}
+
+impl Drop for BufferCache {
+    fn drop(&mut self) {
+        if let Some(ref mut writeback) = self.writeback {
+            {
+                let (ref lock, ref cond) = *writeback.stop;
+                *lock.lock().unwrap() = true;
+                cond.notify_all();
+            }
+            if let Some(handle) = writeback.handle.take() {
+                let _ = handle.join();
+            }
+        }
+        // Pinned blocks belong to transactions that were never installed, so they are dropped
+        // instead of being written like the other dirty blocks.
+        let mut state = self.shared.state.lock().unwrap();
+        for entry in state.entries.values_mut().filter(|entry| entry.pinned) {
+            if let Some(buffer) = Arc::get_mut(&mut entry.buffer) {
+                buffer.dirty = false;
+            }
+        }
+    }
 }
 
-pub struct Disk {
+/// Disk accessed through a buffer cache.
+///
+/// The blocks are stored in a `BlockBackend`. `Disk::new` opens a file or block device with
+/// `O_DIRECT`, other backends are used with `Disk::from_backend`, for example to run a
+/// filesystem from an image in memory:
+///
+/// ```ignore
+/// let disk: Disk = Disk::from_backend(Box::new(MemoryBackend::from_vec(image)), BSIZE as u64);
+/// fs.disk = Some(Arc::new(disk));
+/// ```
+pub struct Disk<B: BlockBackend + 'static = Box<dyn BlockBackend>> {
+    backend: Arc<B>,
     cache: BufferCache,
 }
 
 impl Disk {
+    /// Open the disk `name` with the default buffer cache size and writeback interval.
     pub fn new(name: &str, bsize: u64) -> Self {
+        Self::with_cache(name, bsize, DEFAULT_CACHE_BLOCKS, Some(DEFAULT_WRITEBACK_INTERVAL))
+    }
+
+    /// Open the disk `name` with a buffer cache of `capacity` blocks.
+    ///
+    /// Arguments:
+    /// * `name: &str` - Path of the disk.
+    /// * `bsize: u64` - Block size.
+    /// * `capacity: usize` - Number of blocks kept in the cache after they are released.
+    /// * `writeback: Option<Duration>` - Interval between background writebacks of dirty
+    ///   blocks. If `None`, dirty blocks are only written when synced or evicted.
+    pub fn with_cache(
+        name: &str,
+        bsize: u64,
+        capacity: usize,
+        writeback: Option<Duration>,
+    ) -> Self {
+        let backend: Box<dyn BlockBackend> = Box::new(DirectFileBackend::open(name).unwrap());
+        Self::from_backend_with_cache(backend, bsize, capacity, writeback)
+    }
+}
+
+impl<B: BlockBackend + 'static> Disk<B> {
+    /// Use `backend` as the disk with the default buffer cache size and writeback interval.
+    pub fn from_backend(backend: B, bsize: u64) -> Self {
+        Self::from_backend_with_cache(
+            backend,
+            bsize,
+            DEFAULT_CACHE_BLOCKS,
+            Some(DEFAULT_WRITEBACK_INTERVAL),
+        )
+    }
+
+    /// Use `backend` as the disk with a buffer cache of `capacity` blocks, see `with_cache`.
+    pub fn from_backend_with_cache(
+        backend: B,
+        bsize: u64,
+        capacity: usize,
+        writeback: Option<Duration>,
+    ) -> Self {
+        let backend = Arc::new(backend);
+        let cache_backend: Arc<dyn BlockBackend> = backend.clone();
         Self {
-            cache: BufferCache::new(name, bsize),
+            backend,
+            cache: BufferCache::new(cache_backend, bsize, capacity, writeback),
         }
     }
 
+    /// Get the backend of the disk.
+    ///
+    /// Blocks written through the buffer cache only reach the backend once they are synced,
+    /// written back or evicted.
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// Get the buffer cache statistics.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
     pub fn sync_all(&self) -> Result<(), libc::c_int> {
         self.cache.sync_all()
     }
@@ -259,29 +600,101 @@ impl Disk {
     pub fn getblk(&self, blockno: u64) -> Result<BufferHead, libc::c_int> {
         self.cache.getblk(blockno)
     }
+
+    /// Keep a cached block from being written to the disk until it is unpinned.
+    ///
+    /// A journal pins the blocks of a transaction, so they are not written back, evicted or
+    /// synced before the transaction is committed to the log. Writing the block through its
+    /// `BufferHead` with `sync_dirty_buffer` still writes it. The block must be held by a
+    /// `BufferHead` when it is pinned.
+    ///
+    /// Arguments:
+    /// * `blockno: u64` - Block number.
+    pub fn pin_block(&self, blockno: u64) {
+        self.cache.set_pinned(blockno, true);
+    }
+
+    /// Let a block pinned with `pin_block` be written to the disk again.
+    ///
+    /// A journal unpins the blocks of a transaction once they are installed.
+    ///
+    /// Arguments:
+    /// * `blockno: u64` - Block number.
+    pub fn unpin_block(&self, blockno: u64) {
+        self.cache.set_pinned(blockno, false);
+    }
 }
 
-impl AsRawFd for Disk {
+impl<B: BlockBackend + 'static> AsRawFd for Disk<B> {
     fn as_raw_fd(&self) -> RawFd {
-        self.cache.file.as_raw_fd()
+        self.backend.raw_fd().unwrap_or(-1)
     }
 }
 
-pub struct DiskFile {
+pub struct DiskFile<B: BlockBackend + 'static = Box<dyn BlockBackend>> {
+    backend: Arc<B>,
     cache: BufferCache,
     bsize: u64,
 }
 
 impl DiskFile {
+    /// Open the disk `name` with the default buffer cache size and writeback interval.
     pub fn new(name: &str, bsize: u64) -> Self {
+        Self::with_cache(name, bsize, DEFAULT_CACHE_BLOCKS, Some(DEFAULT_WRITEBACK_INTERVAL))
+    }
+
+    /// Open the disk `name` with a buffer cache of `capacity` blocks, see `Disk::with_cache`.
+    pub fn with_cache(
+        name: &str,
+        bsize: u64,
+        capacity: usize,
+        writeback: Option<Duration>,
+    ) -> Self {
+        let backend: Box<dyn BlockBackend> = Box::new(DirectFileBackend::open(name).unwrap());
+        Self::from_backend_with_cache(backend, bsize, capacity, writeback)
+    }
+}
+
+impl<B: BlockBackend + 'static> DiskFile<B> {
+    /// Use `backend` as the disk with the default buffer cache size and writeback interval.
+    pub fn from_backend(backend: B, bsize: u64) -> Self {
+        Self::from_backend_with_cache(
+            backend,
+            bsize,
+            DEFAULT_CACHE_BLOCKS,
+            Some(DEFAULT_WRITEBACK_INTERVAL),
+        )
+    }
+
+    /// Use `backend` as the disk with a buffer cache of `capacity` blocks, see
+    /// `Disk::with_cache`.
+    pub fn from_backend_with_cache(
+        backend: B,
+        bsize: u64,
+        capacity: usize,
+        writeback: Option<Duration>,
+    ) -> Self {
+        let backend = Arc::new(backend);
+        let cache_backend: Arc<dyn BlockBackend> = backend.clone();
         Self {
-            cache: BufferCache::new(name, bsize),
+            backend,
+            cache: BufferCache::new(cache_backend, bsize, capacity, writeback),
             bsize: bsize,
         }
     }
 
+    /// Get the backend of the disk, see `Disk::backend`.
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
     pub fn sync_all(&self) -> Result<(), libc::c_int> {
-        self.cache.file.sync_all().map_err(|_| libc::EIO)
+        self.cache.sync_all()
+    }
+
+    /// Get the buffer cache statistics.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
     }
 
     pub fn sync_data(&self) -> Result<(), libc::c_int> {
@@ -293,13 +706,13 @@ impl DiskFile {
     }
 }
 
-impl AsRawFd for DiskFile {
+impl<B: BlockBackend + 'static> AsRawFd for DiskFile<B> {
     fn as_raw_fd(&self) -> RawFd {
-        self.cache.file.as_raw_fd()
+        self.backend.raw_fd().unwrap_or(-1)
     }
 }
 
-impl FileExt for DiskFile {
+impl<B: BlockBackend + 'static> FileExt for DiskFile<B> {
     fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
         let mut read = 0;
         while read < buf.len() {
@@ -339,3 +752,195 @@ impl FileExt for DiskFile {
         return Ok(written);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::MemoryBackend;
+    use std::sync::mpsc;
+    use std::time::Instant;
+
+    const BSIZE: u64 = 512;
+
+    fn disk() -> Disk<MemoryBackend> {
+        Disk::from_backend_with_cache(MemoryBackend::new(8 * BSIZE as usize), BSIZE, 2, None)
+    }
+
+    fn write_block<B: BlockBackend>(disk: &Disk<B>, blockno: u64, byte: u8) {
+        let mut bh = disk.bread(blockno).unwrap();
+        bh.data_mut()[0] = byte;
+        bh.mark_buffer_dirty();
+    }
+
+    fn on_disk(disk: &Disk<MemoryBackend>, blockno: u64) -> u8 {
+        disk.backend().to_vec()[(blockno * BSIZE) as usize]
+    }
+
+    #[test]
+    fn sync_writes_dirty_blocks() {
+        let disk = disk();
+        write_block(&disk, 1, 7);
+        assert_eq!(on_disk(&disk, 1), 0);
+        disk.sync_all().unwrap();
+        assert_eq!(on_disk(&disk, 1), 7);
+
+        let backend = MemoryBackend::new(8 * BSIZE as usize);
+        let file = DiskFile::from_backend_with_cache(backend, BSIZE, 2, None);
+        file.write_at(&[9; 4], BSIZE + 2).unwrap();
+        file.sync_all().unwrap();
+        let image = file.backend().to_vec();
+        assert_eq!(image[(BSIZE + 2) as usize..(BSIZE + 6) as usize], [9; 4]);
+    }
+
+    #[test]
+    fn pinned_blocks_stay_in_the_cache() {
+        let disk = disk();
+        {
+            let mut bh = disk.bread(1).unwrap();
+            bh.data_mut()[0] = 7;
+            bh.mark_buffer_dirty();
+            disk.pin_block(1);
+        }
+        // Neither a sync nor evicting the block writes it while it is pinned
+        disk.sync_all().unwrap();
+        for blockno in 2..8 {
+            write_block(&disk, blockno, 1);
+        }
+        assert_eq!(on_disk(&disk, 1), 0);
+        assert_eq!(disk.bread(1).unwrap().data()[0], 7);
+
+        disk.unpin_block(1);
+        disk.sync_all().unwrap();
+        assert_eq!(on_disk(&disk, 1), 7);
+    }
+
+    #[test]
+    fn pinned_blocks_are_dropped() {
+        let backend = Arc::new(MemoryBackend::new(8 * BSIZE as usize));
+        {
+            let disk = Disk::from_backend_with_cache(Arc::clone(&backend), BSIZE, 2, None);
+            write_block(&disk, 1, 7);
+            let mut bh = disk.bread(2).unwrap();
+            bh.data_mut()[0] = 8;
+            bh.mark_buffer_dirty();
+            disk.pin_block(2);
+        }
+        assert_eq!(backend.to_vec()[BSIZE as usize], 7);
+        assert_eq!(backend.to_vec()[2 * BSIZE as usize], 0);
+    }
+
+    #[test]
+    fn cache_stats() {
+        let disk = disk();
+        drop(disk.bread(1).unwrap());
+        drop(disk.bread(1).unwrap());
+        drop(disk.bread(2).unwrap());
+        // Block 1 is the least recently used, so it makes room for block 3
+        drop(disk.bread(3).unwrap());
+        let stats = disk.cache_stats();
+        assert_eq!((stats.hits, stats.misses, stats.evictions, stats.cached), (1, 3, 1, 2));
+
+        drop(disk.bread(2).unwrap());
+        drop(disk.bread(1).unwrap());
+        let stats = disk.cache_stats();
+        assert_eq!((stats.hits, stats.misses, stats.evictions, stats.cached), (2, 4, 2, 2));
+    }
+
+    #[test]
+    fn capacity_bounds_released_blocks() {
+        let disk = disk();
+        write_block(&disk, 1, 7);
+        // Held blocks stay cached beyond the capacity
+        let held: Vec<BufferHead> = (2..6).map(|blockno| disk.bread(blockno).unwrap()).collect();
+        assert_eq!(disk.cache_stats().cached, 4);
+        // The dirty block was released, so it was evicted and written
+        assert_eq!(on_disk(&disk, 1), 7);
+        drop(held);
+        drop(disk.bread(6).unwrap());
+        assert_eq!(disk.cache_stats().cached, 2);
+    }
+
+    #[test]
+    fn timed_writeback() {
+        let disk = Disk::from_backend_with_cache(
+            MemoryBackend::new(8 * BSIZE as usize),
+            BSIZE,
+            8,
+            Some(Duration::from_millis(10)),
+        );
+        write_block(&disk, 1, 7);
+        let mut held = disk.bread(2).unwrap();
+        held.data_mut()[0] = 8;
+        held.mark_buffer_dirty();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while on_disk(&disk, 1) != 7 {
+            assert!(Instant::now() < deadline, "block was not written back");
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(disk.cache_stats().writebacks >= 1);
+        // Blocks that are held are left to an explicit sync
+        assert_eq!(on_disk(&disk, 2), 0);
+        assert_eq!(disk.cache_stats().cached, 2);
+    }
+
+    // Blocks reads until it is told to go on for each read of `blockno`.
+    struct GatedBackend {
+        inner: MemoryBackend,
+        blockno: u64,
+        gate: Mutex<mpsc::Receiver<()>>,
+    }
+
+    impl BlockBackend for GatedBackend {
+        fn read_block(&self, blockno: u64, buf: &mut [u8]) -> Result<(), libc::c_int> {
+            if blockno == self.blockno {
+                self.gate.lock().unwrap().recv().map_err(|_| libc::EIO)?;
+            }
+            self.inner.read_block(blockno, buf)
+        }
+
+        fn write_block(&self, blockno: u64, data: &[u8]) -> Result<(), libc::c_int> {
+            self.inner.write_block(blockno, data)
+        }
+
+        fn flush(&self) -> Result<(), libc::c_int> {
+            self.inner.flush()
+        }
+
+        fn len(&self) -> u64 {
+            self.inner.len()
+        }
+    }
+
+    #[test]
+    fn misses_read_without_the_lock() {
+        let (go, gate) = mpsc::channel();
+        let backend = GatedBackend {
+            inner: MemoryBackend::new(8 * BSIZE as usize),
+            blockno: 3,
+            gate: Mutex::new(gate),
+        };
+        let disk = Arc::new(Disk::from_backend_with_cache(backend, BSIZE, 8, None));
+        let readers: Vec<_> = (0..2)
+            .map(|_| {
+                let disk = Arc::clone(&disk);
+                thread::spawn(move || disk.bread(3).unwrap().data()[0])
+            })
+            .collect();
+        while disk.cache_stats().misses == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        // Other blocks can be used while block 3 is being read
+        write_block(&*disk, 1, 7);
+        disk.sync_all().unwrap();
+        assert_eq!(disk.backend().inner.to_vec()[BSIZE as usize], 7);
+
+        // Both readers get the block from a single read
+        go.send(()).unwrap();
+        for reader in readers {
+            assert_eq!(reader.join().unwrap(), 0);
+        }
+        let stats = disk.cache_stats();
+        assert_eq!((stats.hits, stats.misses), (1, 2));
+    }
+}
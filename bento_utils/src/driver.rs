@@ -0,0 +1,715 @@
+use std::ffi::OsStr;
+use std::marker::PhantomData;
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc;
+
+use fuse::*;
+
+use time::Timespec;
+
+use super::{BentoFilesystem, DirEntryPlus, DirectoryEntry, FuseConnInfo, Lock, MountOptions};
+use super::{Opened, Statfs, Xattr};
+use super::session::{ioctl_is_mutating, open_is_mutating};
+
+// Size of the reply buffer used for readdir and readdirplus, one page like the kernel uses
+const READDIR_SIZE: usize = 4096;
+
+/// Calls the methods of a `BentoFilesystem` directly, without FUSE or the kernel.
+///
+/// Every method builds a `Request`, passes a reply to the matching `bento_*` method and waits for
+/// the filesystem to answer it, so tests can check the result of each operation without mounting
+/// the filesystem. Replies that the filesystem defers to another thread are waited for as well.
+/// A reply that is dropped without being answered returns `EIO`.
+///
+/// Requests are made as the uid, gid and pid of the current process unless the caller is changed
+/// with `set_caller`.
+///
+/// If the filesystem was initialized read-only, requests that change it fail with `EROFS` without
+/// reaching the filesystem, like they do on a mount.
+///
+/// ```ignore
+/// let mut fs = Xv6FileSystem::new();
+/// let image = std::fs::read("xv6.img")?;
+/// let disk: Disk = Disk::from_backend(Box::new(MemoryBackend::from_vec(image)), BSIZE as u64);
+/// fs.disk = Some(Arc::new(disk));
+/// fs.diskname = Some("xv6.img".to_string());
+/// let mut driver = TestDriver::new(fs);
+/// driver.init("xv6.img", "")?;
+/// let attr = driver.mkdir(1, "dir", 0o755)?;
+/// assert_eq!(driver.lookup(1, "dir")?.ino, attr.ino);
+/// ```
+pub struct TestDriver<'de, T, TransferIn = i32, TransferOut = i32> {
+    fs: T,
+    uid: u32,
+    gid: u32,
+    pid: u32,
+    unique: AtomicU64,
+    read_only: bool,
+    _transfer: TransferTypes<'de, TransferIn, TransferOut>,
+}
+
+// Marks the lifetime and state types the filesystem uses for upgrades.
+type TransferTypes<'de, In, Out> = PhantomData<(&'de (), fn(In) -> Out)>;
+
+// Create the callback for a reply and the channel its result arrives on.
+#[allow(clippy::type_complexity)]
+fn reply_channel<R: Send + 'static>() -> (
+    impl FnOnce(Result<R, i32>) + Send + 'static,
+    mpsc::Receiver<Result<R, i32>>,
+) {
+    let (tx, rx) = mpsc::channel();
+    let sender = move |res| {
+        let _ = tx.send(res);
+    };
+    (sender, rx)
+}
+
+fn wait<R>(rx: mpsc::Receiver<Result<R, i32>>) -> Result<R, i32> {
+    rx.recv().unwrap_or(Err(libc::EIO))
+}
+
+impl<'de, T, TransferIn, TransferOut> TestDriver<'de, T, TransferIn, TransferOut>
+where
+    T: BentoFilesystem<'de, TransferIn, TransferOut>,
+{
+    /// Create a driver for the filesystem `fs`.
+    ///
+    /// `fs` is not initialized yet, see `init`.
+    pub fn new(fs: T) -> Self {
+        TestDriver {
+            fs,
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+            pid: process::id(),
+            unique: AtomicU64::new(1),
+            read_only: false,
+            _transfer: PhantomData,
+        }
+    }
+
+    /// Make the following requests as the user `uid` and group `gid`.
+    ///
+    /// Permission checks that use `RequestExt` still see the groups and capabilities of the
+    /// current process.
+    pub fn set_caller(&mut self, uid: u32, gid: u32) {
+        self.uid = uid;
+        self.gid = gid;
+    }
+
+    /// Get the filesystem.
+    pub fn fs(&self) -> &T {
+        &self.fs
+    }
+
+    /// Get the filesystem.
+    pub fn fs_mut(&mut self) -> &mut T {
+        &mut self.fs
+    }
+
+    /// Take the filesystem back from the driver.
+    pub fn into_inner(self) -> T {
+        self.fs
+    }
+
+    // Fails with `EROFS` if the filesystem is read-only.
+    fn writable(&self) -> Result<(), i32> {
+        if self.read_only {
+            return Err(libc::EROFS);
+        }
+        Ok(())
+    }
+
+    fn request(&self) -> Request {
+        let unique = self.unique.fetch_add(1, Ordering::SeqCst);
+        Request::new(unique, self.uid, self.gid, self.pid)
+    }
+
+    /// Initialize the filesystem like a mount would.
+    ///
+    /// Returns the connection information filled in by `bento_init`.
+    ///
+    /// Arguments:
+    /// * `devname: &str` - Name of the backing device file.
+    /// * `opts: &str` - Mount options, e.g. `ro,ttl=5`.
+    pub fn init(&mut self, devname: &str, opts: &str) -> Result<FuseConnInfo, i32> {
+        let req = self.request();
+        let opts = MountOptions::parse(opts);
+        let mut fc_info = FuseConnInfo::new();
+        fc_info.read_only =
+            opts.get_bool("ro")?.unwrap_or(false) || opts.get_bool("read_only")?.unwrap_or(false);
+        self.fs
+            .bento_init(&req, OsStr::new(devname), &opts, &mut fc_info)?;
+        self.read_only = fc_info.read_only;
+        Ok(fc_info)
+    }
+
+    /// Clean up the filesystem like an unmount would.
+    pub fn destroy(&mut self) {
+        let req = self.request();
+        self.fs.bento_destroy(&req);
+    }
+
+    pub fn lookup(&self, parent: u64, name: &str) -> Result<FileAttr, i32> {
+        let (sender, rx) = reply_channel();
+        self.fs.bento_lookup(
+            &self.request(),
+            parent,
+            OsStr::new(name),
+            ReplyEntry::new(sender),
+        );
+        wait(rx).map(|(_, attr, _)| attr)
+    }
+
+    pub fn forget(&self, ino: u64, nlookup: u64) {
+        self.fs.bento_forget(&self.request(), ino, nlookup);
+    }
+
+    pub fn getattr(&self, ino: u64) -> Result<FileAttr, i32> {
+        let (sender, rx) = reply_channel();
+        self.fs
+            .bento_getattr(&self.request(), ino, ReplyAttr::new(sender));
+        wait(rx).map(|(_, attr)| attr)
+    }
+
+    /// Set the attributes that are not `None`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn setattr(
+        &self,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<Timespec>,
+        mtime: Option<Timespec>,
+        fh: Option<u64>,
+    ) -> Result<FileAttr, i32> {
+        self.writable()?;
+        let (sender, rx) = reply_channel();
+        self.fs.bento_setattr(
+            &self.request(),
+            ino,
+            mode,
+            uid,
+            gid,
+            size,
+            atime,
+            mtime,
+            fh,
+            None,
+            None,
+            None,
+            None,
+            ReplyAttr::new(sender),
+        );
+        wait(rx).map(|(_, attr)| attr)
+    }
+
+    pub fn readlink(&self, ino: u64) -> Result<Vec<u8>, i32> {
+        let (sender, rx) = reply_channel();
+        self.fs
+            .bento_readlink(&self.request(), ino, ReplyData::new(sender));
+        wait(rx)
+    }
+
+    pub fn mknod(&self, parent: u64, name: &str, mode: u32, rdev: u32) -> Result<FileAttr, i32> {
+        self.writable()?;
+        let (sender, rx) = reply_channel();
+        self.fs.bento_mknod(
+            &self.request(),
+            parent,
+            OsStr::new(name),
+            mode,
+            rdev,
+            ReplyEntry::new(sender),
+        );
+        wait(rx).map(|(_, attr, _)| attr)
+    }
+
+    pub fn mkdir(&self, parent: u64, name: &str, mode: u32) -> Result<FileAttr, i32> {
+        self.writable()?;
+        let (sender, rx) = reply_channel();
+        self.fs.bento_mkdir(
+            &self.request(),
+            parent,
+            OsStr::new(name),
+            mode,
+            ReplyEntry::new(sender),
+        );
+        wait(rx).map(|(_, attr, _)| attr)
+    }
+
+    pub fn unlink(&self, parent: u64, name: &str) -> Result<(), i32> {
+        self.writable()?;
+        let (sender, rx) = reply_channel();
+        self.fs.bento_unlink(
+            &self.request(),
+            parent,
+            OsStr::new(name),
+            ReplyEmpty::new(sender),
+        );
+        wait(rx)
+    }
+
+    pub fn rmdir(&self, parent: u64, name: &str) -> Result<(), i32> {
+        self.writable()?;
+        let (sender, rx) = reply_channel();
+        self.fs.bento_rmdir(
+            &self.request(),
+            parent,
+            OsStr::new(name),
+            ReplyEmpty::new(sender),
+        );
+        wait(rx)
+    }
+
+    pub fn symlink(&self, parent: u64, name: &str, link: &str) -> Result<FileAttr, i32> {
+        self.writable()?;
+        let (sender, rx) = reply_channel();
+        self.fs.bento_symlink(
+            &self.request(),
+            parent,
+            OsStr::new(name),
+            Path::new(link),
+            ReplyEntry::new(sender),
+        );
+        wait(rx).map(|(_, attr, _)| attr)
+    }
+
+    pub fn rename(
+        &self,
+        parent: u64,
+        name: &str,
+        newparent: u64,
+        newname: &str,
+        flags: u32,
+    ) -> Result<(), i32> {
+        self.writable()?;
+        let (sender, rx) = reply_channel();
+        self.fs.bento_rename(
+            &self.request(),
+            parent,
+            OsStr::new(name),
+            newparent,
+            OsStr::new(newname),
+            flags,
+            ReplyEmpty::new(sender),
+        );
+        wait(rx)
+    }
+
+    pub fn link(&self, ino: u64, newparent: u64, newname: &str) -> Result<FileAttr, i32> {
+        self.writable()?;
+        let (sender, rx) = reply_channel();
+        self.fs.bento_link(
+            &self.request(),
+            ino,
+            newparent,
+            OsStr::new(newname),
+            ReplyEntry::new(sender),
+        );
+        wait(rx).map(|(_, attr, _)| attr)
+    }
+
+    pub fn open(&self, ino: u64, flags: u32) -> Result<Opened, i32> {
+        if open_is_mutating(flags) {
+            self.writable()?;
+        }
+        let (sender, rx) = reply_channel();
+        self.fs
+            .bento_open(&self.request(), ino, flags, ReplyOpen::new(sender));
+        wait(rx).map(|(fh, flags)| Opened {
+            fh,
+            flags,
+        })
+    }
+
+    pub fn read(&self, ino: u64, fh: u64, offset: i64, size: u32) -> Result<Vec<u8>, i32> {
+        let (sender, rx) = reply_channel();
+        self.fs.bento_read(
+            &self.request(),
+            ino,
+            fh,
+            offset,
+            size,
+            ReplyData::new(sender),
+        );
+        wait(rx)
+    }
+
+    pub fn write(
+        &self,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        flags: u32,
+    ) -> Result<u32, i32> {
+        self.writable()?;
+        let (sender, rx) = reply_channel();
+        self.fs.bento_write(
+            &self.request(),
+            ino,
+            fh,
+            offset,
+            data,
+            flags,
+            ReplyWrite::new(sender),
+        );
+        wait(rx)
+    }
+
+    pub fn flush(&self, ino: u64, fh: u64, lock_owner: u64) -> Result<(), i32> {
+        let (sender, rx) = reply_channel();
+        self.fs.bento_flush(
+            &self.request(),
+            ino,
+            fh,
+            lock_owner,
+            ReplyEmpty::new(sender),
+        );
+        wait(rx)
+    }
+
+    pub fn release(
+        &self,
+        ino: u64,
+        fh: u64,
+        flags: u32,
+        lock_owner: u64,
+        flush: bool,
+    ) -> Result<(), i32> {
+        let (sender, rx) = reply_channel();
+        self.fs.bento_release(
+            &self.request(),
+            ino,
+            fh,
+            flags,
+            lock_owner,
+            flush,
+            ReplyEmpty::new(sender),
+        );
+        wait(rx)
+    }
+
+    pub fn fsync(&self, ino: u64, fh: u64, datasync: bool) -> Result<(), i32> {
+        let (sender, rx) = reply_channel();
+        self.fs
+            .bento_fsync(&self.request(), ino, fh, datasync, ReplyEmpty::new(sender));
+        wait(rx)
+    }
+
+    pub fn opendir(&self, ino: u64, flags: u32) -> Result<Opened, i32> {
+        let (sender, rx) = reply_channel();
+        self.fs
+            .bento_opendir(&self.request(), ino, flags, ReplyOpen::new(sender));
+        wait(rx).map(|(fh, flags)| Opened {
+            fh,
+            flags,
+        })
+    }
+
+    /// Read the entries of a directory that fit in one page, starting after `offset`.
+    pub fn readdir(&self, ino: u64, fh: u64, offset: i64) -> Result<Vec<DirectoryEntry>, i32> {
+        let (sender, rx) = reply_channel();
+        self.fs.bento_readdir(
+            &self.request(),
+            ino,
+            fh,
+            offset,
+            ReplyDirectory::new(READDIR_SIZE, sender),
+        );
+        wait(rx)
+    }
+
+    /// Read all entries of a directory, using as many readdir requests as needed.
+    pub fn readdir_all(&self, ino: u64, fh: u64) -> Result<Vec<DirectoryEntry>, i32> {
+        let mut entries: Vec<DirectoryEntry> = Vec::new();
+        let mut offset = 0;
+        loop {
+            let mut next = self.readdir(ino, fh, offset)?;
+            match next.last() {
+                Some(entry) => offset = entry.offset,
+                None => return Ok(entries),
+            }
+            entries.append(&mut next);
+        }
+    }
+
+    /// Read the entries of a directory with their attributes that fit in one page, starting after
+    /// `offset`.
+    pub fn readdirplus(&self, ino: u64, fh: u64, offset: i64) -> Result<Vec<DirEntryPlus>, i32> {
+        let (sender, rx) = reply_channel();
+        self.fs.bento_readdirplus(
+            &self.request(),
+            ino,
+            fh,
+            offset,
+            ReplyDirectoryPlus::new(READDIR_SIZE, sender),
+        );
+        wait(rx)
+    }
+
+    pub fn releasedir(&self, ino: u64, fh: u64, flags: u32) -> Result<(), i32> {
+        let (sender, rx) = reply_channel();
+        self.fs
+            .bento_releasedir(&self.request(), ino, fh, flags, ReplyEmpty::new(sender));
+        wait(rx)
+    }
+
+    pub fn fsyncdir(&self, ino: u64, fh: u64, datasync: bool) -> Result<(), i32> {
+        let (sender, rx) = reply_channel();
+        self.fs
+            .bento_fsyncdir(&self.request(), ino, fh, datasync, ReplyEmpty::new(sender));
+        wait(rx)
+    }
+
+    pub fn statfs(&self, ino: u64) -> Result<Statfs, i32> {
+        let (sender, rx) = reply_channel();
+        self.fs
+            .bento_statfs(&self.request(), ino, ReplyStatfs::new(sender));
+        wait(rx)
+    }
+
+    pub fn setxattr(
+        &self,
+        ino: u64,
+        name: &str,
+        value: &[u8],
+        flags: u32,
+        position: u32,
+    ) -> Result<(), i32> {
+        self.writable()?;
+        let (sender, rx) = reply_channel();
+        self.fs.bento_setxattr(
+            &self.request(),
+            ino,
+            OsStr::new(name),
+            value,
+            flags,
+            position,
+            ReplyEmpty::new(sender),
+        );
+        wait(rx)
+    }
+
+    pub fn getxattr(&self, ino: u64, name: &str, size: u32) -> Result<Xattr, i32> {
+        let (sender, rx) = reply_channel();
+        self.fs.bento_getxattr(
+            &self.request(),
+            ino,
+            OsStr::new(name),
+            size,
+            ReplyXattr::new(sender),
+        );
+        wait(rx)
+    }
+
+    pub fn listxattr(&self, ino: u64, size: u32) -> Result<Xattr, i32> {
+        let (sender, rx) = reply_channel();
+        self.fs
+            .bento_listxattr(&self.request(), ino, size, ReplyXattr::new(sender));
+        wait(rx)
+    }
+
+    pub fn removexattr(&self, ino: u64, name: &str) -> Result<(), i32> {
+        self.writable()?;
+        let (sender, rx) = reply_channel();
+        self.fs.bento_removexattr(
+            &self.request(),
+            ino,
+            OsStr::new(name),
+            ReplyEmpty::new(sender),
+        );
+        wait(rx)
+    }
+
+    pub fn access(&self, ino: u64, mask: u32) -> Result<(), i32> {
+        let (sender, rx) = reply_channel();
+        self.fs
+            .bento_access(&self.request(), ino, mask, ReplyEmpty::new(sender));
+        wait(rx)
+    }
+
+    pub fn create(
+        &self,
+        parent: u64,
+        name: &str,
+        mode: u32,
+        flags: u32,
+    ) -> Result<(FileAttr, Opened), i32> {
+        self.writable()?;
+        let (sender, rx) = reply_channel();
+        self.fs.bento_create(
+            &self.request(),
+            parent,
+            OsStr::new(name),
+            mode,
+            flags,
+            ReplyCreate::new(sender),
+        );
+        wait(rx).map(|(_, attr, _, fh, flags)| {
+            (
+                attr,
+                Opened {
+                    fh,
+                    flags,
+                },
+            )
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn getlk(
+        &self,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: u32,
+        pid: u32,
+    ) -> Result<Lock, i32> {
+        let (sender, rx) = reply_channel();
+        self.fs.bento_getlk(
+            &self.request(),
+            ino,
+            fh,
+            lock_owner,
+            start,
+            end,
+            typ,
+            pid,
+            ReplyLock::new(sender),
+        );
+        wait(rx)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn setlk(
+        &self,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: u32,
+        pid: u32,
+        sleep: bool,
+    ) -> Result<(), i32> {
+        let (sender, rx) = reply_channel();
+        self.fs.bento_setlk(
+            &self.request(),
+            ino,
+            fh,
+            lock_owner,
+            start,
+            end,
+            typ,
+            pid,
+            sleep,
+            ReplyEmpty::new(sender),
+        );
+        wait(rx)
+    }
+
+    pub fn bmap(&self, ino: u64, blocksize: u32, idx: u64) -> Result<u64, i32> {
+        let (sender, rx) = reply_channel();
+        self.fs
+            .bento_bmap(&self.request(), ino, blocksize, idx, ReplyBmap::new(sender));
+        wait(rx)
+    }
+
+    pub fn fallocate(
+        &self,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        length: i64,
+        mode: u32,
+    ) -> Result<(), i32> {
+        self.writable()?;
+        let (sender, rx) = reply_channel();
+        self.fs.bento_fallocate(
+            &self.request(),
+            ino,
+            fh,
+            offset,
+            length,
+            mode,
+            ReplyEmpty::new(sender),
+        );
+        wait(rx)
+    }
+
+    pub fn lseek(&self, ino: u64, fh: u64, offset: i64, whence: u32) -> Result<i64, i32> {
+        let (sender, rx) = reply_channel();
+        self.fs.bento_lseek(
+            &self.request(),
+            ino,
+            fh,
+            offset,
+            whence,
+            ReplyLseek::new(sender),
+        );
+        wait(rx)
+    }
+
+    /// Perform an ioctl. Returns the ioctl return value and the data copied back to the caller.
+    pub fn ioctl(
+        &self,
+        ino: u64,
+        fh: u64,
+        flags: u32,
+        cmd: u32,
+        in_data: &[u8],
+        out_size: u32,
+    ) -> Result<(i32, Vec<u8>), i32> {
+        if ioctl_is_mutating(cmd) {
+            self.writable()?;
+        }
+        let (sender, rx) = reply_channel();
+        self.fs.bento_ioctl(
+            &self.request(),
+            ino,
+            fh,
+            flags,
+            cmd,
+            in_data,
+            out_size,
+            ReplyIoctl::new(sender),
+        );
+        wait(rx)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn copy_file_range(
+        &self,
+        ino_in: u64,
+        fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        flags: u32,
+    ) -> Result<u32, i32> {
+        self.writable()?;
+        let (sender, rx) = reply_channel();
+        self.fs.bento_copy_file_range(
+            &self.request(),
+            ino_in,
+            fh_in,
+            offset_in,
+            ino_out,
+            fh_out,
+            offset_out,
+            len,
+            flags,
+            ReplyWrite::new(sender),
+        );
+        wait(rx)
+    }
+}
@@ -0,0 +1,124 @@
+use libc;
+
+// Define `Errno` with one variant per name, each with the value of the libc constant of the same
+// name.
+macro_rules! def_errno {
+    ($($name:ident),*) => {
+        /// Typed error codes
+        ///
+        /// Errors returned by `BentoFilesystemResult` methods. Each named variant has the value of
+        /// the corresponding positive errno from /include/uapi/asm-generic/errno-base.h and
+        /// errno.h, which is what the `error` methods on replies expect. Other errno values are
+        /// kept in `Other`.
+        #[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+        pub enum Errno {
+            $($name,)*
+            /// A positive errno value without a named variant.
+            Other(i32),
+        }
+
+        impl Errno {
+            /// Get the errno value to pass to a reply's `error` method.
+            pub fn code(self) -> i32 {
+                match self {
+                    $(Errno::$name => libc::$name,)*
+                    Errno::Other(x) => x,
+                }
+            }
+
+            /// Convert an errno value into an `Errno`.
+            ///
+            /// Negative values, as returned by the kernel, are accepted as well. Values without a
+            /// named variant are kept in `Other`, except for 0 and `i32::MIN`, which are not
+            /// errors and are converted to `EIO`.
+            pub fn from_i32(err: i32) -> Errno {
+                match err.wrapping_abs() {
+                    $(libc::$name => Errno::$name,)*
+                    x if x > 0 => Errno::Other(x),
+                    _ => Errno::EIO,
+                }
+            }
+        }
+    }
+}
+
+def_errno!(
+    EPERM,
+    ENOENT,
+    EINTR,
+    EIO,
+    ENXIO,
+    E2BIG,
+    EBADF,
+    EAGAIN,
+    ENOMEM,
+    EACCES,
+    EFAULT,
+    EBUSY,
+    EEXIST,
+    EXDEV,
+    ENODEV,
+    ENOTDIR,
+    EISDIR,
+    EINVAL,
+    ENFILE,
+    EMFILE,
+    ENOTTY,
+    ETXTBSY,
+    EFBIG,
+    ENOSPC,
+    ESPIPE,
+    EROFS,
+    EMLINK,
+    ERANGE,
+    EDEADLK,
+    ENAMETOOLONG,
+    ENOLCK,
+    ENOSYS,
+    ENOTEMPTY,
+    ELOOP,
+    ENODATA,
+    EOVERFLOW,
+    EOPNOTSUPP,
+    ENOTCONN,
+    ETIMEDOUT,
+    ESTALE,
+    EDQUOT,
+    ECANCELED
+);
+
+impl From<Errno> for i32 {
+    fn from(err: Errno) -> i32 {
+        err.code()
+    }
+}
+
+impl From<i32> for Errno {
+    fn from(err: i32) -> Errno {
+        Errno::from_i32(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        for code in 1..200 {
+            assert_eq!(Errno::from_i32(code).code(), code);
+            assert_eq!(Errno::from_i32(-code).code(), code);
+        }
+        assert_eq!(Errno::from_i32(libc::ENOENT), Errno::ENOENT);
+        assert_eq!(Errno::from_i32(-libc::EROFS), Errno::EROFS);
+    }
+
+    #[test]
+    fn unknown_codes() {
+        assert_eq!(Errno::from_i32(4000), Errno::Other(4000));
+        assert_eq!(Errno::from_i32(-4000), Errno::Other(4000));
+        assert_eq!(Errno::from_i32(0), Errno::EIO);
+        assert_eq!(Errno::from_i32(i32::MIN), Errno::EIO);
+        assert_eq!(Errno::from_i32(i32::MAX).code(), i32::MAX);
+    }
+}
@@ -0,0 +1,14 @@
+// Userspace version of the `fuse` module of the kernel version.
+//
+// Filesystems import `fuse` as `bento_utils::fuse` in the userspace version, so the same
+// `use fuse::*;` gives them the Bento `Request` and reply types in both versions. Attributes,
+// constants and mounting come from the `fuse` crate.
+
+pub use fuse_sys::{FileAttr, FileType};
+pub use fuse_sys::consts;
+pub use fuse_sys::{mount, spawn_mount};
+
+pub use super::request::Request;
+pub use super::reply::{ReplyEmpty, ReplyData, ReplyEntry, ReplyAttr, ReplyOpen};
+pub use super::reply::{ReplyWrite, ReplyStatfs, ReplyCreate, ReplyLock, ReplyBmap, ReplyDirectory};
+pub use super::reply::{ReplyXattr, ReplyLseek, ReplyIoctl, ReplyDirectoryPlus};
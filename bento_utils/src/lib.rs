@@ -1,12 +1,40 @@
 #![feature(get_mut_unchecked)]
 
-extern crate fuse;
+#[doc(hidden)]
+pub extern crate fuse as fuse_sys;
 extern crate libc;
 extern crate serde;
 extern crate time;
 
+mod access;
+mod backend;
+mod crash;
 mod disk;
+mod driver;
+mod errno;
+pub mod fuse;
+mod lookup;
+mod notify;
+mod options;
+mod reply;
+mod request;
+mod result;
+mod session;
+mod stats;
+pub use self::access::*;
+pub use self::backend::*;
+pub use self::crash::*;
 pub use self::disk::*;
+pub use self::driver::*;
+pub use self::errno::*;
+pub use self::lookup::*;
+pub use self::notify::*;
+pub use self::options::*;
+pub use self::reply::*;
+pub use self::request::*;
+pub use self::result::*;
+pub use self::session::*;
+pub use self::stats::*;
 
 use libc::ENOSYS;
 
@@ -15,8 +43,6 @@ use std::path::Path;
 
 use time::Timespec;
 
-use fuse::*;
-
 use serde::{Serialize, Deserialize};
 
 pub const BENTO_KERNEL_VERSION: u32 = 1;
@@ -42,6 +68,21 @@ pub mod consts {
     pub const FUSE_PARALLEL_DIROPS: u32     = 1 << 18;
     pub const FUSE_HANDLE_KILLPRIV: u32     = 1 << 19;
     pub const FUSE_POSIX_ACL: u32           = 1 << 20;
+
+    // Ioctl flags
+    pub const FUSE_IOCTL_COMPAT: u32        = 1 << 0;
+    pub const FUSE_IOCTL_UNRESTRICTED: u32  = 1 << 1;
+    pub const FUSE_IOCTL_RETRY: u32         = 1 << 2;
+    pub const FUSE_IOCTL_32BIT: u32         = 1 << 3;
+    pub const FUSE_IOCTL_DIR: u32           = 1 << 4;
+
+    // Capabilities used for permission checks, from /include/uapi/linux/capability.h
+    pub const CAP_CHOWN: i32                = 0;
+    pub const CAP_DAC_OVERRIDE: i32         = 1;
+    pub const CAP_DAC_READ_SEARCH: i32      = 2;
+    pub const CAP_FOWNER: i32               = 3;
+    pub const CAP_FSETID: i32               = 4;
+    pub const CAP_SYS_ADMIN: i32            = 21;
 }
 
 #[derive(Default)]
@@ -57,6 +98,15 @@ pub struct FuseConnInfo {
     pub max_background: u32,
     pub congestion_threshold: u32,
     pub time_gran: u32,
+    /// Set from the `ro` or `read_only` mount option before `bento_init` is called. The filesystem
+    /// may also set it in `bento_init`. `Session` and `TestDriver` then reject requests that
+    /// change the filesystem with `EROFS`. With `impl_filesystem`, such requests are only rejected
+    /// by the kernel, for `ro`, since it is passed on to the FUSE mount.
+    pub read_only: bool,
+    /// Sends cache invalidation notifications for this mount. Set by `Session` before
+    /// `bento_init` is called. It is `None` with `impl_filesystem`, where the `/dev/fuse` file
+    /// descriptor isn't known, and with `TestDriver`.
+    pub notifier: Option<Notifier>,
     reserved: [u32; 22],
 }
 
@@ -73,6 +123,8 @@ impl FuseConnInfo {
             max_background: 0,
             congestion_threshold: 0,
             time_gran: 0,
+            read_only: false,
+            notifier: None,
             reserved: [0; 22]
         }
     }
@@ -95,17 +147,20 @@ pub trait BentoFilesystem<'de, TransferIn=i32, TransferOut=i32> {
     /// Initialize the file system and fill in initialization flags.
     ///
     /// Possible initialization flags are defined /include/uapi/linux/fuse.h.
-    /// No support is provided for readdirplus and async DIO.
+    /// Readdirplus is supported through `bento_readdirplus` if `FUSE_DO_READDIRPLUS` is set, but
+    /// only in the kernel version. No support is provided for async DIO.
     ///
     /// Arguments:
     /// * `req: &Request` - Request data structure.
     /// * `devname: &OsStr` - Name of the backing device file.
+    /// * `opts: &MountOptions` - Mount options passed with `-o`.
     /// * `fc_info: &mut FuseConnInfo` - Connection information used to pass initialization
     /// arguments to Bento.
     fn bento_init(
         &mut self,
         _req: &Request,
         _devname: &OsStr,
+        _opts: &MountOptions,
         _fc_info: &mut FuseConnInfo,
     ) -> Result<(), i32> {
         return Err(ENOSYS);
@@ -154,6 +209,9 @@ pub trait BentoFilesystem<'de, TransferIn=i32, TransferOut=i32> {
     /// On unmount the lookup count for all inodes implicitly drops to zero. It is not guaranteed
     /// that the file system will receive corresponding forget messages for the affected inodes.
     ///
+    /// Batched forgets are delivered as one call per inode. `LookupCountTable` can be used to keep
+    /// track of the lookup counts.
+    ///
     /// Arguments:
     /// * `req: &Request` - Request data structure.
     /// * `ino: u64` - Filesystem-provided inode number of the inode to forget.
@@ -656,6 +714,37 @@ pub trait BentoFilesystem<'de, TransferIn=i32, TransferOut=i32> {
         return reply.error(ENOSYS);
     }
 
+    /// Read directory with attributes
+    ///
+    /// Like `bento_readdir`, but each entry also carries the attributes that a lookup of the entry
+    /// would return, so the kernel does not need a separate lookup for every entry. Only sent if
+    /// `FUSE_DO_READDIRPLUS` was set in `bento_init`. Entries are added with
+    /// `ReplyDirectoryPlus::add`. Send an empty buffer on end of stream.
+    ///
+    /// Unlike readdir, returning an entry increments its lookup count, except for the '.' and
+    /// '..' entries, exactly as if a lookup had been done for it.
+    ///
+    /// The same rules for `offset` apply as for `bento_readdir`.
+    ///
+    /// Arguments:
+    /// * `req: &Request` - Request data structure.
+    /// * `ino: u64` - Filesystem-provided inode number.
+    /// * `fh: u64` - Filesystem-provided file handle.
+    /// * `offset: i64` - Offset into the directory. From the offset values used in previous
+    ///   readdirplus requests.
+    /// * `reply: ReplyDirectoryPlus` - Output data structure for the read directory information or
+    ///   error value.
+    fn bento_readdirplus(
+        &self,
+        _req: &Request,
+        _ino: u64,
+        _fh: u64,
+        _offset: i64,
+        reply: ReplyDirectoryPlus,
+    ) {
+        reply.error(ENOSYS)
+    }
+
     /// Release an open directory
     ///
     /// For every opendir call there will be exactly one releasedir call (unless the filesystem is
@@ -833,6 +922,8 @@ pub trait BentoFilesystem<'de, TransferIn=i32, TransferOut=i32> {
     /// success, i.e. this and all future `access()` requests will succeed without being sent to
     /// the filesystem.
     ///
+    /// `check_access` implements the POSIX permission check for a file's attributes.
+    ///
     /// Arguments:
     /// * `req: &Request` - Request data structure.
     /// * `ino: u64` - Filesystem-provided inode number.
@@ -968,52 +1059,214 @@ pub trait BentoFilesystem<'de, TransferIn=i32, TransferOut=i32> {
         return reply.error(ENOSYS);
     }
 
+    /// Allocate requested space
+    ///
+    /// If this function returns success then subsequent writes to the specified range shall not
+    /// fail due to the lack of free space on the file system storage media.
+    ///
+    /// `mode` may contain `FALLOC_FL_KEEP_SIZE`, in which case the file size is not changed even
+    /// if the range extends past the end of the file, and `FALLOC_FL_PUNCH_HOLE`, in which case
+    /// the range is deallocated instead. `FALLOC_FL_PUNCH_HOLE` is always combined with
+    /// `FALLOC_FL_KEEP_SIZE`.
+    ///
+    /// If this request is answered with an error code of `ENOSYS`, this is treated as a permanent
+    /// failure with error code `EOPNOTSUPP`, i.e. all future `fallocate()` requests will fail with
+    /// `EOPNOTSUPP` without being sent to the filesystem.
+    ///
+    /// Arguments:
+    /// * `req: &Request` - Request data structure.
+    /// * `ino: u64` - Filesystem-provided inode number.
+    /// * `fh: u64` - Filesystem-provided file handle.
+    /// * `offset: i64` - Offset into the file where the range starts.
+    /// * `length: i64` - Length of the range.
+    /// * `mode: u32` - Fallocate mode flags.
+    /// * `reply: ReplyEmpty` - Output data structure for a possible error value.
+    fn bento_fallocate(
+        &self,
+        _req: &Request,
+        _ino: u64,
+        _fh: u64,
+        _offset: i64,
+        _length: i64,
+        _mode: u32,
+        reply: ReplyEmpty,
+    ) {
+        reply.error(ENOSYS)
+    }
+
+    /// Find next data or hole after the specified offset
+    ///
+    /// This is called for `lseek()` with `SEEK_DATA` or `SEEK_HOLE`. `SEEK_SET`, `SEEK_CUR` and
+    /// `SEEK_END` are handled by Bento without consulting the filesystem. If there is no data
+    /// (`SEEK_DATA`) at or after `offset`, or `offset` is past the end of the file, the filesystem
+    /// should return `ENXIO`. The end of the file counts as an implicit hole.
+    ///
+    /// If this request is answered with an error code of `ENOSYS`, this is treated as a permanent
+    /// failure, i.e. all future `lseek()` requests will fail with the same error code without being
+    /// sent to the filesystem.
+    ///
+    /// Arguments:
+    /// * `req: &Request` - Request data structure.
+    /// * `ino: u64` - Filesystem-provided inode number.
+    /// * `fh: u64` - Filesystem-provided file handle.
+    /// * `offset: i64` - Offset into the file to start searching from.
+    /// * `whence: u32` - Either `SEEK_DATA` or `SEEK_HOLE`.
+    /// * `reply: ReplyLseek` - Output data structure for the resulting offset or error value.
+    fn bento_lseek(
+        &self,
+        _req: &Request,
+        _ino: u64,
+        _fh: u64,
+        _offset: i64,
+        _whence: u32,
+        reply: ReplyLseek,
+    ) {
+        reply.error(ENOSYS)
+    }
+
+    /// Perform an ioctl on a file
+    ///
+    /// Only restricted ioctls are supported: the size of the input and output data is encoded in
+    /// `cmd` (see `_IOR`/`_IOW`), so the kernel copies in `in_data` and copies back at most
+    /// `out_size` bytes of the reply. Unrestricted ioctls, which would require the filesystem to
+    /// ask for specific iovecs, are rejected with `ENOSYS` before reaching the filesystem.
+    ///
+    /// `FUSE_IOCTL_DIR` is set in `flags` if the ioctl was issued on a directory. Filesystems
+    /// should reply with `ENOTTY` for commands they do not recognize.
+    ///
+    /// Arguments:
+    /// * `req: &Request` - Request data structure.
+    /// * `ino: u64` - Filesystem-provided inode number.
+    /// * `fh: u64` - Filesystem-provided file handle.
+    /// * `flags: u32` - Ioctl flags (`FUSE_IOCTL_*`).
+    /// * `cmd: u32` - Ioctl command.
+    /// * `in_data: &[u8]` - Data copied in from the caller.
+    /// * `out_size: u32` - Maximum size of the data that will be copied back to the caller.
+    /// * `reply: ReplyIoctl` - Output data structure for the result and data or error value.
+    fn bento_ioctl(
+        &self,
+        _req: &Request,
+        _ino: u64,
+        _fh: u64,
+        _flags: u32,
+        _cmd: u32,
+        _in_data: &[u8],
+        _out_size: u32,
+        reply: ReplyIoctl,
+    ) {
+        reply.error(ENOSYS)
+    }
+
+    /// Copy a range of data from one file to another
+    ///
+    /// Lets the filesystem copy data without it passing through the page cache or the caller. If
+    /// this request is answered with an error code of `ENOSYS`, this is treated as a permanent
+    /// failure, and the kernel falls back to copying with reads and writes.
+    ///
+    /// The reply is the number of bytes copied, which may be less than `len`.
+    ///
+    /// Arguments:
+    /// * `req: &Request` - Request data structure.
+    /// * `ino_in: u64` - Filesystem-provided inode number of the source file.
+    /// * `fh_in: u64` - Filesystem-provided file handle of the source file.
+    /// * `offset_in: i64` - Offset into the source file to copy from.
+    /// * `ino_out: u64` - Filesystem-provided inode number of the destination file.
+    /// * `fh_out: u64` - Filesystem-provided file handle of the destination file.
+    /// * `offset_out: i64` - Offset into the destination file to copy to.
+    /// * `len: u64` - Number of bytes to copy.
+    /// * `flags: u32` - Flags passed to `copy_file_range()`. Currently always 0.
+    /// * `reply: ReplyWrite` - Output data structure for the number of bytes copied or error value.
+    fn bento_copy_file_range(
+        &self,
+        _req: &Request,
+        _ino_in: u64,
+        _fh_in: u64,
+        _offset_in: i64,
+        _ino_out: u64,
+        _fh_out: u64,
+        _offset_out: i64,
+        _len: u64,
+        _flags: u32,
+        reply: ReplyWrite,
+    ) {
+        reply.error(ENOSYS)
+    }
+
     /// Unused in user version
     fn bento_update_prepare(&mut self) -> Option<TransferOut> {
         None
     }
 
     /// Unused in user version
-    fn bento_update_transfer(&mut self, Option<TransferIn>) { }
+    fn bento_update_transfer(&mut self, _state: Option<TransferIn>) -> Result<(), i32> {
+        Ok(())
+    }
+
+    /// Unused in user version
+    fn bento_update_version(&self) -> u32 {
+        0
+    }
+
+    /// Unused in user version
+    fn bento_update_migrate(&mut self, _version: u32, _payload: &[u8]) -> Result<TransferIn, i32> {
+        Err(libc::EINVAL)
+    }
 }
 
 #[macro_export]
 macro_rules! impl_filesystem {
     ($name:ty) => {
-        impl Filesystem for $name {
+        impl $crate::fuse_sys::Filesystem for $name {
             fn init(
                 &mut self,
-                req: &Request,
+                req: &$crate::fuse_sys::Request,
             ) -> Result<(), libc::c_int> {
+                let timer = $crate::OpTimer::start("init");
                 let mut fc_info = FuseConnInfo::new();
-                self.bento_init(req, OsStr::new(""), &mut fc_info)
+                let opts = $crate::MountOptions::from_args();
+                fc_info.read_only = opts.get_bool("ro")?.unwrap_or(false)
+                    || opts.get_bool("read_only")?.unwrap_or(false);
+                $crate::set_dump_stats_on_destroy(opts.get_bool("stats")?.unwrap_or(false));
+                let ret = self.bento_init(&$crate::Request::from(req), OsStr::new(""), &opts, &mut fc_info);
+                for opt in opts.unknown() {
+                    println!("{}: unknown mount option {}", self.get_name().trim_end_matches('\0'), opt);
+                }
+                timer.finish(ret.err().map(|x| -x).unwrap_or(0));
+                ret
             }
         
-            fn destroy(&mut self, req: &Request) {
-                self.bento_destroy(req);
+            fn destroy(&mut self, req: &$crate::fuse_sys::Request) {
+                {
+                    let _timer = $crate::OpTimer::start("destroy");
+                    self.bento_destroy(&$crate::Request::from(req));
+                }
+                $crate::dump_stats_on_destroy();
             }
         
             fn lookup(
                 &mut self,
-                req: &Request,
+                req: &$crate::fuse_sys::Request,
                 parent: u64,
                 name: &OsStr,
-                reply: ReplyEntry,
+                reply: $crate::fuse_sys::ReplyEntry,
             ) {
-                self.bento_lookup(req, parent, name, reply)
+                let timer = $crate::OpTimer::start("lookup");
+                self.bento_lookup(&$crate::Request::from(req), parent, name, $crate::Timed::timed(reply.into(), timer))
             }
         
-            fn forget(&mut self, req: &Request, ino: u64, nlookup: u64) {
-                self.bento_forget(req, ino, nlookup)
+            fn forget(&mut self, req: &$crate::fuse_sys::Request, ino: u64, nlookup: u64) {
+                let _timer = $crate::OpTimer::start("forget");
+                self.bento_forget(&$crate::Request::from(req), ino, nlookup)
             }
         
-            fn getattr(&mut self, req: &Request, ino: u64, reply: ReplyAttr) {
-                self.bento_getattr(req, ino, reply)
+            fn getattr(&mut self, req: &$crate::fuse_sys::Request, ino: u64, reply: $crate::fuse_sys::ReplyAttr) {
+                let timer = $crate::OpTimer::start("getattr");
+                self.bento_getattr(&$crate::Request::from(req), ino, $crate::Timed::timed(reply.into(), timer))
             }
         
             fn setattr(
                 &mut self,
-                req: &Request,
+                req: &$crate::fuse_sys::Request,
                 ino: u64,
                 mode: Option<u32>,
                 uid: Option<u32>,
@@ -1026,278 +1279,304 @@ macro_rules! impl_filesystem {
                 chgtime: Option<Timespec>,
                 bkuptime: Option<Timespec>,
                 flags: Option<u32>,
-                reply: ReplyAttr,
+                reply: $crate::fuse_sys::ReplyAttr,
             ) {
-                self.bento_setattr(req, ino, mode, uid, gid, size, atime, mtime, fh, crtime, chgtime, bkuptime, flags, reply)
+                let timer = $crate::OpTimer::start("setattr");
+                self.bento_setattr(&$crate::Request::from(req), ino, mode, uid, gid, size, atime, mtime, fh, crtime, chgtime, bkuptime, flags, $crate::Timed::timed(reply.into(), timer))
             }
         
-            fn readlink(&mut self, req: &Request, ino: u64, reply: ReplyData) {
-                self.bento_readlink(req, ino, reply)
+            fn readlink(&mut self, req: &$crate::fuse_sys::Request, ino: u64, reply: $crate::fuse_sys::ReplyData) {
+                let timer = $crate::OpTimer::start("readlink");
+                self.bento_readlink(&$crate::Request::from(req), ino, $crate::Timed::timed(reply.into(), timer))
             }
         
             fn mknod(
                 &mut self,
-                req: &Request,
+                req: &$crate::fuse_sys::Request,
                 parent: u64,
                 name: &OsStr,
                 mode: u32,
                 rdev: u32,
-                reply: ReplyEntry,
+                reply: $crate::fuse_sys::ReplyEntry,
             ) {
-                self.bento_mknod(req, parent, name, mode, rdev, reply)
+                let timer = $crate::OpTimer::start("mknod");
+                self.bento_mknod(&$crate::Request::from(req), parent, name, mode, rdev, $crate::Timed::timed(reply.into(), timer))
             }
         
             fn mkdir(
                 &mut self,
-                req: &Request,
+                req: &$crate::fuse_sys::Request,
                 parent: u64,
                 name: &OsStr,
                 mode: u32,
-                reply: ReplyEntry,
+                reply: $crate::fuse_sys::ReplyEntry,
             ) {
-                self.bento_mkdir(req, parent, name, mode, reply)
+                let timer = $crate::OpTimer::start("mkdir");
+                self.bento_mkdir(&$crate::Request::from(req), parent, name, mode, $crate::Timed::timed(reply.into(), timer))
             }
         
             fn unlink(
                 &mut self,
-                req: &Request,
+                req: &$crate::fuse_sys::Request,
                 parent: u64,
                 name: &OsStr,
-                reply: ReplyEmpty,
+                reply: $crate::fuse_sys::ReplyEmpty,
             ) {
-                self.bento_unlink(req, parent, name, reply)
+                let timer = $crate::OpTimer::start("unlink");
+                self.bento_unlink(&$crate::Request::from(req), parent, name, $crate::Timed::timed(reply.into(), timer))
             }
         
             fn rmdir(
                 &mut self,
-                req: &Request,
+                req: &$crate::fuse_sys::Request,
                 parent: u64,
                 name: &OsStr,
-                reply: ReplyEmpty,
+                reply: $crate::fuse_sys::ReplyEmpty,
             ) {
-                self.bento_rmdir(req, parent, name, reply)
+                let timer = $crate::OpTimer::start("rmdir");
+                self.bento_rmdir(&$crate::Request::from(req), parent, name, $crate::Timed::timed(reply.into(), timer))
             }
         
             fn symlink(
                 &mut self,
-                req: &Request,
+                req: &$crate::fuse_sys::Request,
                 parent: u64,
                 name: &OsStr,
                 link: &Path,
-                reply: ReplyEntry,
+                reply: $crate::fuse_sys::ReplyEntry,
             ) {
-                self.bento_symlink(req, parent, name, link, reply)
+                let timer = $crate::OpTimer::start("symlink");
+                self.bento_symlink(&$crate::Request::from(req), parent, name, link, $crate::Timed::timed(reply.into(), timer))
             }
         
             fn rename(
                 &mut self,
-                req: &Request,
+                req: &$crate::fuse_sys::Request,
                 parent: u64,
                 name: &OsStr,
                 newparent: u64,
                 newname: &OsStr,
-                reply: ReplyEmpty,
+                reply: $crate::fuse_sys::ReplyEmpty,
             ) {
-                self.bento_rename(req, parent, name, newparent, newname, 0, reply)
+                let timer = $crate::OpTimer::start("rename");
+                self.bento_rename(&$crate::Request::from(req), parent, name, newparent, newname, 0, $crate::Timed::timed(reply.into(), timer))
             }
         
             fn link(
                 &mut self,
-                req: &Request,
+                req: &$crate::fuse_sys::Request,
                 ino: u64,
                 newparent: u64,
                 newname: &OsStr,
-                reply: ReplyEntry,
+                reply: $crate::fuse_sys::ReplyEntry,
             ) {
-                self.bento_link(req, ino, newparent, newname, reply)
+                let timer = $crate::OpTimer::start("link");
+                self.bento_link(&$crate::Request::from(req), ino, newparent, newname, $crate::Timed::timed(reply.into(), timer))
             }
         
             fn open(
                 &mut self,
-                req: &Request,
+                req: &$crate::fuse_sys::Request,
                 ino: u64,
                 flags: u32,
-                reply: ReplyOpen,
+                reply: $crate::fuse_sys::ReplyOpen,
             ) {
-                self.bento_open(req, ino, flags, reply)
+                let timer = $crate::OpTimer::start("open");
+                self.bento_open(&$crate::Request::from(req), ino, flags, $crate::Timed::timed(reply.into(), timer))
             }
         
             fn read(
                 &mut self,
-                req: &Request,
+                req: &$crate::fuse_sys::Request,
                 ino: u64,
                 fh: u64,
                 offset: i64,
                 size: u32,
-                reply: ReplyData,
+                reply: $crate::fuse_sys::ReplyData,
             ) {
-                self.bento_read(req, ino, fh,offset, size, reply)
+                let timer = $crate::OpTimer::start("read");
+                self.bento_read(&$crate::Request::from(req), ino, fh,offset, size, $crate::Timed::timed(reply.into(), timer))
             }
         
             fn write(
                 &mut self,
-                req: &Request,
+                req: &$crate::fuse_sys::Request,
                 ino: u64,
                 fh: u64,
                 offset: i64,
                 data: &[u8],
                 flags: u32,
-                reply: ReplyWrite,
+                reply: $crate::fuse_sys::ReplyWrite,
             ) {
-                self.bento_write(req, ino, fh, offset, data, flags, reply)
+                let timer = $crate::OpTimer::start("write");
+                self.bento_write(&$crate::Request::from(req), ino, fh, offset, data, flags, $crate::Timed::timed(reply.into(), timer))
             }
         
             fn flush(
                 &mut self,
-                req: &Request,
+                req: &$crate::fuse_sys::Request,
                 ino: u64,
                 fh: u64,
                 lock_owner: u64,
-                reply: ReplyEmpty,
+                reply: $crate::fuse_sys::ReplyEmpty,
             ) {
-                self.bento_flush(req, ino, fh, lock_owner, reply)
+                let timer = $crate::OpTimer::start("flush");
+                self.bento_flush(&$crate::Request::from(req), ino, fh, lock_owner, $crate::Timed::timed(reply.into(), timer))
             }
         
             fn release(
                 &mut self,
-                req: &Request,
+                req: &$crate::fuse_sys::Request,
                 ino: u64,
                 fh: u64,
                 flags: u32,
                 lock_owner: u64,
                 flush: bool,
-                reply: ReplyEmpty,
+                reply: $crate::fuse_sys::ReplyEmpty,
             ) {
-                self.bento_release(req, ino, fh, flags, lock_owner, flush, reply)
+                let timer = $crate::OpTimer::start("release");
+                self.bento_release(&$crate::Request::from(req), ino, fh, flags, lock_owner, flush, $crate::Timed::timed(reply.into(), timer))
             }
         
             fn fsync(
                 &mut self,
-                req: &Request,
+                req: &$crate::fuse_sys::Request,
                 ino: u64,
                 fh: u64,
                 datasync: bool,
-                reply: ReplyEmpty,
+                reply: $crate::fuse_sys::ReplyEmpty,
             ) {
-                self.bento_fsync(req, ino, fh, datasync, reply)
+                let timer = $crate::OpTimer::start("fsync");
+                self.bento_fsync(&$crate::Request::from(req), ino, fh, datasync, $crate::Timed::timed(reply.into(), timer))
             }
         
             fn opendir(
                 &mut self,
-                req: &Request,
+                req: &$crate::fuse_sys::Request,
                 ino: u64,
                 flags: u32,
-                reply: ReplyOpen,
+                reply: $crate::fuse_sys::ReplyOpen,
             ) {
-                self.bento_opendir(req, ino, flags, reply)
+                let timer = $crate::OpTimer::start("opendir");
+                self.bento_opendir(&$crate::Request::from(req), ino, flags, $crate::Timed::timed(reply.into(), timer))
             }
         
             fn readdir(
                 &mut self,
-                req: &Request,
+                req: &$crate::fuse_sys::Request,
                 ino: u64,
                 fh: u64,
                 offset: i64,
-                reply: ReplyDirectory,
+                reply: $crate::fuse_sys::ReplyDirectory,
             ) {
-                self.bento_readdir(req, ino, fh, offset, reply)
+                let timer = $crate::OpTimer::start("readdir");
+                self.bento_readdir(&$crate::Request::from(req), ino, fh, offset, $crate::Timed::timed(reply.into(), timer))
             }
         
             fn releasedir(
                 &mut self,
-                req: &Request,
+                req: &$crate::fuse_sys::Request,
                 ino: u64,
                 fh: u64,
                 flags: u32,
-                reply: ReplyEmpty,
+                reply: $crate::fuse_sys::ReplyEmpty,
             ) {
-                self.bento_releasedir(req, ino, fh, flags, reply)
+                let timer = $crate::OpTimer::start("releasedir");
+                self.bento_releasedir(&$crate::Request::from(req), ino, fh, flags, $crate::Timed::timed(reply.into(), timer))
             }
         
             fn fsyncdir(
                 &mut self,
-                req: &Request,
+                req: &$crate::fuse_sys::Request,
                 ino: u64,
                 fh: u64,
                 datasync: bool,
-                reply: ReplyEmpty,
+                reply: $crate::fuse_sys::ReplyEmpty,
             ) {
-                self.bento_fsyncdir(req, ino, fh, datasync, reply)
+                let timer = $crate::OpTimer::start("fsyncdir");
+                self.bento_fsyncdir(&$crate::Request::from(req), ino, fh, datasync, $crate::Timed::timed(reply.into(), timer))
             }
         
-            fn statfs(&mut self, req: &Request, ino: u64, reply: ReplyStatfs) {
-                self.bento_statfs(req, ino, reply)
+            fn statfs(&mut self, req: &$crate::fuse_sys::Request, ino: u64, reply: $crate::fuse_sys::ReplyStatfs) {
+                let timer = $crate::OpTimer::start("statfs");
+                self.bento_statfs(&$crate::Request::from(req), ino, $crate::Timed::timed(reply.into(), timer))
             }
         
             fn setxattr(
                 &mut self,
-                req: &Request,
+                req: &$crate::fuse_sys::Request,
                 ino: u64,
                 name: &OsStr,
                 value: &[u8],
                 flags: u32,
                 position: u32,
-                reply: ReplyEmpty,
+                reply: $crate::fuse_sys::ReplyEmpty,
             ) {
-                self.bento_setxattr(req, ino, name, value, flags, position, reply)
+                let timer = $crate::OpTimer::start("setxattr");
+                self.bento_setxattr(&$crate::Request::from(req), ino, name, value, flags, position, $crate::Timed::timed(reply.into(), timer))
             }
         
             fn getxattr(
                 &mut self,
-                req: &Request,
+                req: &$crate::fuse_sys::Request,
                 ino: u64,
                 name: &OsStr,
                 size: u32,
-                reply: ReplyXattr,
+                reply: $crate::fuse_sys::ReplyXattr,
             ) {
-                self.bento_getxattr(req, ino, name, size, reply)
+                let timer = $crate::OpTimer::start("getxattr");
+                self.bento_getxattr(&$crate::Request::from(req), ino, name, size, $crate::Timed::timed(reply.into(), timer))
             }
         
             fn listxattr(
                 &mut self,
-                req: &Request,
+                req: &$crate::fuse_sys::Request,
                 ino: u64,
                 size: u32,
-                reply: ReplyXattr,
+                reply: $crate::fuse_sys::ReplyXattr,
             ) {
-                self.bento_listxattr(req, ino, size, reply)
+                let timer = $crate::OpTimer::start("listxattr");
+                self.bento_listxattr(&$crate::Request::from(req), ino, size, $crate::Timed::timed(reply.into(), timer))
             }
         
             fn removexattr(
                 &mut self,
-                req: &Request,
+                req: &$crate::fuse_sys::Request,
                 ino: u64,
                 name: &OsStr,
-                reply: ReplyEmpty,
+                reply: $crate::fuse_sys::ReplyEmpty,
             ) {
-                self.bento_removexattr(req, ino, name, reply)
+                let timer = $crate::OpTimer::start("removexattr");
+                self.bento_removexattr(&$crate::Request::from(req), ino, name, $crate::Timed::timed(reply.into(), timer))
             }
         
             fn access(
                 &mut self,
-                req: &Request,
+                req: &$crate::fuse_sys::Request,
                 ino: u64,
                 mask: u32,
-                reply: ReplyEmpty,
+                reply: $crate::fuse_sys::ReplyEmpty,
             ) {
-                self.bento_access(req, ino, mask, reply)
+                let timer = $crate::OpTimer::start("access");
+                self.bento_access(&$crate::Request::from(req), ino, mask, $crate::Timed::timed(reply.into(), timer))
             }
         
             fn create(
                 &mut self,
-                req: &Request,
+                req: &$crate::fuse_sys::Request,
                 parent: u64,
                 name: &OsStr,
                 mode: u32,
                 flags: u32,
-                reply: ReplyCreate,
+                reply: $crate::fuse_sys::ReplyCreate,
             ) {
-                self.bento_create(req, parent, name, mode, flags, reply)
+                let timer = $crate::OpTimer::start("create");
+                self.bento_create(&$crate::Request::from(req), parent, name, mode, flags, $crate::Timed::timed(reply.into(), timer))
             }
         
             fn getlk(
                 &mut self,
-                req: &Request,
+                req: &$crate::fuse_sys::Request,
                 ino: u64,
                 fh: u64,
                 lock_owner: u64,
@@ -1305,14 +1584,15 @@ macro_rules! impl_filesystem {
                 end: u64,
                 typ: u32,
                 pid: u32,
-                reply: ReplyLock,
+                reply: $crate::fuse_sys::ReplyLock,
             ) {
-                self.bento_getlk(req, ino, fh, lock_owner, start, end, typ, pid, reply)
+                let timer = $crate::OpTimer::start("getlk");
+                self.bento_getlk(&$crate::Request::from(req), ino, fh, lock_owner, start, end, typ, pid, $crate::Timed::timed(reply.into(), timer))
             }
         
             fn setlk(
                 &mut self,
-                req: &Request,
+                req: &$crate::fuse_sys::Request,
                 ino: u64,
                 fh: u64,
                 lock_owner: u64,
@@ -1321,20 +1601,22 @@ macro_rules! impl_filesystem {
                 typ: u32,
                 pid: u32,
                 sleep: bool,
-                reply: ReplyEmpty,
+                reply: $crate::fuse_sys::ReplyEmpty,
             ) {
-                self.bento_setlk(req, ino, fh, lock_owner, start, end, typ, pid, sleep, reply)
+                let timer = $crate::OpTimer::start("setlk");
+                self.bento_setlk(&$crate::Request::from(req), ino, fh, lock_owner, start, end, typ, pid, sleep, $crate::Timed::timed(reply.into(), timer))
             }
         
             fn bmap(
                 &mut self,
-                req: &Request,
+                req: &$crate::fuse_sys::Request,
                 ino: u64,
                 blocksize: u32,
                 idx: u64,
-                reply: ReplyBmap,
+                reply: $crate::fuse_sys::ReplyBmap,
             ) {
-                self.bento_bmap(req, ino, blocksize, idx, reply)
+                let timer = $crate::OpTimer::start("bmap");
+                self.bento_bmap(&$crate::Request::from(req), ino, blocksize, idx, $crate::Timed::timed(reply.into(), timer))
             }
         }
     }
@@ -0,0 +1,89 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// Table of kernel lookup counts
+///
+/// The kernel keeps a lookup count for every inode it has been handed by a reply. The count goes
+/// up by one for each successful `bento_lookup`, `bento_create`, `bento_mknod`, `bento_mkdir`,
+/// `bento_symlink` and `bento_link` reply, and for every entry other than '.' and '..' in a
+/// `bento_readdirplus` reply. It goes down through `bento_forget`. An inode that has been unlinked
+/// may still receive requests until its lookup count reaches zero, so its resources should only be
+/// reclaimed then.
+///
+/// Filesystems call `lookup` whenever they hand out an inode and `forget` from `bento_forget`,
+/// and reclaim an unlinked inode once `forget` returns true or, if it was not referenced at the
+/// time of the unlink, right away.
+pub struct LookupCountTable {
+    counts: Mutex<BTreeMap<u64, u64>>,
+}
+
+impl Default for LookupCountTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LookupCountTable {
+    pub fn new() -> Self {
+        LookupCountTable {
+            counts: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Record that `ino` was handed to the kernel once more.
+    pub fn lookup(&self, ino: u64) {
+        if let Ok(mut counts) = self.counts.lock() {
+            *counts.entry(ino).or_insert(0) += 1;
+        }
+    }
+
+    /// Drop `nlookup` references to `ino`.
+    ///
+    /// Returns true if the kernel no longer references `ino`, in which case it is removed from the
+    /// table.
+    pub fn forget(&self, ino: u64, nlookup: u64) -> bool {
+        let mut counts = match self.counts.lock() {
+            Ok(x) => x,
+            Err(_) => return false,
+        };
+        let remaining = match counts.get_mut(&ino) {
+            Some(count) => {
+                *count = count.saturating_sub(nlookup);
+                *count
+            }
+            None => 0,
+        };
+        if remaining == 0 {
+            counts.remove(&ino);
+            return true;
+        }
+        false
+    }
+
+    /// Get the current lookup count of `ino`.
+    pub fn count(&self, ino: u64) -> u64 {
+        match self.counts.lock() {
+            Ok(counts) => *counts.get(&ino).unwrap_or(&0),
+            Err(_) => 0,
+        }
+    }
+
+    /// Check whether the kernel still references `ino`.
+    pub fn is_referenced(&self, ino: u64) -> bool {
+        self.count(ino) > 0
+    }
+
+    /// Drop all lookup counts, as happens implicitly on unmount.
+    ///
+    /// Returns the inodes that were still referenced, which the filesystem may now reclaim.
+    pub fn clear(&self) -> Vec<u64> {
+        match self.counts.lock() {
+            Ok(mut counts) => {
+                let inodes = counts.keys().cloned().collect();
+                counts.clear();
+                inodes
+            }
+            Err(_) => Vec::new(),
+        }
+    }
+}
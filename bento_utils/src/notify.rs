@@ -0,0 +1,201 @@
+use std::ffi::OsStr;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::sync::Weak;
+
+use libc::{ENOTCONN, EIO};
+
+use super::session::Channel;
+
+// Notification codes from /include/uapi/linux/fuse.h
+const FUSE_NOTIFY_INVAL_INODE: i32 = 2;
+const FUSE_NOTIFY_INVAL_ENTRY: i32 = 3;
+const FUSE_NOTIFY_STORE: i32 = 4;
+const FUSE_NOTIFY_DELETE: i32 = 6;
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+pub(crate) struct fuse_out_header {
+    pub(crate) len: u32,
+    pub(crate) error: i32,
+    pub(crate) unique: u64,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct fuse_notify_inval_inode_out {
+    ino: u64,
+    off: i64,
+    len: i64,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct fuse_notify_inval_entry_out {
+    parent: u64,
+    namelen: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct fuse_notify_store_out {
+    nodeid: u64,
+    offset: u64,
+    size: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct fuse_notify_delete_out {
+    parent: u64,
+    child: u64,
+    namelen: u32,
+    padding: u32,
+}
+
+/// Handle for sending cache invalidation notifications to the kernel.
+///
+/// Notifications are written directly to the `/dev/fuse` file descriptor of one mount. A
+/// `Notifier` for a `Session` is passed to `bento_init` in `FuseConnInfo::notifier`, or can be
+/// obtained with `Session::notifier`, and fails with `ENOTCONN` once the session has ended. Each session has its own notifier, so several sessions can run in one
+/// process.
+///
+/// Notifications must not be sent from a request handler while the kernel holds a lock on the
+/// affected inode or directory, e.g. `inval_entry` on `parent` from inside a lookup in `parent`,
+/// or the request will deadlock.
+#[derive(Clone, Debug)]
+pub struct Notifier {
+    target: Target,
+}
+
+#[derive(Clone, Debug)]
+enum Target {
+    Session(Weak<Channel>),
+    Fd(RawFd),
+}
+
+impl Notifier {
+    // Create a notifier for the mount of a running session.
+    pub(crate) fn for_channel(channel: Weak<Channel>) -> Self {
+        Notifier {
+            target: Target::Session(channel),
+        }
+    }
+
+    /// Create a notifier that writes to a `/dev/fuse` file descriptor.
+    ///
+    /// The session loop from the `fuse` crate does not expose its file descriptor, so with
+    /// `impl_filesystem` the notifier must be created by whoever opened `/dev/fuse`. The file
+    /// descriptor is not owned by the notifier and must stay open while the notifier is used.
+    ///
+    /// Arguments:
+    /// * `fd: RawFd` - The `/dev/fuse` file descriptor of the mount.
+    pub fn from_fd(fd: RawFd) -> Self {
+        Notifier {
+            target: Target::Fd(fd),
+        }
+    }
+
+    /// Invalidate cached attributes and data of an inode.
+    ///
+    /// Data in the range starting at `offset` of length `len` is dropped from the page cache. A
+    /// negative `offset` only invalidates the attributes, and a `len` of 0 invalidates up to the
+    /// end of the file.
+    pub fn inval_inode(&self, ino: u64, offset: i64, len: i64) -> Result<(), libc::c_int> {
+        let arg = fuse_notify_inval_inode_out {
+            ino,
+            off: offset,
+            len,
+        };
+        self.send(FUSE_NOTIFY_INVAL_INODE, &arg, &[])
+    }
+
+    /// Invalidate a cached directory entry and the attributes of its parent.
+    pub fn inval_entry(&self, parent: u64, name: &OsStr) -> Result<(), libc::c_int> {
+        let arg = fuse_notify_inval_entry_out {
+            parent,
+            namelen: name.len() as u32,
+            padding: 0,
+        };
+        self.send(FUSE_NOTIFY_INVAL_ENTRY, &arg, &[name.as_bytes(), &[0]])
+    }
+
+    /// Store data in the page cache of an inode.
+    ///
+    /// The data is written to the page cache starting at `offset`, and the file size is extended
+    /// if needed. This does not cause a write request.
+    pub fn store(&self, ino: u64, offset: u64, data: &[u8]) -> Result<(), libc::c_int> {
+        let arg = fuse_notify_store_out {
+            nodeid: ino,
+            offset,
+            size: data.len() as u32,
+            padding: 0,
+        };
+        self.send(FUSE_NOTIFY_STORE, &arg, &[data])
+    }
+
+    /// Notify the kernel that a directory entry has been removed.
+    ///
+    /// Like `inval_entry`, but if the entry still points to `child`, the kernel also removes it
+    /// from any open directory handles and drops the dentry as if it was unlinked.
+    pub fn delete(&self, parent: u64, child: u64, name: &OsStr) -> Result<(), libc::c_int> {
+        let arg = fuse_notify_delete_out {
+            parent,
+            child,
+            namelen: name.len() as u32,
+            padding: 0,
+        };
+        self.send(FUSE_NOTIFY_DELETE, &arg, &[name.as_bytes(), &[0]])
+    }
+
+    // Notifications are sent as replies with a unique of 0 and the notification code in the error
+    // field of the header.
+    fn send<T>(&self, code: i32, arg: &T, data: &[&[u8]]) -> Result<(), libc::c_int> {
+        // Holding the channel keeps the session from closing the file descriptor while the
+        // notification is written.
+        let (fd, _channel) = match self.target {
+            Target::Session(ref x) => {
+                let channel = x.upgrade().ok_or(ENOTCONN)?;
+                (channel.fd, Some(channel))
+            }
+            Target::Fd(fd) => (fd, None),
+        };
+        if fd < 0 {
+            return Err(ENOTCONN);
+        }
+
+        let arg_len = mem::size_of::<T>();
+        let data_len: usize = data.iter().map(|d| d.len()).sum();
+        let header = fuse_out_header {
+            len: (mem::size_of::<fuse_out_header>() + arg_len + data_len) as u32,
+            error: code,
+            unique: 0,
+        };
+
+        let mut iov = vec![
+            libc::iovec {
+                iov_base: &header as *const fuse_out_header as *mut libc::c_void,
+                iov_len: mem::size_of::<fuse_out_header>(),
+            },
+            libc::iovec {
+                iov_base: arg as *const T as *mut libc::c_void,
+                iov_len: arg_len,
+            },
+        ];
+        for d in data {
+            iov.push(libc::iovec {
+                iov_base: d.as_ptr() as *mut libc::c_void,
+                iov_len: d.len(),
+            });
+        }
+
+        let ret = unsafe { libc::writev(fd, iov.as_ptr(), iov.len() as libc::c_int) };
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error().raw_os_error().unwrap_or(EIO));
+        }
+        Ok(())
+    }
+}
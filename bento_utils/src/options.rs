@@ -0,0 +1,257 @@
+use std::cell::Cell;
+use std::env;
+
+use libc;
+
+/// Options that are handled by the FUSE mount itself instead of the filesystem.
+const FUSE_MOUNT_OPTIONS: &[&str] = &[
+    "allow_other",
+    "allow_root",
+    "auto_unmount",
+    "blkdev",
+    "default_permissions",
+    "fsname",
+    "ro",
+    "rw",
+    "subtype",
+];
+
+struct MountOption {
+    name: String,
+    value: Option<String>,
+    // Set once a getter asked for this option
+    used: Cell<bool>,
+}
+
+/// Mount options passed with `-o` when the filesystem was mounted.
+///
+/// Options are a comma-separated list of `name` or `name=value` entries, e.g.
+/// `ttl=5,noatime,server=127.0.0.1:1234`. If an option is given more than once, the last one is
+/// used. Options that no getter asked for are reported by `unknown`, so typos in mount options
+/// don't go unnoticed.
+///
+/// In the userspace version, the options are parsed from the `-o` arguments of the program by
+/// `from_args`. Options that are handled by the FUSE mount, such as `blkdev`, are passed on to
+/// `fuse::mount` through `fuse_options`.
+pub struct MountOptions {
+    opts: Vec<MountOption>,
+}
+
+impl Default for MountOptions {
+    fn default() -> Self {
+        MountOptions::new()
+    }
+}
+
+impl MountOptions {
+    /// Create an empty set of mount options.
+    pub fn new() -> Self {
+        MountOptions { opts: Vec::new() }
+    }
+
+    /// Parse a comma-separated mount option string.
+    ///
+    /// Arguments:
+    /// * `opts: &str` - The mount options, e.g. `ttl=5,noatime`.
+    pub fn parse(opts: &str) -> Self {
+        let mut options = MountOptions::new();
+        for opt in opts.split(',') {
+            let opt = opt.trim();
+            if opt.is_empty() {
+                continue;
+            }
+            let mut parts = opt.splitn(2, '=');
+            let name = parts.next().unwrap_or("");
+            options.opts.push(MountOption {
+                name: name.to_string(),
+                value: parts.next().map(|value| value.to_string()),
+                used: Cell::new(false),
+            });
+        }
+        options
+    }
+
+    /// Parse the `-o` arguments of the program.
+    ///
+    /// Both `-o opts` and `-oopts` are accepted, and `-o` may be given more than once.
+    pub fn from_args() -> Self {
+        let mut opts = Vec::new();
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            if arg == "-o" {
+                if let Some(value) = args.next() {
+                    opts.push(value);
+                }
+            } else if let Some(value) = arg.strip_prefix("-o") {
+                opts.push(value.to_string());
+            }
+        }
+        MountOptions::parse(&opts.join(","))
+    }
+
+    /// Get the options that are handled by the FUSE mount, as arguments for `fuse::mount`.
+    pub fn fuse_options(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        for opt in self.opts.iter().filter(|opt| FUSE_MOUNT_OPTIONS.contains(&opt.name.as_str())) {
+            args.push("-o".to_string());
+            match opt.value {
+                Some(ref value) => args.push(format!("{}={}", opt.name, value)),
+                None => args.push(opt.name.clone()),
+            }
+        }
+        args
+    }
+
+    fn find(&self, name: &str) -> Option<&MountOption> {
+        let opt = self.opts.iter().rev().find(|opt| opt.name == name)?;
+        for other in self.opts.iter().filter(|opt| opt.name == name) {
+            other.used.set(true);
+        }
+        Some(opt)
+    }
+
+    /// Returns true if the option `name` was given, with or without a value.
+    ///
+    /// Arguments:
+    /// * `name: &str` - Name of the option.
+    pub fn contains(&self, name: &str) -> bool {
+        self.find(name).is_some()
+    }
+
+    /// Get the value of the option `name`.
+    ///
+    /// Returns `None` if the option wasn't given and an empty string if it was given without a
+    /// value.
+    ///
+    /// Arguments:
+    /// * `name: &str` - Name of the option.
+    pub fn get_str(&self, name: &str) -> Option<&str> {
+        self.find(name)
+            .map(|opt| opt.value.as_deref().unwrap_or(""))
+    }
+
+    /// Get the value of the option `name` as a number.
+    ///
+    /// Returns `Ok(None)` if the option wasn't given and `EINVAL` if its value is not a number.
+    /// Hexadecimal values starting with `0x` are accepted.
+    ///
+    /// Arguments:
+    /// * `name: &str` - Name of the option.
+    pub fn get_u64(&self, name: &str) -> Result<Option<u64>, i32> {
+        let value = match self.find(name) {
+            Some(opt) => opt.value.as_ref().ok_or(libc::EINVAL)?,
+            None => return Ok(None),
+        };
+        let parsed = if let Some(hex) = value.strip_prefix("0x") {
+            u64::from_str_radix(hex, 16)
+        } else {
+            value.parse::<u64>()
+        };
+        parsed.map(Some).map_err(|_| libc::EINVAL)
+    }
+
+    /// Get the value of the flag `name`.
+    ///
+    /// `name`, `name=1`, `name=true`, `name=yes` and `name=on` turn the flag on. `noname`,
+    /// `name=0`, `name=false`, `name=no` and `name=off` turn it off. Returns `Ok(None)` if the
+    /// flag wasn't given and `EINVAL` for any other value.
+    ///
+    /// Arguments:
+    /// * `name: &str` - Name of the flag, without the `no` prefix.
+    pub fn get_bool(&self, name: &str) -> Result<Option<bool>, i32> {
+        let mut negated = String::from("no");
+        negated.push_str(name);
+        // The last of `name` and `noname` wins.
+        let pos = self.opts.iter().rposition(|opt| opt.name == name || opt.name == negated);
+        self.find(name);
+        self.find(&negated);
+        let opt = match pos {
+            Some(pos) => &self.opts[pos],
+            None => return Ok(None),
+        };
+        if opt.name == negated {
+            return match opt.value {
+                None => Ok(Some(false)),
+                Some(_) => Err(libc::EINVAL),
+            };
+        }
+        match opt.value.as_deref() {
+            None | Some("1") | Some("true") | Some("yes") | Some("on") => Ok(Some(true)),
+            Some("0") | Some("false") | Some("no") | Some("off") => Ok(Some(false)),
+            Some(_) => Err(libc::EINVAL),
+        }
+    }
+
+    /// Get the names of the options that no getter asked for.
+    ///
+    /// Bento reports these after `bento_init` returns. Options handled by the FUSE mount are
+    /// never reported.
+    pub fn unknown(&self) -> Vec<&str> {
+        let mut unknown: Vec<&str> = Vec::new();
+        for opt in self.opts.iter().filter(|opt| !opt.used.get()) {
+            if FUSE_MOUNT_OPTIONS.contains(&opt.name.as_str()) {
+                continue;
+            }
+            if !unknown.contains(&opt.name.as_str()) {
+                unknown.push(opt.name.as_str());
+            }
+        }
+        unknown
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_values() {
+        let opts = MountOptions::parse(" ttl=5, ,server=127.0.0.1:1234,noatime,ttl=7,empty=");
+        assert_eq!(opts.get_str("server"), Some("127.0.0.1:1234"));
+        assert_eq!(opts.get_str("noatime"), Some(""));
+        assert_eq!(opts.get_str("empty"), Some(""));
+        assert_eq!(opts.get_str("missing"), None);
+        // The last value wins
+        assert_eq!(opts.get_u64("ttl"), Ok(Some(7)));
+        assert!(opts.contains("noatime"));
+        assert!(!opts.contains("atime"));
+    }
+
+    #[test]
+    fn get_u64() {
+        let opts = MountOptions::parse("a=42,b=0x1f,c=abc,d,e=-1,f=0x");
+        assert_eq!(opts.get_u64("a"), Ok(Some(42)));
+        assert_eq!(opts.get_u64("b"), Ok(Some(0x1f)));
+        assert_eq!(opts.get_u64("c"), Err(libc::EINVAL));
+        assert_eq!(opts.get_u64("d"), Err(libc::EINVAL));
+        assert_eq!(opts.get_u64("e"), Err(libc::EINVAL));
+        assert_eq!(opts.get_u64("f"), Err(libc::EINVAL));
+        assert_eq!(opts.get_u64("g"), Ok(None));
+    }
+
+    #[test]
+    fn get_bool() {
+        let opts = MountOptions::parse("a,b=off,noc,d=maybe,nod=1,e,noe,nof,f=yes");
+        assert_eq!(opts.get_bool("a"), Ok(Some(true)));
+        assert_eq!(opts.get_bool("b"), Ok(Some(false)));
+        assert_eq!(opts.get_bool("c"), Ok(Some(false)));
+        // `nod=1` comes last, and `noname` takes no value
+        assert_eq!(opts.get_bool("d"), Err(libc::EINVAL));
+        // The last of `name` and `noname` wins
+        assert_eq!(opts.get_bool("e"), Ok(Some(false)));
+        assert_eq!(opts.get_bool("f"), Ok(Some(true)));
+        assert_eq!(opts.get_bool("g"), Ok(None));
+    }
+
+    #[test]
+    fn unknown_options() {
+        let opts = MountOptions::parse("ro,blkdev,ttl=5,typo,typo=1,noatime,fsname=x");
+        opts.get_u64("ttl").unwrap();
+        opts.get_bool("atime").unwrap();
+        assert_eq!(opts.unknown(), vec!["typo"]);
+        assert_eq!(
+            opts.fuse_options(),
+            vec!["-o", "ro", "-o", "blkdev", "-o", "fsname=x"]
+        );
+    }
+}
@@ -0,0 +1,764 @@
+use libc::EIO;
+
+use std::ffi::{OsStr, OsString};
+
+use fuse::{FileAttr, FileType};
+use fuse_sys;
+
+use time::Timespec;
+
+use super::{Lock, OpTimer, Statfs};
+
+/// Defer a reply so it can be completed after the handler returns.
+///
+/// In the kernel version, replies borrow buffers owned by the dispatcher, and `defer` turns them
+/// into a `Deferred` reply that can be moved to a worker thread. Replies in the userspace version
+/// are already owned and can be moved to another thread as they are, so `defer` just returns the
+/// reply. It exists so filesystems can use the same code in both versions:
+///
+/// ```ignore
+/// let mut reply = reply.defer();
+/// std::thread::spawn(move || {
+///     reply.data(&buf);
+/// });
+/// ```
+pub trait Defer: Sized + Send + 'static {
+    fn defer(self) -> Self {
+        self
+    }
+}
+
+/// Record the result of a reply in the statistics when it is answered.
+///
+/// `impl_filesystem` attaches the timer of each request to its reply, so requests are recorded
+/// with the errno they failed with, like in the kernel version. A reply that is dropped without
+/// being answered is recorded as failed with `EIO`.
+pub trait Timed: Sized {
+    /// Attach `timer` to the reply.
+    ///
+    /// Arguments:
+    /// * `timer: OpTimer` - Timer started when the request was received.
+    fn timed(self, timer: OpTimer) -> Self;
+}
+
+macro_rules! impl_timed {
+    ($($reply:ident),*) => {
+        $(
+            impl Timed for $reply {
+                fn timed(mut self, timer: OpTimer) -> Self {
+                    self.reply.timer = Some(timer);
+                    self
+                }
+            }
+        )*
+    }
+}
+
+impl_timed!(
+    ReplyEmpty, ReplyData, ReplyEntry, ReplyAttr, ReplyOpen, ReplyWrite, ReplyStatfs, ReplyCreate,
+    ReplyLock, ReplyBmap, ReplyDirectory, ReplyXattr, ReplyLseek, ReplyIoctl, ReplyDirectoryPlus
+);
+
+impl Defer for ReplyEmpty {}
+impl Defer for ReplyData {}
+impl Defer for ReplyEntry {}
+impl Defer for ReplyAttr {}
+impl Defer for ReplyOpen {}
+impl Defer for ReplyWrite {}
+impl Defer for ReplyStatfs {}
+impl Defer for ReplyCreate {}
+impl Defer for ReplyLock {}
+impl Defer for ReplyBmap {}
+impl Defer for ReplyDirectory {}
+impl Defer for ReplyXattr {}
+impl Defer for ReplyLseek {}
+impl Defer for ReplyIoctl {}
+impl Defer for ReplyDirectoryPlus {}
+
+/// Callback used to hand a finished reply back to whoever issued the request.
+type ReplySender<T> = Box<dyn FnOnce(Result<T, libc::c_int>) + Send>;
+
+/// Reply to a request, answered by passing the result to a callback.
+///
+/// Like the reply types from the `fuse` crate, a reply is consumed when it is answered. A reply
+/// that is dropped without being answered is answered with `EIO`.
+struct ReplyRaw<T> {
+    sender: Option<ReplySender<T>>,
+    timer: Option<OpTimer>,
+}
+
+impl<T> ReplyRaw<T> {
+    fn new<F>(sender: F) -> Self
+    where
+        F: FnOnce(Result<T, libc::c_int>) + Send + 'static,
+    {
+        Self {
+            sender: Some(Box::new(sender)),
+            timer: None,
+        }
+    }
+
+    fn send(&mut self, res: Result<T, libc::c_int>) {
+        if let Some(timer) = self.timer.take() {
+            timer.finish(match res {
+                Ok(_) => 0,
+                Err(x) => -x,
+            });
+        }
+        if let Some(sender) = self.sender.take() {
+            sender(res);
+        }
+    }
+}
+
+impl<T> Drop for ReplyRaw<T> {
+    fn drop(&mut self) {
+        self.send(Err(EIO));
+    }
+}
+
+/// Reply to a request that only returns whether it succeeded.
+pub struct ReplyEmpty {
+    reply: ReplyRaw<()>,
+}
+
+impl ReplyEmpty {
+    pub fn new<F>(sender: F) -> Self
+    where
+        F: FnOnce(Result<(), libc::c_int>) + Send + 'static,
+    {
+        Self {
+            reply: ReplyRaw::new(sender),
+        }
+    }
+
+    /// Reply that the request succeeded.
+    pub fn ok(mut self) {
+        self.reply.send(Ok(()));
+    }
+
+    /// Reply with an error code.
+    pub fn error(mut self, err: libc::c_int) {
+        self.reply.send(Err(err));
+    }
+}
+
+/// Reply to a `bento_read` or `bento_readlink` request.
+pub struct ReplyData {
+    reply: ReplyRaw<Vec<u8>>,
+}
+
+impl ReplyData {
+    pub fn new<F>(sender: F) -> Self
+    where
+        F: FnOnce(Result<Vec<u8>, libc::c_int>) + Send + 'static,
+    {
+        Self {
+            reply: ReplyRaw::new(sender),
+        }
+    }
+
+    /// Reply with the data.
+    pub fn data(mut self, data: &[u8]) {
+        self.reply.send(Ok(data.to_vec()));
+    }
+
+    /// Reply with an error code.
+    pub fn error(mut self, err: libc::c_int) {
+        self.reply.send(Err(err));
+    }
+}
+
+/// Reply to a request that looks up or creates a directory entry.
+///
+/// The reply is sent as the time the entry may be cached for, its attributes and its generation.
+pub struct ReplyEntry {
+    reply: ReplyRaw<(Timespec, FileAttr, u64)>,
+}
+
+impl ReplyEntry {
+    pub fn new<F>(sender: F) -> Self
+    where
+        F: FnOnce(Result<(Timespec, FileAttr, u64), libc::c_int>) + Send + 'static,
+    {
+        Self {
+            reply: ReplyRaw::new(sender),
+        }
+    }
+
+    /// Reply with the attributes of the entry.
+    pub fn entry(mut self, ttl: &Timespec, attr: &FileAttr, generation: u64) {
+        self.reply.send(Ok((*ttl, *attr, generation)));
+    }
+
+    /// Reply with an error code.
+    pub fn error(mut self, err: libc::c_int) {
+        self.reply.send(Err(err));
+    }
+}
+
+/// Reply to a `bento_getattr` or `bento_setattr` request.
+pub struct ReplyAttr {
+    reply: ReplyRaw<(Timespec, FileAttr)>,
+}
+
+impl ReplyAttr {
+    pub fn new<F>(sender: F) -> Self
+    where
+        F: FnOnce(Result<(Timespec, FileAttr), libc::c_int>) + Send + 'static,
+    {
+        Self {
+            reply: ReplyRaw::new(sender),
+        }
+    }
+
+    /// Reply with the attributes and the time they may be cached for.
+    pub fn attr(mut self, ttl: &Timespec, attr: &FileAttr) {
+        self.reply.send(Ok((*ttl, *attr)));
+    }
+
+    /// Reply with an error code.
+    pub fn error(mut self, err: libc::c_int) {
+        self.reply.send(Err(err));
+    }
+}
+
+/// Reply to a `bento_open` or `bento_opendir` request.
+///
+/// The reply is sent as the file handle and the open flags.
+pub struct ReplyOpen {
+    reply: ReplyRaw<(u64, u32)>,
+}
+
+impl ReplyOpen {
+    pub fn new<F>(sender: F) -> Self
+    where
+        F: FnOnce(Result<(u64, u32), libc::c_int>) + Send + 'static,
+    {
+        Self {
+            reply: ReplyRaw::new(sender),
+        }
+    }
+
+    /// Reply with the file handle and open flags (`FOPEN_*`).
+    pub fn opened(mut self, fh: u64, flags: u32) {
+        self.reply.send(Ok((fh, flags)));
+    }
+
+    /// Reply with an error code.
+    pub fn error(mut self, err: libc::c_int) {
+        self.reply.send(Err(err));
+    }
+}
+
+/// Reply to a `bento_write` or `bento_copy_file_range` request.
+pub struct ReplyWrite {
+    reply: ReplyRaw<u32>,
+}
+
+impl ReplyWrite {
+    pub fn new<F>(sender: F) -> Self
+    where
+        F: FnOnce(Result<u32, libc::c_int>) + Send + 'static,
+    {
+        Self {
+            reply: ReplyRaw::new(sender),
+        }
+    }
+
+    /// Reply with the number of bytes written.
+    pub fn written(mut self, size: u32) {
+        self.reply.send(Ok(size));
+    }
+
+    /// Reply with an error code.
+    pub fn error(mut self, err: libc::c_int) {
+        self.reply.send(Err(err));
+    }
+}
+
+/// Reply to a `bento_statfs` request.
+pub struct ReplyStatfs {
+    reply: ReplyRaw<Statfs>,
+}
+
+impl ReplyStatfs {
+    pub fn new<F>(sender: F) -> Self
+    where
+        F: FnOnce(Result<Statfs, libc::c_int>) + Send + 'static,
+    {
+        Self {
+            reply: ReplyRaw::new(sender),
+        }
+    }
+
+    /// Reply with the filesystem statistics.
+    #[allow(clippy::too_many_arguments)]
+    pub fn statfs(
+        mut self,
+        blocks: u64,
+        bfree: u64,
+        bavail: u64,
+        files: u64,
+        ffree: u64,
+        bsize: u32,
+        namelen: u32,
+        frsize: u32,
+    ) {
+        self.reply.send(Ok(Statfs {
+            blocks,
+            bfree,
+            bavail,
+            files,
+            ffree,
+            bsize,
+            namelen,
+            frsize,
+        }));
+    }
+
+    /// Reply with an error code.
+    pub fn error(mut self, err: libc::c_int) {
+        self.reply.send(Err(err));
+    }
+}
+
+/// Reply to a `bento_create` request.
+///
+/// The reply is sent as the time the entry may be cached for, its attributes, its generation, the
+/// file handle and the open flags.
+pub struct ReplyCreate {
+    reply: ReplyRaw<(Timespec, FileAttr, u64, u64, u32)>,
+}
+
+impl ReplyCreate {
+    pub fn new<F>(sender: F) -> Self
+    where
+        F: FnOnce(Result<(Timespec, FileAttr, u64, u64, u32), libc::c_int>) + Send + 'static,
+    {
+        Self {
+            reply: ReplyRaw::new(sender),
+        }
+    }
+
+    /// Reply with the attributes of the new entry and the handle of the opened file.
+    pub fn created(mut self, ttl: &Timespec, attr: &FileAttr, generation: u64, fh: u64, flags: u32) {
+        self.reply.send(Ok((*ttl, *attr, generation, fh, flags)));
+    }
+
+    /// Reply with an error code.
+    pub fn error(mut self, err: libc::c_int) {
+        self.reply.send(Err(err));
+    }
+}
+
+/// Reply to a `bento_getlk` request.
+pub struct ReplyLock {
+    reply: ReplyRaw<Lock>,
+}
+
+impl ReplyLock {
+    pub fn new<F>(sender: F) -> Self
+    where
+        F: FnOnce(Result<Lock, libc::c_int>) + Send + 'static,
+    {
+        Self {
+            reply: ReplyRaw::new(sender),
+        }
+    }
+
+    /// Reply with the conflicting lock.
+    pub fn locked(mut self, start: u64, end: u64, typ: u32, pid: u32) {
+        self.reply.send(Ok(Lock {
+            start,
+            end,
+            typ,
+            pid,
+        }));
+    }
+
+    /// Reply with an error code.
+    pub fn error(mut self, err: libc::c_int) {
+        self.reply.send(Err(err));
+    }
+}
+
+/// Reply to a `bento_bmap` request.
+pub struct ReplyBmap {
+    reply: ReplyRaw<u64>,
+}
+
+impl ReplyBmap {
+    pub fn new<F>(sender: F) -> Self
+    where
+        F: FnOnce(Result<u64, libc::c_int>) + Send + 'static,
+    {
+        Self {
+            reply: ReplyRaw::new(sender),
+        }
+    }
+
+    /// Reply with the block number on the device.
+    pub fn bmap(mut self, block: u64) {
+        self.reply.send(Ok(block));
+    }
+
+    /// Reply with an error code.
+    pub fn error(mut self, err: libc::c_int) {
+        self.reply.send(Err(err));
+    }
+}
+
+/// An entry added to a `ReplyDirectory`.
+#[derive(Clone, Debug)]
+pub struct DirectoryEntry {
+    pub ino: u64,
+    pub offset: i64,
+    pub kind: FileType,
+    pub name: OsString,
+}
+
+/// Reply to a `bento_readdir` request.
+pub struct ReplyDirectory {
+    reply: ReplyRaw<Vec<DirectoryEntry>>,
+    entries: Vec<DirectoryEntry>,
+    size: usize,
+    length: usize,
+}
+
+impl ReplyDirectory {
+    /// Create a reply whose entries may take up at most `size` bytes, the same limit the kernel
+    /// would impose on the encoded `fuse_dirent` records.
+    pub fn new<F>(size: usize, sender: F) -> Self
+    where
+        F: FnOnce(Result<Vec<DirectoryEntry>, libc::c_int>) + Send + 'static,
+    {
+        Self {
+            reply: ReplyRaw::new(sender),
+            entries: Vec::new(),
+            size,
+            length: 0,
+        }
+    }
+
+    /// Add an entry to the directory reply buffer. Returns true if the buffer is full.
+    pub fn add<T: AsRef<OsStr>>(&mut self, ino: u64, offset: i64, kind: FileType, name: T) -> bool {
+        let name = name.as_ref();
+        // fuse_dirent header, padded to 8 bytes like the kernel version
+        let entlen = (24 + name.len() + 7) & !7;
+        if self.length + entlen > self.size {
+            return true;
+        }
+        self.length += entlen;
+        self.entries.push(DirectoryEntry {
+            ino,
+            offset,
+            kind,
+            name: name.to_os_string(),
+        });
+        false
+    }
+
+    /// Reply with the entries added so far.
+    pub fn ok(mut self) {
+        let entries = std::mem::take(&mut self.entries);
+        self.reply.send(Ok(entries));
+    }
+
+    /// Reply with an error code.
+    pub fn error(mut self, err: libc::c_int) {
+        self.reply.send(Err(err));
+    }
+}
+
+/// Result of a `bento_getxattr` or `bento_listxattr` request.
+#[derive(Clone, Debug)]
+pub enum Xattr {
+    /// Size of the value or list, for a request with a size of 0
+    Size(u32),
+    /// The value or list
+    Data(Vec<u8>),
+}
+
+/// Reply to a `bento_getxattr` or `bento_listxattr` request.
+pub struct ReplyXattr {
+    reply: ReplyRaw<Xattr>,
+}
+
+impl ReplyXattr {
+    pub fn new<F>(sender: F) -> Self
+    where
+        F: FnOnce(Result<Xattr, libc::c_int>) + Send + 'static,
+    {
+        Self {
+            reply: ReplyRaw::new(sender),
+        }
+    }
+
+    /// Reply with the size of the value or list.
+    pub fn size(mut self, size: u32) {
+        self.reply.send(Ok(Xattr::Size(size)));
+    }
+
+    /// Reply with the value or list.
+    pub fn data(mut self, data: &[u8]) {
+        self.reply.send(Ok(Xattr::Data(data.to_vec())));
+    }
+
+    /// Reply with an error code.
+    pub fn error(mut self, err: libc::c_int) {
+        self.reply.send(Err(err));
+    }
+}
+
+/// Reply to a `bento_lseek` request.
+pub struct ReplyLseek {
+    reply: ReplyRaw<i64>,
+}
+
+impl ReplyLseek {
+    pub fn new<F>(sender: F) -> Self
+    where
+        F: FnOnce(Result<i64, libc::c_int>) + Send + 'static,
+    {
+        Self {
+            reply: ReplyRaw::new(sender),
+        }
+    }
+
+    /// Reply with the resulting offset.
+    pub fn offset(mut self, offset: i64) {
+        self.reply.send(Ok(offset));
+    }
+
+    /// Reply with an error code.
+    pub fn error(mut self, err: libc::c_int) {
+        self.reply.send(Err(err));
+    }
+}
+
+/// Reply to a `bento_ioctl` request.
+pub struct ReplyIoctl {
+    reply: ReplyRaw<(i32, Vec<u8>)>,
+}
+
+impl ReplyIoctl {
+    pub fn new<F>(sender: F) -> Self
+    where
+        F: FnOnce(Result<(i32, Vec<u8>), libc::c_int>) + Send + 'static,
+    {
+        Self {
+            reply: ReplyRaw::new(sender),
+        }
+    }
+
+    /// Reply with the ioctl return value and the data to copy back to the caller.
+    pub fn ioctl(mut self, result: i32, data: &[u8]) {
+        self.reply.send(Ok((result, data.to_vec())));
+    }
+
+    /// Reply with an error code.
+    pub fn error(mut self, err: libc::c_int) {
+        self.reply.send(Err(err));
+    }
+}
+
+/// An entry added to a `ReplyDirectoryPlus`.
+pub struct DirEntryPlus {
+    pub ino: u64,
+    pub offset: i64,
+    pub attr: FileAttr,
+    pub ttl: Timespec,
+    pub generation: u64,
+    pub name: OsString,
+}
+
+/// Reply to a `bento_readdirplus` request.
+pub struct ReplyDirectoryPlus {
+    reply: ReplyRaw<Vec<DirEntryPlus>>,
+    entries: Vec<DirEntryPlus>,
+    size: usize,
+    length: usize,
+}
+
+impl ReplyDirectoryPlus {
+    /// Create a reply whose entries may take up at most `size` bytes, the same limit the kernel
+    /// would impose on the encoded `fuse_direntplus` records.
+    pub fn new<F>(size: usize, sender: F) -> Self
+    where
+        F: FnOnce(Result<Vec<DirEntryPlus>, libc::c_int>) + Send + 'static,
+    {
+        Self {
+            reply: ReplyRaw::new(sender),
+            entries: Vec::new(),
+            size,
+            length: 0,
+        }
+    }
+
+    /// Add an entry to the directory reply buffer. Returns true if the buffer is full.
+    pub fn add<T: AsRef<OsStr>>(
+        &mut self,
+        ino: u64,
+        offset: i64,
+        attr: &FileAttr,
+        ttl: &Timespec,
+        generation: u64,
+        name: T,
+    ) -> bool {
+        let name = name.as_ref();
+        // fuse_entry_out and fuse_dirent header, padded to 8 bytes like the kernel version
+        let entlen = (128 + 24 + name.len() + 7) & !7;
+        if self.length + entlen > self.size {
+            return true;
+        }
+        self.length += entlen;
+        let mut entry_attr = *attr;
+        entry_attr.ino = ino;
+        self.entries.push(DirEntryPlus {
+            ino,
+            offset,
+            attr: entry_attr,
+            ttl: *ttl,
+            generation,
+            name: name.to_os_string(),
+        });
+        false
+    }
+
+    /// Reply with the entries added so far.
+    pub fn ok(mut self) {
+        let entries = std::mem::take(&mut self.entries);
+        self.reply.send(Ok(entries));
+    }
+
+    /// Reply with an error code.
+    pub fn error(mut self, err: libc::c_int) {
+        self.reply.send(Err(err));
+    }
+}
+
+// The `fuse` crate hands `impl_filesystem` its own reply types. These conversions wrap them, so the
+// filesystem answers a Bento reply and the result is passed on to the `fuse` crate.
+
+impl From<fuse_sys::ReplyEmpty> for ReplyEmpty {
+    fn from(reply: fuse_sys::ReplyEmpty) -> Self {
+        ReplyEmpty::new(move |res| match res {
+            Ok(()) => reply.ok(),
+            Err(x) => reply.error(x),
+        })
+    }
+}
+
+impl From<fuse_sys::ReplyData> for ReplyData {
+    fn from(reply: fuse_sys::ReplyData) -> Self {
+        ReplyData::new(move |res| match res {
+            Ok(data) => reply.data(&data),
+            Err(x) => reply.error(x),
+        })
+    }
+}
+
+impl From<fuse_sys::ReplyEntry> for ReplyEntry {
+    fn from(reply: fuse_sys::ReplyEntry) -> Self {
+        ReplyEntry::new(move |res| match res {
+            Ok((ttl, attr, generation)) => reply.entry(&ttl, &attr, generation),
+            Err(x) => reply.error(x),
+        })
+    }
+}
+
+impl From<fuse_sys::ReplyAttr> for ReplyAttr {
+    fn from(reply: fuse_sys::ReplyAttr) -> Self {
+        ReplyAttr::new(move |res| match res {
+            Ok((ttl, attr)) => reply.attr(&ttl, &attr),
+            Err(x) => reply.error(x),
+        })
+    }
+}
+
+impl From<fuse_sys::ReplyOpen> for ReplyOpen {
+    fn from(reply: fuse_sys::ReplyOpen) -> Self {
+        ReplyOpen::new(move |res| match res {
+            Ok((fh, flags)) => reply.opened(fh, flags),
+            Err(x) => reply.error(x),
+        })
+    }
+}
+
+impl From<fuse_sys::ReplyWrite> for ReplyWrite {
+    fn from(reply: fuse_sys::ReplyWrite) -> Self {
+        ReplyWrite::new(move |res| match res {
+            Ok(size) => reply.written(size),
+            Err(x) => reply.error(x),
+        })
+    }
+}
+
+impl From<fuse_sys::ReplyStatfs> for ReplyStatfs {
+    fn from(reply: fuse_sys::ReplyStatfs) -> Self {
+        ReplyStatfs::new(move |res| match res {
+            Ok(st) => reply.statfs(
+                st.blocks, st.bfree, st.bavail, st.files, st.ffree, st.bsize, st.namelen, st.frsize,
+            ),
+            Err(x) => reply.error(x),
+        })
+    }
+}
+
+impl From<fuse_sys::ReplyCreate> for ReplyCreate {
+    fn from(reply: fuse_sys::ReplyCreate) -> Self {
+        ReplyCreate::new(move |res| match res {
+            Ok((ttl, attr, generation, fh, flags)) => reply.created(&ttl, &attr, generation, fh, flags),
+            Err(x) => reply.error(x),
+        })
+    }
+}
+
+impl From<fuse_sys::ReplyLock> for ReplyLock {
+    fn from(reply: fuse_sys::ReplyLock) -> Self {
+        ReplyLock::new(move |res| match res {
+            Ok(lock) => reply.locked(lock.start, lock.end, lock.typ, lock.pid),
+            Err(x) => reply.error(x),
+        })
+    }
+}
+
+impl From<fuse_sys::ReplyBmap> for ReplyBmap {
+    fn from(reply: fuse_sys::ReplyBmap) -> Self {
+        ReplyBmap::new(move |res| match res {
+            Ok(block) => reply.bmap(block),
+            Err(x) => reply.error(x),
+        })
+    }
+}
+
+// The `fuse` crate doesn't tell how large its buffer is. Readdir requests from the kernel are one
+// page, so entries are limited to that and any that still don't fit are left for the next request.
+const READDIR_SIZE: usize = 4096;
+
+impl From<fuse_sys::ReplyDirectory> for ReplyDirectory {
+    fn from(mut reply: fuse_sys::ReplyDirectory) -> Self {
+        ReplyDirectory::new(READDIR_SIZE, move |res| match res {
+            Ok(entries) => {
+                for entry in entries.iter() {
+                    if reply.add(entry.ino, entry.offset, entry.kind, &entry.name) {
+                        break;
+                    }
+                }
+                reply.ok();
+            }
+            Err(x) => reply.error(x),
+        })
+    }
+}
+
+impl From<fuse_sys::ReplyXattr> for ReplyXattr {
+    fn from(reply: fuse_sys::ReplyXattr) -> Self {
+        ReplyXattr::new(move |res| match res {
+            Ok(Xattr::Size(size)) => reply.size(size),
+            Ok(Xattr::Data(data)) => reply.data(&data),
+            Err(x) => reply.error(x),
+        })
+    }
+}
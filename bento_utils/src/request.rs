@@ -0,0 +1,156 @@
+use std::fs;
+
+use fuse_sys;
+
+/// Request data structure
+///
+/// Identifies the request and the process that made it, like the `Request` of the kernel
+/// version. Requests from the `fuse` crate are converted into this type by `impl_filesystem`,
+/// and `TestDriver` creates them to call a filesystem directly.
+#[derive(Clone, Copy, Debug)]
+pub struct Request {
+    unique: u64,
+    uid: u32,
+    gid: u32,
+    pid: u32,
+}
+
+impl Request {
+    /// Create a request made by the process `pid`, running as `uid` and `gid`.
+    ///
+    /// Arguments:
+    /// * `unique: u64` - Unique id of the request.
+    /// * `uid: u32` - User id of the caller.
+    /// * `gid: u32` - Group id of the caller.
+    /// * `pid: u32` - Process id of the caller.
+    pub fn new(unique: u64, uid: u32, gid: u32, pid: u32) -> Self {
+        Request {
+            unique,
+            uid,
+            gid,
+            pid,
+        }
+    }
+
+    /// Returns the unique id of this request
+    #[inline]
+    pub fn unique(&self) -> u64 {
+        self.unique
+    }
+
+    /// Returns the uid of this request
+    #[inline]
+    pub fn uid(&self) -> u32 {
+        self.uid
+    }
+
+    /// Returns the gid of this request
+    #[inline]
+    pub fn gid(&self) -> u32 {
+        self.gid
+    }
+
+    /// Returns the pid of this request
+    #[inline]
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+}
+
+impl<'a, 'b> From<&'a fuse_sys::Request<'b>> for Request {
+    fn from(req: &'a fuse_sys::Request<'b>) -> Self {
+        Request::new(req.unique(), req.uid(), req.gid(), req.pid())
+    }
+}
+
+/// Additional information about the process that made a request
+///
+/// This is the userspace version of the `umask`, `groups` and `capable` methods of the kernel
+/// `Request`. The `fuse` crate only provides the uid, gid and pid of the caller, so the rest is
+/// read from `/proc/<pid>/status`, like `fuse_req_getgroups` in libfuse. If the
+/// process has already exited, a umask of 022, no supplementary groups and no capabilities are
+/// assumed, even for uid 0. Requests that are not made on behalf of a process, such as
+/// writeback, have a pid of 0 and get a umask of 022, no groups and no capabilities, like in the
+/// kernel version.
+pub trait RequestExt {
+    /// Returns the umask of the process that made this request
+    fn umask(&self) -> u32;
+
+    /// Returns the supplementary group ids of the process that made this request
+    fn groups(&self) -> Vec<u32>;
+
+    /// Returns true if the process that made this request has the capability `cap`
+    ///
+    /// Capabilities are defined in `bento_utils::consts`, e.g. `CAP_DAC_OVERRIDE` and
+    /// `CAP_FOWNER`.
+    fn capable(&self, cap: i32) -> bool;
+}
+
+impl RequestExt for Request {
+    fn umask(&self) -> u32 {
+        status_field(self.pid(), "Umask")
+            .and_then(|umask| u32::from_str_radix(&umask, 8).ok())
+            .unwrap_or(0o022)
+    }
+
+    fn groups(&self) -> Vec<u32> {
+        match status_field(self.pid(), "Groups") {
+            Some(groups) => groups
+                .split_whitespace()
+                .filter_map(|gid| gid.parse::<u32>().ok())
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    fn capable(&self, cap: i32) -> bool {
+        if !(0..64).contains(&cap) || self.pid() == 0 {
+            return false;
+        }
+        match status_field(self.pid(), "CapEff")
+            .and_then(|caps| u64::from_str_radix(&caps, 16).ok())
+        {
+            Some(caps) => caps & (1 << cap) != 0,
+            // The process is gone, so its capabilities can't be checked
+            None => false,
+        }
+    }
+}
+
+// Lines in /proc/<pid>/status look like "Name:\tvalue".
+fn status_field(pid: u32, name: &str) -> Option<String> {
+    if pid == 0 {
+        return None;
+    }
+    let status = fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    for line in status.lines() {
+        let mut parts = line.splitn(2, ':');
+        if parts.next() == Some(name) {
+            return parts.next().map(|value| value.trim().to_string());
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use consts::CAP_SYS_ADMIN;
+
+    // A pid above the kernel's PID_MAX_LIMIT, so it never has a /proc entry
+    const MISSING_PID: u32 = 1 << 23;
+
+    #[test]
+    fn missing_process_has_no_capabilities() {
+        let req = Request::new(1, 0, 0, MISSING_PID);
+        assert!(!req.capable(CAP_SYS_ADMIN));
+        assert_eq!(req.umask(), 0o022);
+        assert!(req.groups().is_empty());
+    }
+
+    #[test]
+    fn writeback_has_no_capabilities() {
+        let req = Request::new(1, 0, 0, 0);
+        assert!(!req.capable(CAP_SYS_ADMIN));
+    }
+}
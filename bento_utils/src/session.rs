@@ -0,0 +1,1546 @@
+use std::cmp;
+use std::ffi::{CString, OsStr, OsString};
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::thread;
+use std::time::Instant;
+
+use libc::{c_char, c_int, EINVAL, EIO, ENODEV, ENOSYS, EPROTO, EROFS};
+
+use fuse::*;
+
+use time::Timespec;
+
+use super::notify::fuse_out_header;
+use super::{dump_stats_on_destroy, record_stats, set_dump_stats_on_destroy, Notifier};
+use super::{BentoFilesystem, DirEntryPlus, DirectoryEntry, FuseConnInfo, Lock, MountOptions};
+use super::{OpTimer, Statfs, Xattr};
+
+// Version of the FUSE protocol spoken by the session
+const FUSE_KERNEL_VERSION: u32 = 7;
+const FUSE_KERNEL_MINOR_VERSION: u32 = 28;
+// Oldest minor version whose request layouts the session can parse
+const FUSE_MIN_MINOR_VERSION: u32 = 12;
+// Size of fuse_init_out before `time_gran` was added in 7.23
+const FUSE_COMPAT_22_INIT_OUT_SIZE: usize = 24;
+
+/// Largest write request the session accepts.
+const MAX_WRITE: u32 = 128 * 1024;
+
+/// Size of the buffer each worker reads requests into: the largest write plus room for the
+/// request headers.
+const BUFFER_SIZE: usize = MAX_WRITE as usize + 4096;
+
+/// Number of worker threads used if the `threads` mount option isn't given.
+const DEFAULT_THREADS: usize = 4;
+
+// Opcodes from /include/uapi/linux/fuse.h
+const FUSE_LOOKUP: u32 = 1;
+const FUSE_FORGET: u32 = 2;
+const FUSE_GETATTR: u32 = 3;
+const FUSE_SETATTR: u32 = 4;
+const FUSE_READLINK: u32 = 5;
+const FUSE_SYMLINK: u32 = 6;
+const FUSE_MKNOD: u32 = 8;
+const FUSE_MKDIR: u32 = 9;
+const FUSE_UNLINK: u32 = 10;
+const FUSE_RMDIR: u32 = 11;
+const FUSE_RENAME: u32 = 12;
+const FUSE_LINK: u32 = 13;
+const FUSE_OPEN: u32 = 14;
+const FUSE_READ: u32 = 15;
+const FUSE_WRITE: u32 = 16;
+const FUSE_STATFS: u32 = 17;
+const FUSE_RELEASE: u32 = 18;
+const FUSE_FSYNC: u32 = 20;
+const FUSE_SETXATTR: u32 = 21;
+const FUSE_GETXATTR: u32 = 22;
+const FUSE_LISTXATTR: u32 = 23;
+const FUSE_REMOVEXATTR: u32 = 24;
+const FUSE_FLUSH: u32 = 25;
+const FUSE_INIT: u32 = 26;
+const FUSE_OPENDIR: u32 = 27;
+const FUSE_READDIR: u32 = 28;
+const FUSE_RELEASEDIR: u32 = 29;
+const FUSE_FSYNCDIR: u32 = 30;
+const FUSE_GETLK: u32 = 31;
+const FUSE_SETLK: u32 = 32;
+const FUSE_SETLKW: u32 = 33;
+const FUSE_ACCESS: u32 = 34;
+const FUSE_CREATE: u32 = 35;
+const FUSE_INTERRUPT: u32 = 36;
+const FUSE_BMAP: u32 = 37;
+const FUSE_DESTROY: u32 = 38;
+const FUSE_IOCTL: u32 = 39;
+const FUSE_BATCH_FORGET: u32 = 42;
+const FUSE_FALLOCATE: u32 = 43;
+const FUSE_READDIRPLUS: u32 = 44;
+const FUSE_RENAME2: u32 = 45;
+const FUSE_LSEEK: u32 = 46;
+const FUSE_COPY_FILE_RANGE: u32 = 47;
+
+// Setattr valid bits
+const FATTR_MODE: u32 = 1 << 0;
+const FATTR_UID: u32 = 1 << 1;
+const FATTR_GID: u32 = 1 << 2;
+const FATTR_SIZE: u32 = 1 << 3;
+const FATTR_ATIME: u32 = 1 << 4;
+const FATTR_MTIME: u32 = 1 << 5;
+const FATTR_FH: u32 = 1 << 6;
+
+const FUSE_RELEASE_FLUSH: u32 = 1 << 0;
+const FUSE_FSYNC_FDATASYNC: u32 = 1 << 0;
+
+// Direction bits of an ioctl command, from /include/uapi/asm-generic/ioctl.h
+const IOC_DIRSHIFT: u32 = 30;
+const IOC_READ: u32 = 2;
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct fuse_in_header {
+    len: u32,
+    opcode: u32,
+    unique: u64,
+    nodeid: u64,
+    uid: u32,
+    gid: u32,
+    pid: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct fuse_attr {
+    ino: u64,
+    size: u64,
+    blocks: u64,
+    atime: u64,
+    mtime: u64,
+    ctime: u64,
+    atimensec: u32,
+    mtimensec: u32,
+    ctimensec: u32,
+    mode: u32,
+    nlink: u32,
+    uid: u32,
+    gid: u32,
+    rdev: u32,
+    blksize: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct fuse_entry_out {
+    nodeid: u64,
+    generation: u64,
+    entry_valid: u64,
+    attr_valid: u64,
+    entry_valid_nsec: u32,
+    attr_valid_nsec: u32,
+    attr: fuse_attr,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct fuse_attr_out {
+    attr_valid: u64,
+    attr_valid_nsec: u32,
+    dummy: u32,
+    attr: fuse_attr,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct fuse_forget_in {
+    nlookup: u64,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct fuse_forget_one {
+    nodeid: u64,
+    nlookup: u64,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct fuse_batch_forget_in {
+    count: u32,
+    dummy: u32,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct fuse_setattr_in {
+    valid: u32,
+    padding: u32,
+    fh: u64,
+    size: u64,
+    lock_owner: u64,
+    atime: u64,
+    mtime: u64,
+    ctime: u64,
+    atimensec: u32,
+    mtimensec: u32,
+    ctimensec: u32,
+    mode: u32,
+    unused4: u32,
+    uid: u32,
+    gid: u32,
+    unused5: u32,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct fuse_mknod_in {
+    mode: u32,
+    rdev: u32,
+    umask: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct fuse_mkdir_in {
+    mode: u32,
+    umask: u32,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct fuse_rename_in {
+    newdir: u64,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct fuse_rename2_in {
+    newdir: u64,
+    flags: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct fuse_link_in {
+    oldnodeid: u64,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct fuse_open_in {
+    flags: u32,
+    unused: u32,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct fuse_create_in {
+    flags: u32,
+    mode: u32,
+    umask: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct fuse_open_out {
+    fh: u64,
+    open_flags: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct fuse_release_in {
+    fh: u64,
+    flags: u32,
+    release_flags: u32,
+    lock_owner: u64,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct fuse_flush_in {
+    fh: u64,
+    unused: u32,
+    padding: u32,
+    lock_owner: u64,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct fuse_read_in {
+    fh: u64,
+    offset: u64,
+    size: u32,
+    read_flags: u32,
+    lock_owner: u64,
+    flags: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct fuse_write_in {
+    fh: u64,
+    offset: u64,
+    size: u32,
+    write_flags: u32,
+    lock_owner: u64,
+    flags: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct fuse_write_out {
+    size: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct fuse_kstatfs {
+    blocks: u64,
+    bfree: u64,
+    bavail: u64,
+    files: u64,
+    ffree: u64,
+    bsize: u32,
+    namelen: u32,
+    frsize: u32,
+    padding: u32,
+    spare: [u32; 6],
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct fuse_fsync_in {
+    fh: u64,
+    fsync_flags: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct fuse_setxattr_in {
+    size: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct fuse_getxattr_in {
+    size: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct fuse_getxattr_out {
+    size: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct fuse_file_lock {
+    start: u64,
+    end: u64,
+    typ: u32,
+    pid: u32,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct fuse_lk_in {
+    fh: u64,
+    owner: u64,
+    lk: fuse_file_lock,
+    lk_flags: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct fuse_access_in {
+    mask: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct fuse_init_in {
+    major: u32,
+    minor: u32,
+    max_readahead: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct fuse_init_out {
+    major: u32,
+    minor: u32,
+    max_readahead: u32,
+    flags: u32,
+    max_background: u16,
+    congestion_threshold: u16,
+    max_write: u32,
+    time_gran: u32,
+    max_pages: u16,
+    padding: u16,
+    unused: [u32; 8],
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct fuse_bmap_in {
+    block: u64,
+    blocksize: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct fuse_ioctl_in {
+    fh: u64,
+    flags: u32,
+    cmd: u32,
+    arg: u64,
+    in_size: u32,
+    out_size: u32,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct fuse_ioctl_out {
+    result: i32,
+    flags: u32,
+    in_iovs: u32,
+    out_iovs: u32,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct fuse_fallocate_in {
+    fh: u64,
+    offset: u64,
+    length: u64,
+    mode: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct fuse_lseek_in {
+    fh: u64,
+    offset: u64,
+    whence: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct fuse_copy_file_range_in {
+    fh_in: u64,
+    off_in: u64,
+    nodeid_out: u64,
+    fh_out: u64,
+    off_out: u64,
+    len: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct fuse_dirent {
+    ino: u64,
+    off: u64,
+    namelen: u32,
+    typ: u32,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct fuse_args {
+    argc: c_int,
+    argv: *const *const c_char,
+    allocated: c_int,
+}
+
+// libfuse is linked by the `fuse` crate. Only its mount helpers are used, which run fusermount if
+// the process isn't allowed to mount.
+extern "C" {
+    fn fuse_mount_compat25(mountpoint: *const c_char, args: *const fuse_args) -> c_int;
+    fn fuse_unmount_compat22(mountpoint: *const c_char);
+}
+
+fn as_bytes<T>(x: &T) -> &[u8] {
+    unsafe { slice::from_raw_parts(x as *const T as *const u8, mem::size_of::<T>()) }
+}
+
+fn to_vec<T>(x: &T) -> Vec<u8> {
+    as_bytes(x).to_vec()
+}
+
+// Reads the arguments of a request from the buffer it was read into.
+struct Args<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Args<'a> {
+    fn fetch<T>(&mut self) -> Result<T, i32> {
+        let len = mem::size_of::<T>();
+        if self.data.len() < len {
+            return Err(EINVAL);
+        }
+        let x = unsafe { ptr::read_unaligned(self.data.as_ptr() as *const T) };
+        self.data = &self.data[len..];
+        Ok(x)
+    }
+
+    fn fetch_bytes(&mut self, len: usize) -> Result<&'a [u8], i32> {
+        if self.data.len() < len {
+            return Err(EINVAL);
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    // Names are terminated by a NUL byte
+    fn fetch_str(&mut self) -> Result<&'a OsStr, i32> {
+        let len = self.data.iter().position(|b| *b == 0).ok_or(EINVAL)?;
+        let name = self.fetch_bytes(len + 1)?;
+        Ok(OsStr::from_bytes(&name[..len]))
+    }
+}
+
+fn kind_to_mode(kind: FileType) -> u32 {
+    match kind {
+        FileType::NamedPipe => libc::S_IFIFO,
+        FileType::CharDevice => libc::S_IFCHR,
+        FileType::BlockDevice => libc::S_IFBLK,
+        FileType::Directory => libc::S_IFDIR,
+        FileType::RegularFile => libc::S_IFREG,
+        FileType::Symlink => libc::S_IFLNK,
+        FileType::Socket => libc::S_IFSOCK,
+    }
+}
+
+fn attr_out(attr: &FileAttr) -> fuse_attr {
+    fuse_attr {
+        ino: attr.ino,
+        size: attr.size,
+        blocks: attr.blocks,
+        atime: attr.atime.sec as u64,
+        mtime: attr.mtime.sec as u64,
+        ctime: attr.ctime.sec as u64,
+        atimensec: attr.atime.nsec as u32,
+        mtimensec: attr.mtime.nsec as u32,
+        ctimensec: attr.ctime.nsec as u32,
+        mode: kind_to_mode(attr.kind) | attr.perm as u32,
+        nlink: attr.nlink,
+        uid: attr.uid,
+        gid: attr.gid,
+        rdev: attr.rdev,
+        blksize: 0,
+        padding: 0,
+    }
+}
+
+fn entry_out(ttl: &Timespec, attr: &FileAttr, generation: u64) -> fuse_entry_out {
+    fuse_entry_out {
+        nodeid: attr.ino,
+        generation,
+        entry_valid: ttl.sec as u64,
+        attr_valid: ttl.sec as u64,
+        entry_valid_nsec: ttl.nsec as u32,
+        attr_valid_nsec: ttl.nsec as u32,
+        attr: attr_out(attr),
+    }
+}
+
+// Append a fuse_dirent and its name, padded to 8 bytes
+fn push_dirent(buf: &mut Vec<u8>, ino: u64, offset: i64, kind: FileType, name: &OsStr) {
+    let dirent = fuse_dirent {
+        ino,
+        off: offset as u64,
+        namelen: name.len() as u32,
+        typ: kind_to_mode(kind) >> 12,
+    };
+    buf.extend_from_slice(as_bytes(&dirent));
+    buf.extend_from_slice(name.as_bytes());
+    let padded = (buf.len() + 7) & !7;
+    buf.resize(padded, 0);
+}
+
+fn encode_empty(_: ()) -> Vec<u8> {
+    Vec::new()
+}
+
+fn encode_entry((ttl, attr, generation): (Timespec, FileAttr, u64)) -> Vec<u8> {
+    to_vec(&entry_out(&ttl, &attr, generation))
+}
+
+fn encode_attr((ttl, attr): (Timespec, FileAttr)) -> Vec<u8> {
+    to_vec(&fuse_attr_out {
+        attr_valid: ttl.sec as u64,
+        attr_valid_nsec: ttl.nsec as u32,
+        dummy: 0,
+        attr: attr_out(&attr),
+    })
+}
+
+fn encode_dirents(entries: Vec<DirectoryEntry>) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for entry in entries {
+        push_dirent(&mut buf, entry.ino, entry.offset, entry.kind, &entry.name);
+    }
+    buf
+}
+
+fn encode_direntplus(entries: Vec<DirEntryPlus>) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for entry in entries {
+        buf.extend_from_slice(as_bytes(&entry_out(
+            &entry.ttl,
+            &entry.attr,
+            entry.generation,
+        )));
+        push_dirent(
+            &mut buf,
+            entry.ino,
+            entry.offset,
+            entry.attr.kind,
+            &entry.name,
+        );
+    }
+    buf
+}
+
+// The `/dev/fuse` file descriptor of a mount. It is closed once the session and all replies that
+// are still pending have been dropped.
+pub(crate) struct Channel {
+    pub(crate) fd: RawFd,
+    mountpoint: CString,
+}
+
+impl Channel {
+    // Read one request into `buf`. Returns `None` once the filesystem has been unmounted.
+    fn receive(&self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        loop {
+            let ret =
+                unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+            if ret >= 0 {
+                return Ok(Some(ret as usize));
+            }
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                // The request was interrupted before it could be read
+                Some(libc::ENOENT) | Some(libc::EINTR) | Some(libc::EAGAIN) => continue,
+                Some(ENODEV) => return Ok(None),
+                _ => return Err(err),
+            }
+        }
+    }
+
+    fn unmount(&self) {
+        unsafe { fuse_unmount_compat22(self.mountpoint.as_ptr()) };
+    }
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+// Sends the reply to one request and records it in the statistics.
+#[derive(Clone)]
+struct ReplyCtx {
+    channel: Arc<Channel>,
+    unique: u64,
+    name: &'static str,
+    start: Instant,
+}
+
+impl ReplyCtx {
+    fn send(&self, res: Result<Vec<u8>, i32>) {
+        let elapsed = self.start.elapsed();
+        let elapsed_ns = elapsed.as_secs() * 1_000_000_000 + elapsed.subsec_nanos() as u64;
+        record_stats(
+            self.name,
+            res.as_ref().err().map(|x| -x).unwrap_or(0),
+            elapsed_ns,
+        );
+
+        let (error, data) = match res {
+            Ok(data) => (0, data),
+            Err(x) => (-x, Vec::new()),
+        };
+        let header = fuse_out_header {
+            len: (mem::size_of::<fuse_out_header>() + data.len()) as u32,
+            error,
+            unique: self.unique,
+        };
+        let iov = [
+            libc::iovec {
+                iov_base: &header as *const fuse_out_header as *mut libc::c_void,
+                iov_len: mem::size_of::<fuse_out_header>(),
+            },
+            libc::iovec {
+                iov_base: data.as_ptr() as *mut libc::c_void,
+                iov_len: data.len(),
+            },
+        ];
+        // Fails with ENOENT if the request was interrupted, in which case nobody waits for the
+        // reply anymore.
+        unsafe { libc::writev(self.channel.fd, iov.as_ptr(), iov.len() as c_int) };
+    }
+
+    // Create the callback for a Bento reply, which encodes the result with `encode`.
+    fn sender<R, F>(&self, encode: F) -> impl FnOnce(Result<R, i32>) + Send + 'static
+    where
+        R: 'static,
+        F: FnOnce(R) -> Vec<u8> + Send + 'static,
+    {
+        let ctx = self.clone();
+        move |res: Result<R, i32>| ctx.send(res.map(encode))
+    }
+}
+
+/// Multi-threaded FUSE session for the userspace version.
+///
+/// Unlike `impl_filesystem`, which serves requests one at a time through the `fuse` crate, a
+/// session reads requests from `/dev/fuse` with a pool of worker threads and calls the
+/// `BentoFilesystem` methods directly. Requests are handled concurrently through `&self`, like in
+/// the kernel version, so locking bugs in the filesystem show up in userspace too.
+///
+/// The number of worker threads is taken from the `threads` mount option, or can be set with
+/// `set_threads`. Notifications are sent through the `Notifier` returned by `notifier`, and the
+/// result of every request is recorded in the statistics, including errors.
+///
+/// ```ignore
+/// let opts = MountOptions::from_args();
+/// let session = Session::new(fs, &disk_name, Path::new(&mountpoint), opts)?;
+/// session.run()?;
+/// ```
+pub struct Session<T> {
+    fs: T,
+    devname: OsString,
+    opts: MountOptions,
+    channel: Arc<Channel>,
+    threads: usize,
+}
+
+// Shared by the worker threads of a running session
+struct Shared<T> {
+    fs: RwLock<T>,
+    channel: Arc<Channel>,
+    destroyed: AtomicBool,
+    // Reject requests that change the filesystem, see `FuseConnInfo::read_only`
+    read_only: bool,
+}
+
+impl<T> Session<T> {
+    /// Mount the filesystem.
+    ///
+    /// The filesystem is not initialized until `run` is called.
+    ///
+    /// Arguments:
+    /// * `fs: T` - The filesystem.
+    /// * `devname: &OsStr` - Name of the backing device file, passed to `bento_init` and used as
+    ///   the name of the mount.
+    /// * `mountpoint: &Path` - Where to mount the filesystem.
+    /// * `opts: MountOptions` - Mount options. Options handled by the FUSE mount are passed on
+    ///   to it, and the rest are passed to `bento_init`.
+    pub fn new(fs: T, devname: &OsStr, mountpoint: &Path, opts: MountOptions) -> io::Result<Self> {
+        let threads = match opts.get_u64("threads") {
+            Ok(Some(0)) | Err(_) => return Err(io::Error::from_raw_os_error(EINVAL)),
+            Ok(Some(x)) => x as usize,
+            Ok(None) => DEFAULT_THREADS,
+        };
+
+        let to_cstring =
+            |s: &[u8]| CString::new(s).map_err(|_| io::Error::from_raw_os_error(EINVAL));
+        let mut args = vec![to_cstring(b"bento")?];
+        if !devname.is_empty() {
+            args.push(to_cstring(b"-o")?);
+            let mut fsname = b"fsname=".to_vec();
+            fsname.extend_from_slice(devname.as_bytes());
+            args.push(to_cstring(&fsname)?);
+        }
+        for arg in opts.fuse_options() {
+            args.push(to_cstring(arg.as_bytes())?);
+        }
+        let argv: Vec<*const c_char> = args.iter().map(|arg| arg.as_ptr()).collect();
+        let fuse_args = fuse_args {
+            argc: argv.len() as c_int,
+            argv: argv.as_ptr(),
+            allocated: 0,
+        };
+
+        let mountpoint = to_cstring(mountpoint.as_os_str().as_bytes())?;
+        let fd = unsafe { fuse_mount_compat25(mountpoint.as_ptr(), &fuse_args) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Session {
+            fs,
+            devname: devname.to_os_string(),
+            opts,
+            channel: Arc::new(Channel {
+                fd,
+                mountpoint,
+            }),
+            threads,
+        })
+    }
+
+    /// Set the number of worker threads.
+    ///
+    /// Arguments:
+    /// * `threads: usize` - Number of worker threads, at least 1.
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = cmp::max(threads, 1);
+    }
+
+    /// Get the `/dev/fuse` file descriptor of the mount.
+    pub fn fd(&self) -> RawFd {
+        self.channel.fd
+    }
+
+    /// Get a handle for sending cache invalidation notifications for this mount.
+    ///
+    /// The filesystem gets the same notifier in `FuseConnInfo::notifier` when it is initialized.
+    /// It fails with `ENOTCONN` once the session has ended.
+    pub fn notifier(&self) -> Notifier {
+        Notifier::for_channel(Arc::downgrade(&self.channel))
+    }
+
+    /// Initialize the filesystem and serve requests until it is unmounted.
+    ///
+    /// `bento_destroy` is called once all worker threads have stopped, or when the kernel sends a
+    /// destroy request, after the requests that are being handled have finished.
+    pub fn run<TransferIn, TransferOut>(self) -> io::Result<()>
+    where
+        T: BentoFilesystem<'static, TransferIn, TransferOut> + Send + Sync + 'static,
+        TransferIn: 'static,
+        TransferOut: 'static,
+    {
+        let Session {
+            mut fs,
+            devname,
+            opts,
+            channel,
+            threads,
+        } = self;
+
+        let mut buf = vec![0; BUFFER_SIZE];
+        let read_only =
+            match init::<T, TransferIn, TransferOut>(&mut fs, &devname, &opts, &channel, &mut buf) {
+                Ok(x) => x,
+                Err(x) => {
+                    channel.unmount();
+                    return Err(x);
+                }
+            };
+
+        let shared = Arc::new(Shared {
+            fs: RwLock::new(fs),
+            channel,
+            destroyed: AtomicBool::new(false),
+            read_only,
+        });
+        let mut workers = Vec::new();
+        for i in 0..threads {
+            let worker_shared = Arc::clone(&shared);
+            let worker = thread::Builder::new()
+                .name(format!("bento-worker-{}", i))
+                .spawn(move || worker_shared.serve::<TransferIn, TransferOut>());
+            match worker {
+                Ok(x) => workers.push(x),
+                Err(x) => {
+                    shared.channel.unmount();
+                    return Err(x);
+                }
+            }
+        }
+
+        let mut ret = Ok(());
+        for worker in workers {
+            match worker.join() {
+                Ok(Ok(())) => {}
+                Ok(Err(x)) => ret = Err(x),
+                Err(_) => ret = Err(io::Error::from_raw_os_error(EIO)),
+            }
+        }
+
+        shared.destroy::<TransferIn, TransferOut>(&Request::new(0, 0, 0, 0));
+        dump_stats_on_destroy();
+        ret
+    }
+}
+
+// Wait for the INIT request and initialize the filesystem. The kernel sends no other requests
+// until INIT has been answered. Returns whether the filesystem is read-only.
+fn init<T, TransferIn, TransferOut>(
+    fs: &mut T,
+    devname: &OsStr,
+    opts: &MountOptions,
+    channel: &Arc<Channel>,
+    buf: &mut [u8],
+) -> io::Result<bool>
+where
+    T: BentoFilesystem<'static, TransferIn, TransferOut>,
+{
+    loop {
+        let len = match channel.receive(buf)? {
+            Some(x) => x,
+            None => return Err(io::Error::from_raw_os_error(ENODEV)),
+        };
+        let mut args = Args { data: &buf[..len] };
+        let h: fuse_in_header = args.fetch().map_err(io::Error::from_raw_os_error)?;
+        let ctx = ReplyCtx {
+            channel: Arc::clone(channel),
+            unique: h.unique,
+            name: opcode_name(h.opcode),
+            start: Instant::now(),
+        };
+        if h.opcode != FUSE_INIT {
+            ctx.send(Err(EIO));
+            continue;
+        }
+
+        let init_in: fuse_init_in = args.fetch().map_err(io::Error::from_raw_os_error)?;
+        let mut init_out: fuse_init_out = unsafe { mem::zeroed() };
+        init_out.major = FUSE_KERNEL_VERSION;
+        init_out.minor = FUSE_KERNEL_MINOR_VERSION;
+        if init_in.major > FUSE_KERNEL_VERSION {
+            // The kernel retries with our major version
+            ctx.send(Ok(to_vec(&init_out)));
+            continue;
+        }
+        if init_in.major < FUSE_KERNEL_VERSION || init_in.minor < FUSE_MIN_MINOR_VERSION {
+            ctx.send(Err(EPROTO));
+            return Err(io::Error::from_raw_os_error(EPROTO));
+        }
+
+        let req = Request::new(h.unique, h.uid, h.gid, h.pid);
+        let mut fc_info = FuseConnInfo::new();
+        fc_info.proto_major = init_in.major;
+        fc_info.proto_minor = init_in.minor;
+        fc_info.max_readahead = init_in.max_readahead;
+        fc_info.capable = init_in.flags;
+        fc_info.notifier = Some(Notifier::for_channel(Arc::downgrade(channel)));
+        // `ro` is passed on to the FUSE mount as well, so the kernel rejects changes too.
+        // `read_only` is only seen by the session.
+        fc_info.read_only = match (opts.get_bool("ro"), opts.get_bool("read_only")) {
+            (Ok(ro), Ok(read_only)) => ro.unwrap_or(false) || read_only.unwrap_or(false),
+            (Err(x), _) | (_, Err(x)) => {
+                ctx.send(Err(x));
+                return Err(io::Error::from_raw_os_error(x));
+            }
+        };
+        set_dump_stats_on_destroy(opts.get_bool("stats").ok().and_then(|x| x).unwrap_or(false));
+        let ret = fs.bento_init(&req, devname, opts, &mut fc_info);
+        for opt in opts.unknown() {
+            println!(
+                "{}: unknown mount option {}",
+                fs.get_name().trim_end_matches('\0'),
+                opt
+            );
+        }
+        if let Err(x) = ret {
+            ctx.send(Err(x));
+            return Err(io::Error::from_raw_os_error(x));
+        }
+
+        // `proto_major` and `proto_minor` are set to the Bento version by the filesystem, so the
+        // session negotiates the FUSE version on its own.
+        init_out.minor = cmp::min(init_in.minor, FUSE_KERNEL_MINOR_VERSION);
+        init_out.max_readahead = cmp::min(fc_info.max_readahead, init_in.max_readahead);
+        init_out.flags = fc_info.want & fc_info.capable;
+        init_out.max_background = fc_info.max_background as u16;
+        init_out.congestion_threshold = fc_info.congestion_threshold as u16;
+        init_out.max_write = match fc_info.max_write {
+            0 => MAX_WRITE,
+            x => cmp::min(x, MAX_WRITE),
+        };
+        init_out.time_gran = fc_info.time_gran;
+        let mut data = to_vec(&init_out);
+        if init_in.minor < 23 {
+            data.truncate(FUSE_COMPAT_22_INIT_OUT_SIZE);
+        }
+        ctx.send(Ok(data));
+        return Ok(fc_info.read_only);
+    }
+}
+
+impl<T> Shared<T> {
+    fn destroy<TransferIn, TransferOut>(&self, req: &Request)
+    where
+        T: BentoFilesystem<'static, TransferIn, TransferOut>,
+    {
+        if self.destroyed.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        // Waits for the requests that are being handled
+        let mut fs = self.fs.write().unwrap_or_else(PoisonError::into_inner);
+        let _timer = OpTimer::start("destroy");
+        fs.bento_destroy(req);
+    }
+
+    // Worker thread loop. Returns once the filesystem has been unmounted.
+    fn serve<TransferIn, TransferOut>(&self) -> io::Result<()>
+    where
+        T: BentoFilesystem<'static, TransferIn, TransferOut>,
+    {
+        let mut buf = vec![0; BUFFER_SIZE];
+        loop {
+            let len = match self.channel.receive(&mut buf) {
+                Ok(Some(x)) => x,
+                Ok(None) => return Ok(()),
+                Err(x) => {
+                    // Make the other workers stop as well
+                    self.channel.unmount();
+                    return Err(x);
+                }
+            };
+            // A panic in the filesystem drops the reply, which answers the request with EIO, so
+            // the worker can keep serving requests.
+            let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+                self.handle::<TransferIn, TransferOut>(&buf[..len])
+            }));
+        }
+    }
+
+    fn handle<TransferIn, TransferOut>(&self, data: &[u8])
+    where
+        T: BentoFilesystem<'static, TransferIn, TransferOut>,
+    {
+        let mut args = Args { data };
+        let h: fuse_in_header = match args.fetch() {
+            Ok(x) => x,
+            Err(_) => return,
+        };
+        let req = Request::new(h.unique, h.uid, h.gid, h.pid);
+        let ctx = ReplyCtx {
+            channel: Arc::clone(&self.channel),
+            unique: h.unique,
+            name: opcode_name(h.opcode),
+            start: Instant::now(),
+        };
+
+        let ret = match h.opcode {
+            FUSE_DESTROY => {
+                self.destroy::<TransferIn, TransferOut>(&req);
+                ctx.send(Ok(Vec::new()));
+                Ok(())
+            }
+            FUSE_INIT => Err(EIO),
+            _ => {
+                if self.destroyed.load(Ordering::SeqCst) {
+                    Err(EIO)
+                } else if self.read_only && is_mutating(h.opcode, &args) {
+                    Err(EROFS)
+                } else {
+                    let fs = self.fs.read().unwrap_or_else(PoisonError::into_inner);
+                    dispatch::<T, TransferIn, TransferOut>(&*fs, &req, &h, &mut args, &ctx)
+                }
+            }
+        };
+        if let Err(x) = ret {
+            ctx.send(Err(x));
+        }
+    }
+}
+
+// Returns true if the request changes the filesystem, so it must be rejected on read-only mounts.
+fn is_mutating(opcode: u32, args: &Args) -> bool {
+    let mut args = Args { data: args.data };
+    match opcode {
+        FUSE_WRITE
+        | FUSE_SETATTR
+        | FUSE_MKNOD
+        | FUSE_MKDIR
+        | FUSE_UNLINK
+        | FUSE_RMDIR
+        | FUSE_SYMLINK
+        | FUSE_RENAME
+        | FUSE_RENAME2
+        | FUSE_LINK
+        | FUSE_CREATE
+        | FUSE_SETXATTR
+        | FUSE_REMOVEXATTR
+        | FUSE_FALLOCATE
+        | FUSE_COPY_FILE_RANGE => true,
+        FUSE_IOCTL => match args.fetch::<fuse_ioctl_in>() {
+            Ok(ioctl_in) => ioctl_is_mutating(ioctl_in.cmd),
+            Err(_) => true,
+        },
+        FUSE_OPEN => match args.fetch::<fuse_open_in>() {
+            Ok(open_in) => open_is_mutating(open_in.flags),
+            Err(_) => false,
+        },
+        _ => false,
+    }
+}
+
+// Returns true if the ioctl `cmd` may change the filesystem. Only ioctls that just copy data out,
+// like FS_IOC_GETFLAGS, are allowed on read-only mounts. Ioctls without a direction, like a
+// forced journal commit, may change the filesystem too.
+pub(crate) fn ioctl_is_mutating(cmd: u32) -> bool {
+    cmd >> IOC_DIRSHIFT != IOC_READ
+}
+
+// Returns true if opening a file with `flags` opens it for writing or truncates it.
+pub(crate) fn open_is_mutating(flags: u32) -> bool {
+    let flags = flags as i32;
+    flags & libc::O_ACCMODE != 0 || flags & libc::O_TRUNC != 0
+}
+
+// Call the filesystem for one request. Returns an error if the request couldn't be passed to the
+// filesystem, which must then be sent as the reply.
+fn dispatch<T, TransferIn, TransferOut>(
+    fs: &T,
+    req: &Request,
+    h: &fuse_in_header,
+    args: &mut Args,
+    ctx: &ReplyCtx,
+) -> Result<(), i32>
+where
+    T: BentoFilesystem<'static, TransferIn, TransferOut>,
+{
+    let ino = h.nodeid;
+    match h.opcode {
+        FUSE_LOOKUP => {
+            let name = args.fetch_str()?;
+            let reply = ReplyEntry::new(ctx.sender(encode_entry));
+            fs.bento_lookup(req, ino, name, reply);
+        }
+        FUSE_FORGET => {
+            // Forget requests have no reply
+            let forget_in: fuse_forget_in = args.fetch()?;
+            fs.bento_forget(req, ino, forget_in.nlookup);
+            record_stats(ctx.name, 0, 0);
+        }
+        FUSE_BATCH_FORGET => {
+            let batch_in: fuse_batch_forget_in = args.fetch()?;
+            for _ in 0..batch_in.count {
+                let forget: fuse_forget_one = args.fetch()?;
+                fs.bento_forget(req, forget.nodeid, forget.nlookup);
+            }
+            record_stats(ctx.name, 0, 0);
+        }
+        FUSE_GETATTR => {
+            let reply = ReplyAttr::new(ctx.sender(encode_attr));
+            fs.bento_getattr(req, ino, reply);
+        }
+        FUSE_SETATTR => {
+            let setattr_in: fuse_setattr_in = args.fetch()?;
+            let valid = setattr_in.valid;
+            let reply = ReplyAttr::new(ctx.sender(encode_attr));
+            fs.bento_setattr(
+                req,
+                ino,
+                (valid & FATTR_MODE != 0).then_some(setattr_in.mode),
+                (valid & FATTR_UID != 0).then_some(setattr_in.uid),
+                (valid & FATTR_GID != 0).then_some(setattr_in.gid),
+                (valid & FATTR_SIZE != 0).then_some(setattr_in.size),
+                (valid & FATTR_ATIME != 0)
+                    .then_some(Timespec::new(setattr_in.atime as i64, setattr_in.atimensec as i32)),
+                (valid & FATTR_MTIME != 0)
+                    .then_some(Timespec::new(setattr_in.mtime as i64, setattr_in.mtimensec as i32)),
+                (valid & FATTR_FH != 0).then_some(setattr_in.fh),
+                None,
+                None,
+                None,
+                None,
+                reply,
+            );
+        }
+        FUSE_READLINK => {
+            fs.bento_readlink(req, ino, ReplyData::new(ctx.sender(|data: Vec<u8>| data)));
+        }
+        FUSE_SYMLINK => {
+            let name = args.fetch_str()?;
+            let link = args.fetch_str()?;
+            let reply = ReplyEntry::new(ctx.sender(encode_entry));
+            fs.bento_symlink(req, ino, name, Path::new(link), reply);
+        }
+        FUSE_MKNOD => {
+            let mknod_in: fuse_mknod_in = args.fetch()?;
+            let name = args.fetch_str()?;
+            let reply = ReplyEntry::new(ctx.sender(encode_entry));
+            fs.bento_mknod(req, ino, name, mknod_in.mode, mknod_in.rdev, reply);
+        }
+        FUSE_MKDIR => {
+            let mkdir_in: fuse_mkdir_in = args.fetch()?;
+            let name = args.fetch_str()?;
+            let reply = ReplyEntry::new(ctx.sender(encode_entry));
+            fs.bento_mkdir(req, ino, name, mkdir_in.mode, reply);
+        }
+        FUSE_UNLINK => {
+            let name = args.fetch_str()?;
+            fs.bento_unlink(req, ino, name, ReplyEmpty::new(ctx.sender(encode_empty)));
+        }
+        FUSE_RMDIR => {
+            let name = args.fetch_str()?;
+            fs.bento_rmdir(req, ino, name, ReplyEmpty::new(ctx.sender(encode_empty)));
+        }
+        FUSE_RENAME | FUSE_RENAME2 => {
+            let (newdir, flags) = if h.opcode == FUSE_RENAME {
+                let rename_in: fuse_rename_in = args.fetch()?;
+                (rename_in.newdir, 0)
+            } else {
+                let rename_in: fuse_rename2_in = args.fetch()?;
+                (rename_in.newdir, rename_in.flags)
+            };
+            let name = args.fetch_str()?;
+            let newname = args.fetch_str()?;
+            let reply = ReplyEmpty::new(ctx.sender(encode_empty));
+            fs.bento_rename(req, ino, name, newdir, newname, flags, reply);
+        }
+        FUSE_LINK => {
+            let link_in: fuse_link_in = args.fetch()?;
+            let newname = args.fetch_str()?;
+            let reply = ReplyEntry::new(ctx.sender(encode_entry));
+            fs.bento_link(req, link_in.oldnodeid, ino, newname, reply);
+        }
+        FUSE_OPEN | FUSE_OPENDIR => {
+            let open_in: fuse_open_in = args.fetch()?;
+            let reply = ReplyOpen::new(ctx.sender(|(fh, flags): (u64, u32)| {
+                to_vec(&fuse_open_out {
+                    fh,
+                    open_flags: flags,
+                    padding: 0,
+                })
+            }));
+            if h.opcode == FUSE_OPEN {
+                fs.bento_open(req, ino, open_in.flags, reply);
+            } else {
+                fs.bento_opendir(req, ino, open_in.flags, reply);
+            }
+        }
+        FUSE_READ => {
+            let read_in: fuse_read_in = args.fetch()?;
+            let reply = ReplyData::new(ctx.sender(|data: Vec<u8>| data));
+            fs.bento_read(
+                req,
+                ino,
+                read_in.fh,
+                read_in.offset as i64,
+                read_in.size,
+                reply,
+            );
+        }
+        FUSE_WRITE => {
+            let write_in: fuse_write_in = args.fetch()?;
+            let data = args.fetch_bytes(write_in.size as usize)?;
+            let reply = ReplyWrite::new(ctx.sender(|size: u32| {
+                to_vec(&fuse_write_out {
+                    size,
+                    padding: 0,
+                })
+            }));
+            fs.bento_write(
+                req,
+                ino,
+                write_in.fh,
+                write_in.offset as i64,
+                data,
+                write_in.write_flags,
+                reply,
+            );
+        }
+        FUSE_STATFS => {
+            let reply = ReplyStatfs::new(ctx.sender(|st: Statfs| {
+                to_vec(&fuse_kstatfs {
+                    blocks: st.blocks,
+                    bfree: st.bfree,
+                    bavail: st.bavail,
+                    files: st.files,
+                    ffree: st.ffree,
+                    bsize: st.bsize,
+                    namelen: st.namelen,
+                    frsize: st.frsize,
+                    padding: 0,
+                    spare: [0; 6],
+                })
+            }));
+            fs.bento_statfs(req, ino, reply);
+        }
+        FUSE_RELEASE | FUSE_RELEASEDIR => {
+            let release_in: fuse_release_in = args.fetch()?;
+            let reply = ReplyEmpty::new(ctx.sender(encode_empty));
+            if h.opcode == FUSE_RELEASE {
+                fs.bento_release(
+                    req,
+                    ino,
+                    release_in.fh,
+                    release_in.flags,
+                    release_in.lock_owner,
+                    release_in.release_flags & FUSE_RELEASE_FLUSH != 0,
+                    reply,
+                );
+            } else {
+                fs.bento_releasedir(req, ino, release_in.fh, release_in.flags, reply);
+            }
+        }
+        FUSE_FSYNC | FUSE_FSYNCDIR => {
+            let fsync_in: fuse_fsync_in = args.fetch()?;
+            let datasync = fsync_in.fsync_flags & FUSE_FSYNC_FDATASYNC != 0;
+            let reply = ReplyEmpty::new(ctx.sender(encode_empty));
+            if h.opcode == FUSE_FSYNC {
+                fs.bento_fsync(req, ino, fsync_in.fh, datasync, reply);
+            } else {
+                fs.bento_fsyncdir(req, ino, fsync_in.fh, datasync, reply);
+            }
+        }
+        FUSE_SETXATTR => {
+            let setxattr_in: fuse_setxattr_in = args.fetch()?;
+            let name = args.fetch_str()?;
+            let value = args.fetch_bytes(setxattr_in.size as usize)?;
+            let reply = ReplyEmpty::new(ctx.sender(encode_empty));
+            fs.bento_setxattr(req, ino, name, value, setxattr_in.flags, 0, reply);
+        }
+        FUSE_GETXATTR | FUSE_LISTXATTR => {
+            let getxattr_in: fuse_getxattr_in = args.fetch()?;
+            let reply = ReplyXattr::new(ctx.sender(|xattr: Xattr| match xattr {
+                Xattr::Size(size) => to_vec(&fuse_getxattr_out {
+                    size,
+                    padding: 0,
+                }),
+                Xattr::Data(data) => data,
+            }));
+            if h.opcode == FUSE_GETXATTR {
+                let name = args.fetch_str()?;
+                fs.bento_getxattr(req, ino, name, getxattr_in.size, reply);
+            } else {
+                fs.bento_listxattr(req, ino, getxattr_in.size, reply);
+            }
+        }
+        FUSE_REMOVEXATTR => {
+            let name = args.fetch_str()?;
+            fs.bento_removexattr(req, ino, name, ReplyEmpty::new(ctx.sender(encode_empty)));
+        }
+        FUSE_FLUSH => {
+            let flush_in: fuse_flush_in = args.fetch()?;
+            let reply = ReplyEmpty::new(ctx.sender(encode_empty));
+            fs.bento_flush(req, ino, flush_in.fh, flush_in.lock_owner, reply);
+        }
+        FUSE_READDIR => {
+            let read_in: fuse_read_in = args.fetch()?;
+            let reply = ReplyDirectory::new(read_in.size as usize, ctx.sender(encode_dirents));
+            fs.bento_readdir(req, ino, read_in.fh, read_in.offset as i64, reply);
+        }
+        FUSE_READDIRPLUS => {
+            let read_in: fuse_read_in = args.fetch()?;
+            let sender = ctx.sender(encode_direntplus);
+            let reply = ReplyDirectoryPlus::new(read_in.size as usize, sender);
+            fs.bento_readdirplus(req, ino, read_in.fh, read_in.offset as i64, reply);
+        }
+        FUSE_GETLK => {
+            let lk_in: fuse_lk_in = args.fetch()?;
+            let reply = ReplyLock::new(ctx.sender(|lock: Lock| {
+                to_vec(&fuse_file_lock {
+                    start: lock.start,
+                    end: lock.end,
+                    typ: lock.typ,
+                    pid: lock.pid,
+                })
+            }));
+            fs.bento_getlk(
+                req,
+                ino,
+                lk_in.fh,
+                lk_in.owner,
+                lk_in.lk.start,
+                lk_in.lk.end,
+                lk_in.lk.typ,
+                lk_in.lk.pid,
+                reply,
+            );
+        }
+        FUSE_SETLK | FUSE_SETLKW => {
+            let lk_in: fuse_lk_in = args.fetch()?;
+            let reply = ReplyEmpty::new(ctx.sender(encode_empty));
+            fs.bento_setlk(
+                req,
+                ino,
+                lk_in.fh,
+                lk_in.owner,
+                lk_in.lk.start,
+                lk_in.lk.end,
+                lk_in.lk.typ,
+                lk_in.lk.pid,
+                h.opcode == FUSE_SETLKW,
+                reply,
+            );
+        }
+        FUSE_ACCESS => {
+            let access_in: fuse_access_in = args.fetch()?;
+            let reply = ReplyEmpty::new(ctx.sender(encode_empty));
+            fs.bento_access(req, ino, access_in.mask, reply);
+        }
+        FUSE_CREATE => {
+            let create_in: fuse_create_in = args.fetch()?;
+            let name = args.fetch_str()?;
+            let reply = ReplyCreate::new(ctx.sender(
+                |(ttl, attr, generation, fh, flags): (Timespec, FileAttr, u64, u64, u32)| {
+                    let mut data = to_vec(&entry_out(&ttl, &attr, generation));
+                    data.extend_from_slice(as_bytes(&fuse_open_out {
+                        fh,
+                        open_flags: flags,
+                        padding: 0,
+                    }));
+                    data
+                },
+            ));
+            fs.bento_create(req, ino, name, create_in.mode, create_in.flags, reply);
+        }
+        FUSE_BMAP => {
+            let bmap_in: fuse_bmap_in = args.fetch()?;
+            let reply = ReplyBmap::new(ctx.sender(|block: u64| to_vec(&block)));
+            fs.bento_bmap(req, ino, bmap_in.blocksize, bmap_in.block, reply);
+        }
+        FUSE_IOCTL => {
+            let ioctl_in: fuse_ioctl_in = args.fetch()?;
+            let in_data = args.fetch_bytes(ioctl_in.in_size as usize)?;
+            let reply = ReplyIoctl::new(ctx.sender(|(result, data): (i32, Vec<u8>)| {
+                let mut out = to_vec(&fuse_ioctl_out {
+                    result,
+                    flags: 0,
+                    in_iovs: 0,
+                    out_iovs: 0,
+                });
+                out.extend_from_slice(&data);
+                out
+            }));
+            fs.bento_ioctl(
+                req,
+                ino,
+                ioctl_in.fh,
+                ioctl_in.flags,
+                ioctl_in.cmd,
+                in_data,
+                ioctl_in.out_size,
+                reply,
+            );
+        }
+        FUSE_FALLOCATE => {
+            let fallocate_in: fuse_fallocate_in = args.fetch()?;
+            let reply = ReplyEmpty::new(ctx.sender(encode_empty));
+            fs.bento_fallocate(
+                req,
+                ino,
+                fallocate_in.fh,
+                fallocate_in.offset as i64,
+                fallocate_in.length as i64,
+                fallocate_in.mode,
+                reply,
+            );
+        }
+        FUSE_LSEEK => {
+            let lseek_in: fuse_lseek_in = args.fetch()?;
+            let reply = ReplyLseek::new(ctx.sender(|offset: i64| to_vec(&offset)));
+            fs.bento_lseek(
+                req,
+                ino,
+                lseek_in.fh,
+                lseek_in.offset as i64,
+                lseek_in.whence,
+                reply,
+            );
+        }
+        FUSE_COPY_FILE_RANGE => {
+            let copy_in: fuse_copy_file_range_in = args.fetch()?;
+            let reply = ReplyWrite::new(ctx.sender(|size: u32| {
+                to_vec(&fuse_write_out {
+                    size,
+                    padding: 0,
+                })
+            }));
+            fs.bento_copy_file_range(
+                req,
+                ino,
+                copy_in.fh_in,
+                copy_in.off_in as i64,
+                copy_in.nodeid_out,
+                copy_in.fh_out,
+                copy_in.off_out as i64,
+                copy_in.len,
+                copy_in.flags as u32,
+                reply,
+            );
+        }
+        // Interrupts aren't supported, so the kernel stops sending them
+        FUSE_INTERRUPT => return Err(ENOSYS),
+        _ => return Err(ENOSYS),
+    }
+    Ok(())
+}
+
+// Operation names match the names used in the statistics of the kernel version.
+fn opcode_name(opcode: u32) -> &'static str {
+    match opcode {
+        FUSE_LOOKUP => "lookup",
+        FUSE_FORGET => "forget",
+        FUSE_GETATTR => "getattr",
+        FUSE_SETATTR => "setattr",
+        FUSE_READLINK => "readlink",
+        FUSE_SYMLINK => "symlink",
+        FUSE_MKNOD => "mknod",
+        FUSE_MKDIR => "mkdir",
+        FUSE_UNLINK => "unlink",
+        FUSE_RMDIR => "rmdir",
+        FUSE_RENAME => "rename",
+        FUSE_LINK => "link",
+        FUSE_OPEN => "open",
+        FUSE_READ => "read",
+        FUSE_WRITE => "write",
+        FUSE_STATFS => "statfs",
+        FUSE_RELEASE => "release",
+        FUSE_FSYNC => "fsync",
+        FUSE_SETXATTR => "setxattr",
+        FUSE_GETXATTR => "getxattr",
+        FUSE_LISTXATTR => "listxattr",
+        FUSE_REMOVEXATTR => "removexattr",
+        FUSE_FLUSH => "flush",
+        FUSE_INIT => "init",
+        FUSE_OPENDIR => "opendir",
+        FUSE_READDIR => "readdir",
+        FUSE_RELEASEDIR => "releasedir",
+        FUSE_FSYNCDIR => "fsyncdir",
+        FUSE_GETLK => "getlk",
+        FUSE_SETLK => "setlk",
+        FUSE_SETLKW => "setlkw",
+        FUSE_ACCESS => "access",
+        FUSE_CREATE => "create",
+        FUSE_INTERRUPT => "interrupt",
+        FUSE_BMAP => "bmap",
+        FUSE_DESTROY => "destroy",
+        FUSE_IOCTL => "ioctl",
+        FUSE_BATCH_FORGET => "batch_forget",
+        FUSE_FALLOCATE => "fallocate",
+        FUSE_READDIRPLUS => "readdirplus",
+        FUSE_RENAME2 => "rename2",
+        FUSE_LSEEK => "lseek",
+        FUSE_COPY_FILE_RANGE => "copy_file_range",
+        _ => "unknown",
+    }
+}
@@ -0,0 +1,201 @@
+use std::cmp;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fmt::Write;
+use std::ptr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use std::time::Instant;
+
+/// Number of latency buckets. Bucket `i` counts requests that took less than `2^i` ns, but at
+/// least `2^(i-1)` ns. The last bucket also counts all slower requests.
+pub const LATENCY_BUCKETS: usize = 40;
+
+/// Statistics of one operation.
+#[derive(Clone)]
+pub struct OpStats {
+    /// Number of requests
+    pub count: u64,
+    /// Total time spent handling the requests in nanoseconds
+    pub total_ns: u64,
+    /// Time spent handling the slowest request in nanoseconds
+    pub max_ns: u64,
+    /// Number of failed requests per errno
+    pub errors: BTreeMap<i32, u64>,
+    /// Latency histogram, see `LATENCY_BUCKETS`
+    pub latency: [u64; LATENCY_BUCKETS],
+}
+
+impl OpStats {
+    fn new() -> Self {
+        OpStats {
+            count: 0,
+            total_ns: 0,
+            max_ns: 0,
+            errors: BTreeMap::new(),
+            latency: [0; LATENCY_BUCKETS],
+        }
+    }
+
+    fn record(&mut self, ret: i32, elapsed_ns: u64) {
+        self.count += 1;
+        self.total_ns += elapsed_ns;
+        self.max_ns = cmp::max(self.max_ns, elapsed_ns);
+        if ret < 0 {
+            *self.errors.entry(-ret).or_insert(0) += 1;
+        }
+        let bucket = (64 - elapsed_ns.leading_zeros()) as usize;
+        self.latency[cmp::min(bucket, LATENCY_BUCKETS - 1)] += 1;
+    }
+}
+
+// Statistics of the mounted filesystem, keyed by operation name
+static STATS: AtomicPtr<Mutex<BTreeMap<&'static str, OpStats>>> = AtomicPtr::new(ptr::null_mut());
+
+fn stats() -> &'static Mutex<BTreeMap<&'static str, OpStats>> {
+    let mut stats = STATS.load(Ordering::SeqCst);
+    if stats.is_null() {
+        let new = Box::into_raw(Box::new(Mutex::new(BTreeMap::new())));
+        match STATS.compare_exchange(ptr::null_mut(), new, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => stats = new,
+            Err(x) => {
+                unsafe { drop(Box::from_raw(new)) };
+                stats = x;
+            }
+        }
+    }
+    unsafe { &*stats }
+}
+
+// Set by the `stats` mount option
+static DUMP_ON_DESTROY: AtomicBool = AtomicBool::new(false);
+
+/// Record a request handled by the filesystem.
+///
+/// Arguments:
+/// * `name: &'static str` - Name of the operation.
+/// * `ret: i32` - Result of the request, a negative errno if it failed.
+/// * `elapsed_ns: u64` - Time spent handling the request in nanoseconds.
+pub fn record_stats(name: &'static str, ret: i32, elapsed_ns: u64) {
+    if let Ok(mut stats) = stats().lock() {
+        stats.entry(name).or_insert_with(OpStats::new).record(ret, elapsed_ns);
+    }
+}
+
+/// Measures how long a request takes and records it.
+///
+/// `impl_filesystem` attaches the timer to the reply with `Timed::timed`, so the request is
+/// recorded with its result when the reply is answered, including replies that are deferred to
+/// another thread. A timer that is dropped without `finish` records a success, which is used for
+/// requests that have no reply, like `forget`.
+pub struct OpTimer {
+    name: &'static str,
+    start: Instant,
+}
+
+impl OpTimer {
+    /// Start measuring a request.
+    ///
+    /// Arguments:
+    /// * `name: &'static str` - Name of the operation.
+    pub fn start(name: &'static str) -> Self {
+        OpTimer {
+            name,
+            start: Instant::now(),
+        }
+    }
+
+    /// Stop measuring and record the result of the request.
+    ///
+    /// Arguments:
+    /// * `ret: i32` - Result of the request, a negative errno if it failed.
+    pub fn finish(self, ret: i32) {
+        self.record(ret);
+        std::mem::forget(self);
+    }
+
+    fn record(&self, ret: i32) {
+        let elapsed = self.start.elapsed();
+        let elapsed_ns = elapsed.as_secs() * 1_000_000_000 + elapsed.subsec_nanos() as u64;
+        record_stats(self.name, ret, elapsed_ns);
+    }
+}
+
+impl Drop for OpTimer {
+    fn drop(&mut self) {
+        self.record(0);
+    }
+}
+
+/// Get the statistics of every operation that was requested at least once.
+pub fn stats_snapshot() -> BTreeMap<&'static str, OpStats> {
+    match stats().lock() {
+        Ok(stats) => stats.clone(),
+        Err(_) => BTreeMap::new(),
+    }
+}
+
+/// Clear the statistics of all operations.
+pub fn reset_stats() {
+    if let Ok(mut stats) = stats().lock() {
+        stats.clear();
+    }
+}
+
+/// Write the statistics of all operations as text.
+///
+/// The output has the same format as `/sys/kernel/debug/bento/<name>/stats` in the kernel
+/// version. Every operation gets a line with its request count and average and maximum latency,
+/// followed by a line with the number of failures per errno and a line with the non-empty
+/// latency buckets, labeled by their upper bound in nanoseconds.
+///
+/// Statistics are also written to stderr when the filesystem is unmounted if it was mounted with
+/// `-o stats`.
+///
+/// Arguments:
+/// * `w: &mut W` - Where to write the statistics.
+pub fn dump_stats<W: Write>(w: &mut W) -> fmt::Result {
+    let stats = stats_snapshot();
+    for (name, op) in stats.iter() {
+        writeln!(
+            w,
+            "{}: count {} avg_ns {} max_ns {}",
+            name,
+            op.count,
+            op.total_ns / cmp::max(op.count, 1),
+            op.max_ns
+        )?;
+        if !op.errors.is_empty() {
+            write!(w, "  errors:")?;
+            for (errno, count) in op.errors.iter() {
+                write!(w, " {}={}", errno, count)?;
+            }
+            writeln!(w)?;
+        }
+        write!(w, "  latency_ns:")?;
+        for (bucket, count) in op.latency.iter().enumerate().filter(|(_, count)| **count > 0) {
+            if bucket == LATENCY_BUCKETS - 1 {
+                write!(w, " inf={}", count)?;
+            } else {
+                write!(w, " {}={}", 1u64 << bucket, count)?;
+            }
+        }
+        writeln!(w)?;
+    }
+    Ok(())
+}
+
+#[doc(hidden)]
+pub fn set_dump_stats_on_destroy(dump: bool) {
+    DUMP_ON_DESTROY.store(dump, Ordering::SeqCst);
+}
+
+#[doc(hidden)]
+pub fn dump_stats_on_destroy() {
+    if DUMP_ON_DESTROY.load(Ordering::SeqCst) {
+        let mut out = String::new();
+        if dump_stats(&mut out).is_ok() {
+            eprint!("{}", out);
+        }
+    }
+}
@@ -44,6 +44,22 @@ pub struct HelloFS {
 
 impl HelloFS {
     const NAME: &'static str = "hello_client\0";
+
+    // Parse a server address of the form "a.b.c.d:port".
+    fn parse_server_addr(server: &str) -> Option<SocketAddrV4> {
+        let mut parts = server.splitn(2, ':');
+        let ip = parts.next()?;
+        let port = parts.next()?.parse::<u16>().ok()?;
+        let octets = ip
+            .split('.')
+            .map(|octet| octet.parse::<u8>().ok())
+            .collect::<Option<Vec<u8>>>()?;
+        if octets.len() != 4 {
+            return None;
+        }
+        let addr = Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]);
+        Some(SocketAddrV4::new(addr, port))
+    }
 }
 
 impl BentoFilesystem<'_> for HelloFS {
@@ -55,6 +71,7 @@ impl BentoFilesystem<'_> for HelloFS {
         &mut self,
         _req: &Request,
         _devname: &OsStr,
+        opts: &MountOptions,
         outarg: &mut FuseConnInfo,
     ) -> Result<(), i32> {
         outarg.proto_major = BENTO_KERNEL_VERSION;
@@ -70,7 +87,10 @@ impl BentoFilesystem<'_> for HelloFS {
         outarg.congestion_threshold = 0;
         outarg.time_gran = 1;
 
-        let srv_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1234);
+        let srv_addr = match opts.get_str("server") {
+            Some(server) => Self::parse_server_addr(server).ok_or(libc::EINVAL)?,
+            None => SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1234),
+        };
         let mut stream = match TcpStream::connect(SocketAddr::V4(srv_addr)) {
             Ok(x) => x,
             Err(_) => return Err(-1),
@@ -1,7 +1,6 @@
 extern crate alloc;
 #[macro_use]
 extern crate bento_utils;
-extern crate fuse;
 extern crate capnp;
 extern crate core;
 extern crate time;
@@ -15,9 +14,11 @@ use time::Timespec;
 
 use hello_ll::HelloFS;
 
+use bento_utils::fuse;
 use fuse::*;
 use bento_utils::BentoFilesystem;
 use bento_utils::FuseConnInfo;
+use bento_utils::MountOptions;
 
 impl_filesystem!(HelloFS);
 
@@ -32,7 +33,8 @@ fn main() {
     };
 
     let mountpoint = env::args_os().nth(1).unwrap();
-    let options: Vec::<&OsStr> = Vec::<&OsStr>::new();
+    let opts_arr = MountOptions::from_args().fuse_options();
+    let options = opts_arr.iter().map(OsStr::new).collect::<Vec<&OsStr>>();
 
     fuse::mount(fs, &mountpoint, &options).unwrap();
 }
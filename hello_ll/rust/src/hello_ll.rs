@@ -102,6 +102,7 @@ impl BentoFilesystem<'_,i32,HelloState> for HelloFS {
         &mut self,
         _req: &Request,
         devname: &OsStr,
+        _opts: &MountOptions,
         outarg: &mut FuseConnInfo,
     ) -> Result<(), i32> {
         outarg.proto_major = BENTO_KERNEL_VERSION;
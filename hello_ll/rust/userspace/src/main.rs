@@ -1,7 +1,6 @@
 extern crate alloc;
 #[macro_use]
 extern crate bento_utils;
-extern crate fuse;
 extern crate core;
 extern crate serde;
 extern crate time;
@@ -17,9 +16,11 @@ use time::Timespec;
 use hello_ll::HelloFS;
 use bento_utils::Disk;
 
+use bento_utils::fuse;
 use fuse::*;
 use bento_utils::BentoFilesystem;
 use bento_utils::FuseConnInfo;
+use bento_utils::MountOptions;
 
 impl_filesystem!(HelloFS);
 
@@ -35,13 +36,57 @@ fn main() {
     };
 
     let mountpoint = env::args_os().nth(2).unwrap();
-    let mut opts_arr = vec!["-o", fsname_arg];
-    if let Some(arg) = env::args_os().nth(3) {
-        if arg.to_str().unwrap() == "blkdev" {
-            opts_arr.append(&mut vec!["-o", "blkdev"]);
-        }
-    }
+    let mut opts_arr = vec!["-o".to_string(), fsname_arg.to_string()];
+    opts_arr.append(&mut MountOptions::from_args().fuse_options());
     let options = opts_arr.iter().map(OsStr::new).collect::<Vec<&OsStr>>();
 
     fuse::mount(fs, &mountpoint, &options).unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bento_utils::{MemoryBackend, TestDriver};
+    use hello_ll::HelloState;
+
+    fn mount() -> TestDriver<'static, HelloFS, i32, HelloState> {
+        let disk: Disk = Disk::from_backend(Box::new(MemoryBackend::new(4096)), 4096);
+        let fs = HelloFS {
+            disk: Some(RwLock::new(disk)),
+            diskname: Some("hello.img".to_string()),
+        };
+        let mut driver = TestDriver::new(fs);
+        driver.init("hello.img", "").unwrap();
+        driver
+    }
+
+    // The file length is kept in a static, so everything that changes it is in one test.
+    #[test]
+    fn hello_file() {
+        let driver = mount();
+
+        let root = driver.getattr(1).unwrap();
+        assert_eq!(root.kind, FileType::Directory);
+        let attr = driver.lookup(1, "hello").unwrap();
+        assert_eq!(attr.ino, 2);
+        assert_eq!(attr.kind, FileType::RegularFile);
+        assert_eq!(driver.lookup(1, "missing").unwrap_err(), libc::ENOENT);
+
+        let dir = driver.opendir(1, 0).unwrap();
+        let names: Vec<String> = driver
+            .readdir_all(1, dir.fh)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name.into_string().unwrap())
+            .collect();
+        assert_eq!(names, vec![".", "hello", ".."]);
+        assert_eq!(driver.open(1, 0).unwrap_err(), libc::EISDIR);
+
+        let file = driver.open(2, 0).unwrap();
+        assert_eq!(driver.write(2, file.fh, 0, b"hello, bento\n", 0).unwrap(), 13);
+        assert_eq!(driver.getattr(2).unwrap().size, 13);
+        assert_eq!(driver.read(2, file.fh, 0, 4096).unwrap(), b"hello, bento\n".to_vec());
+        assert_eq!(driver.read(2, file.fh, 7, 4096).unwrap(), b"bento\n".to_vec());
+        driver.fsync(2, file.fh, false).unwrap();
+    }
+}
@@ -100,6 +100,7 @@ impl BentoFilesystem<'_,HelloState> for HelloFS {
         &mut self,
         _req: &Request,
         devname: &OsStr,
+        _opts: &MountOptions,
         outarg: &mut FuseConnInfo,
     ) -> Result<(), i32> {
         outarg.proto_major = BENTO_KERNEL_VERSION;
@@ -315,11 +316,12 @@ impl BentoFilesystem<'_,HelloState> for HelloFS {
         }
     }
 
-    fn bento_update_transfer(&mut self, state_opt: Option<HelloState>) {
+    fn bento_update_transfer(&mut self, state_opt: Option<HelloState>) -> Result<(), i32> {
         if let Some(state) = state_opt {
             LEN.store(state.len, atomic::Ordering::SeqCst);
             let disk = RwLock::new(Disk::new(state.diskname.as_str(), 4096));
             self.disk = Some(disk);
         }
+        return Ok(());
     }
 }
@@ -1,7 +1,6 @@
 extern crate alloc;
 #[macro_use]
 extern crate bento_utils;
-extern crate fuse;
 extern crate core;
 extern crate serde;
 extern crate time;
@@ -17,9 +16,11 @@ use time::Timespec;
 use hello_ll::HelloFS;
 use bento_utils::Disk;
 
+use bento_utils::fuse;
 use fuse::*;
 use bento_utils::BentoFilesystem;
 use bento_utils::FuseConnInfo;
+use bento_utils::MountOptions;
 
 impl_filesystem!(HelloFS);
 
@@ -34,12 +35,8 @@ fn main() {
     };
 
     let mountpoint = env::args_os().nth(2).unwrap();
-    let mut opts_arr = vec!["-o", fsname_arg];
-    if let Some(arg) = env::args_os().nth(3) {
-        if arg.to_str().unwrap() == "blkdev" {
-            opts_arr.append(&mut vec!["-o", "blkdev"]);
-        }
-    }
+    let mut opts_arr = vec!["-o".to_string(), fsname_arg.to_string()];
+    opts_arr.append(&mut MountOptions::from_args().fuse_options());
     let options = opts_arr.iter().map(OsStr::new).collect::<Vec<&OsStr>>();
 
     fuse::mount(fs, &mountpoint, &options).unwrap();
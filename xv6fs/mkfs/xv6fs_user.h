@@ -46,6 +46,8 @@ struct dinode {
   unsigned long long size; // Size of file (bytes)
 
   uint addrs[NDIRECT + 2]; // Data block addresses
+  uint flags; // Inode flags (FS_IOC_GETFLAGS/FS_IOC_SETFLAGS)
+  uint reserved[15]; // Pads the inode to 128 bytes
 };
 
 // some "special block numbers"
@@ -51,6 +51,7 @@ pub struct InodeInternal {
     pub inode_type: u16,
     pub major: u16,
     pub minor: u16,
+    pub flags: u32,
     pub nlink: u16,
     pub size: u64,
     pub addrs: [u32; NDIRECT as usize + 2],
@@ -63,6 +64,7 @@ impl InodeInternal {
             inode_type: 0,
             major: 0,
             minor: 0,
+            flags: 0,
             nlink: 0,
             size: 0,
             addrs: [0; NDIRECT as usize + 2],
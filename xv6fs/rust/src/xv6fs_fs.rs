@@ -22,10 +22,10 @@ use alloc::collections::btree_map::BTreeMap;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 
-use core::cmp::min;
+use core::cmp::{max, min};
 use core::mem;
 use core::str;
-use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::sync::atomic::Ordering;
 
 use datablock::DataBlock;
 
@@ -47,10 +47,6 @@ use std::sync::*;
 
 use time::Timespec;
 
-static LAST_BLOCK: AtomicUsize = AtomicUsize::new(0);
-static LAST_INODE: AtomicUsize = AtomicUsize::new(0);
-static FIRST_I_LOOP: AtomicBool = AtomicBool::new(true);
-
 impl Xv6FileSystem {
     // Read xv6 superblock from disk
     fn readsb(&mut self) -> Result<(), libc::c_int> {
@@ -98,6 +94,17 @@ impl Xv6FileSystem {
         return Ok(());
     }
 
+    // Report where the block and inode allocators will resume scanning.
+    pub fn alloc_state(&self) -> Xv6fsAllocState {
+        let sb = self.sb.as_ref().unwrap();
+        Xv6fsAllocState {
+            last_block: self.last_block.load(Ordering::SeqCst) as u32,
+            last_inode: self.last_inode.load(Ordering::SeqCst) as u32,
+            nblocks: sb.nblocks,
+            ninodes: sb.ninodes,
+        }
+    }
+
     // Allocate a block on disk, using a slightly different alloc strategy from xv6.
     // xv6 scans from 0th block and allocates the first available block, we scan from the latest used block since last boot.
     fn balloc(&self, handle: &Handle) -> Result<u32, libc::c_int> {
@@ -106,7 +113,7 @@ impl Xv6FileSystem {
         let mut allocated_block = None;
 
         // Bitmap operations on bitmap blocks
-        let most_recent = LAST_BLOCK.load(Ordering::SeqCst);
+        let most_recent = self.last_block.load(Ordering::SeqCst);
         let mut first = true;
         // last_segment is the bitmap block ID and block_offset is the offset for 'most_recent'
         let last_segment = most_recent - most_recent % BPB;
@@ -150,7 +157,7 @@ impl Xv6FileSystem {
             }
             // extract new block ID x
             if let Some(x) = allocated_block {
-                LAST_BLOCK.store(x as usize, Ordering::SeqCst);
+                self.last_block.store(x as usize, Ordering::SeqCst);
                 self.bzero(x as usize, &handle)?;
                 return Ok(x);
             }
@@ -194,9 +201,10 @@ impl Xv6FileSystem {
         return Ok(());
     }
 
-    pub fn iinit(&mut self) {
-        if self.readsb().is_err() {
+    pub fn iinit(&mut self) -> Result<(), libc::c_int> {
+        if let Err(x) = self.readsb() {
             println!("Unable to read super block from disk.");
+            return Err(x);
         }
 
         let mut inode_vec: Vec<RwLock<Inode>> = Vec::with_capacity(NINODE);
@@ -211,10 +219,13 @@ impl Xv6FileSystem {
 
         let sb = self.sb.as_mut().unwrap();
 
-        if self.log.is_none() {
+        // A read-only mount doesn't open the journal, so committed transactions in the log are
+        // not replayed and the disk is left untouched.
+        if self.log.is_none() && !self.read_only {
             let disk_ref = Arc::clone(self.disk.as_ref().unwrap());
             let disk_ref2 = Arc::clone(self.disk.as_ref().unwrap());
-            let log = Journal::new_from_disk(disk_ref, disk_ref2, sb.logstart as u64, sb.nlog as i32, BSIZE as i32).unwrap();
+            let log = Journal::new_from_disk(disk_ref, disk_ref2, sb.logstart as u64, sb.nlog as i32, BSIZE as i32)
+                .ok_or(libc::EIO)?;
             self.log = Some(log);
         }
         println!(
@@ -227,13 +238,94 @@ impl Xv6FileSystem {
             sb.inodestart,
             sb.bmapstart
             );
+        return Ok(());
+    }
+
+    // Copy the inode cache and allocator cursors so they can be handed to a new module.
+    pub fn icache_snapshot(&self) -> Result<Xv6CacheState, libc::c_int> {
+        let icache = self.ilock_cache.as_ref().ok_or(libc::EIO)?;
+        let map = self.icache_map.as_ref().ok_or(libc::EIO)?.read().map_err(|_| libc::EIO)?;
+
+        let mut inodes = Vec::new();
+        for (idx, inode_lock) in icache.iter().enumerate() {
+            let inode = inode_lock.read().map_err(|_| libc::EIO)?;
+            let nref = *inode.nref.read().map_err(|_| libc::EIO)?;
+            let internals = inode.internals.read().map_err(|_| libc::EIO)?;
+            if nref == 0 && internals.valid == 0 {
+                continue;
+            }
+            inodes.push(Xv6CachedInode {
+                idx: idx,
+                inum: inode.inum,
+                nref: nref,
+                valid: internals.valid,
+                inode_type: internals.inode_type,
+                major: internals.major,
+                minor: internals.minor,
+                flags: internals.flags,
+                nlink: internals.nlink,
+                size: internals.size,
+                addrs: internals.addrs.to_vec(),
+            });
+        }
+
+        return Ok(Xv6CacheState {
+            inodes: inodes,
+            icache_map: map.iter().map(|(inum, idx)| (*inum, *idx)).collect(),
+            last_block: self.last_block.load(Ordering::SeqCst),
+            last_inode: self.last_inode.load(Ordering::SeqCst),
+            first_i_loop: self.first_i_loop.load(Ordering::SeqCst),
+        });
+    }
+
+    // Fill the inode cache created by iinit with the cache of the previous module. Inodes are
+    // tagged with the device of the newly opened disk, since the disk is opened again after an
+    // upgrade.
+    pub fn icache_restore(&self, cache: Xv6CacheState) -> Result<(), libc::c_int> {
+        let icache = self.ilock_cache.as_ref().ok_or(libc::EIO)?;
+        let dev_id = self.disk.as_ref().ok_or(libc::EIO)?.as_raw_fd();
+        for cached in cache.inodes {
+            let inode_lock = match icache.get(cached.idx) {
+                Some(x) => x,
+                None => continue,
+            };
+            let mut inode = inode_lock.write().map_err(|_| libc::EIO)?;
+            inode.dev = dev_id as u32;
+            inode.inum = cached.inum;
+            *inode.nref.write().map_err(|_| libc::EIO)? = cached.nref;
+            let mut internals = inode.internals.write().map_err(|_| libc::EIO)?;
+            internals.valid = cached.valid;
+            internals.inode_type = cached.inode_type;
+            internals.major = cached.major;
+            internals.minor = cached.minor;
+            internals.flags = cached.flags;
+            internals.nlink = cached.nlink;
+            internals.size = cached.size;
+            if cached.addrs.len() == internals.addrs.len() {
+                internals.addrs.copy_from_slice(&cached.addrs);
+            } else {
+                internals.valid = 0;
+            }
+        }
+
+        let mut map = self.icache_map.as_ref().ok_or(libc::EIO)?.write().map_err(|_| libc::EIO)?;
+        for (inum, idx) in cache.icache_map {
+            if idx < icache.len() {
+                map.insert(inum, idx);
+            }
+        }
+
+        self.last_block.store(cache.last_block, Ordering::SeqCst);
+        self.last_inode.store(cache.last_inode, Ordering::SeqCst);
+        self.first_i_loop.store(cache.first_i_loop, Ordering::SeqCst);
+        return Ok(());
     }
 
     pub fn ialloc<'a>(&'a self, i_type: u16, handle: &Handle) -> Result<CachedInode<'a>, libc::c_int> {
         let sb = self.sb.as_ref().unwrap();
         let num_inodes = sb.ninodes;
 
-        let most_recent = LAST_INODE.load(Ordering::SeqCst);
+        let most_recent = self.last_inode.load(Ordering::SeqCst);
         let mut first = true;
         let last_segment = most_recent - most_recent % IPB;
         let mut block_inum = last_segment;
@@ -243,8 +335,8 @@ impl Xv6FileSystem {
             let disk = self.disk.as_ref().unwrap();
             let iblock_new = iblock(block_inum, &sb) as u64;
             /* TODO: not actually correct for reusing blocks */
-            let is_first_loop = FIRST_I_LOOP.load(Ordering::SeqCst);
-            let curr_most_recent = LAST_INODE.load(Ordering::SeqCst);
+            let is_first_loop = self.first_i_loop.load(Ordering::SeqCst);
+            let curr_most_recent = self.last_inode.load(Ordering::SeqCst);
             let curr_last_segment = curr_most_recent - curr_most_recent % IPB;
             let new_blk = (iblock_new > iblock(curr_last_segment, &sb) as u64) && is_first_loop;
             if new_blk {
@@ -272,6 +364,7 @@ impl Xv6FileSystem {
                     if dinode.inode_type == 0 {
                         dinode.major = 0;
                         dinode.minor = 0;
+                        dinode.flags = 0;
                         dinode.size = 0;
                         for addr_mut in dinode.addrs.iter_mut() {
                             *addr_mut = 0;
@@ -283,7 +376,7 @@ impl Xv6FileSystem {
                         core::mem::drop(locked_bh);
                         handle.journal_write(&mut bh);
                         if !first || inum > curr_most_recent {
-                            LAST_INODE.store(inum as usize, Ordering::SeqCst);
+                            self.last_inode.store(inum as usize, Ordering::SeqCst);
                         }
                         return self.iget(inum as u64);
                     }
@@ -292,7 +385,7 @@ impl Xv6FileSystem {
                 if block_inum >= num_inodes as usize {
                     block_inum = 0;
                     first = false;
-                    FIRST_I_LOOP.store(false, Ordering::SeqCst);
+                    self.first_i_loop.store(false, Ordering::SeqCst);
                 }
             } else {
                 let mut bh = disk.bread(iblock_new)?;
@@ -318,6 +411,7 @@ impl Xv6FileSystem {
                     if dinode.inode_type == 0 {
                         dinode.major = 0;
                         dinode.minor = 0;
+                        dinode.flags = 0;
                         dinode.size = 0;
                         for addr_mut in dinode.addrs.iter_mut() {
                             *addr_mut = 0;
@@ -327,7 +421,7 @@ impl Xv6FileSystem {
                         dinode.dump_into(inode_slice).map_err(|_| libc::EIO)?;
                         handle.journal_write(&mut bh);
                         if !first || inum > curr_most_recent {
-                            LAST_INODE.store(inum as usize, Ordering::SeqCst);
+                            self.last_inode.store(inum as usize, Ordering::SeqCst);
                         }
                         return self.iget(inum as u64);
                     }
@@ -336,7 +430,7 @@ impl Xv6FileSystem {
                 if block_inum >= num_inodes as usize {
                     block_inum = 0;
                     first = false;
-                    FIRST_I_LOOP.store(false, Ordering::SeqCst);
+                    self.first_i_loop.store(false, Ordering::SeqCst);
                 }
             };
         }
@@ -363,6 +457,7 @@ impl Xv6FileSystem {
         disk_inode.inode_type = internals.inode_type;
         disk_inode.major = internals.major;
         disk_inode.minor = internals.minor;
+        disk_inode.flags = internals.flags;
         disk_inode.nlink = internals.nlink;
         disk_inode.size = internals.size;
         disk_inode.addrs.copy_from_slice(&internals.addrs);
@@ -384,7 +479,11 @@ impl Xv6FileSystem {
             let disk = self.disk.as_ref().unwrap();
             let dev_id = disk.as_raw_fd();
             let mut inode_nref = inode.nref.write().unwrap();
-            if *inode_nref > 0 && inode.dev == dev_id as u32 && inode.inum == inum as u32 {
+            // Unused inodes stay mapped as long as they are valid, including those handed over
+            // by the previous module in an upgrade. Nobody else holds an unused inode, so
+            // checking that it is valid doesn't block.
+            let cached = *inode_nref > 0 || inode.internals.read().unwrap().valid != 0;
+            if cached && inode.dev == dev_id as u32 && inode.inum == inum as u32 {
                 *inode_nref += 1;
 
                 return Ok(CachedInode {
@@ -406,6 +505,10 @@ impl Xv6FileSystem {
                     let mut new_inode_int = inode.internals.write().map_err(|_| libc::EIO)?;
                     new_inode_int.valid = 0;
                 }
+                // The slot may still hold an unused inode that is mapped
+                if map.get(&(inode.inum as u64)) == Some(&idx) {
+                    map.remove(&(inode.inum as u64));
+                }
                 inode.dev = dev_id as u32;
                 inode.inum = inum as u32;
                 *inode.nref.write().unwrap() = 1;
@@ -455,6 +558,7 @@ impl Xv6FileSystem {
                 internals.inode_type = disk_inode.inode_type;
                 internals.major = disk_inode.major;
                 internals.minor = disk_inode.minor;
+                internals.flags = disk_inode.flags;
                 internals.nlink = disk_inode.nlink;
                 internals.size = disk_inode.size;
                 internals.addrs.copy_from_slice(&disk_inode.addrs);
@@ -472,7 +576,7 @@ impl Xv6FileSystem {
         {
             let inode_guard = self.ilock(inode.idx, &icache, inode.inum)?;
             let mut internals = inode_guard.internals.write().map_err(|_| libc::EIO)?;
-            if internals.valid != 0 && internals.nlink == 0 {
+            if internals.valid != 0 && internals.nlink == 0 && self.log.is_some() {
                 let r;
                 {
                     let dinode_lock = icache.get(inode.idx).ok_or(libc::EIO)?;
@@ -494,14 +598,17 @@ impl Xv6FileSystem {
         let dinode = dinode_lock.read().map_err(|_| {libc::EIO})?;
         let mut dinode_nref = dinode.nref.write().unwrap();
         *dinode_nref -= 1;
-        if *dinode_nref == 0 {
+        // Unused inodes stay in the cache until their slot is taken by another inode, unless
+        // they were freed
+        if *dinode_nref == 0 && dinode.internals.read().map_err(|_| libc::EIO)?.valid == 0 {
             map.remove(&(inode.inum as u64));
         }
         return Ok(());
     }
 
     // handle should be Some(_) if this bmap is part of a transaction, None otherwise
-    // bmap may have to write to disk during some read operation
+    // bmap may have to write to disk during some read operation, which fails with EROFS on a
+    // read-only mount
     fn bmap(&self, inode: &mut InodeInternal, blk_idx: usize, handle: Option<&Handle>) -> Result<u32, libc::c_int> {
         let mut idx = blk_idx;
 
@@ -512,7 +619,10 @@ impl Xv6FileSystem {
             if *addr == 0 {
                 let h = match handle {
                     Some(_) => handle.unwrap(),
-                    None => new_tx.get_or_insert_with(|| self.log.as_ref().unwrap().begin_op(2)),
+                    None => {
+                        let log = self.log.as_ref().ok_or(libc::EROFS)?;
+                        new_tx.get_or_insert_with(|| log.begin_op(2))
+                    },
                 };
                 return self.balloc(h).map(|blk_id| {
                     *addr = blk_id;
@@ -529,7 +639,10 @@ impl Xv6FileSystem {
             if *ind_blk_id == 0 {
                 let h = match handle {
                     Some(_) => handle.unwrap(),
-                    None => new_tx.get_or_insert_with(|| self.log.as_ref().unwrap().begin_op(5)),
+                    None => {
+                        let log = self.log.as_ref().ok_or(libc::EROFS)?;
+                        new_tx.get_or_insert_with(|| log.begin_op(5))
+                    },
                 };
                 self.balloc(h).map(|blk_id| {
                     *ind_blk_id = blk_id;
@@ -549,7 +662,10 @@ impl Xv6FileSystem {
                 // need to allocate blk
                 let h = match handle {
                     Some(_) => handle.unwrap(),
-                    None => new_tx.get_or_insert_with(|| self.log.as_ref().unwrap().begin_op(3)),
+                    None => {
+                        let log = self.log.as_ref().ok_or(libc::EROFS)?;
+                        new_tx.get_or_insert_with(|| log.begin_op(3))
+                    },
                 };
                 h.get_write_access(&bh);
                 let b_data = bh.data_mut();
@@ -574,7 +690,10 @@ impl Xv6FileSystem {
             if *dind_blk_id == 0 {
                 let h = match handle {
                     Some(_) => handle.unwrap(),
-                    None => new_tx.get_or_insert_with(|| self.log.as_ref().unwrap().begin_op(2)),
+                    None => {
+                        let log = self.log.as_ref().ok_or(libc::EROFS)?;
+                        new_tx.get_or_insert_with(|| log.begin_op(2))
+                    },
                 };
                 self.balloc(h).map(|blk_id| {
                     *dind_blk_id = blk_id;
@@ -594,7 +713,10 @@ impl Xv6FileSystem {
             if cell == 0 {
                 let h = match handle {
                     Some(_) => handle.unwrap(),
-                    None => new_tx.get_or_insert_with(|| self.log.as_ref().unwrap().begin_op(6)),
+                    None => {
+                        let log = self.log.as_ref().ok_or(libc::EROFS)?;
+                        new_tx.get_or_insert_with(|| log.begin_op(6))
+                    },
                 };
                 h.get_write_access(&bh);
                 let b_data = bh.data_mut();
@@ -619,7 +741,10 @@ impl Xv6FileSystem {
             if dcell == 0 {
                 let h = match handle {
                     Some(_) => handle.unwrap(),
-                    None => new_tx.get_or_insert_with(|| self.log.as_ref().unwrap().begin_op(3)),
+                    None => {
+                        let log = self.log.as_ref().ok_or(libc::EROFS)?;
+                        new_tx.get_or_insert_with(|| log.begin_op(3))
+                    },
                 };
                 h.get_write_access(&dbh);
                 let db_data = dbh.data_mut();
@@ -721,6 +846,154 @@ impl Xv6FileSystem {
         return Err(libc::EIO);
     }
 
+    // Remove the mapping of a file block and return the disk block it pointed to, 0 if unmapped.
+    // The caller is responsible for freeing the returned block.
+    fn bunmap(&self, inode: &mut InodeInternal, blk_idx: usize, handle: &Handle) -> Result<u32, libc::c_int> {
+        let mut idx = blk_idx;
+
+        if idx < NDIRECT as usize {
+            let addr = inode.addrs.get_mut(idx).ok_or(libc::EIO)?;
+            let blk_id = *addr;
+            *addr = 0;
+            return Ok(blk_id);
+        }
+
+        idx -= NDIRECT as usize;
+        let disk = self.disk.as_ref().unwrap();
+        let ind_blk_id: u32;
+        if idx < NINDIRECT as usize {
+            // indirect block
+            ind_blk_id = *inode.addrs.get(NDIRECT as usize).ok_or(libc::EIO)?;
+        } else if idx < (MAXFILE - NDIRECT) as usize {
+            idx -= NINDIRECT as usize;
+            // double indirect block
+            let dind_blk_id = *inode.addrs.get(NDIRECT as usize + 1).ok_or(libc::EIO)?;
+            if dind_blk_id == 0 {
+                return Ok(0);
+            }
+
+            let bh = disk.bread(dind_blk_id as u64)?;
+            let b_data = bh.data();
+            let dind_idx = idx / NINDIRECT as usize;
+
+            let mut cell_data = [0; 4];
+            cell_data.copy_from_slice(&b_data[dind_idx * 4 .. (dind_idx + 1) * 4]);
+            ind_blk_id = u32::from_ne_bytes(cell_data);
+            idx %= NINDIRECT as usize;
+        } else {
+            return Err(libc::EIO);
+        }
+
+        if ind_blk_id == 0 {
+            return Ok(0);
+        }
+
+        let mut bh = disk.bread(ind_blk_id as u64)?;
+        let mut cell_data = [0; 4];
+        cell_data.copy_from_slice(&bh.data()[idx * 4 .. (idx + 1) * 4]);
+        let cell = u32::from_ne_bytes(cell_data);
+        if cell != 0 {
+            handle.get_write_access(&bh);
+            let b_data = bh.data_mut();
+            b_data[idx * 4 .. (idx + 1) * 4].copy_from_slice(&[0; 4]);
+            handle.journal_write(&mut bh);
+        }
+        return Ok(cell);
+    }
+
+    // Allocate the file blocks [start_blk, end_blk) that are not backed by a disk block yet.
+    // Already allocated blocks are left untouched.
+    pub fn fallocatei(
+        &self,
+        internals: &mut InodeInternal,
+        inum: u32,
+        start_blk: usize,
+        end_blk: usize,
+        handle: &Handle,
+    ) -> Result<(), libc::c_int> {
+        if end_blk > MAXFILE as usize {
+            return Err(libc::EFBIG);
+        }
+        for blk_idx in start_blk..end_blk {
+            self.bmap(internals, blk_idx, Some(handle))?;
+        }
+        return self.iupdate(internals, inum, handle);
+    }
+
+    // Deallocate the byte range [off, off + n) of the file without changing its size.
+    // Blocks that are fully covered are unmapped and returned to the bitmap, the partial blocks at
+    // either end of the range are zeroed in place.
+    pub fn punchi(
+        &self,
+        internals: &mut InodeInternal,
+        inum: u32,
+        off: usize,
+        n: usize,
+        handle: &Handle,
+    ) -> Result<(), libc::c_int> {
+        if off + n < off {
+            return Err(libc::EINVAL);
+        }
+        let end = min(off + n, (MAXFILE as usize) * BSIZE);
+        let mut curr = off;
+
+        while curr < end {
+            let blk_idx = curr / BSIZE;
+            let blk_off = curr % BSIZE;
+            let m = min(end - curr, BSIZE - blk_off);
+
+            if m == BSIZE {
+                let blk_id = self.bunmap(internals, blk_idx, handle)?;
+                if blk_id != 0 {
+                    self.bfree(blk_id as usize, handle)?;
+                }
+            } else {
+                match self.bmap_noalloc(internals, blk_idx) {
+                    Ok(blk_id) => {
+                        let disk = self.disk.as_ref().unwrap();
+                        let mut bh = disk.bread(blk_id as u64)?;
+                        handle.get_write_access(&bh);
+                        let b_data = bh.data_mut();
+                        b_data[blk_off..blk_off + m].fill(0);
+                        handle.journal_write(&mut bh);
+                    }
+                    Err(libc::ENOENT) => {}
+                    Err(x) => return Err(x),
+                }
+            }
+            curr += m;
+        }
+
+        return self.iupdate(internals, inum, handle);
+    }
+
+    // Find the first data block (or hole) at or after off. Blocks that are not mapped are holes,
+    // and the end of the file is an implicit hole.
+    pub fn seeki(&self, internals: &InodeInternal, off: usize, data: bool) -> Result<usize, libc::c_int> {
+        let size = internals.size as usize;
+        if off >= size {
+            return Err(libc::ENXIO);
+        }
+
+        let mut blk_idx = off / BSIZE;
+        let end_blk = (size + BSIZE - 1) / BSIZE;
+        while blk_idx < end_blk {
+            let mapped = match self.bmap_noalloc(internals, blk_idx) {
+                Ok(_) => true,
+                Err(libc::ENOENT) => false,
+                Err(x) => return Err(x),
+            };
+            if mapped == data {
+                return Ok(max(off, blk_idx * BSIZE));
+            }
+            blk_idx += 1;
+        }
+
+        if data {
+            return Err(libc::ENXIO);
+        }
+        return Ok(size);
+    }
 
     pub fn itrunc(&self, inode: &mut CachedInode, internals: &mut InodeInternal, handle: &Handle) -> Result<(), libc::c_int> {
         for i in 0..NDIRECT as usize {
@@ -814,6 +1087,151 @@ impl Xv6FileSystem {
         return Ok(attr);
     }
 
+    // Walk the entries of the directory `nodeid` starting after `offset`, calling `filler` with the
+    // inode number, offset, cached inode and name of each entry. Stops early once `filler` returns
+    // true, meaning the reply buffer is full. Entries for which `filler` fails with ENOENT are
+    // being unlinked and are skipped.
+    pub fn readdiri<F>(
+        &self,
+        nodeid: u64,
+        internals: &InodeInternal,
+        offset: i64,
+        mut filler: F,
+    ) -> Result<(), libc::c_int>
+    where
+        F: FnMut(u64, i64, &InodeInternal, &str) -> Result<bool, libc::c_int>,
+    {
+        let icache = self.ilock_cache.as_ref().unwrap();
+        let hroot_len = mem::size_of::<Htree_root>();
+        let hindex_len = mem::size_of::<Htree_index>();
+        let hentry_len = mem::size_of::<Htree_entry>();
+        let de_len = mem::size_of::<Xv6fsDirent>();
+        let mut hroot_vec: Vec<u8> = vec![0; hroot_len];
+        let mut buf_off = 1;
+        let mut inarg_offset = offset as usize;
+        let hroot_slice = hroot_vec.as_mut_slice();
+
+        // try reading directory root
+        let mut root = Htree_root::new();
+        match self.readi(hroot_slice, 0, hroot_len, internals) {
+            Ok(x) if x != hroot_len => return Err(1),
+            Err(x) => return Err(x),
+            _ => {}
+        };
+        root.extract_from(hroot_slice).map_err(|_| libc::EIO)?;
+
+        let num_indeces = root.ind_entries;
+        if num_indeces == 0 {
+            return Ok(());
+        }
+
+        let mut hie_vec: Vec<u8> = vec![0; hentry_len];
+        let hie_slice = hie_vec.as_mut_slice();
+
+        // check the index pointers stored in the root node
+        for off in (hroot_len..(num_indeces as usize * hentry_len) + hroot_len).step_by(hentry_len)
+        {
+            if off >= BSIZE {
+                break;
+            }
+            let mut hie = Htree_entry::new();
+            match self.readi(hie_slice, off as usize, hentry_len, internals) {
+                Ok(x) if x != hentry_len => return Err(1),
+                Err(x) => return Err(x),
+                _ => {}
+            }
+            hie.extract_from(hie_slice).map_err(|_| libc::EIO)?;
+
+            // check the index block for entries
+            let mut ind_arr_vec: Vec<u8> = vec![0; BSIZE];
+            let ind_arr_slice = ind_arr_vec.as_mut_slice();
+            match self.readi(
+                ind_arr_slice,
+                BSIZE * hie.block as usize,
+                BSIZE,
+                internals,
+            ) {
+                Ok(x) if x != BSIZE => return Err(1),
+                Err(x) => return Err(x),
+                _ => {}
+            }
+
+            let ind_header_slice = &mut ind_arr_slice[0..hindex_len];
+            let mut index = Htree_index::new();
+            index.extract_from(ind_header_slice).map_err(|_| libc::EIO)?;
+
+            let num_entries = index.entries;
+
+            if num_entries == 0 {
+                continue;
+            }
+
+            // check entries in index node
+            for ine_idx in
+                (hindex_len..hindex_len + (hentry_len * index.entries as usize)).step_by(hentry_len)
+            {
+                let ine_slice = &mut ind_arr_slice[ine_idx..ine_idx + hentry_len];
+                let mut ine = Htree_entry::new();
+                ine.extract_from(ine_slice).map_err(|_| libc::EIO)?;
+                let dblock_off = ine.block;
+                if dblock_off == 0 {
+                    continue;
+                }
+                let mut de_block_vec: Vec<u8> = vec![0; BSIZE];
+                let de_block_slice = de_block_vec.as_mut_slice();
+
+                self.readi(
+                    de_block_slice,
+                    BSIZE * dblock_off as usize,
+                    BSIZE,
+                    internals,
+                )?;
+
+                // check dirents in leaf node
+                for de_off in (0..BSIZE).step_by(de_len) {
+                    let de_slice = &mut de_block_slice[de_off..de_off + de_len];
+                    let mut de = Xv6fsDirent::new();
+                    de.extract_from(de_slice).map_err(|_| libc::EIO)?;
+
+                    if de.inum == 0 {
+                        continue;
+                    }
+                    if inarg_offset >= 1 {
+                        inarg_offset -= 1;
+                        buf_off += 1;
+                        continue;
+                    }
+
+                    let name_str = match str::from_utf8(&de.name) {
+                        Ok(x) => x,
+                        Err(_) => "",
+                    };
+
+                    // The directory itself is already locked by the caller
+                    let filled = if de.inum as u64 == nodeid {
+                        filler(nodeid, buf_off, internals, name_str)
+                    } else {
+                        let entry = self.iget(de.inum as u64)?;
+                        let entry_inode_guard = self.ilock(entry.idx, &icache, de.inum)?;
+                        let entry_internals = entry_inode_guard
+                            .internals
+                            .read()
+                            .map_err(|_| libc::EIO)?;
+                        filler(de.inum as u64, buf_off, &entry_internals, name_str)
+                    };
+                    match filled {
+                        Ok(true) => return Ok(()),
+                        // Entry is being unlinked, skip it but keep the offsets stable
+                        Ok(false) | Err(libc::ENOENT) => {}
+                        Err(x) => return Err(x),
+                    }
+                    buf_off += 1;
+                }
+            }
+        }
+        return Ok(());
+    }
+
     pub fn readi(
         &self,
         buf: &mut [u8],
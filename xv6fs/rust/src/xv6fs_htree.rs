@@ -129,9 +129,9 @@ impl SliceHasher {
         SliceHasher { state: 5381 as u32 }
     }
 
-    // djb2_hash
+    // djb2_hash, which relies on wrapping arithmetic
     pub fn write_u8(&mut self, i: u8) {
-        self.state = ((self.state << 5) + self.state) + i as u32;
+        self.state = (self.state << 5).wrapping_add(self.state).wrapping_add(i as u32);
     }
 }
 
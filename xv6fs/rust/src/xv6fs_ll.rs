@@ -25,8 +25,9 @@ use alloc::string::ToString;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 
+use core::cmp::min;
 use core::mem;
-use core::str;
+use core::sync::atomic::{AtomicBool, AtomicUsize};
 
 use bento_utils::BentoFilesystem;
 
@@ -55,10 +56,44 @@ use crate::xv6fs_file::*;
 use crate::xv6fs_htree::*;
 use crate::xv6fs_utils::*;
 
+/// Version of `Xv6State`. This must be bumped whenever the fields of `Xv6State` change.
+///
+/// Version 3 added the inode flags, which grew the on-disk inode. Modules with older versions
+/// use the old disk layout, so their state can't be migrated.
+const XV6_STATE_VERSION: u32 = 3;
+
 #[cfg_attr(not(feature = "user"), derive(Serialize, Deserialize))]
 pub struct Xv6State {
     diskname: String,
     log: Option<Journal>,
+    cache: Option<Xv6CacheState>,
+}
+
+/// In-memory caches of the filesystem, handed over during a live upgrade so the new module
+/// doesn't start with a cold inode cache.
+#[cfg_attr(not(feature = "user"), derive(Serialize, Deserialize))]
+pub struct Xv6CacheState {
+    pub inodes: Vec<Xv6CachedInode>,
+    pub icache_map: Vec<(u64, usize)>,
+    pub last_block: usize,
+    pub last_inode: usize,
+    pub first_i_loop: bool,
+}
+
+/// An entry of the inode cache.
+#[cfg_attr(not(feature = "user"), derive(Serialize, Deserialize))]
+pub struct Xv6CachedInode {
+    pub idx: usize,
+    pub inum: u32,
+    pub nref: i32,
+    pub valid: i32,
+    pub inode_type: u16,
+    pub major: u16,
+    pub minor: u16,
+    pub flags: u32,
+    pub nlink: u16,
+    pub size: u64,
+    pub addrs: Vec<u32>,
 }
 
 pub struct Xv6FileSystem {
@@ -70,6 +105,37 @@ pub struct Xv6FileSystem {
     pub ialloc_lock: Option<RwLock<usize>>,
     pub balloc_lock: Option<RwLock<usize>>,
     pub diskname: Option<String>,
+    // Mounted with `-o ro`. The journal is not opened, so it is not replayed either.
+    pub read_only: bool,
+    // Where the block and inode allocators resume scanning
+    pub last_block: AtomicUsize,
+    pub last_inode: AtomicUsize,
+    pub first_i_loop: AtomicBool,
+}
+
+impl Xv6FileSystem {
+    pub const fn new() -> Self {
+        Xv6FileSystem {
+            log: None,
+            sb: None,
+            disk: None,
+            ilock_cache: None,
+            icache_map: None,
+            ialloc_lock: None,
+            balloc_lock: None,
+            diskname: None,
+            read_only: false,
+            last_block: AtomicUsize::new(0),
+            last_inode: AtomicUsize::new(0),
+            first_i_loop: AtomicBool::new(true),
+        }
+    }
+}
+
+impl Default for Xv6FileSystem {
+    fn default() -> Self {
+        Xv6FileSystem::new()
+    }
 }
 
 impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
@@ -88,6 +154,7 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
         &mut self,
         _req: &Request,
         devname: &OsStr,
+        _opts: &MountOptions,
         fc_info: &mut FuseConnInfo,
     ) -> Result<(), i32> {
         fc_info.proto_major = BENTO_KERNEL_VERSION;
@@ -119,13 +186,16 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
             bmapstart: 0,
         };
         self.sb = Some(sb_lock);
+        self.read_only = fc_info.read_only;
 
-        self.iinit();
+        self.iinit()?;
 
         fc_info.want |= FUSE_BIG_WRITES;
         fc_info.want |= FUSE_ATOMIC_O_TRUNC;
         fc_info.want |= FUSE_WRITEBACK_CACHE;
         fc_info.want |= FUSE_PARALLEL_DIROPS;
+        fc_info.want |= FUSE_DO_READDIRPLUS;
+        fc_info.want |= FUSE_READDIRPLUS_AUTO;
 
         fc_info.max_readahead = max_readahead;
         fc_info.max_background = 0;
@@ -142,7 +212,6 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
     }
 
     fn bento_open(&self, _req: &Request, nodeid: u64, flags: u32, reply: ReplyOpen) {
-        let log = self.log.as_ref().unwrap();
         let inode = match self.iget(nodeid) {
             Ok(x) => x,
             Err(x) => {
@@ -174,6 +243,13 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
         }
 
         if flags & libc::O_TRUNC as u32 != 0 {
+            let log = match self.log.as_ref() {
+                Some(x) => x,
+                None => {
+                    reply.error(libc::EROFS);
+                    return;
+                }
+            };
             let handle = log.begin_op(2);
             internals.size = 0;
             if let Err(x) = self.iupdate(&internals, inode.inum, &handle) {
@@ -540,194 +616,69 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
             reply.error(libc::ENOTDIR);
             return;
         }
-        let hroot_len = mem::size_of::<Htree_root>();
-        let hindex_len = mem::size_of::<Htree_index>();
-        let hentry_len = mem::size_of::<Htree_entry>();
-        let de_len = mem::size_of::<Xv6fsDirent>();
-        let mut hroot_vec: Vec<u8> = vec![0; hroot_len];
-        let mut buf_off = 1;
-        let mut inarg_offset = offset as usize;
-        let hroot_slice = hroot_vec.as_mut_slice();
+        // Only the file type is needed here, the attributes are left to READDIRPLUS
+        let result = self.readdiri(nodeid, &internals, offset, |ino, off, entry, name| {
+            let kind = match entry.inode_type {
+                0 => return Err(libc::ENOENT),
+                T_DIR => FileType::Directory,
+                T_LNK => FileType::Symlink,
+                _ => FileType::RegularFile,
+            };
+            Ok(reply.add(ino, off, kind, name))
+        });
+        match result {
+            Ok(_) => reply.ok(),
+            Err(x) => reply.error(x),
+        }
+    }
 
-        // try reading directory root
-        let mut root = Htree_root::new();
-        match self.readi(hroot_slice, 0, hroot_len, &internals) {
-            Ok(x) if x != hroot_len => {
-                reply.error(1);
-                return;
-            }
+    #[allow(unused_mut)]
+    fn bento_readdirplus(
+        &self,
+        _req: &Request,
+        nodeid: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectoryPlus,
+    ) {
+        let inode = match self.iget(nodeid) {
+            Ok(x) => x,
             Err(x) => {
                 reply.error(x);
                 return;
             }
-            _ => {}
         };
-        if root.extract_from(hroot_slice).is_err() {
-            reply.error(libc::EIO);
-            return;
-        }
 
-        let num_indeces = root.ind_entries;
-        if num_indeces == 0 {
-            reply.ok();
-            return;
-        }
-
-        let mut hie_vec: Vec<u8> = vec![0; hentry_len];
-        let hie_slice = hie_vec.as_mut_slice();
-
-        // check the index pointers stored in the root node
-        for off in (hroot_len..(num_indeces as usize * hentry_len) + hroot_len).step_by(hentry_len)
-        {
-            if off >= BSIZE {
-                break;
-            }
-            let mut hie = Htree_entry::new();
-            match self.readi(hie_slice, off as usize, hentry_len, &internals) {
-                Ok(x) if x != hentry_len => {
-                    reply.error(1);
-                    return;
-                }
-                Err(x) => {
-                    reply.error(x);
-                    return;
-                }
-                _ => {}
-            }
-            if hie.extract_from(hie_slice).is_err() {
-                reply.error(libc::EIO);
+        let icache = self.ilock_cache.as_ref().unwrap();
+        let inode_guard = match self.ilock(inode.idx, &icache, inode.inum) {
+            Ok(x) => x,
+            Err(x) => {
+                reply.error(x);
                 return;
             }
-
-            // check the index block for entries
-            let mut ind_arr_vec: Vec<u8> = vec![0; BSIZE];
-            let ind_arr_slice = ind_arr_vec.as_mut_slice();
-            match self.readi(
-                ind_arr_slice,
-                BSIZE * hie.block as usize,
-                BSIZE,
-                &internals,
-            ) {
-                Ok(x) if x != BSIZE => {
-                    reply.error(1);
-                    return;
-                }
-                Err(x) => {
-                    reply.error(x);
-                    return;
-                }
-                _ => {}
-            }
-
-            let ind_header_slice = &mut ind_arr_slice[0..hindex_len];
-            let mut index = Htree_index::new();
-            if index.extract_from(ind_header_slice).is_err() {
+        };
+        let internals = match inode_guard.internals.read() {
+            Ok(x) => x,
+            Err(_) => {
                 reply.error(libc::EIO);
                 return;
             }
+        };
 
-            let num_entries = index.entries;
-
-            if num_entries == 0 {
-                continue;
-            }
-
-            // check entries in index node
-            for ine_idx in
-                (hindex_len..hindex_len + (hentry_len * index.entries as usize)).step_by(hentry_len)
-            {
-                let ine_slice = &mut ind_arr_slice[ine_idx..ine_idx + hentry_len];
-                let mut ine = Htree_entry::new();
-                if ine.extract_from(ine_slice).is_err() {
-                    reply.error(libc::EIO);
-                    return;
-                }
-                let dblock_off = ine.block;
-                if dblock_off == 0 {
-                    continue;
-                }
-                let mut de_block_vec: Vec<u8> = vec![0; BSIZE];
-                let de_block_slice = de_block_vec.as_mut_slice();
-
-                match self.readi(
-                    de_block_slice,
-                    BSIZE * dblock_off as usize,
-                    BSIZE,
-                    &internals,
-                ) {
-                    Err(x) => {
-                        reply.error(x);
-                        return;
-                    }
-                    _ => {}
-                }
-
-                // check dirents in leaf node
-                for de_off in (0..BSIZE).step_by(de_len) {
-                    let de_slice = &mut de_block_slice[de_off..de_off + de_len];
-                    let mut de = Xv6fsDirent::new();
-                    if de.extract_from(de_slice).is_err() {
-                        reply.error(libc::EIO);
-                        return;
-                    }
-
-                    if de.inum == 0 {
-                        continue;
-                    }
-                    if inarg_offset >= 1 {
-                        inarg_offset -= 1;
-                        buf_off += 1;
-                        continue;
-                    }
-
-                    let i_type;
-                    if de.inum as u64 == nodeid {
-                        i_type = FileType::Directory;
-                    } else {
-                        let entry = match self.iget(de.inum as u64) {
-                            Ok(x) => x,
-                            Err(x) => {
-                                reply.error(x);
-                                return;
-                            }
-                        };
-
-                        let entry_inode_guard = match self.ilock(entry.idx, &icache, de.inum) {
-                            Ok(x) => x,
-                            Err(x) => {
-                                reply.error(x);
-                                return;
-                            }
-                        };
-                        let entry_internals = match entry_inode_guard.internals.read() {
-                            Ok(x) => x,
-                            Err(_) => {
-                                reply.error(libc::EIO);
-                                return;
-                            }
-                        };
-
-                        i_type = match entry_internals.inode_type {
-                            T_DIR => FileType::Directory,
-                            T_LNK => FileType::Symlink,
-                            _ => FileType::RegularFile,
-                        };
-                    }
-
-                    let name_str = match str::from_utf8(&de.name) {
-                        Ok(x) => x,
-                        Err(_) => "",
-                    };
-
-                    if reply.add(de.inum as u64, buf_off, i_type, name_str) {
-                        reply.ok();
-                        return;
-                    }
-                    buf_off += 1;
-                }
-            }
+        // Check if inode is directory
+        if internals.inode_type != T_DIR {
+            reply.error(libc::ENOTDIR);
+            return;
+        }
+        let attr_valid = Timespec::new(1, 999999999);
+        let result = self.readdiri(nodeid, &internals, offset, |ino, off, entry, name| {
+            let attr = self.stati(ino, entry)?;
+            Ok(reply.add(ino, off, &attr, &attr_valid, 0, name))
+        });
+        match result {
+            Ok(_) => reply.ok(),
+            Err(x) => reply.error(x),
         }
-        reply.ok();
     }
 
     fn bento_create(
@@ -903,14 +854,16 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
     }
 
     fn bento_fsync(&self, _req: &Request, _ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
-        let log = self.log.as_ref().unwrap();
-        log.force_commit();
+        if let Some(log) = self.log.as_ref() {
+            log.force_commit();
+        }
         reply.ok();
     }
 
     fn bento_fsyncdir(&self, _req: &Request, _ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
-        let log = self.log.as_ref().unwrap();
-        log.force_commit();
+        if let Some(log) = self.log.as_ref() {
+            log.force_commit();
+        }
         reply.ok();
     }
 
@@ -1475,16 +1428,446 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
     
     }
 
+    fn bento_fallocate(
+        &self,
+        _req: &Request,
+        nodeid: u64,
+        _fh: u64,
+        offset: i64,
+        length: i64,
+        mode: u32,
+        reply: ReplyEmpty,
+    ) {
+        let keep_size = mode & libc::FALLOC_FL_KEEP_SIZE as u32 != 0;
+        let punch_hole = mode & libc::FALLOC_FL_PUNCH_HOLE as u32 != 0;
+        let supported = (libc::FALLOC_FL_KEEP_SIZE | libc::FALLOC_FL_PUNCH_HOLE) as u32;
+        if mode & !supported != 0 || (punch_hole && !keep_size) {
+            reply.error(libc::EOPNOTSUPP);
+            return;
+        }
+        if offset < 0 || length <= 0 {
+            reply.error(libc::EINVAL);
+            return;
+        }
+
+        let log = self.log.as_ref().unwrap();
+        let inode = match self.iget(nodeid) {
+            Ok(x) => x,
+            Err(x) => {
+                reply.error(x);
+                return;
+            }
+        };
+
+        let icache = self.ilock_cache.as_ref().unwrap();
+        let inode_guard = match self.ilock(inode.idx, &icache, inode.inum) {
+            Ok(x) => x,
+            Err(x) => {
+                reply.error(x);
+                return;
+            }
+        };
+        let mut internals = match inode_guard.internals.write() {
+            Ok(x) => x,
+            Err(_) => {
+                reply.error(libc::EIO);
+                return;
+            }
+        };
+
+        // Check if inode is a file
+        if internals.inode_type != T_FILE {
+            reply.error(libc::EISDIR);
+            return;
+        }
+
+        let off = offset as usize;
+        let end = off + length as usize;
+
+        // Preallocate and punch in chunks small enough to fit a single transaction, like
+        // bento_write
+        let max_blocks = (MAXOPBLOCKS - 1 - 1 - 2) / 2;
+
+        if punch_hole {
+            // Nothing is mapped past the largest file
+            let end = min(end, (MAXFILE as usize) * BSIZE);
+            let mut curr = off;
+            while curr < end {
+                // Chunks end on a block boundary, so only the first and last block of the range
+                // can be partial
+                let chunk_end = min((curr / BSIZE + max_blocks) * BSIZE, end);
+                let handle = log.begin_op(MAXOPBLOCKS as u32);
+                if let Err(x) = self.punchi(&mut internals, inode.inum, curr, chunk_end - curr, &handle) {
+                    reply.error(x);
+                    return;
+                }
+                curr = chunk_end;
+            }
+            reply.ok();
+            return;
+        }
+
+        if end > (MAXFILE as usize) * BSIZE {
+            reply.error(libc::EFBIG);
+            return;
+        }
+
+        let mut blk_idx = off / BSIZE;
+        let end_blk = (end + BSIZE - 1) / BSIZE;
+        while blk_idx < end_blk {
+            let chunk_end = min(blk_idx + max_blocks, end_blk);
+            let handle = log.begin_op(MAXOPBLOCKS as u32);
+            if let Err(x) = self.fallocatei(&mut internals, inode.inum, blk_idx, chunk_end, &handle) {
+                reply.error(x);
+                return;
+            }
+            blk_idx = chunk_end;
+        }
+
+        if !keep_size && end as u64 > internals.size {
+            let handle = log.begin_op(2);
+            internals.size = end as u64;
+            if let Err(x) = self.iupdate(&internals, inode.inum, &handle) {
+                reply.error(x);
+                return;
+            }
+        }
+        reply.ok();
+    }
+
+    fn bento_lseek(
+        &self,
+        _req: &Request,
+        nodeid: u64,
+        _fh: u64,
+        offset: i64,
+        whence: u32,
+        reply: ReplyLseek,
+    ) {
+        let data = match whence as i32 {
+            libc::SEEK_DATA => true,
+            libc::SEEK_HOLE => false,
+            _ => {
+                reply.error(libc::EINVAL);
+                return;
+            }
+        };
+        if offset < 0 {
+            reply.error(libc::ENXIO);
+            return;
+        }
+
+        let inode = match self.iget(nodeid) {
+            Ok(x) => x,
+            Err(x) => {
+                reply.error(x);
+                return;
+            }
+        };
+
+        let icache = self.ilock_cache.as_ref().unwrap();
+        let inode_guard = match self.ilock(inode.idx, &icache, inode.inum) {
+            Ok(x) => x,
+            Err(x) => {
+                reply.error(x);
+                return;
+            }
+        };
+        let internals = match inode_guard.internals.read() {
+            Ok(x) => x,
+            Err(_) => {
+                reply.error(libc::EIO);
+                return;
+            }
+        };
+
+        // Check if inode is a file
+        if internals.inode_type != T_FILE {
+            reply.error(libc::EISDIR);
+            return;
+        }
+
+        match self.seeki(&internals, offset as usize, data) {
+            Ok(x) => reply.offset(x as i64),
+            Err(x) => reply.error(x),
+        }
+    }
+
+    fn bento_ioctl(
+        &self,
+        req: &Request,
+        nodeid: u64,
+        _fh: u64,
+        _flags: u32,
+        cmd: u32,
+        in_data: &[u8],
+        out_size: u32,
+        reply: ReplyIoctl,
+    ) {
+        match cmd {
+            XV6FS_IOC_FORCE_COMMIT => {
+                if !req.capable(CAP_SYS_ADMIN) {
+                    reply.error(libc::EPERM);
+                    return;
+                }
+                let log = match self.log.as_ref() {
+                    Some(x) => x,
+                    None => {
+                        reply.error(libc::EROFS);
+                        return;
+                    }
+                };
+                match log.force_commit() {
+                    0 => reply.ioctl(0, &[]),
+                    x => reply.error(-x),
+                }
+                return;
+            }
+            XV6FS_IOC_GET_ALLOC_STATE => {
+                let state = self.alloc_state();
+                let mut buf = [0; mem::size_of::<Xv6fsAllocState>()];
+                if (out_size as usize) < buf.len() || state.dump_into(&mut buf).is_err() {
+                    reply.error(libc::EINVAL);
+                    return;
+                }
+                reply.ioctl(0, &buf);
+                return;
+            }
+            FS_IOC_GETFLAGS | FS_IOC32_GETFLAGS | FS_IOC_SETFLAGS | FS_IOC32_SETFLAGS => {}
+            _ => {
+                reply.error(libc::ENOTTY);
+                return;
+            }
+        }
+
+        let inode = match self.iget(nodeid) {
+            Ok(x) => x,
+            Err(x) => {
+                reply.error(x);
+                return;
+            }
+        };
+
+        let icache = self.ilock_cache.as_ref().unwrap();
+        let inode_guard = match self.ilock(inode.idx, &icache, inode.inum) {
+            Ok(x) => x,
+            Err(x) => {
+                reply.error(x);
+                return;
+            }
+        };
+        let mut internals = match inode_guard.internals.write() {
+            Ok(x) => x,
+            Err(_) => {
+                reply.error(libc::EIO);
+                return;
+            }
+        };
+
+        // The flags are stored but not enforced.
+        if cmd == FS_IOC_GETFLAGS || cmd == FS_IOC32_GETFLAGS {
+            if out_size < 4 {
+                reply.error(libc::EINVAL);
+                return;
+            }
+            let flags = internals.flags;
+            let mut buf: Vec<u8> = vec![0; out_size as usize];
+            buf[0..4].copy_from_slice(&flags.to_ne_bytes());
+            reply.ioctl(0, &buf);
+            return;
+        }
+
+        // Like chattr on other filesystems, only the owner may change the flags
+        let attr = match self.stati(nodeid, &internals) {
+            Ok(x) => x,
+            Err(x) => {
+                reply.error(x);
+                return;
+            }
+        };
+        if let Err(x) = check_owner(&attr, req) {
+            reply.error(x);
+            return;
+        }
+        if in_data.len() < 4 {
+            reply.error(libc::EINVAL);
+            return;
+        }
+        let mut flag_data = [0; 4];
+        flag_data.copy_from_slice(&in_data[0..4]);
+        let flags = u32::from_ne_bytes(flag_data);
+
+        let log = match self.log.as_ref() {
+            Some(x) => x,
+            None => {
+                reply.error(libc::EROFS);
+                return;
+            }
+        };
+        let handle = log.begin_op(1);
+        internals.flags = flags;
+        match self.iupdate(&internals, inode.inum, &handle) {
+            Ok(_) => reply.ioctl(0, &[]),
+            Err(x) => reply.error(x),
+        }
+    }
+
+    fn bento_copy_file_range(
+        &self,
+        _req: &Request,
+        ino_in: u64,
+        _fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        _fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        flags: u32,
+        reply: ReplyWrite,
+    ) {
+        if flags != 0 || offset_in < 0 || offset_out < 0 {
+            reply.error(libc::EINVAL);
+            return;
+        }
+        let n = min(len, u32::MAX as u64) as usize;
+        let mut off_in = offset_in as usize;
+        let mut off_out = offset_out as usize;
+        if ino_in == ino_out && off_in < off_out + n && off_out < off_in + n {
+            reply.error(libc::EINVAL);
+            return;
+        }
+
+        // Copy in chunks that fit in a single transaction, like bento_write. The source is only
+        // locked while reading a chunk, so the two inodes are never locked at the same time.
+        let log = self.log.as_ref().unwrap();
+        let icache = self.ilock_cache.as_ref().unwrap();
+        let max = ((MAXOPBLOCKS - 1 - 1 - 2) / 2) * BSIZE;
+        let mut buf_vec: Vec<u8> = vec![0; min(n, max)];
+        let mut i = 0;
+        let mut err = 0;
+        while i < n {
+            let n1 = min(n - i, max);
+            let buf_slice = &mut buf_vec.as_mut_slice()[0..n1];
+
+            let inode_in = match self.iget(ino_in) {
+                Ok(x) => x,
+                Err(x) => {
+                    err = x;
+                    break;
+                }
+            };
+            let inode_in_guard = match self.ilock(inode_in.idx, &icache, inode_in.inum) {
+                Ok(x) => x,
+                Err(x) => {
+                    err = x;
+                    break;
+                }
+            };
+            let internals_in = match inode_in_guard.internals.read() {
+                Ok(x) => x,
+                Err(_) => {
+                    err = libc::EIO;
+                    break;
+                }
+            };
+
+            // Check if inode is a file
+            if internals_in.inode_type != T_FILE {
+                err = libc::EISDIR;
+                break;
+            }
+            // Nothing left to copy past the end of the source
+            if off_in >= internals_in.size as usize {
+                break;
+            }
+            let read_rs = match self.readi(buf_slice, off_in, n1, &internals_in) {
+                Ok(x) => x,
+                Err(x) => {
+                    err = x;
+                    break;
+                }
+            };
+            drop(internals_in);
+            drop(inode_in_guard);
+            if read_rs == 0 {
+                break;
+            }
+
+            let inode_out = match self.iget(ino_out) {
+                Ok(x) => x,
+                Err(x) => {
+                    err = x;
+                    break;
+                }
+            };
+            let inode_out_guard = match self.ilock(inode_out.idx, &icache, inode_out.inum) {
+                Ok(x) => x,
+                Err(x) => {
+                    err = x;
+                    break;
+                }
+            };
+            let mut internals_out = match inode_out_guard.internals.write() {
+                Ok(x) => x,
+                Err(_) => {
+                    err = libc::EIO;
+                    break;
+                }
+            };
+
+            // Check if inode is a file
+            if internals_out.inode_type != T_FILE {
+                err = libc::EISDIR;
+                break;
+            }
+
+            let handle = log.begin_op(MAXOPBLOCKS as u32);
+            let r = match self.writei(
+                &buf_slice[0..read_rs],
+                off_out,
+                read_rs,
+                &mut internals_out,
+                inode_out.inum,
+                &handle,
+                true,
+            ) {
+                Ok(x) => x,
+                Err(x) => {
+                    err = x;
+                    break;
+                }
+            };
+
+            off_in += r;
+            off_out += r;
+            i += r;
+            if r < n1 {
+                break;
+            }
+        }
+        // Like write, an error is only reported if nothing was copied
+        if i == 0 && err != 0 {
+            reply.error(err);
+            return;
+        }
+        reply.written(i as u32);
+    }
+
     fn bento_update_prepare(&mut self) -> Option<Xv6State> {
         let mut state = Xv6State {
             diskname: self.diskname.as_ref().unwrap().clone(),
             log: None,
+            cache: self.icache_snapshot().ok(),
         };
         mem::swap(&mut self.log, &mut state.log);
         Some(state)
     }
 
-    fn bento_update_transfer(&mut self, state_opt: Option<Xv6State>) {
+    fn bento_update_version(&self) -> u32 {
+        XV6_STATE_VERSION
+    }
+
+    fn bento_update_transfer(&mut self, state_opt: Option<Xv6State>) -> Result<(), i32> {
         if let Some(mut state) = state_opt {
             let disk = Arc::new(Disk::new(state.diskname.as_str(), 4096));
             self.disk = Some(disk);
@@ -1499,10 +1882,24 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
                 bmapstart: 0,
             };
             self.sb = Some(sb_lock);
+            // A read-only mount has no journal to hand over.
+            self.read_only = state.log.is_none();
             mem::swap(&mut self.log, &mut state.log);
 
-            self.iinit();
+            if let Err(x) = self.iinit() {
+                // The journal is handed back to the previous module on rollback, so it must not
+                // be destroyed here.
+                mem::forget(self.log.take());
+                return Err(x);
+            }
+            if let Some(cache) = state.cache {
+                if let Err(x) = self.icache_restore(cache) {
+                    mem::forget(self.log.take());
+                    return Err(x);
+                }
+            }
         }
+        return Ok(());
     }
 }
 
@@ -36,6 +36,16 @@ pub const MAXOPBLOCKS: usize = 32;
 #[allow(dead_code)]
 pub const LOGSIZE: usize = 1023;
 
+// ioctl commands
+pub const FS_IOC_GETFLAGS: u32 = 0x80086601;
+pub const FS_IOC_SETFLAGS: u32 = 0x40086602;
+pub const FS_IOC32_GETFLAGS: u32 = 0x80046601;
+pub const FS_IOC32_SETFLAGS: u32 = 0x40046602;
+// _IO('x', 1): commit the running transaction to disk
+pub const XV6FS_IOC_FORCE_COMMIT: u32 = 0x00007801;
+// _IOR('x', 2, Xv6fsAllocState): read the allocator state
+pub const XV6FS_IOC_GET_ALLOC_STATE: u32 = 0x80107802;
+
 pub fn iblock(i: usize, sb: &Xv6fsSB) -> usize {
     i / IPB + sb.inodestart as usize
 }
@@ -53,6 +63,11 @@ pub struct Xv6fsInode {
     pub nlink: u16,
     pub size: u64,
     pub addrs: [u32; NDIRECT as usize + 2],
+    // Inode flags (FS_IOC_GETFLAGS/FS_IOC_SETFLAGS). Images made by mkfs have 0 there, which
+    // means no flags.
+    pub flags: u32,
+    // Pads the inode to 128 bytes, so a block holds a whole number of inodes.
+    pub reserved: [u32; 15],
 }
 
 impl Xv6fsInode {
@@ -64,6 +79,8 @@ impl Xv6fsInode {
             nlink: 0,
             size: 0,
             addrs: [0; NDIRECT as usize + 2],
+            flags: 0,
+            reserved: [0; 15],
         }
     }
 }
@@ -80,6 +97,16 @@ pub struct Xv6fsSB {
     pub bmapstart: u32,
 }
 
+// Allocator state reported by XV6FS_IOC_GET_ALLOC_STATE
+#[repr(C)]
+#[derive(DataBlock)]
+pub struct Xv6fsAllocState {
+    pub last_block: u32,
+    pub last_inode: u32,
+    pub nblocks: u32,
+    pub ninodes: u32,
+}
+
 #[repr(C)]
 #[derive(DataBlock)]
 pub struct Xv6fsDirent {
@@ -5,7 +5,6 @@ extern crate alloc;
 extern crate bento_utils;
 extern crate core;
 extern crate datablock;
-extern crate fuse;
 extern crate serde;
 extern crate time;
 
@@ -20,42 +19,333 @@ pub mod xv6fs_utils;
 use alloc::sync::Arc;
 
 use std::env;
-use std::ffi::OsStr;
+use std::process;
+use std::time::Duration;
 use xv6fs_ll::Xv6FileSystem;
 use xv6fs_utils::BSIZE;
 
 use bento_utils::*;
-use fuse::*;
+use bento_utils::fuse;
 use std::path::Path;
-use time::Timespec;
 
-impl_filesystem!(Xv6FileSystem);
+// Get a numeric mount option, exiting with an error if its value isn't a number.
+fn u64_option(opts: &MountOptions, name: &str) -> Option<u64> {
+    match opts.get_u64(name) {
+        Ok(x) => x,
+        Err(_) => {
+            eprintln!("invalid value for mount option {}: {}", name, opts.get_str(name).unwrap_or(""));
+            process::exit(1);
+        }
+    }
+}
 
 fn main() {
     env_logger::init();
     let disk_name = env::args_os().nth(1).unwrap();
-    let fsname_arg_str = format!("fsname={}", disk_name.to_str().unwrap());
-    let fsname_arg = fsname_arg_str.as_str();
-    let disk = Disk::new(disk_name.to_str().unwrap(), BSIZE as u64);
-    let fs = Xv6FileSystem {
-        log: None,
-        sb: None,
-        disk: Some(Arc::new(disk)),
-        ilock_cache: None,
-        icache_map: None,
-        ialloc_lock: None,
-        balloc_lock: None,
-        diskname: Some(disk_name.to_str().unwrap().to_string()),
+    let opts = MountOptions::from_args();
+    let cache_blocks = match u64_option(&opts, "cache_blocks") {
+        Some(x) => x as usize,
+        None => DEFAULT_CACHE_BLOCKS,
     };
+    // A writeback interval of 0 disables background writeback
+    let writeback = match u64_option(&opts, "writeback_ms") {
+        Some(0) => None,
+        Some(x) => Some(Duration::from_millis(x)),
+        None => Some(DEFAULT_WRITEBACK_INTERVAL),
+    };
+    let disk = Disk::with_cache(disk_name.to_str().unwrap(), BSIZE as u64, cache_blocks, writeback);
+    let mut fs = Xv6FileSystem::new();
+    fs.disk = Some(Arc::new(disk));
+    fs.diskname = Some(disk_name.to_str().unwrap().to_string());
 
     let mountpoint = env::args_os().nth(2).unwrap();
-    let mut opts_arr = vec!["-o", fsname_arg];
-    if let Some(arg) = env::args_os().nth(3) {
-        if arg.to_str().unwrap() == "blkdev" {
-            opts_arr.append(&mut vec!["-o", "blkdev"]);
+    let session = Session::new(fs, &disk_name, Path::new(&mountpoint), opts).unwrap();
+    session.run().unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bento_utils::fuse::FileType;
+    use datablock::DataBlock;
+    use std::mem;
+    use xv6fs_htree::Htree_root;
+    use xv6fs_ll::Xv6State;
+    use xv6fs_utils::*;
+
+    // A small image with the layout mkfs uses: boot block, superblock, log, inodes, bitmap and
+    // data. The log has room for the largest transaction the journal writes.
+    const FSSIZE: usize = 2048;
+    const NINODES: usize = 200;
+    const NLOG: usize = 130;
+    const ROOTINO: u32 = 1;
+
+    type Driver = TestDriver<'static, Xv6FileSystem, Xv6State, Xv6State>;
+
+    fn write_inode(image: &mut [u8], sb: &Xv6fsSB, inum: u32, inode: &Xv6fsInode) {
+        let offset = iblock(inum as usize, sb) * BSIZE
+            + (inum as usize % IPB) * mem::size_of::<Xv6fsInode>();
+        inode.dump_into(&mut image[offset..]).unwrap();
+    }
+
+    // Make an empty filesystem with only the root directory.
+    fn mkfs() -> Vec<u8> {
+        let mut image = vec![0; FSSIZE * BSIZE];
+        let ninodeblocks = NINODES / IPB + 1;
+        let nbitmap = FSSIZE / BPB + 1;
+        let nmeta = 2 + NLOG + ninodeblocks + nbitmap;
+        let sb = Xv6fsSB {
+            size: FSSIZE as u32,
+            nblocks: (FSSIZE - nmeta) as u32,
+            ninodes: NINODES as u32,
+            nlog: NLOG as u32,
+            logstart: 2,
+            inodestart: (2 + NLOG) as u32,
+            bmapstart: (2 + NLOG + ninodeblocks) as u32,
+        };
+        sb.dump_into(&mut image[BSIZE..]).unwrap();
+
+        // The root directory is an htree without any entries besides "." and ".."
+        let rootblock = nmeta;
+        let mut root = Htree_root::new();
+        root.dot.inum = ROOTINO;
+        root.dot.name[0] = b'.';
+        root.dotdot.inum = ROOTINO;
+        root.dotdot.name[..2].copy_from_slice(b"..");
+        root.blocks = 1;
+        root.dump_into(&mut image[rootblock * BSIZE..]).unwrap();
+        let mut inode = Xv6fsInode::new();
+        inode.inode_type = T_DIR;
+        inode.nlink = 2;
+        inode.size = mem::size_of::<Htree_root>() as u64;
+        inode.addrs[0] = rootblock as u32;
+        write_inode(&mut image, &sb, ROOTINO, &inode);
+
+        // Mark the metadata and the root directory block as used
+        let bmap = sb.bmapstart as usize * BSIZE;
+        for block in 0..=rootblock {
+            image[bmap + block / 8] |= 1 << (block % 8);
+        }
+        image
+    }
+
+    // Mount the image in `backend`, without background writeback so only the filesystem decides
+    // when blocks are written.
+    fn mount(backend: Box<dyn BlockBackend>) -> Driver {
+        let disk: Disk =
+            Disk::from_backend_with_cache(backend, BSIZE as u64, DEFAULT_CACHE_BLOCKS, None);
+        let mut fs = Xv6FileSystem::new();
+        fs.disk = Some(Arc::new(disk));
+        fs.diskname = Some("xv6.img".to_string());
+        let mut driver = TestDriver::new(fs);
+        driver.init("xv6.img", "").unwrap();
+        driver
+    }
+
+    fn contents(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn memory_backend_round_trip() {
+        let memory = Arc::new(MemoryBackend::from_vec(mkfs()));
+        let data = contents(3 * BSIZE + 100);
+        {
+            let mut driver = mount(Box::new(Arc::clone(&memory)));
+            let dir = driver.mkdir(1, "dir", 0o755).unwrap();
+            assert_eq!(dir.kind, FileType::Directory);
+            let (file, opened) = driver
+                .create(dir.ino, "file", 0o644, libc::O_RDWR as u32)
+                .unwrap();
+            for (i, chunk) in data.chunks(1000).enumerate() {
+                let offset = (i * 1000) as i64;
+                let written = driver.write(file.ino, opened.fh, offset, chunk, 0).unwrap();
+                assert_eq!(written as usize, chunk.len());
+            }
+            assert_eq!(driver.read(file.ino, opened.fh, 0, data.len() as u32).unwrap(), data);
+
+            let root = driver.opendir(1, 0).unwrap();
+            let entries = driver.readdir_all(1, root.fh).unwrap();
+            assert!(entries
+                .iter()
+                .any(|entry| entry.ino == dir.ino && entry.kind == FileType::Directory));
+            let entries = driver.readdirplus(dir.ino, 0, 0).unwrap();
+            let entry = entries.iter().find(|entry| entry.ino == file.ino).unwrap();
+            assert_eq!(entry.attr.kind, FileType::RegularFile);
+            assert_eq!(entry.attr.size, data.len() as u64);
+            driver.destroy();
+        }
+
+        // Everything is still there after mounting the image again
+        let driver = mount(Box::new(Arc::clone(&memory)));
+        let dir = driver.lookup(1, "dir").unwrap();
+        let file = driver.lookup(dir.ino, "file").unwrap();
+        assert_eq!(file.size, data.len() as u64);
+        let opened = driver.open(file.ino, libc::O_RDONLY as u32).unwrap();
+        assert_eq!(driver.read(file.ino, opened.fh, 0, data.len() as u32).unwrap(), data);
+        assert_eq!(driver.lookup(1, "missing").unwrap_err(), libc::ENOENT);
+    }
+
+    // Inode flags have their own field in the inode, so all 32 bits survive a remount.
+    #[test]
+    fn inode_flags_persist() {
+        let memory = Arc::new(MemoryBackend::from_vec(mkfs()));
+        let flags: u32 = 0x0008_0010;
+        {
+            let mut driver = mount(Box::new(Arc::clone(&memory)));
+            let (file, opened) = driver
+                .create(1, "file", 0o644, libc::O_RDWR as u32)
+                .unwrap();
+            let (_, out) = driver
+                .ioctl(file.ino, opened.fh, 0, FS_IOC_GETFLAGS, &[], 4)
+                .unwrap();
+            assert_eq!(out, 0u32.to_ne_bytes());
+            driver
+                .ioctl(file.ino, opened.fh, 0, FS_IOC_SETFLAGS, &flags.to_ne_bytes(), 0)
+                .unwrap();
+            driver.destroy();
         }
+
+        let driver = mount(Box::new(Arc::clone(&memory)));
+        let file = driver.lookup(1, "file").unwrap();
+        let (_, out) = driver.ioctl(file.ino, 0, 0, FS_IOC_GETFLAGS, &[], 4).unwrap();
+        assert_eq!(out, flags.to_ne_bytes());
     }
-    let options = opts_arr.iter().map(OsStr::new).collect::<Vec<&OsStr>>();
 
-    fuse::mount(fs, &mountpoint, &options).unwrap();
+    // A `read_only` mount rejects changes with EROFS before they reach the filesystem, but can
+    // still be read.
+    #[test]
+    fn read_only_mount() {
+        let memory = Arc::new(MemoryBackend::from_vec(mkfs()));
+        {
+            let mut driver = mount(Box::new(Arc::clone(&memory)));
+            let (file, opened) = driver
+                .create(1, "file", 0o644, libc::O_RDWR as u32)
+                .unwrap();
+            driver.write(file.ino, opened.fh, 0, b"data", 0).unwrap();
+            driver.destroy();
+        }
+
+        let disk: Disk = Disk::from_backend_with_cache(
+            Box::new(Arc::clone(&memory)),
+            BSIZE as u64,
+            DEFAULT_CACHE_BLOCKS,
+            None,
+        );
+        let mut fs = Xv6FileSystem::new();
+        fs.disk = Some(Arc::new(disk));
+        fs.diskname = Some("xv6.img".to_string());
+        let mut driver: Driver = TestDriver::new(fs);
+        assert!(driver.init("xv6.img", "read_only").unwrap().read_only);
+        let file = driver.lookup(1, "file").unwrap();
+        assert_eq!(driver.mkdir(1, "dir", 0o755).unwrap_err(), libc::EROFS);
+        assert_eq!(driver.unlink(1, "file").unwrap_err(), libc::EROFS);
+        assert_eq!(
+            driver.open(file.ino, libc::O_WRONLY as u32).unwrap_err(),
+            libc::EROFS
+        );
+        assert_eq!(
+            driver
+                .ioctl(file.ino, 0, 0, FS_IOC_SETFLAGS, &0u32.to_ne_bytes(), 0)
+                .unwrap_err(),
+            libc::EROFS
+        );
+        let opened = driver.open(file.ino, libc::O_RDONLY as u32).unwrap();
+        assert_eq!(driver.read(file.ino, opened.fh, 0, 4).unwrap(), b"data");
+        driver.ioctl(file.ino, 0, 0, FS_IOC_GETFLAGS, &[], 4).unwrap();
+    }
+
+    // The inode cache is handed to the new module in an upgrade, so inodes that were used before
+    // are not read from disk again.
+    #[test]
+    fn upgrade_keeps_inode_cache() {
+        let path = env::temp_dir().join(format!("xv6fs-upgrade-{}.img", std::process::id()));
+        std::fs::write(&path, mkfs()).unwrap();
+        let name = path.to_str().unwrap();
+        let data = contents(BSIZE + 100);
+
+        let mut fs = Xv6FileSystem::new();
+        let disk = Disk::with_cache(name, BSIZE as u64, DEFAULT_CACHE_BLOCKS, None);
+        fs.disk = Some(Arc::new(disk));
+        fs.diskname = Some(name.to_string());
+        let mut driver: Driver = TestDriver::new(fs);
+        driver.init(name, "").unwrap();
+        let (file, opened) = driver
+            .create(1, "file", 0o644, libc::O_RDWR as u32)
+            .unwrap();
+        driver.write(file.ino, opened.fh, 0, &data, 0).unwrap();
+        driver.fsync(file.ino, opened.fh, false).unwrap();
+        let state = driver.fs_mut().bento_update_prepare();
+
+        let mut upgraded = Xv6FileSystem::new();
+        upgraded.bento_update_transfer(state).unwrap();
+        let disk = Arc::clone(upgraded.disk.as_ref().unwrap());
+        let before = disk.cache_stats();
+        {
+            let inode = upgraded.iget(file.ino).unwrap();
+            let icache = upgraded.ilock_cache.as_ref().unwrap();
+            let inode_guard = upgraded.ilock(inode.idx, icache, inode.inum).unwrap();
+            let internals = inode_guard.internals.read().unwrap();
+            assert_eq!(internals.size, data.len() as u64);
+        }
+        let after = disk.cache_stats();
+        assert_eq!(after.hits + after.misses, before.hits + before.misses);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    // Every image the disk can hold after a crash during a small workload recovers to a
+    // consistent tree, where the file holds a prefix of what was written to it.
+    #[test]
+    fn crash_consistency() {
+        let recording = Arc::new(RecordingBackend::new(mkfs()));
+        let data = contents(2 * BSIZE);
+        {
+            let mut driver = mount(Box::new(Arc::clone(&recording)));
+            // Mounting replays the empty log, which isn't part of the workload
+            recording.checkpoint();
+            let dir = driver.mkdir(1, "dir", 0o755).unwrap();
+            let (file, opened) = driver
+                .create(dir.ino, "file", 0o644, libc::O_RDWR as u32)
+                .unwrap();
+            for (i, chunk) in data.chunks(BSIZE).enumerate() {
+                let offset = (i * BSIZE) as i64;
+                driver.write(file.ino, opened.fh, offset, chunk, 0).unwrap();
+            }
+            driver.fsync(file.ino, opened.fh, false).unwrap();
+            driver.destroy();
+        }
+
+        // Every epoch of the workload is small enough to try all of its crash states
+        let states = recording.crash_states(DEFAULT_MAX_REORDER).unwrap();
+        let mount = |disk: Disk| {
+            let mut fs = Xv6FileSystem::new();
+            fs.disk = Some(Arc::new(disk));
+            fs.diskname = Some("xv6.img".to_string());
+            fs
+        };
+        let check = |_: &CrashState, driver: &Driver| -> Result<(), String> {
+            let dir = match driver.lookup(1, "dir") {
+                Ok(dir) => dir,
+                Err(libc::ENOENT) => return Ok(()),
+                Err(err) => return Err(format!("lookup dir failed with errno {}", err)),
+            };
+            let file = match driver.lookup(dir.ino, "file") {
+                Ok(file) => file,
+                Err(libc::ENOENT) => return Ok(()),
+                Err(err) => return Err(format!("lookup dir/file failed with errno {}", err)),
+            };
+            let opened = driver
+                .open(file.ino, libc::O_RDONLY as u32)
+                .map_err(|err| format!("open dir/file failed with errno {}", err))?;
+            let read = driver
+                .read(file.ino, opened.fh, 0, data.len() as u32)
+                .map_err(|err| format!("read dir/file failed with errno {}", err))?;
+            if read.len() as u64 != file.size || !data.starts_with(&read) {
+                return Err(format!("dir/file holds {} bytes it was never written", read.len()));
+            }
+            Ok(())
+        };
+        let checked = check_crash_states(states, BSIZE as u64, mount, check).unwrap();
+        assert!(checked > 0);
+    }
 }
@@ -193,9 +193,10 @@ impl Xv6FileSystem {
         return Ok(());
     }
 
-    pub fn iinit(&mut self) {
-        if self.readsb().is_err() {
+    pub fn iinit(&mut self) -> Result<(), libc::c_int> {
+        if let Err(x) = self.readsb() {
             println!("Unable to read super block from disk.");
+            return Err(x);
         }
 
         let mut inode_vec: Vec<RwLock<Inode>> = Vec::with_capacity(NINODE);
@@ -213,7 +214,8 @@ impl Xv6FileSystem {
         if self.log.is_none() {
             let disk_ref = Arc::clone(self.disk.as_ref().unwrap());
             let disk_ref2 = Arc::clone(self.disk.as_ref().unwrap());
-            let log = Journal::new_from_disk(disk_ref, disk_ref2, sb.logstart as u64, sb.nlog as i32, BSIZE as i32).unwrap();
+            let log = Journal::new_from_disk(disk_ref, disk_ref2, sb.logstart as u64, sb.nlog as i32, BSIZE as i32)
+                .ok_or(libc::EIO)?;
             self.log = Some(log);
         }
         println!(
@@ -226,6 +228,7 @@ impl Xv6FileSystem {
             sb.inodestart,
             sb.bmapstart
             );
+        return Ok(());
     }
 
     pub fn ialloc<'a>(&'a self, i_type: u16, handle: &Handle) -> Result<CachedInode<'a>, libc::c_int> {
@@ -223,15 +223,11 @@ impl Journal {
         let mut guard = self.log_globl.lock().unwrap();
         let log: &mut Log = &mut *guard;
         if log.lh.n as usize >= LOGSIZE || log.lh.n >= log.size {
-            // TODO: panic
-            println!("log_write: panic: too big transaction {}", log.lh.n);
-            loop {}
+            panic!("log_write: too big transaction {}", log.lh.n);
         }
     
         if log.outstanding < 1 {
-            // TODO: panic
-            println!("log_write: panic: log_write outside of transaction");
-            loop {}
+            panic!("log_write: log_write outside of transaction");
         }
     
         let mut i: usize = 0;
@@ -301,6 +297,9 @@ impl Journal {
         }
         jsuper.dump_into(jsuper_slice).map_err(|_| libc::EIO)?;
         bh.mark_buffer_dirty();
+        // The buffer cache doesn't write the header when it is released, so the commit is only
+        // on disk once it is synced here.
+        bh.sync_dirty_buffer();
         
         Ok(())
     }
@@ -317,6 +316,8 @@ impl Journal {
                 dst_slice.copy_from_slice(src_slice);
                 dst_bh.mark_buffer_dirty();
                 dst_bh.sync_dirty_buffer();
+                // The block is installed, so the buffer cache may write it back again
+                self.disk.unpin_block(*dst_blk_id as u64);
             };
         }
         
@@ -326,8 +327,11 @@ impl Journal {
     pub fn recover_from_log(&self, log: &mut Log) -> Result<(), libc::c_int> {
         self.read_head(log)?;
         self.install_trans(log)?;
+        // The installed blocks must be on disk before the header that points at them is cleared
+        self.disk.sync_all()?;
         log.lh.n = 0;
-        self.write_head(log)
+        self.write_head(log)?;
+        self.disk.sync_all()
     }
      
     fn write_log(&self, log: &mut Log) -> Result<(), libc::c_int> {
@@ -348,14 +352,21 @@ impl Journal {
     }
      
     // Commits in-log transaction, persists data to disk.
+    //
+    // The disk may reorder writes between flushes, so each step is flushed before the next one
+    // starts: the log before the header that commits it, the header before the blocks are
+    // installed, and the installed blocks before the header is cleared and the log reused.
     fn commit(&self, log: &mut Log) -> Result<(), libc::c_int> {
         if log.lh.n > 0 {
             self.write_log(log)?;
+            self.disk.sync_all()?;
             self.write_head(log)?;
+            self.disk.sync_all()?;
             self.install_trans(log)?;
+            self.disk.sync_all()?;
             log.lh.n = 0;
-            let res = self.write_head(log);
-            return res;
+            self.write_head(log)?;
+            return self.disk.sync_all();
         } else {
             return Ok(());
         }
@@ -378,6 +389,8 @@ impl Handle<'_> {
 
     pub fn journal_write(&self, bh: &mut BufferHead) -> i32 {
         bh.mark_buffer_dirty();
+        // Keep the buffer cache from writing the block before the transaction is in the log
+        self.xv6_log.disk.pin_block(bh.blk_no);
         self.xv6_log.log_write(bh.blk_no as u32);
         0
     }
@@ -391,8 +404,7 @@ impl Drop for Handle<'_> {
             let log: &mut Log = &mut *guard;
             log.outstanding -= 1;
             if log.committing != 0 {
-                println!("PANIC: log_committing");
-                loop {}
+                panic!("log_committing");
             }
 
             if log.outstanding == 0 {
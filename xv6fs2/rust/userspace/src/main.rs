@@ -4,7 +4,6 @@ extern crate alloc;
 extern crate bento_utils;
 extern crate core;
 extern crate datablock;
-extern crate fuse;
 extern crate serde;
 extern crate time;
 
@@ -19,23 +18,41 @@ pub mod xv6fs_utils;
 use alloc::sync::Arc;
 
 use std::env;
-use std::ffi::OsStr;
+use std::process;
+use std::time::Duration;
 use xv6fs_ll::Xv6FileSystem;
 use xv6fs_utils::BSIZE;
 
-use fuse::*;
 use bento_utils::*;
-use time::Timespec;
+use bento_utils::fuse;
 use std::path::Path;
 
-impl_filesystem!(Xv6FileSystem);
+// Get a numeric mount option, exiting with an error if its value isn't a number.
+fn u64_option(opts: &MountOptions, name: &str) -> Option<u64> {
+    match opts.get_u64(name) {
+        Ok(x) => x,
+        Err(_) => {
+            eprintln!("invalid value for mount option {}: {}", name, opts.get_str(name).unwrap_or(""));
+            process::exit(1);
+        }
+    }
+}
 
 fn main() {
     env_logger::init();
     let disk_name = env::args_os().nth(1).unwrap();
-    let fsname_arg_str = format!("fsname={}", disk_name.to_str().unwrap());
-    let fsname_arg = fsname_arg_str.as_str();
-    let disk = Disk::new(disk_name.to_str().unwrap(), BSIZE as u64);
+    let opts = MountOptions::from_args();
+    let cache_blocks = match u64_option(&opts, "cache_blocks") {
+        Some(x) => x as usize,
+        None => DEFAULT_CACHE_BLOCKS,
+    };
+    // A writeback interval of 0 disables background writeback
+    let writeback = match u64_option(&opts, "writeback_ms") {
+        Some(0) => None,
+        Some(x) => Some(Duration::from_millis(x)),
+        None => Some(DEFAULT_WRITEBACK_INTERVAL),
+    };
+    let disk = Disk::with_cache(disk_name.to_str().unwrap(), BSIZE as u64, cache_blocks, writeback);
     let fs = Xv6FileSystem {
         log: None,
         sb: None,
@@ -48,13 +65,6 @@ fn main() {
     };
 
     let mountpoint = env::args_os().nth(2).unwrap();
-    let mut opts_arr = vec!["-o", fsname_arg];
-    if let Some(arg) = env::args_os().nth(3) {
-        if arg.to_str().unwrap() == "blkdev" {
-            opts_arr.append(&mut vec!["-o", "blkdev"]);
-        }
-    }
-    let options = opts_arr.iter().map(OsStr::new).collect::<Vec<&OsStr>>();
-
-    fuse::mount(fs, &mountpoint, &options).unwrap();
+    let session = Session::new(fs, &disk_name, Path::new(&mountpoint), opts).unwrap();
+    session.run().unwrap();
 }
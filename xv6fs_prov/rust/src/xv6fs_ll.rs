@@ -92,6 +92,7 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
         &mut self,
         _req: &Request,
         devname: &OsStr,
+        _opts: &MountOptions,
         fc_info: &mut FuseConnInfo,
     ) -> Result<(), i32> {
         fc_info.proto_major = BENTO_KERNEL_VERSION;
@@ -124,7 +125,7 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
         };
         self.sb = Some(sb_lock);
 
-        self.iinit();
+        self.iinit()?;
 
         fc_info.want |= FUSE_BIG_WRITES;
         fc_info.want |= FUSE_ATOMIC_O_TRUNC;
@@ -1575,7 +1576,7 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
         Some(state)
     }
 
-    fn bento_update_transfer(&mut self, state_opt: Option<Xv6State>) {
+    fn bento_update_transfer(&mut self, state_opt: Option<Xv6State>) -> Result<(), i32> {
         if let Some(mut state) = state_opt {
             let disk = Arc::new(Disk::new(state.diskname.as_str(), 4096));
             self.disk = Some(disk);
@@ -1592,9 +1593,15 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
             self.sb = Some(sb_lock);
             mem::swap(&mut self.log, &mut state.log);
 
-            self.iinit();
+            if let Err(x) = self.iinit() {
+                // The journal is handed back to the previous module on rollback, so it must not
+                // be destroyed here.
+                mem::forget(self.log.take());
+                return Err(x);
+            }
             self.create_prov_file();
         }
+        return Ok(());
     }
 }
 
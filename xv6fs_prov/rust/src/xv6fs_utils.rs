@@ -53,6 +53,8 @@ pub struct Xv6fsInode {
     pub nlink: u16,
     pub size: u64,
     pub addrs: [u32; NDIRECT as usize + 2],
+    pub flags: u32,
+    pub reserved: [u32; 15],
 }
 
 impl Xv6fsInode {
@@ -64,6 +66,8 @@ impl Xv6fsInode {
             nlink: 0,
             size: 0,
             addrs: [0; NDIRECT as usize + 2],
+            flags: 0,
+            reserved: [0; 15],
         }
     }
 }